@@ -41,6 +41,12 @@ impl Camera {
               // Camera::SimpleCamera(inner.with_aspect_ratio(aspect_ratio))
         }
     }
+    pub fn medium_id(&self) -> Option<usize> {
+        match self {
+            Camera::ProjectiveCamera(inner) => inner.medium_id,
+            Camera::RealisticCamera(inner) => inner.medium_id,
+        }
+    }
     pub fn get_surface(&self) -> Option<&Instance> {
         match self {
             Camera::ProjectiveCamera(inner) => inner.get_surface(),
@@ -54,16 +60,19 @@ impl Camera {
         }
     }
 
-    pub fn eval_we(&self, _lambda: f32, normal: Vec3, from: Point3, to: Point3) -> (f32, PDF) {
-        // from is on surface of camera
-        // (0.01, 1.0.into())
-        // (1.0, 0.01.into())
-        let direction = to - from;
-        if direction * normal < 0.0 {
-            // connection from opposite side.
-            (0.0, 0.0.into())
-        } else {
-            (1.0, 1.0.into())
+    pub fn lens_area(&self) -> f32 {
+        match self {
+            Camera::ProjectiveCamera(inner) => inner.lens_area(),
+            Camera::RealisticCamera(inner) => inner.lens_area(),
+        }
+    }
+
+    // importance emitted from the point on the lens towards to, and the solid angle pdf of sampling that direction.
+    // connections from behind the camera get no importance.
+    pub fn eval_we(&self, lambda: f32, from: Point3, to: Point3) -> (f32, PDF) {
+        match self {
+            Camera::ProjectiveCamera(inner) => inner.eval_we(lambda, from, to),
+            Camera::RealisticCamera(inner) => inner.eval_we(lambda, from, to),
         }
     }
 
@@ -19,6 +19,8 @@ pub struct ProjectiveCamera {
     lens_radius: f32,
     t0: f32,
     t1: f32,
+    // the medium the camera sits in. None means it's in the world medium
+    pub medium_id: Option<usize>,
 }
 
 impl ProjectiveCamera {
@@ -87,6 +89,7 @@ impl ProjectiveCamera {
             lens_radius: aperture / 2.0,
             t0,
             t1,
+            medium_id: None,
         }
     }
     pub fn get_surface(&self) -> Option<&Instance> {
@@ -105,7 +108,7 @@ impl ProjectiveCamera {
         // circular aperture/lens
         let rd: Vec3 = self.lens_radius * random_in_unit_disk(sampler.draw_2d());
         let offset = self.u * rd.x() + self.v * rd.y();
-        let time: f32 = self.t0 + random() * (self.t1 - self.t0);
+        let time: f32 = self.t0 + sampler.draw_1d().x * (self.t1 - self.t0);
         let ray_origin: Point3 = self.origin + offset;
 
         let point_on_plane = self.lower_left_corner + s * self.horizontal + t * self.vertical;
@@ -159,6 +162,10 @@ impl ProjectiveCamera {
             Some((u, v))
         }
     }
+    pub fn with_medium(mut self, medium_id: Option<usize>) -> Self {
+        self.medium_id = medium_id;
+        self
+    }
     pub fn with_aspect_ratio(mut self, aspect_ratio: f32) -> Self {
         assert!(self.focal_distance > 0.0 && self.vfov > 0.0);
         let theta: f32 = self.vfov.to_radians();
@@ -170,8 +177,39 @@ impl ProjectiveCamera {
             - self.w * self.focal_distance;
         self.horizontal = self.u * 2.0 * half_width * self.focal_distance;
         self.vertical = self.v * 2.0 * half_height * self.focal_distance;
+        self.half_width = half_width;
+        self.half_height = half_height;
         self
     }
+    // area of the lens, or 1 for a pinhole so that it cancels out of the importance
+    pub fn lens_area(&self) -> f32 {
+        if self.lens_radius > 0.0 {
+            PI * self.lens_radius * self.lens_radius
+        } else {
+            1.0
+        }
+    }
+    // importance emitted from the point on the lens towards to, along with the solid angle pdf of get_ray sampling that direction.
+    // importance is normalized so that it integrates to 1 over the lens and the film, which is placed at distance 1 from the lens,
+    // so that a ray through the film with radiance L ends up contributing L to the image.
+    pub fn eval_we(&self, lambda: f32, from: Point3, to: Point3) -> (f32, PDF) {
+        let direction = (to - from).normalized();
+        let cos_theta = direction * self.direction;
+        if cos_theta <= 0.0 {
+            return (0.0, 0.0.into());
+        }
+        if self
+            .get_pixel_for_ray(Ray::new(from, direction), lambda)
+            .is_none()
+        {
+            return (0.0, 0.0.into());
+        }
+        let film_area = 4.0 * self.half_width * self.half_height;
+        let cos2_theta = cos_theta * cos_theta;
+        let we = 1.0 / (self.lens_area() * film_area * cos2_theta * cos2_theta);
+        let pdf = 1.0 / (film_area * cos2_theta * cos_theta);
+        (we, pdf.into())
+    }
 }
 
 unsafe impl Send for ProjectiveCamera {}
@@ -180,6 +218,7 @@ unsafe impl Sync for ProjectiveCamera {}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sampler::IndependentSampler;
 
     #[test]
     fn test_camera() {
@@ -208,6 +247,37 @@ mod tests {
         println!("{:?}", pixel_uv);
     }
 
+    #[test]
+    fn test_importance_normalization() {
+        let camera: ProjectiveCamera = ProjectiveCamera::new(
+            Point3::new(-5.0, 0.0, 0.0),
+            Point3::ZERO,
+            Vec3::Z,
+            40.0,
+            5.0,
+            0.0,
+            0.0,
+            1.0,
+        )
+        .with_aspect_ratio(1.5);
+        // the pdf of the directions through the film should integrate to 1 over the sphere,
+        // and the importance over the pdf should cancel with the cosine at the lens
+        let mut sampler = IndependentSampler::new(0, 0);
+        let n = 200000;
+        let mut integral = 0.0;
+        for _ in 0..n {
+            let direction = random_on_unit_sphere(sampler.draw_2d());
+            let (we, pdf) = camera.eval_we(550.0, camera.origin, camera.origin + direction);
+            integral += pdf.0 * 4.0 * PI;
+            if pdf.0 > 0.0 {
+                let cos_theta = direction * camera.direction;
+                assert!((we * cos_theta / pdf.0 - 1.0).abs() < 0.001);
+            }
+        }
+        integral /= n as f32;
+        assert!((integral - 1.0).abs() < 0.03, "{}", integral);
+    }
+
     #[test]
     fn test_camera_wide_aspect() {
         let (width, height) = (1920.0, 1080.0);
@@ -3,12 +3,16 @@ use std::f32::consts::SQRT_2;
 use crate::geometry::*;
 use crate::materials::MaterialId;
 use crate::math::*;
+use crate::sampler::IndependentSampler;
 
 use optics::{
     bladed_aperture, lens_sampler::RadialSampler, Input, LensAssembly, LensInterface, Output,
 };
 use spectral::EXTENDED_VISIBLE_RANGE;
 
+// angle between the rays traced to find how much film area a cone of directions covers, in radians
+const JACOBIAN_DELTA: f32 = 0.0005;
+
 #[derive(Debug, Clone)]
 pub struct RealisticCamera {
     pub origin: Point3,
@@ -26,6 +30,8 @@ pub struct RealisticCamera {
     lens_radius: f32,
     t0: f32,
     t1: f32,
+    // the medium the camera sits in. None means it's in the world medium
+    pub medium_id: Option<usize>,
 }
 
 impl RealisticCamera {
@@ -102,6 +108,7 @@ impl RealisticCamera {
             lens_radius,
             t0,
             t1,
+            medium_id: None,
         }
     }
     pub fn get_surface(&self) -> Option<&Instance> {
@@ -118,17 +125,8 @@ impl RealisticCamera {
         t: f32,
     ) -> (Ray, f32) {
         // circular aperture/lens
-        let _time: f32 = self.t0 + random() * (self.t1 - self.t0);
-        // crop sensor to match aspect ratio
-        let (x_factor, y_factor) = if self.aspect_ratio > 1.0 {
-            // x larger than y
-            // thus x needs to be scaled down
-            (1.0, self.aspect_ratio)
-        } else {
-            // y larger than x
-            // thus x is scaled down
-            (1.0 / self.aspect_ratio, 1.0)
-        };
+        let time: f32 = self.t0 + sampler.draw_1d().x * (self.t1 - self.t0);
+        let (x_factor, y_factor) = self.film_factors();
         let central_point: Point3 = Point3::new(
             (s - 0.5) * self.sensor_size * x_factor,
             (t - 0.5) * self.sensor_size * y_factor,
@@ -137,7 +135,11 @@ impl RealisticCamera {
         let mut _attempts = 0;
         let mut result = None;
 
-        for _ in 0..100 {
+        // the first attempt uses the lens samples from the sampler, and every retry draws from its own stream seeded by another sample,
+        // so that proposals are independent and the number of dimensions consumed doesn't depend on how many attempts were needed
+        let (aperture_sample, wavelength_sample) = (sampler.draw_2d(), sampler.draw_1d());
+        let mut retry_sampler = IndependentSampler::new(sampler.draw_1d().x.to_bits(), 0);
+        for attempt in 0..100 {
             // let s0 = sampler.draw_2d();
             // let [x, y, z, _]: [f32; 4] = central_point.0.into();
             // x += (s0.x - 0.5) / width as f32 * self.sensor_size;
@@ -145,9 +147,14 @@ impl RealisticCamera {
 
             // let point = Point3::new(x, y, z);
             let point = central_point;
+            let (aperture_sample, wavelength_sample) = if attempt == 0 {
+                (aperture_sample, wavelength_sample)
+            } else {
+                (retry_sampler.draw_2d(), retry_sampler.draw_1d())
+            };
             let v = self
                 .sampler
-                .sample(lambda, point, sampler.draw_2d(), sampler.draw_1d());
+                .sample(lambda, point, aperture_sample, wavelength_sample);
             let ray = Ray::new(point, v);
 
             _attempts += 1;
@@ -166,7 +173,12 @@ impl RealisticCamera {
                 // pupil_ray.origin = Point3::from(Vec3::from(pupil_ray.origin) / 1000.0);
                 pupil_ray = self.transform.to_world(pupil_ray);
                 pupil_ray.direction = pupil_ray.direction.normalized();
-                result = Some((pupil_ray, tau));
+                pupil_ray.time = time;
+                // light arriving at an angle to the sensor falls off with the cosine to the 4th power,
+                // which is the natural vignetting that a physical lens has
+                let cos_theta = v.normalized().z().abs();
+                let cos2_theta = cos_theta * cos_theta;
+                result = Some((pupil_ray, tau * cos2_theta * cos2_theta));
                 break;
             }
         }
@@ -185,10 +197,107 @@ impl RealisticCamera {
             (Ray::new(central_point, Vec3::Z), 0.0)
         }
     }
+    // crop sensor to match aspect ratio
+    fn film_factors(&self) -> (f32, f32) {
+        if self.aspect_ratio > 1.0 {
+            // x larger than y
+            // thus x needs to be scaled down
+            (1.0, self.aspect_ratio)
+        } else {
+            // y larger than x
+            // thus x is scaled down
+            (1.0 / self.aspect_ratio, 1.0)
+        }
+    }
+    // the s and t that get_ray would have been called with to start at a point on the film
+    fn film_st(&self, film_point: Point3) -> (f32, f32) {
+        let (x_factor, y_factor) = self.film_factors();
+        (
+            film_point.x() / (self.sensor_size * x_factor) + 0.5,
+            film_point.y() / (self.sensor_size * y_factor) + 0.5,
+        )
+    }
+    // traces a ray leaving the front of the lens backwards through the lens system.
+    // returns where it lands on the film in lens space, the direction it arrives at the film with, and the transmittance along the way
+    fn trace_to_film(&self, ray: Ray, lambda: f32) -> Option<(Point3, Vec3, f32)> {
+        let mut local_ray = self.transform.to_local(ray);
+        local_ray.direction = local_ray.direction.normalized();
+        // start a little in front of the lens, heading back into it
+        let ray = Ray::new(local_ray.point_at_parameter(10.0), -local_ray.direction);
+        let Output { ray: film_ray, tau } =
+            self.assembly
+                .trace_reverse(self.lens_zoom, &Input { ray, lambda }, 1.0, |e| {
+                    (bladed_aperture(self.aperture_radius, 6, e), false)
+                })?;
+        let t = (self.film_position - film_ray.origin.z()) / film_ray.direction.z();
+        if !(t > 0.0) {
+            return None;
+        }
+        Some((
+            film_ray.point_at_parameter(t),
+            film_ray.direction.normalized(),
+            tau,
+        ))
+    }
     // returns None if the point on the lens was not from a valid pixel
-    pub fn get_pixel_for_ray(&self, _ray: Ray, _lambda: f32) -> Option<(f32, f32)> {
-        // TODO: implement backwards tracing. requires a reversed radial sampler cache or something like an OMP fit.
-        todo!();
+    pub fn get_pixel_for_ray(&self, ray: Ray, lambda: f32) -> Option<(f32, f32)> {
+        let (film_point, _, _) = self.trace_to_film(ray, lambda)?;
+        let (s, t) = self.film_st(film_point);
+        if s < 0.0 || s >= 1.0 || t < 0.0 || t >= 1.0 {
+            None
+        } else {
+            // flipped vertically, same as the projective camera
+            Some((s, 1.0 - t))
+        }
+    }
+    // the front element of the lens, in meters
+    pub fn lens_area(&self) -> f32 {
+        let radius = self.lens_radius / 1000.0;
+        PI * radius * radius
+    }
+    // importance emitted from the front of the lens towards to, and the solid angle pdf of sampling that direction.
+    // like the projective camera, the pdf is that of picking a uniform point on the film, converted to solid angle at the lens.
+    // there's no closed form for that conversion through a lens system, so it's found by tracing two more rays a small angle apart
+    // and measuring the film area they span. the importance is weighted by the lens transmittance and the cos^4 falloff, same as get_ray.
+    pub fn eval_we(&self, lambda: f32, from: Point3, to: Point3) -> (f32, PDF) {
+        let zero = (0.0, 0.0.into());
+        let direction = (to - from).normalized();
+        let cos_theta = direction * self.direction;
+        if cos_theta <= 0.0 {
+            return zero;
+        }
+        let (film_point, film_direction, tau) =
+            match self.trace_to_film(Ray::new(from, direction), lambda) {
+                Some(traced) => traced,
+                None => return zero,
+            };
+        let (s, t) = self.film_st(film_point);
+        if s < 0.0 || s >= 1.0 || t < 0.0 || t >= 1.0 {
+            return zero;
+        }
+        let frame = TangentFrame::from_normal(direction);
+        let mut edges = [Vec3::ZERO; 2];
+        for (edge, axis) in edges.iter_mut().zip([Vec3::X, Vec3::Y].iter()) {
+            let offset_direction = (direction + JACOBIAN_DELTA * frame.to_world(axis)).normalized();
+            match self.trace_to_film(Ray::new(from, offset_direction), lambda) {
+                Some((offset_point, _, _)) => *edge = offset_point - film_point,
+                None => return zero,
+            }
+        }
+        // film area per unit solid angle, in the same units as the sensor size
+        let jacobian = edges[0].cross(edges[1]).norm() / (JACOBIAN_DELTA * JACOBIAN_DELTA);
+        let (x_factor, y_factor) = self.film_factors();
+        let film_area = self.sensor_size * self.sensor_size * x_factor * y_factor;
+        let pdf = jacobian / film_area;
+
+        let cos_film = film_direction.z().abs();
+        let cos2_film = cos_film * cos_film;
+        let we = tau * cos2_film * cos2_film * pdf / (self.lens_area() * cos_theta);
+        (we, pdf.into())
+    }
+    pub fn with_medium(mut self, medium_id: Option<usize>) -> Self {
+        self.medium_id = medium_id;
+        self
     }
     pub fn with_aspect_ratio(mut self, aspect_ratio: f32) -> Self {
         self.aspect_ratio = aspect_ratio;
@@ -239,6 +348,76 @@ mod tests {
         println!("{:?}", r);
     }
 
+    #[test]
+    fn test_backwards_tracing() {
+        let interfaces = read_from_file("data/cameras/petzval_kodak.txt");
+        let camera: RealisticCamera = RealisticCamera::new(
+            Point3::new(-5.0, 0.0, 0.0),
+            Point3::ZERO,
+            Vec3::Z,
+            10.0,
+            35.0,
+            6.0,
+            0.0,
+            interfaces,
+            0.0,
+            1.0,
+            128,
+            128,
+            0.01,
+        );
+        let mut sampler: Box<dyn Sampler> = Box::new(IndependentSampler::new(0, 0));
+        // rays from the film traced backwards end up on the pixel they started from, flipped vertically
+        for &(s, t) in [(0.5, 0.5), (0.3, 0.6), (0.8, 0.2)].iter() {
+            let (ray, weight) = camera.get_ray(&mut sampler, 550.0, s, t);
+            assert!(weight > 0.0);
+            let (u, v) = camera.get_pixel_for_ray(ray, 550.0).unwrap();
+            assert!(
+                (u - s).abs() < 0.01 && (v - (1.0 - t)).abs() < 0.01,
+                "{} {}",
+                u,
+                v
+            );
+        }
+    }
+
+    #[test]
+    fn test_importance_normalization() {
+        let interfaces = read_from_file("data/cameras/petzval_kodak.txt");
+        let camera: RealisticCamera = RealisticCamera::new(
+            Point3::new(-5.0, 0.0, 0.0),
+            Point3::ZERO,
+            Vec3::Z,
+            10.0,
+            35.0,
+            6.0,
+            0.0,
+            interfaces,
+            0.0,
+            1.0,
+            128,
+            128,
+            0.01,
+        );
+        // same as the projective camera, the pdf of directions through the film from the center of the lens should integrate to 1,
+        // less whatever part of the film can't be reached from there. the importance over the pdf is the transmittance and falloff
+        let mut sampler = IndependentSampler::new(0, 0);
+        let n = 200000;
+        let mut integral = 0.0;
+        for _ in 0..n {
+            let direction = random_on_unit_sphere(sampler.draw_2d());
+            let (we, pdf) = camera.eval_we(550.0, camera.origin, camera.origin + direction);
+            integral += pdf.0 * 4.0 * PI;
+            if pdf.0 > 0.0 {
+                let cos_theta = direction * camera.direction;
+                let weight = we * cos_theta * camera.lens_area() / pdf.0;
+                assert!(weight > 0.0 && weight <= 1.0 + 0.001, "{}", weight);
+            }
+        }
+        integral /= n as f32;
+        assert!(integral > 0.5 && integral < 1.05, "{}", integral);
+    }
+
     // #[test]
     // fn test_camera_wide_aspect() {
     //     let (width, height) = (1920.0, 1080.0);
@@ -29,6 +29,9 @@ pub struct SimpleCameraSettings {
     pub aperture_size: Option<f32>,
     pub shutter_open_time: Option<f32>,
     pub shutter_close_time: Option<f32>,
+    pub medium_id: Option<usize>, // defaults to the world medium
+    pub iso: Option<f32>, // if set, the image is exposed physically instead of being normalized to the brightest pixel
+    pub fstop: Option<f32>, // defaults to what a 35mm camera with the same fov and aperture would have
 }
 
 #[derive(Deserialize, Clone)]
@@ -39,7 +42,7 @@ pub struct RealisticCameraSettings {
     pub look_at: [f32; 3],
     pub v_up: Option<[f32; 3]>,          // defaults to 0,0,1
     pub focal_adjustment: Option<f32>,   // defaults to 0.0
-    pub fstop: Option<f32>,              // defaults to f/2.0
+    pub fstop: f32,                      // required, since it isn't in the lens file
     pub shutter_open_time: Option<f32>,  // defaults to 0.0
     pub shutter_close_time: Option<f32>, // defaults to 1.0
     pub lens_zoom: Option<f32>,          // defaults to 0.0
@@ -47,6 +50,8 @@ pub struct RealisticCameraSettings {
     pub wavelength_bins: usize,
     pub sensor_size: Option<f32>, // defaults to 35mm
     pub solver_heat: Option<f32>, // defaults to 0.01
+    pub medium_id: Option<usize>, // defaults to the world medium
+    pub iso: Option<f32>,         // if set, the image is exposed physically
 }
 
 #[derive(Deserialize, Clone)]
@@ -69,8 +74,55 @@ pub enum IntegratorKind {
         camera_samples: u16,
     },
     SPPM {
-        photon_cache_size: usize,
+        photons_per_iteration: Option<usize>,
+        alpha: Option<f32>,
+        initial_radius: Option<f32>,
+        time_limit: Option<f32>,
     },
+    MLT {
+        bootstrap_samples: Option<usize>,
+        chains: Option<usize>,
+        large_step_probability: Option<f32>,
+        sigma: Option<f32>,
+    },
+    VCM {
+        initial_radius: Option<f32>,
+        alpha: Option<f32>,
+        time_limit: Option<f32>,
+    },
+}
+
+#[derive(Deserialize, Copy, Clone, Debug)]
+#[serde(tag = "type")]
+pub enum SamplerKind {
+    Random,
+    Stratified { x: usize, y: usize, z: usize },
+    Sobol,
+    Halton,
+    PMJ02,
+}
+
+// how a real camera would be exposed, given its sensitivity, shutter time in seconds and f-number
+#[derive(Copy, Clone, Debug)]
+pub struct PhysicalExposure {
+    pub iso: f32,
+    pub shutter_time: f32,
+    pub fstop: f32,
+}
+
+impl PhysicalExposure {
+    // the luminance in cd/m^2 that just saturates the sensor, using the saturation based speed from ISO 12232,
+    // where 78 is the standard constant and 0.65 accounts for the lens transmission and vignetting.
+    pub fn saturation_luminance(&self) -> f32 {
+        78.0 / (0.65 * self.iso) * self.fstop * self.fstop / self.shutter_time
+    }
+}
+
+// an extra layer of the output with only the light that took paths matching expression, see integrator/lpe.rs
+#[derive(Deserialize, Clone)]
+pub struct PassSettings {
+    pub name: String,
+    pub expression: String,
 }
 
 #[derive(Deserialize, Clone)]
@@ -89,6 +141,13 @@ pub struct RenderSettings {
     pub russian_roulette: Option<bool>,
     pub only_direct: Option<bool>,
     pub wavelength_bounds: Option<(f32, f32)>,
+    pub sampler: Option<SamplerKind>,
+    pub seed: Option<u32>,
+    pub light_groups: Option<bool>, // also write each light group's contribution to its own layer of the exr
+    pub passes: Option<Vec<PassSettings>>,
+    // filled in from the camera's settings
+    #[serde(skip)]
+    pub physical_exposure: Option<PhysicalExposure>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -107,6 +166,10 @@ pub struct TOMLRenderSettings {
     pub russian_roulette: Option<bool>,
     pub only_direct: Option<bool>,
     pub wavelength_bounds: Option<(f32, f32)>,
+    pub sampler: Option<SamplerKind>,
+    pub seed: Option<u32>,
+    pub light_groups: Option<bool>,
+    pub passes: Option<Vec<PassSettings>>,
 }
 
 impl From<TOMLRenderSettings> for RenderSettings {
@@ -126,6 +189,11 @@ impl From<TOMLRenderSettings> for RenderSettings {
             russian_roulette: data.russian_roulette,
             only_direct: data.only_direct,
             wavelength_bounds: data.wavelength_bounds,
+            sampler: data.sampler,
+            seed: data.seed,
+            light_groups: data.light_groups,
+            passes: data.passes,
+            physical_exposure: None,
         }
     }
 }
@@ -185,23 +253,46 @@ impl From<TOMLConfig> for Config {
 pub fn parse_cameras_from(settings: &TOMLConfig) -> (Config, Vec<Camera>) {
     let mut cameras: Vec<Camera> = Vec::new();
     let mut camera_map: HashMap<String, Camera> = HashMap::new();
+    let mut exposure_map: HashMap<String, Option<PhysicalExposure>> = HashMap::new();
     let mut config = Config::from(settings.clone());
     for camera_config in &settings.cameras {
         let (name, camera): (String, Camera) = match camera_config {
             CameraSettings::SimpleCamera(cam) => {
                 let shutter_open_time = cam.shutter_open_time.unwrap_or(0.0);
+                let exposure = cam.iso.map(|iso| {
+                    let fstop = cam.fstop.unwrap_or_else(|| {
+                        let aperture_size = cam.aperture_size.unwrap_or(0.0);
+                        assert!(
+                            aperture_size > 0.0,
+                            "camera {} is a pinhole, so it needs an fstop to be exposed physically",
+                            cam.name
+                        );
+                        // the focal length of a lens with the same fov on a 24mm tall sensor
+                        let focal_length = 0.012 / (cam.vfov.to_radians() / 2.0).tan();
+                        focal_length / aperture_size
+                    });
+                    PhysicalExposure {
+                        iso,
+                        shutter_time: cam.shutter_close_time.unwrap_or(1.0) - shutter_open_time,
+                        fstop,
+                    }
+                });
+                exposure_map.insert(cam.name.clone(), exposure);
                 (
                     cam.name.clone(),
-                    Camera::ProjectiveCamera(ProjectiveCamera::new(
-                        Point3::from(cam.look_from),
-                        Point3::from(cam.look_at),
-                        Vec3::from(cam.v_up.unwrap_or([0.0, 0.0, 1.0])),
-                        cam.vfov,
-                        cam.focal_distance.unwrap_or(10.0),
-                        cam.aperture_size.unwrap_or(0.0),
-                        shutter_open_time,
-                        cam.shutter_close_time.unwrap_or(1.0).max(shutter_open_time),
-                    )),
+                    Camera::ProjectiveCamera(
+                        ProjectiveCamera::new(
+                            Point3::from(cam.look_from),
+                            Point3::from(cam.look_at),
+                            Vec3::from(cam.v_up.unwrap_or([0.0, 0.0, 1.0])),
+                            cam.vfov,
+                            cam.focal_distance.unwrap_or(10.0),
+                            cam.aperture_size.unwrap_or(0.0),
+                            shutter_open_time,
+                            cam.shutter_close_time.unwrap_or(1.0).max(shutter_open_time),
+                        )
+                        .with_medium(cam.medium_id),
+                    ),
                 )
             }
             CameraSettings::RealisticCamera(cam) => {
@@ -210,24 +301,33 @@ pub fn parse_cameras_from(settings: &TOMLConfig) -> (Config, Vec<Camera>) {
                 camera_file.read_to_string(&mut camera_spec).unwrap();
                 let (interfaces, _n0, _n1) = parse_lenses_from(&camera_spec);
                 let shutter_open_time = cam.shutter_open_time.unwrap_or(0.0);
+                let exposure = cam.iso.map(|iso| PhysicalExposure {
+                    iso,
+                    shutter_time: cam.shutter_close_time.unwrap_or(1.0) - shutter_open_time,
+                    fstop: cam.fstop,
+                });
+                exposure_map.insert(cam.name.clone(), exposure);
                 println!("{}", "reached camera constructor");
                 (
                     cam.name.clone(),
-                    Camera::RealisticCamera(RealisticCamera::new(
-                        Point3::from(cam.look_from),
-                        Point3::from(cam.look_at),
-                        Vec3::from(cam.v_up.unwrap_or([0.0, 0.0, 1.0])),
-                        cam.focal_adjustment.unwrap_or(0.0),
-                        cam.sensor_size.unwrap_or(35.0),
-                        cam.fstop.unwrap_or(2.0),
-                        cam.lens_zoom.unwrap_or(0.0),
-                        interfaces,
-                        shutter_open_time,
-                        cam.shutter_close_time.unwrap_or(1.0).max(shutter_open_time),
-                        cam.radial_bins,
-                        cam.wavelength_bins,
-                        cam.solver_heat.unwrap_or(0.01),
-                    )),
+                    Camera::RealisticCamera(
+                        RealisticCamera::new(
+                            Point3::from(cam.look_from),
+                            Point3::from(cam.look_at),
+                            Vec3::from(cam.v_up.unwrap_or([0.0, 0.0, 1.0])),
+                            cam.focal_adjustment.unwrap_or(0.0),
+                            cam.sensor_size.unwrap_or(35.0),
+                            cam.fstop,
+                            cam.lens_zoom.unwrap_or(0.0),
+                            interfaces,
+                            shutter_open_time,
+                            cam.shutter_close_time.unwrap_or(1.0).max(shutter_open_time),
+                            cam.radial_bins,
+                            cam.wavelength_bins,
+                            cam.solver_heat.unwrap_or(0.01),
+                        )
+                        .with_medium(cam.medium_id),
+                    ),
                 )
             }
         };
@@ -242,6 +342,7 @@ pub fn parse_cameras_from(settings: &TOMLConfig) -> (Config, Vec<Camera>) {
         let cam_id = cameras.len();
         let camera = camera_map[&toml_settings.camera_id].clone();
         render_settings.camera_id = cam_id;
+        render_settings.physical_exposure = exposure_map[&toml_settings.camera_id];
         cameras.push(camera);
     }
     println!("{}", "reached camera return");
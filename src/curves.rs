@@ -71,3 +71,45 @@ pub fn void() -> SPD {
         mode: InterpolationMode::Linear,
     }
 }
+
+// lumens per watt at the peak of the photopic luminous efficiency curve
+pub const LUMINOUS_EFFICACY: f32 = 683.0;
+
+// the cie y color matching function, which peaks at 1 around 555nm
+pub fn y_bar(lambda: f32) -> f32 {
+    XYZColor::from(SingleWavelength::new(lambda, SingleEnergy::ONE)).y()
+}
+
+// integrates a spectral quantity over the wavelength range, returning the total and its integral against y_bar.
+// the first is proportional to radiometric units and the second to photometric units, without the luminous efficacy.
+pub fn radiometric_and_photometric_integrals(
+    f: impl Fn(f32) -> f32,
+    wavelength_range: Bounds1D,
+) -> (f32, f32) {
+    const WAVELENGTH_STEPS: usize = 256;
+    let step = wavelength_range.span() / WAVELENGTH_STEPS as f32;
+    let (mut radiometric, mut photometric) = (0.0, 0.0);
+    for i in 0..WAVELENGTH_STEPS {
+        let lambda = wavelength_range.lower + (i as f32 + 0.5) * step;
+        let value = f(lambda);
+        radiometric += value * step;
+        photometric += value * y_bar(lambda) * step;
+    }
+    (radiometric, photometric)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_luminous_integrals() {
+        assert!((y_bar(555.0) - 1.0).abs() < 0.01);
+        assert!(y_bar(300.0) < 0.001);
+        // a flat spectrum of 1 over the visible range has about 106.9nm worth of y_bar
+        let (radiometric, photometric) =
+            radiometric_and_photometric_integrals(|_| 1.0, EXTENDED_VISIBLE_RANGE);
+        assert!((radiometric - EXTENDED_VISIBLE_RANGE.span()).abs() < 0.01);
+        assert!((photometric - 106.9).abs() < 5.0, "{}", photometric);
+    }
+}
@@ -1,6 +1,7 @@
 use crate::aabb::{HasBoundingBox, AABB};
 use crate::hittable::{HitRecord, Hittable};
 use crate::math::*;
+use crate::world::Distribution2D;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Disk {
@@ -17,6 +18,14 @@ impl Disk {
             two_sided,
         }
     }
+
+    // planar uvs, with the disk inscribed in the unit square
+    fn uv_at(&self, xh: f32, yh: f32) -> (f32, f32) {
+        (
+            (0.5 + 0.5 * xh / self.radius).clamp(0.0, 1.0),
+            (0.5 + 0.5 * yh / self.radius).clamp(0.0, 1.0),
+        )
+    }
 }
 
 impl HasBoundingBox for Disk {
@@ -50,8 +59,7 @@ impl Hittable for Disk {
         Some(HitRecord::new(
             t,
             r.point_at_parameter(t),
-            // TODO: compute UV for disk
-            (0.0, 0.0),
+            self.uv_at(xh, yh),
             0.0,
             hit_normal,
             0.into(),
@@ -59,7 +67,7 @@ impl Hittable for Disk {
             None,
         ))
     }
-    fn sample_surface(&self, mut s: Sample2D) -> (Point3, Vec3, PDF) {
+    fn sample_surface(&self, mut s: Sample2D) -> (Point3, Vec3, (f32, f32), PDF) {
         let mut normal = Vec3::Z;
         // if dual sided, randomly pick the opposite side when sampling
         if self.two_sided {
@@ -68,12 +76,44 @@ impl Hittable for Disk {
             normal = normal * choice.1;
         }
         // otherwise stick with Z+
-        let point = self.origin + self.radius * random_in_unit_disk(s);
+        let offset = self.radius * random_in_unit_disk(s);
+        let point = self.origin + offset;
         let area = PI * self.radius * self.radius;
-        (point, normal, (1.0 / area).into())
+        (
+            point,
+            normal,
+            self.uv_at(offset.x(), offset.y()),
+            (1.0 / area).into(),
+        )
+    }
+    fn sample_surface_with_uv(
+        &self,
+        mut s: Sample2D,
+        distribution: &Distribution2D,
+    ) -> Option<(Point3, Vec3, (f32, f32), PDF)> {
+        let mut normal = Vec3::Z;
+        if self.two_sided {
+            let choice = Sample1D::new(s.x).choose(0.5, -1.0f32, 1.0f32);
+            s.x = choice.0.x;
+            normal = normal * choice.1;
+        }
+        let ((u, v), uv_pdf) = distribution.sample(s);
+        // the inverse of uv_at. the distribution covers the whole square the disk is inscribed in,
+        // so uvs in the corners miss the disk and get a pdf of 0
+        let (xh, yh) = ((2.0 * u - 1.0) * self.radius, (2.0 * v - 1.0) * self.radius);
+        let point = self.origin + Vec3::new(xh, yh, 0.0);
+        let pdf = if xh * xh + yh * yh <= self.radius * self.radius {
+            uv_pdf.0 / self.uv_area_density((u, v))
+        } else {
+            0.0
+        };
+        Some((point, normal, (u, v), pdf.into()))
+    }
+    fn uv_area_density(&self, _uv: (f32, f32)) -> f32 {
+        4.0 * self.radius * self.radius
     }
     fn sample(&self, s: Sample2D, from: Point3) -> (Vec3, PDF) {
-        let (point, normal, area_pdf) = self.sample_surface(s);
+        let (point, normal, _, area_pdf) = self.sample_surface(s);
         debug_assert!(point.0.is_finite().all());
         debug_assert!(normal.0.is_finite().all());
         debug_assert!(area_pdf.0.is_finite());
@@ -1,6 +1,7 @@
 use crate::hittable::{HasBoundingBox, HitRecord, Hittable, AABB};
 use crate::materials::MaterialId;
 use crate::math::*;
+use crate::world::Distribution2D;
 
 use crate::accelerator::BHShape;
 use crate::geometry::*;
@@ -49,6 +50,13 @@ impl Instance {
         material_id: Option<MaterialId>,
         instance_id: usize,
     ) -> Self {
+        let mut aggregate = aggregate;
+        if let Aggregate::Mesh(mesh) = &mut aggregate {
+            // meshes sample their lights in world space, since the area of each triangle can change differently under the transform
+            if transform.is_some() && mesh.triangles.is_some() {
+                mesh.init_light_sampling(transform);
+            }
+        }
         Instance {
             aggregate,
             transform,
@@ -135,6 +143,10 @@ impl Hittable for Instance {
         }
     }
     fn sample(&self, s: Sample2D, from: Point3) -> (Vec3, PDF) {
+        if let Aggregate::Mesh(mesh) = &self.aggregate {
+            // already in world space
+            return mesh.sample(s, from);
+        }
         if let Some(transform) = self.transform {
             let (vec, pdf) = self.aggregate.sample(s, transform.to_local(from));
             (transform.to_world(vec).normalized(), pdf)
@@ -142,25 +154,46 @@ impl Hittable for Instance {
             self.aggregate.sample(s, from)
         }
     }
-    fn sample_surface(&self, s: Sample2D) -> (Point3, Vec3, PDF) {
+    fn sample_surface(&self, s: Sample2D) -> (Point3, Vec3, (f32, f32), PDF) {
+        if let Aggregate::Mesh(mesh) = &self.aggregate {
+            return mesh.sample_surface(s);
+        }
         if let Some(transform) = self.transform {
-            let (point, normal, pdf) = self.aggregate.sample_surface(s);
+            let (point, normal, uv, pdf) = self.aggregate.sample_surface(s);
+            // the area pdf is in local space, so account for how much the transform scales the surface
             (
                 transform.to_world(point),
                 transform.to_world(normal).normalized(),
-                pdf,
+                uv,
+                PDF::from(pdf.0 * self.local_to_world_area_ratio()),
             )
         } else {
             self.aggregate.sample_surface(s)
         }
     }
     fn psa_pdf(&self, cos_o: f32, from: Point3, to: Point3) -> PDF {
-        let (from, to) = if let Some(transform) = self.transform {
-            (transform.to_world(from), transform.to_world(to))
-        } else {
-            (from, to)
+        if let Aggregate::Mesh(mesh) = &self.aggregate {
+            return mesh.psa_pdf(cos_o, from, to);
+        }
+        let transform = match self.transform {
+            Some(transform) => transform,
+            None => return self.aggregate.psa_pdf(cos_o, from, to),
         };
-        self.aggregate.psa_pdf(cos_o, from, to)
+        // the aggregate's pdf is distance squared over cosine and area, all in local space.
+        // cos_o is already in world space, so swap the local distance and area for the world ones,
+        // which stays correct under non uniform scaling
+        let (local_from, local_to) = (transform.to_local(from), transform.to_local(to));
+        let local_pdf = self.aggregate.psa_pdf(cos_o, local_from, local_to);
+        let local_distance_squared = (local_to - local_from).norm_squared();
+        let world_area = self.light_area();
+        if local_pdf.0 == 0.0 || local_distance_squared == 0.0 || world_area == 0.0 {
+            return 0.0.into();
+        }
+        let local_area = self.aggregate.light_area();
+        PDF::from(
+            local_pdf.0 * (to - from).norm_squared() / local_distance_squared * local_area
+                / world_area,
+        )
     }
 
     fn surface_area(&self, transform: &Transform3) -> f32 {
@@ -171,9 +204,55 @@ impl Hittable for Instance {
             self.aggregate.surface_area(transform)
         }
     }
+    fn light_area(&self) -> f32 {
+        if let Aggregate::Mesh(mesh) = &self.aggregate {
+            // already in world space
+            return mesh.light_area();
+        }
+        self.surface_area(&Transform3::from_scale(Vec3::new(1.0, 1.0, 1.0)))
+    }
+    fn sample_surface_with_uv(
+        &self,
+        s: Sample2D,
+        distribution: &Distribution2D,
+    ) -> Option<(Point3, Vec3, (f32, f32), PDF)> {
+        let (point, normal, uv, pdf) = self.aggregate.sample_surface_with_uv(s, distribution)?;
+        match self.transform {
+            Some(transform) => Some((
+                transform.to_world(point),
+                transform.to_world(normal).normalized(),
+                uv,
+                // like sample_surface, account for how much the transform scales the surface
+                PDF::from(pdf.0 * self.local_to_world_area_ratio()),
+            )),
+            None => Some((point, normal, uv, pdf)),
+        }
+    }
+    fn uv_area_density(&self, uv: (f32, f32)) -> f32 {
+        let density = self.aggregate.uv_area_density(uv);
+        if self.transform.is_none() {
+            return density;
+        }
+        let ratio = self.local_to_world_area_ratio();
+        if ratio > 0.0 {
+            density / ratio
+        } else {
+            0.0
+        }
+    }
 }
 
 impl Instance {
+    // the local surface area over the world surface area
+    fn local_to_world_area_ratio(&self) -> f32 {
+        let identity = Transform3::from_scale(Vec3::new(1.0, 1.0, 1.0));
+        let world_area = self.surface_area(&identity);
+        if world_area > 0.0 {
+            self.aggregate.surface_area(&identity) / world_area
+        } else {
+            0.0
+        }
+    }
     pub fn get_instance_id(&self) -> usize {
         self.instance_id
     }
@@ -4,6 +4,7 @@ use crate::accelerator::{BHShape, BoundingHierarchy, FlatBVH};
 use crate::hittable::{HasBoundingBox, HitRecord, Hittable};
 use crate::materials::MaterialId;
 use crate::math::*;
+use crate::world::Distribution1D;
 
 use packed_simd::{f32x4, i32x4};
 
@@ -18,6 +19,122 @@ pub fn vec_shuffle(vec: f32x4, m: u32) -> f32x4 {
     }
 }
 
+// solid angles smaller than this are sampled by area instead, since spherical triangle sampling gets unstable
+const MIN_SPHERICAL_SAMPLE_AREA: f32 = 3e-4;
+
+// uniformly samples a point on the triangle, returning its barycentric coordinates
+fn sample_barycentrics(s: Sample2D) -> (f32, f32, f32) {
+    let su = s.x.sqrt();
+    let b0 = 1.0 - su;
+    let b1 = s.y * su;
+    (b0, b1, 1.0 - b0 - b1)
+}
+
+fn triangle_area(p0: Point3, p1: Point3, p2: Point3) -> f32 {
+    0.5 * (p1 - p0).cross(p2 - p0).norm()
+}
+
+// solid angle subtended by the spherical triangle with unit length corners a, b and c
+fn spherical_triangle_area(a: Vec3, b: Vec3, c: Vec3) -> f32 {
+    (2.0 * (a * b.cross(c)).abs().atan2(1.0 + a * b + b * c + c * a)).abs()
+}
+
+// removes the component of v that's parallel to w, w being normalized
+fn gram_schmidt(v: Vec3, w: Vec3) -> Vec3 {
+    v - (v * w) * w
+}
+
+// samples a direction uniformly within the spherical triangle with unit length corners a, b and c, see Arvo 1995.
+// returns the direction and its solid angle pdf
+fn sample_spherical_triangle(a: Vec3, b: Vec3, c: Vec3, s: Sample2D) -> Option<(Vec3, PDF)> {
+    let area = spherical_triangle_area(a, b, c);
+    if area < MIN_SPHERICAL_SAMPLE_AREA || !area.is_finite() {
+        return None;
+    }
+    let n_ab = a.cross(b).normalized();
+    let n_bc = b.cross(c).normalized();
+    let n_ca = c.cross(a).normalized();
+    // interior angles of the spherical triangle
+    let alpha = (n_ab * -n_ca).clamp(-1.0, 1.0).acos();
+    let beta = (n_bc * -n_ab).clamp(-1.0, 1.0).acos();
+    let gamma = (n_ca * -n_bc).clamp(-1.0, 1.0).acos();
+
+    // pick the sub triangle with the sampled area, which determines the point c' on the edge from a to c
+    let sub_area = PI + s.x * (alpha + beta + gamma - PI);
+    let (sin_alpha, cos_alpha) = alpha.sin_cos();
+    let (sin_sub, cos_sub) = sub_area.sin_cos();
+    let sin_phi = sin_sub * cos_alpha - cos_sub * sin_alpha;
+    let cos_phi = cos_sub * cos_alpha + sin_sub * sin_alpha;
+    let k1 = cos_phi + cos_alpha;
+    let k2 = sin_phi - sin_alpha * (a * b);
+    let cos_b_prime = ((k2 + (k2 * cos_phi - k1 * sin_phi) * cos_alpha)
+        / ((k2 * sin_phi + k1 * cos_phi) * sin_alpha))
+        .clamp(-1.0, 1.0);
+    let sin_b_prime = (1.0 - cos_b_prime * cos_b_prime).max(0.0).sqrt();
+    let c_prime = cos_b_prime * a + sin_b_prime * gram_schmidt(c, a).normalized();
+
+    // then pick a point along the arc from b to c'
+    let cos_theta = 1.0 - s.y * (1.0 - c_prime * b);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let direction = cos_theta * b + sin_theta * gram_schmidt(c_prime, b).normalized();
+    if !direction.is_finite() {
+        return None;
+    }
+    Some((direction.normalized(), PDF::from(1.0 / area)))
+}
+
+// samples a direction from `from` towards the triangle, preferring spherical triangle sampling when the triangle isn't too small or far away.
+// returns the direction and its solid angle pdf
+fn sample_triangle_from(
+    p0: Point3,
+    p1: Point3,
+    p2: Point3,
+    s: Sample2D,
+    from: Point3,
+) -> (Vec3, PDF) {
+    let (a, b, c) = (
+        (p0 - from).normalized(),
+        (p1 - from).normalized(),
+        (p2 - from).normalized(),
+    );
+    if let Some(sampled) = sample_spherical_triangle(a, b, c, s) {
+        return sampled;
+    }
+    let (b0, b1, b2) = sample_barycentrics(s);
+    let point = Point3::from(b0 * Vec3::from(p0) + b1 * Vec3::from(p1) + b2 * Vec3::from(p2));
+    let direction = point - from;
+    let normal = (p1 - p0).cross(p2 - p0).normalized();
+    let pdf = direction.norm_squared()
+        / ((normal * direction.normalized()).abs() * triangle_area(p0, p1, p2));
+    if pdf.is_finite() {
+        (direction.normalized(), PDF::from(pdf))
+    } else {
+        (direction.normalized(), 0.0.into())
+    }
+}
+
+// solid angle pdf of sample_triangle_from, for a direction that hits the triangle at `to`
+fn triangle_pdf_from(p0: Point3, p1: Point3, p2: Point3, from: Point3, to: Point3) -> PDF {
+    let (a, b, c) = (
+        (p0 - from).normalized(),
+        (p1 - from).normalized(),
+        (p2 - from).normalized(),
+    );
+    let area = spherical_triangle_area(a, b, c);
+    if area >= MIN_SPHERICAL_SAMPLE_AREA && area.is_finite() {
+        return PDF::from(1.0 / area);
+    }
+    let direction = to - from;
+    let normal = (p1 - p0).cross(p2 - p0).normalized();
+    let pdf = direction.norm_squared()
+        / ((normal * direction.normalized()).abs() * triangle_area(p0, p1, p2));
+    if pdf.is_finite() {
+        PDF::from(pdf)
+    } else {
+        0.0.into()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct MeshTriangleRef {
     pub idx: usize,
@@ -52,6 +169,63 @@ impl MeshTriangleRef {
             0u16.into()
         }
     }
+
+    // the corners of the triangle, optionally brought into world space
+    fn points(&self, transform: Option<Transform3>) -> (Point3, Point3, Point3) {
+        let p0 = self.vertices[self.indices[3 * self.idx + 0]];
+        let p1 = self.vertices[self.indices[3 * self.idx + 1]];
+        let p2 = self.vertices[self.indices[3 * self.idx + 2]];
+        match transform {
+            Some(transform) => (
+                transform.to_world(p0),
+                transform.to_world(p1),
+                transform.to_world(p2),
+            ),
+            None => (p0, p1, p2),
+        }
+    }
+
+    // the normal at the given barycentric coordinates, matching the normal reported by hit
+    fn normal_at(&self, (b0, b1, b2): (f32, f32, f32)) -> Vec3 {
+        if self.normals.len() > 0 {
+            let (n0, n1, n2) = (
+                self.normals[self.indices[3 * self.idx + 0]],
+                self.normals[self.indices[3 * self.idx + 1]],
+                self.normals[self.indices[3 * self.idx + 2]],
+            );
+            (b0 * n0 + b1 * n1 + b2 * n2).normalized()
+        } else {
+            let (p0, p1, p2) = self.points(None);
+            (p0 - p2).cross(p1 - p2).normalized()
+        }
+    }
+
+    // uniformly samples a point on the triangle, with the area pdf measured after applying transform
+    fn sample_surface_with(
+        &self,
+        s: Sample2D,
+        transform: Option<Transform3>,
+    ) -> (Point3, Vec3, PDF) {
+        let (p0, p1, p2) = self.points(None);
+        let barycentrics = sample_barycentrics(s);
+        let (b0, b1, b2) = barycentrics;
+        let point = Point3::from(b0 * Vec3::from(p0) + b1 * Vec3::from(p1) + b2 * Vec3::from(p2));
+        let normal = self.normal_at(barycentrics);
+        let (point, normal) = match transform {
+            Some(transform) => (
+                transform.to_world(point),
+                transform.to_world(normal).normalized(),
+            ),
+            None => (point, normal),
+        };
+        let (p0, p1, p2) = self.points(transform);
+        let area = triangle_area(p0, p1, p2);
+        if area > 0.0 {
+            (point, normal, PDF::from(1.0 / area))
+        } else {
+            (point, normal, 0.0.into())
+        }
+    }
 }
 
 impl HasBoundingBox for MeshTriangleRef {
@@ -194,19 +368,26 @@ impl Hittable for MeshTriangleRef {
         Some(hit)
     }
 
-    #[allow(unused)]
     fn sample(&self, s: Sample2D, from: Point3) -> (Vec3, PDF) {
-        (Vec3::ZERO, 0.0.into())
+        let (p0, p1, p2) = self.points(None);
+        sample_triangle_from(p0, p1, p2, s, from)
     }
 
-    #[allow(unused)]
-    fn sample_surface(&self, s: Sample2D) -> (Point3, Vec3, PDF) {
-        (Point3::ORIGIN, Vec3::ZERO, 0.0.into())
+    fn sample_surface(&self, s: Sample2D) -> (Point3, Vec3, (f32, f32), PDF) {
+        let (point, normal, pdf) = self.sample_surface_with(s, None);
+        // triangles don't have uvs yet, so this matches hit
+        (point, normal, (0.0, 0.0), pdf)
     }
 
-    #[allow(unused)]
     fn psa_pdf(&self, cos_o: f32, from: Point3, to: Point3) -> PDF {
-        0.0.into()
+        // same convention as the other primitives, based on uniform area sampling
+        let (p0, p1, p2) = self.points(None);
+        let denominator = cos_o.abs() * triangle_area(p0, p1, p2);
+        if denominator == 0.0 {
+            0.0.into()
+        } else {
+            PDF::from((to - from).norm_squared() / denominator)
+        }
     }
     fn surface_area(&self, transform: &Transform3) -> f32 {
         // calculates the surface area using heron's formula.
@@ -241,6 +422,14 @@ pub struct Mesh {
     bounding_box: AABB,
     pub bvh: Option<FlatBVH>,
     pub triangles: Option<Vec<MeshTriangleRef>>,
+    // indices of the triangles that are sampled when the mesh is used as a light.
+    // these are the emissive triangles, or all of them if none are emissive
+    light_triangles: Vec<usize>,
+    // picks one of light_triangles proportional to its area after light_transform is applied
+    light_distribution: Option<Distribution1D>,
+    light_area: f32,
+    // the transform of the instance this mesh belongs to. light sampling is done in world space so that pdfs stay correct under scaling
+    light_transform: Option<Transform3>,
 }
 
 impl Mesh {
@@ -268,6 +457,10 @@ impl Mesh {
             bounding_box,
             bvh: None,
             triangles: None,
+            light_triangles: Vec::new(),
+            light_distribution: None,
+            light_area: 0.0,
+            light_transform: None,
         }
     }
     pub fn init(&mut self) {
@@ -287,6 +480,54 @@ impl Mesh {
             self.node_indices[tri_num] = tri.node_index;
         }
         self.triangles = Some(triangles);
+        self.init_light_sampling(None);
+    }
+
+    // builds the area distribution used to sample points on the mesh, in the space given by transform.
+    // must be called after init, and again whenever the mesh is instanced with a transform.
+    pub fn init_light_sampling(&mut self, transform: Option<Transform3>) {
+        let triangles = self
+            .triangles
+            .as_ref()
+            .expect("mesh must be initialized before light sampling");
+        let mut light_triangles: Vec<usize> = triangles
+            .iter()
+            .enumerate()
+            .filter(|(_, tri)| matches!(tri.get_material_id(), MaterialId::Light(_)))
+            .map(|(index, _)| index)
+            .collect();
+        if light_triangles.is_empty() {
+            light_triangles = (0..triangles.len()).collect();
+        }
+        let areas: Vec<f32> = light_triangles
+            .iter()
+            .map(|index| {
+                let (p0, p1, p2) = triangles[*index].points(transform);
+                triangle_area(p0, p1, p2)
+            })
+            .collect();
+        self.light_area = areas.iter().sum();
+        self.light_distribution = if areas.is_empty() {
+            None
+        } else {
+            Some(Distribution1D::new(areas))
+        };
+        self.light_triangles = light_triangles;
+        self.light_transform = transform;
+    }
+
+    // picks one of the light triangles proportional to its area, returning it, the probability of picking it and the rescaled sample
+    fn pick_light_triangle(&self, s: Sample1D) -> Option<(&MeshTriangleRef, f32, Sample1D)> {
+        let distribution = self.light_distribution.as_ref()?;
+        let (x, _, index) = distribution.sample(s);
+        let n = self.light_triangles.len() as f32;
+        let rescaled = (x * n - index as f32).clamp(0.0, 1.0 - std::f32::EPSILON);
+        let triangle = &self.triangles.as_ref().unwrap()[self.light_triangles[index]];
+        Some((
+            triangle,
+            distribution.discrete_pdf(index),
+            Sample1D { x: rescaled },
+        ))
     }
 }
 
@@ -333,19 +574,127 @@ impl Hittable for Mesh {
         }
         hit_record
     }
+    // from and the returned direction are in the space given to init_light_sampling, which is world space for instanced meshes.
+    // the pdf is of picking the triangle and then the direction within it, ignoring other triangles that might be behind it
     fn sample(&self, s: Sample2D, from: Point3) -> (Vec3, PDF) {
-        (Vec3::ZERO, 0.0.into())
+        match self.pick_light_triangle(Sample1D { x: s.x }) {
+            Some((triangle, pick_pdf, rescaled)) => {
+                let (p0, p1, p2) = triangle.points(self.light_transform);
+                let (direction, pdf) =
+                    sample_triangle_from(p0, p1, p2, Sample2D::new(rescaled.x, s.y), from);
+                (direction, PDF::from(pick_pdf * pdf.0))
+            }
+            None => (Vec3::ZERO, 0.0.into()),
+        }
     }
-    fn sample_surface(&self, s: Sample2D) -> (Point3, Vec3, PDF) {
-        (Point3::ORIGIN, Vec3::ZERO, 0.0.into())
+    // the returned point and normal are in the space given to init_light_sampling, which is world space for instanced meshes
+    fn sample_surface(&self, s: Sample2D) -> (Point3, Vec3, (f32, f32), PDF) {
+        if self.light_area <= 0.0 {
+            return (Point3::ORIGIN, Vec3::ZERO, (0.0, 0.0), 0.0.into());
+        }
+        match self.pick_light_triangle(Sample1D { x: s.x }) {
+            Some((triangle, _, rescaled)) => {
+                let (point, normal, _) = triangle
+                    .sample_surface_with(Sample2D::new(rescaled.x, s.y), self.light_transform);
+                // picking proportional to area and then sampling uniformly is uniform over the whole emissive area
+                (point, normal, (0.0, 0.0), PDF::from(1.0 / self.light_area))
+            }
+            None => (Point3::ORIGIN, Vec3::ZERO, (0.0, 0.0), 0.0.into()),
+        }
     }
     fn psa_pdf(&self, cos_o: f32, from: Point3, to: Point3) -> PDF {
-        0.0.into()
+        let denominator = cos_o.abs() * self.light_area;
+        if denominator == 0.0 {
+            0.0.into()
+        } else {
+            PDF::from((to - from).norm_squared() / denominator)
+        }
     }
     fn surface_area(&self, transform: &Transform3) -> f32 {
-        0.0
+        match &self.triangles {
+            Some(triangles) => triangles
+                .iter()
+                .map(|tri| tri.surface_area(transform))
+                .sum(),
+            None => 0.0,
+        }
+    }
+    fn light_area(&self) -> f32 {
+        self.light_area
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a unit square in the xy plane made of two triangles, where only the first one is emissive
+    fn square_mesh() -> Mesh {
+        let vertices = vec![
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(1.0, 1.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+        ];
+        let mut mesh = Mesh::new(
+            2,
+            vec![0, 1, 2, 0, 2, 3],
+            vertices,
+            Vec::new(),
+            vec![MaterialId::Light(0), MaterialId::Material(0)],
+        );
+        mesh.init();
+        mesh
+    }
+
+    #[test]
+    fn test_mesh_sample_surface() {
+        let mut mesh = square_mesh();
+        assert!(
+            (mesh.surface_area(&Transform3::from_scale(Vec3::new(1.0, 1.0, 1.0))) - 1.0).abs()
+                < 0.0001
+        );
+        mesh.init_light_sampling(Some(Transform3::from_scale(Vec3::new(2.0, 3.0, 1.0))));
+        for _ in 0..1000 {
+            let (point, normal, _, pdf) = mesh.sample_surface(Sample2D::new_random_sample());
+            // only the emissive triangle, below the diagonal, gets sampled. it has an area of 3 after scaling
+            assert!((pdf.0 - 1.0 / 3.0).abs() < 0.0001, "{:?}", pdf);
+            assert!(point.x() / 2.0 >= point.y() / 3.0 - 0.0001, "{:?}", point);
+            assert!(point.x() <= 2.0 && point.y() >= 0.0 && point.z() == 0.0);
+            assert!((normal.z().abs() - 1.0).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn test_spherical_triangle_sampling() {
+        let mesh = square_mesh();
+        let triangle = &mesh.triangles.as_ref().unwrap()[0];
+        let from = Point3::new(0.3, 0.1, 1.0);
+        let (p0, p1, p2) = triangle.points(None);
+        let solid_angle = spherical_triangle_area(
+            (p0 - from).normalized(),
+            (p1 - from).normalized(),
+            (p2 - from).normalized(),
+        );
+        let mut estimate = 0.0;
+        let n = 10000;
+        for _ in 0..n {
+            let (direction, pdf) = triangle.sample(Sample2D::new_random_sample(), from);
+            assert!((pdf.0 - 1.0 / solid_angle).abs() < 0.001 * pdf.0);
+            // every sampled direction should hit the triangle
+            let hit = triangle.hit(Ray::new(from, direction), 0.0, INFINITY);
+            assert!(hit.is_some(), "{:?}", direction);
+            estimate += 1.0 / pdf.0;
+        }
+        estimate /= n as f32;
+        assert!(
+            (estimate - solid_angle).abs() < 0.001,
+            "{} {}",
+            estimate,
+            solid_angle
+        );
+    }
+}
+
 /*
 #[cfg(test)]
 mod tests {
@@ -12,6 +12,7 @@ pub use sphere::Sphere;
 
 use crate::hittable::{HasBoundingBox, HitRecord, Hittable, AABB};
 use crate::math::*;
+use crate::world::Distribution2D;
 
 #[derive(Clone, Debug)]
 pub enum Aggregate {
@@ -86,18 +87,18 @@ impl Hittable for Aggregate {
         debug_assert!((pair.1).0.is_finite());
         pair
     }
-    fn sample_surface(&self, s: Sample2D) -> (Point3, Vec3, PDF) {
-        let triplet = match self {
+    fn sample_surface(&self, s: Sample2D) -> (Point3, Vec3, (f32, f32), PDF) {
+        let sampled = match self {
             Aggregate::Sphere(inner) => inner.sample_surface(s),
             Aggregate::Disk(inner) => inner.sample_surface(s),
             Aggregate::AARect(inner) => inner.sample_surface(s),
             Aggregate::Mesh(inner) => inner.sample_surface(s),
             Aggregate::Triangle(inner) => inner.sample_surface(s),
         };
-        debug_assert!((triplet.0).0.is_finite().all());
-        debug_assert!((triplet.1).0.is_finite().all());
-        debug_assert!((triplet.2).0.is_finite());
-        triplet
+        debug_assert!((sampled.0).0.is_finite().all());
+        debug_assert!((sampled.1).0.is_finite().all());
+        debug_assert!((sampled.3).0.is_finite());
+        sampled
     }
     fn psa_pdf(&self, cos_o: f32, from: Point3, to: Point3) -> PDF {
         debug_assert!(cos_o.is_finite());
@@ -132,4 +133,35 @@ impl Hittable for Aggregate {
         debug_assert!(res.is_finite());
         res
     }
+    fn light_area(&self) -> f32 {
+        match self {
+            Aggregate::Sphere(inner) => inner.light_area(),
+            Aggregate::Disk(inner) => inner.light_area(),
+            Aggregate::AARect(inner) => inner.light_area(),
+            Aggregate::Mesh(inner) => inner.light_area(),
+            Aggregate::Triangle(inner) => inner.light_area(),
+        }
+    }
+    fn sample_surface_with_uv(
+        &self,
+        s: Sample2D,
+        distribution: &Distribution2D,
+    ) -> Option<(Point3, Vec3, (f32, f32), PDF)> {
+        match self {
+            Aggregate::Sphere(inner) => inner.sample_surface_with_uv(s, distribution),
+            Aggregate::Disk(inner) => inner.sample_surface_with_uv(s, distribution),
+            Aggregate::AARect(inner) => inner.sample_surface_with_uv(s, distribution),
+            Aggregate::Mesh(inner) => inner.sample_surface_with_uv(s, distribution),
+            Aggregate::Triangle(inner) => inner.sample_surface_with_uv(s, distribution),
+        }
+    }
+    fn uv_area_density(&self, uv: (f32, f32)) -> f32 {
+        match self {
+            Aggregate::Sphere(inner) => inner.uv_area_density(uv),
+            Aggregate::Disk(inner) => inner.uv_area_density(uv),
+            Aggregate::AARect(inner) => inner.uv_area_density(uv),
+            Aggregate::Mesh(inner) => inner.uv_area_density(uv),
+            Aggregate::Triangle(inner) => inner.uv_area_density(uv),
+        }
+    }
 }
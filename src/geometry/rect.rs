@@ -1,6 +1,7 @@
 use crate::aabb::{HasBoundingBox, AABB};
 use crate::hittable::{HitRecord, Hittable};
 use crate::math::*;
+use crate::world::Distribution2D;
 
 fn vec_shuffle(vec: Vec3, axis: &Axis) -> Vec3 {
     match axis {
@@ -103,7 +104,7 @@ impl Hittable for AARect {
             None,
         ))
     }
-    fn sample_surface(&self, s: Sample2D) -> (Point3, Vec3, PDF) {
+    fn sample_surface(&self, s: Sample2D) -> (Point3, Vec3, (f32, f32), PDF) {
         let Sample2D { mut x, y } = s;
         let mut normal = Vec3::from_axis(self.normal);
         if self.two_sided {
@@ -117,10 +118,35 @@ impl Hittable for AARect {
                 &self.normal,
             );
         let area = self.size.0 * self.size.1;
-        (point, normal, (1.0 / area).into())
+        (point, normal, (x, y), (1.0 / area).into())
+    }
+    fn sample_surface_with_uv(
+        &self,
+        s: Sample2D,
+        distribution: &Distribution2D,
+    ) -> Option<(Point3, Vec3, (f32, f32), PDF)> {
+        let Sample2D { mut x, y } = s;
+        let mut normal = Vec3::from_axis(self.normal);
+        if self.two_sided {
+            let choice = Sample1D { x }.choose(0.5, -1.0f32, 1.0f32);
+            x = choice.0.x;
+            normal = normal * choice.1;
+        }
+        // uvs are linear over the rect, so they map straight back onto it
+        let ((u, v), uv_pdf) = distribution.sample(Sample2D::new(x, y));
+        let point = self.origin
+            + vec_shuffle(
+                Vec3::new((u - 0.5) * self.size.0, (v - 0.5) * self.size.1, 0.0),
+                &self.normal,
+            );
+        let area = self.size.0 * self.size.1;
+        Some((point, normal, (u, v), (uv_pdf.0 / area).into()))
+    }
+    fn uv_area_density(&self, _uv: (f32, f32)) -> f32 {
+        self.size.0 * self.size.1
     }
     fn sample(&self, s: Sample2D, from: Point3) -> (Vec3, PDF) {
-        let (point, normal, area_pdf) = self.sample_surface(s);
+        let (point, normal, _, area_pdf) = self.sample_surface(s);
         let direction = point - from;
         let cos_i = normal * direction.normalized();
         if !self.two_sided {
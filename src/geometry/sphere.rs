@@ -1,6 +1,7 @@
 use crate::aabb::{HasBoundingBox, AABB};
 use crate::hittable::{HitRecord, Hittable};
 use crate::math::*;
+use crate::world::Distribution2D;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Sphere {
@@ -13,6 +14,13 @@ impl Sphere {
         Sphere { radius, origin }
     }
 
+    // spherical uvs, with u going around z and v going from +z to -z
+    fn uv_at(normal: Vec3) -> (f32, f32) {
+        let u = 0.5 + normal.y().atan2(normal.x()) / (2.0 * PI);
+        let v = normal.z().clamp(-1.0, 1.0).acos() / PI;
+        (u.clamp(0.0, 1.0), v.clamp(0.0, 1.0))
+    }
+
     // fn solid_angle(&self, point: Point3, wi: Vec3) -> f32 {
     //     let cos_theta_max =
     //         (1.0 - self.radius * self.radius / (self.origin - point).norm_squared()).sqrt();
@@ -51,7 +59,7 @@ impl Hittable for Sphere {
                 return Some(HitRecord::new(
                     time,
                     point,
-                    (0.0, 0.0),
+                    Sphere::uv_at(normal),
                     0.0,
                     normal,
                     0.into(),
@@ -69,7 +77,7 @@ impl Hittable for Sphere {
                 return Some(HitRecord::new(
                     time,
                     point,
-                    (0.0, 0.0),
+                    Sphere::uv_at(normal),
                     0.0,
                     normal,
                     0.into(),
@@ -80,14 +88,49 @@ impl Hittable for Sphere {
         }
         None
     }
-    fn sample_surface(&self, s: Sample2D) -> (Point3, Vec3, PDF) {
+    fn sample_surface(&self, s: Sample2D) -> (Point3, Vec3, (f32, f32), PDF) {
         let normal = random_on_unit_sphere(s);
         let point_on_sphere = self.origin + self.radius * normal;
         let surface_area = self.radius * self.radius * 4.0 * PI;
-        (point_on_sphere, normal, PDF::from(1.0 / surface_area))
+        (
+            point_on_sphere,
+            normal,
+            Sphere::uv_at(normal),
+            PDF::from(1.0 / surface_area),
+        )
+    }
+    fn sample_surface_with_uv(
+        &self,
+        s: Sample2D,
+        distribution: &Distribution2D,
+    ) -> Option<(Point3, Vec3, (f32, f32), PDF)> {
+        let ((u, v), uv_pdf) = distribution.sample(s);
+        // the inverse of uv_at
+        let (phi, theta) = ((u - 0.5) * 2.0 * PI, v * PI);
+        let normal = Vec3::new(
+            theta.sin() * phi.cos(),
+            theta.sin() * phi.sin(),
+            theta.cos(),
+        );
+        let density = self.uv_area_density((u, v));
+        let pdf = if density > 0.0 {
+            uv_pdf.0 / density
+        } else {
+            0.0
+        };
+        Some((
+            self.origin + self.radius * normal,
+            normal,
+            (u, v),
+            PDF::from(pdf),
+        ))
+    }
+    fn uv_area_density(&self, uv: (f32, f32)) -> f32 {
+        // the area element is r^2 sin(theta) dtheta dphi, where theta spans pi and phi spans 2 pi
+        2.0 * PI * PI * self.radius * self.radius * (uv.1 * PI).sin()
     }
     fn sample(&self, s: Sample2D, from: Point3) -> (Vec3, PDF) {
-        let (point_on_sphere, normal, area_pdf) = self.sample_surface(s);
+        let (point_on_sphere, normal, _, area_pdf) = self.sample_surface(s);
         let direction = point_on_sphere - from;
         debug_assert!(
             direction.0.is_finite().all(),
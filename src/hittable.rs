@@ -3,7 +3,7 @@ use crate::math::*;
 use std::fmt::Debug;
 
 pub use crate::aabb::{HasBoundingBox, AABB};
-use crate::world::TransportMode;
+use crate::world::{Distribution2D, TransportMode};
 
 
 #[derive(Clone, Copy, Debug)]
@@ -62,13 +62,32 @@ pub trait Hittable: Send + Sync + HasBoundingBox {
     // returns the solid angle PDF.
     fn sample(&self, s: Sample2D, from: Point3) -> (Vec3, PDF);
     // method that should implement randomly sampling a point and normal on the surface of the object in object space
-    // returns a point on the surface, the normal at that point, and the probability of that Point being chosen
-    fn sample_surface(&self, s: Sample2D) -> (Point3, Vec3, PDF);
+    // returns a point on the surface, the normal at that point, the uv at that point (matching what hit would return),
+    // and the probability of that Point being chosen
+    fn sample_surface(&self, s: Sample2D) -> (Point3, Vec3, (f32, f32), PDF);
 
     // method that should implement the projected solid angle pdf of sampling this primitive from Vertex {from, normal}
     // to is on the surface of the hittable/light
     fn psa_pdf(&self, cos_o: f32, from: Point3, to: Point3) -> PDF;
     fn surface_area(&self, transform: &Transform3) -> f32;
+    // the world space area that sample_surface samples uniformly over, which is less than the surface area
+    // when only part of the surface emits
+    fn light_area(&self) -> f32 {
+        self.surface_area(&Transform3::from_scale(Vec3::new(1.0, 1.0, 1.0)))
+    }
+    // like sample_surface, but with the uv drawn from distribution instead of uniformly by area,
+    // so that emitters can importance sample their textures. returns None if the uvs can't be mapped back onto the surface
+    fn sample_surface_with_uv(
+        &self,
+        _s: Sample2D,
+        _distribution: &Distribution2D,
+    ) -> Option<(Point3, Vec3, (f32, f32), PDF)> {
+        None
+    }
+    // how much surface area there is per unit of uv area at uv, or 0 if the uvs can't be mapped back onto the surface
+    fn uv_area_density(&self, _uv: (f32, f32)) -> f32 {
+        0.0
+    }
 }
 
 // a supertrait of Hittable that allows indexing into it
@@ -0,0 +1,177 @@
+use super::utils::*;
+
+use crate::integrator::utils::*;
+use crate::math::*;
+use crate::profile::Profile;
+use crate::world::World;
+
+use packed_simd::f32x4;
+use std::sync::Arc;
+
+// hero wavelength sampling for bidirectional methods, see Wilkie et al. 2014 "Hero Wavelength Spectral Sampling".
+// subpaths are traced with random_walk_hero, so every vertex carries throughputs and pdfs for all four wavelengths.
+// connections and bdpt mis weights are evaluated per wavelength with the single wavelength code,
+// and the wavelengths are then weighted against each other with spectral mis.
+
+// samples a light vertex like sample_light_vertex, but with the emission evaluated at all four hero wavelengths.
+// also returns the ray leaving the light.
+pub fn sample_light_vertex_hero(
+    world: &Arc<World>,
+    lambda: f32x4,
+    wavelength_bounds: Bounds1D,
+    sampler: &mut Box<dyn Sampler>,
+) -> (HeroSurfaceVertex, Ray) {
+    let (vertex, sampled) = sample_light_vertex(world, wavelength_bounds, sampler);
+    let mut emission = f32x4::splat(0.0);
+    for i in 0..4 {
+        emission = emission.replace(
+            i,
+            eval_light_vertex_emission(world, &vertex, sampled.0.direction, lambda.extract(i)).0,
+        );
+    }
+    let hero_vertex = HeroSurfaceVertex::new(
+        vertex.vertex_type,
+        vertex.time,
+        lambda,
+        vertex.local_wi,
+        vertex.point,
+        vertex.normal,
+        vertex.uv,
+        vertex.material_id,
+        vertex.instance_id,
+        HeroEnergy(emission),
+        f32x4::splat(vertex.pdf_forward),
+        f32x4::splat(vertex.pdf_backward),
+        vertex.veach_g,
+    );
+    (hero_vertex, sampled.0)
+}
+
+// splits a hero path into one single wavelength path per lane
+pub fn split_lanes(path: &Vec<HeroSurfaceVertex>) -> Vec<Vec<SurfaceVertex>> {
+    (0..4)
+        .map(|lane| path.iter().map(|vertex| vertex.lane(lane)).collect())
+        .collect()
+}
+
+// the probability of sampling the subpaths of strategy (s, t) with each wavelength as the hero, relative to the actual hero.
+// only the vertices that were actually sampled count, the directions leaving the connecting vertices don't.
+// lanes that were terminated at a dispersive interface get 0.
+pub fn spectral_ratios(
+    light_path: &Vec<HeroSurfaceVertex>,
+    s: usize,
+    eye_path: &Vec<HeroSurfaceVertex>,
+    t: usize,
+) -> f32x4 {
+    let mut ratios = f32x4::splat(1.0);
+    let sampled_light_vertices = light_path[..s.saturating_sub(1)].iter();
+    let sampled_eye_vertices = eye_path[..t.saturating_sub(1)].iter();
+    for vertex in sampled_light_vertices.chain(sampled_eye_vertices) {
+        let hero_pdf = vertex.pdf_forward.extract(0);
+        if hero_pdf > 0.0 {
+            ratios *= vertex.pdf_forward / hero_pdf;
+        }
+    }
+    ratios
+}
+
+// evaluates strategy (s, t) for all four wavelengths, with combined bdpt and spectral mis weights, using the power heuristic.
+// the result for each lane is an estimate for that lane's wavelength, such that the four lanes should be averaged.
+// also returns whether the result needs to be splatted.
+pub fn eval_hero_strategy(
+    world: &Arc<World>,
+    light_path: &Vec<HeroSurfaceVertex>,
+    light_lanes: &Vec<Vec<SurfaceVertex>>,
+    s: usize,
+    eye_path: &Vec<HeroSurfaceVertex>,
+    eye_lanes: &Vec<Vec<SurfaceVertex>>,
+    t: usize,
+    sampler: &mut Box<dyn Sampler>,
+    russian_roulette_threshold: f32,
+    profile: &mut Profile,
+) -> (f32x4, bool) {
+    let ratios = spectral_ratios(light_path, s, eye_path, t);
+
+    let mut factors = f32x4::splat(0.0);
+    let mut splatted = false;
+    let mut connecting_g = 0.0;
+    for lane in 0..4 {
+        if ratios.extract(lane) == 0.0 {
+            continue;
+        }
+        let (factor, g) = match eval_unweighted_contribution(
+            world,
+            &light_lanes[lane],
+            s,
+            &eye_lanes[lane],
+            t,
+            sampler,
+            russian_roulette_threshold,
+            profile,
+        ) {
+            SampleKind::Sampled((factor, g)) => (factor, g),
+            SampleKind::Splatted((factor, g)) => {
+                splatted = true;
+                (factor, g)
+            }
+        };
+        if g != 0.0 {
+            // the connection geometry is the same for every lane
+            connecting_g = g;
+            factors = factors.replace(lane, factor.0);
+        }
+    }
+    if connecting_g == 0.0 || factors.eq(f32x4::splat(0.0)).all() {
+        return (f32x4::splat(0.0), splatted);
+    }
+
+    // every strategy with every wavelength as the hero could have produced this path
+    let mut sum = 0.0;
+    for lane in 0..4 {
+        let ratio = ratios.extract(lane);
+        if ratio == 0.0 {
+            continue;
+        }
+        let (ps, _path) = eval_path_probabilities(
+            world,
+            &light_lanes[lane],
+            s,
+            &eye_lanes[lane],
+            t,
+            connecting_g,
+        );
+        sum += ratio * ratio * ps.iter().map(|&p| p * p).sum::<f32>();
+    }
+    if sum == 0.0 || !sum.is_finite() {
+        return (f32x4::splat(0.0), splatted);
+    }
+    // the 4 compensates for averaging the lanes afterwards
+    (factors * 4.0 / sum, splatted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials::MaterialId;
+
+    #[test]
+    fn test_spectral_ratios() {
+        let lambda = f32x4::new(450.0, 520.0, 590.0, 660.0);
+        let mut vertex = HeroSurfaceVertex::default();
+        vertex.lambda = lambda;
+        vertex.material_id = MaterialId::Material(0);
+        vertex.pdf_forward = f32x4::splat(0.5);
+        let path = vec![vertex; 4];
+        // wavelength independent pdfs, every lane is as likely as the hero
+        assert!(spectral_ratios(&path, 3, &path, 2) == f32x4::splat(1.0));
+
+        // a dispersive vertex terminated every lane but the hero
+        let mut dispersive = vertex;
+        dispersive.pdf_forward = f32x4::new(0.5, 0.0, 0.0, 0.0);
+        let mut light_path = path.clone();
+        light_path[1] = dispersive;
+        assert!(spectral_ratios(&light_path, 3, &path, 2) == f32x4::new(1.0, 0.0, 0.0, 0.0));
+        // unless the terminated vertex's outgoing direction was replaced by the connection
+        assert!(spectral_ratios(&light_path, 2, &path, 2) == f32x4::splat(1.0));
+    }
+}
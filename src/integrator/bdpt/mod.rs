@@ -1,16 +1,20 @@
+pub mod hero;
 pub mod utils;
 
+use hero::*;
 use utils::*;
 
 // use crate::aabb::HasBoundingBox;
 use crate::config::RenderSettings;
-use crate::hittable::Hittable;
+use crate::integrator::pt_hwss::generate_hero;
 use crate::integrator::utils::*;
 use crate::integrator::*;
 use crate::materials::*;
 use crate::math::*;
 use crate::world::TransportMode;
 use crate::world::World;
+
+use packed_simd::f32x4;
 // use std::f32::INFINITY;
 use std::sync::Arc;
 
@@ -18,127 +22,235 @@ pub struct BDPTIntegrator {
     pub max_bounces: u16,
     pub world: Arc<World>,
     pub wavelength_bounds: Bounds1D,
+    pub hwss: bool,
+}
+
+impl BDPTIntegrator {
+    // bdpt with hero wavelength spectral sampling. the strategies are the same as the single wavelength version,
+    // and each strategy is evaluated for all four wavelengths, see hero.rs.
+    fn color_hero(
+        &self,
+        sampler: &mut Box<dyn Sampler>,
+        settings: &RenderSettings,
+        camera_sample: ((f32, f32), CameraId),
+        light_groups: &mut [XYZColor],
+        samples: &mut Vec<(Sample, CameraId)>,
+        profile: &mut Profile,
+    ) -> XYZColor {
+        let lambda = generate_hero(sampler.draw_1d().x, self.wavelength_bounds);
+        // every lane is uniformly distributed over the wavelength bounds
+        let wavelength_pdf = 1.0 / self.wavelength_bounds.span();
+        let (start_light_vertex, light_ray) =
+            sample_light_vertex_hero(&self.world, lambda, self.wavelength_bounds, sampler);
+
+        let camera_id = camera_sample.1;
+        let camera = self.world.get_camera(camera_id);
+        let film_sample = Sample2D::new(
+            (camera_sample.0).0.clamp(0.0, 1.0 - std::f32::EPSILON),
+            (camera_sample.0).1.clamp(0.0, 1.0 - std::f32::EPSILON),
+        );
+        let (camera_ray, lens_normal, _) =
+            camera.sample_we(film_sample, sampler, lambda.extract(0));
+        // the eye path starts with the directional pdf of the camera ray, to match what eval_we gives when connecting to the lens
+        let camera_pdf = camera
+            .eval_we(
+                lambda.extract(0),
+                camera_ray.origin,
+                camera_ray.point_at_parameter(1.0),
+            )
+            .1;
+
+        let mut light_path: Vec<HeroSurfaceVertex> =
+            Vec::with_capacity(1 + self.max_bounces as usize);
+        let mut eye_path: Vec<HeroSurfaceVertex> =
+            Vec::with_capacity(1 + self.max_bounces as usize);
+        eye_path.push(HeroSurfaceVertex::new(
+            VertexType::Camera,
+            camera_ray.time,
+            lambda,
+            Vec3::ZERO,
+            camera_ray.origin,
+            lens_normal,
+            (0.0, 0.0),
+            MaterialId::Camera(camera_id as u16),
+            0,
+            HeroEnergy(f32x4::splat(1.0)),
+            f32x4::splat(camera_pdf.0),
+            f32x4::splat(0.0),
+            1.0,
+        ));
+        let radiance = start_light_vertex.throughput.0;
+        light_path.push(start_light_vertex);
+
+        let (sp1, tp1) = if let IntegratorKind::BDPT {
+            selected_pair: Some((s, t)),
+        } = settings.integrator
+        {
+            (s + 1, t + 1)
+        } else {
+            (self.max_bounces as usize, self.max_bounces as usize)
+        };
+        random_walk_hero(
+            camera_ray,
+            lambda,
+            tp1 as u16,
+            f32x4::splat(1.0),
+            TransportMode::Importance,
+            sampler,
+            &self.world,
+            &mut eye_path,
+            settings.min_bounces.unwrap_or(3),
+            profile,
+        );
+        random_walk_hero(
+            light_ray,
+            lambda,
+            sp1 as u16,
+            radiance,
+            TransportMode::Radiance,
+            sampler,
+            &self.world,
+            &mut light_path,
+            settings.min_bounces.unwrap_or(3),
+            profile,
+        );
+        profile.camera_rays += 1;
+        profile.light_rays += 1;
+
+        let light_lanes = split_lanes(&light_path);
+        let eye_lanes = split_lanes(&eye_path);
+        let (eye_vertex_count, light_vertex_count) = (eye_path.len(), light_path.len());
+        let russian_roulette_threshold = 0.005;
+
+        let selected_pair = match settings.integrator {
+            IntegratorKind::BDPT { selected_pair } => selected_pair,
+            _ => None,
+        };
+        let mut sum = f32x4::splat(0.0);
+        for path_length in 1..(1 + self.max_bounces as usize) {
+            let path_vertex_count = path_length + 1;
+            for s in 0..path_vertex_count {
+                let t = path_vertex_count - s;
+                if s > light_vertex_count || t > eye_vertex_count {
+                    continue;
+                }
+                if (s == 0 && t < 2) || (t == 0 && s < 2) || (s + t) < 2 {
+                    continue;
+                }
+                if let Some(pair) = selected_pair {
+                    if pair != (s, t) {
+                        continue;
+                    }
+                }
+                let (contribution, splatted) = eval_hero_strategy(
+                    &self.world,
+                    &light_path,
+                    &light_lanes,
+                    s,
+                    &eye_path,
+                    &eye_lanes,
+                    t,
+                    sampler,
+                    russian_roulette_threshold,
+                    profile,
+                );
+                if contribution.eq(f32x4::splat(0.0)).all() {
+                    continue;
+                }
+                // the light is at the end of the eye path when it hit it, and at the start of the light path otherwise
+                let light_vertex = if s == 0 {
+                    eye_path[t - 1]
+                } else {
+                    light_path[0]
+                };
+                let light_group = light_group_of_vertex(
+                    &self.world,
+                    light_vertex.vertex_type,
+                    light_vertex.instance_id,
+                );
+                if splatted {
+                    let (vert_on_lens, vert_in_scene) = if t == 1 {
+                        (eye_path[0].point, light_path[s - 1].point)
+                    } else {
+                        (light_path[s - 1].point, light_path[s - 2].point)
+                    };
+                    let ray = Ray::new(vert_on_lens, (vert_in_scene - vert_on_lens).normalized());
+                    if let Some(pixel_uv) = camera.get_pixel_for_ray(ray, lambda.extract(0)) {
+                        let color = hero_to_xyz(lambda, contribution / wavelength_pdf);
+                        samples
+                            .push((Sample::LightSample(color, pixel_uv, light_group), camera_id));
+                    }
+                } else {
+                    sum += contribution;
+                    if let Some(group_color) =
+                        light_group.and_then(|group| light_groups.get_mut(group))
+                    {
+                        *group_color += hero_to_xyz(lambda, contribution / wavelength_pdf);
+                    }
+                }
+            }
+        }
+        debug_assert!(sum.is_finite().all());
+        hero_to_xyz(lambda, sum / wavelength_pdf)
+    }
+}
+
+// averages the four lanes of a hero wavelength estimate into a single color
+fn hero_to_xyz(lambda: f32x4, energy: f32x4) -> XYZColor {
+    let mut color = XYZColor::BLACK;
+    for i in 0..4 {
+        color += XYZColor::from(SingleWavelength::new(
+            lambda.extract(i),
+            SingleEnergy(energy.extract(i) / 4.0),
+        ));
+    }
+    color
 }
 
 impl GenericIntegrator for BDPTIntegrator {
     fn color(
+        &self,
+        sampler: &mut Box<dyn Sampler>,
+        settings: &RenderSettings,
+        camera_sample: ((f32, f32), CameraId),
+        sample_id: usize,
+        samples: &mut Vec<(Sample, CameraId)>,
+        profile: &mut Profile,
+    ) -> XYZColor {
+        self.color_with_light_groups(
+            sampler,
+            settings,
+            camera_sample,
+            sample_id,
+            &mut [],
+            samples,
+            profile,
+        )
+    }
+    fn color_with_light_groups(
         &self,
         mut sampler: &mut Box<dyn Sampler>,
         settings: &RenderSettings,
         camera_sample: ((f32, f32), CameraId),
         _sample_id: usize,
+        light_groups: &mut [XYZColor],
         samples: &mut Vec<(Sample, CameraId)>,
         mut profile: &mut Profile,
     ) -> XYZColor {
-        // setup: decide light, emit ray from light, decide camera, emit ray from camera, connect light path vertices to camera path vertices.
-
-        let wavelength_sample = sampler.draw_1d();
-        let light_pick_sample = sampler.draw_1d();
-        let env_sampling_probability = self.world.get_env_sampling_probability();
-
-        let sampled;
-
-        let start_light_vertex;
-        let (light_pick_sample, sample_env) =
-            light_pick_sample.choose(env_sampling_probability, true, false);
-        if !sample_env {
-            let (light, light_pick_pdf) = self.world.pick_random_light(light_pick_sample).unwrap();
-
-            // if we picked a light
-            let (light_surface_point, light_surface_normal, area_pdf) =
-                light.sample_surface(sampler.draw_2d());
-
-            let mat_id = light.get_material_id();
-            let material = self.world.get_material(mat_id);
-            // println!("sampled light emission in instance light branch");
-            let maybe_sampled = material.sample_emission(
-                light_surface_point,
-                light_surface_normal,
-                self.wavelength_bounds,
-                sampler.draw_2d(),
-                wavelength_sample,
-            );
-            sampled = if let Some(data) = maybe_sampled {
-                data
-            } else {
-                println!("failed to sample, material is {:?}", material.get_name());
-                panic!();
-            };
-
-            let directional_pdf = sampled.2;
-            // if delta light, the pdf_forward is only directional_pdf
-            let pdf_forward: PDF =
-                directional_pdf / (light_surface_normal * (&sampled.0).direction).abs();
-            let pdf_backward: PDF = light_pick_pdf * area_pdf;
-            debug_assert!(
-                pdf_forward.0.is_finite(),
-                "pdf_forward was not finite {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
-                pdf_forward,
-                pdf_backward,
-                sampled.0,
-                material.get_name(),
-                directional_pdf,
-                light_surface_point,
-                light_surface_normal,
-                sampled.1.energy
-            );
-            debug_assert!(
-                pdf_backward.0.is_finite(),
-                "pdf_backward was not finite {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
-                pdf_backward,
-                pdf_forward,
-                material.get_name(),
-                directional_pdf,
-                light_surface_point,
-                light_surface_normal,
-                sampled.1.energy
+        if self.hwss {
+            return self.color_hero(
+                sampler,
+                settings,
+                camera_sample,
+                light_groups,
+                samples,
+                profile,
             );
+        }
+        // setup: decide light, emit ray from light, decide camera, emit ray from camera, connect light path vertices to camera path vertices.
 
-            start_light_vertex = SurfaceVertex::new(
-                VertexType::LightSource(LightSourceType::Instance),
-                0.0,
-                sampled.1.lambda,
-                Vec3::ZERO,
-                light_surface_point,
-                light_surface_normal,
-                (0.0, 0.0),
-                mat_id,
-                light.get_instance_id(),
-                sampled.1.energy,
-                pdf_forward.into(),
-                pdf_backward.into(),
-                1.0,
-            );
-        } else {
-            // sample world env
-            let world_radius = self.world.get_world_radius();
-            let world_center = self.world.get_center();
-            sampled = self.world.environment.sample_emission(
-                world_radius,
-                world_center,
-                sampler.draw_2d(),
-                sampler.draw_2d(),
-                self.wavelength_bounds,
-                wavelength_sample,
-            );
-            let light_g_term = 1.0;
-            let directional_pdf = sampled.2;
-            start_light_vertex = SurfaceVertex::new(
-                VertexType::LightSource(LightSourceType::Environment),
-                0.0,
-                sampled.1.lambda,
-                Vec3::ZERO,
-                sampled.0.origin,
-                -sampled.0.direction,
-                (0.0, 0.0),
-                MaterialId::Light(0),
-                0,
-                sampled.1.energy,
-                directional_pdf.0,
-                1.0,
-                light_g_term,
-            );
-        };
+        let (start_light_vertex, sampled) =
+            sample_light_vertex(&self.world, self.wavelength_bounds, &mut sampler);
 
         let light_ray = sampled.0;
         let lambda = sampled.1.lambda;
@@ -159,10 +271,18 @@ impl GenericIntegrator for BDPTIntegrator {
             (camera_sample.0).1.clamp(0.0, 1.0 - std::f32::EPSILON),
         );
 
-        let (sampled_camera_ray, lens_normal, camera_pdf) =
+        let (sampled_camera_ray, lens_normal, _) =
             camera.sample_we(film_sample, &mut sampler, lambda);
         // let camera_pdf = pdf;
         camera_ray = sampled_camera_ray;
+        // the eye path starts with the directional pdf of the camera ray, to match what eval_we gives when connecting to the lens
+        let camera_pdf = camera
+            .eval_we(
+                lambda,
+                camera_ray.origin,
+                camera_ray.point_at_parameter(1.0),
+            )
+            .1;
 
         let radiance = sampled.1.energy;
 
@@ -185,6 +305,8 @@ impl GenericIntegrator for BDPTIntegrator {
             0.0,
             1.0,
         ));
+        let camera_medium = self.world.get_camera_medium(camera_id);
+        eye_path[0].medium_id = camera_medium;
         light_path.push(start_light_vertex);
         let (sp1, tp1) = if let IntegratorKind::BDPT {
             selected_pair: Some((s, t)),
@@ -195,7 +317,7 @@ impl GenericIntegrator for BDPTIntegrator {
             (self.max_bounces as usize, self.max_bounces as usize)
         };
 
-        let _additional_contribution_eye_path = random_walk(
+        let additional_contribution = random_walk_medium_from(
             camera_ray,
             lambda,
             tp1 as u16,
@@ -206,8 +328,9 @@ impl GenericIntegrator for BDPTIntegrator {
             &mut eye_path,
             settings.min_bounces.unwrap_or(3),
             &mut profile,
+            camera_medium,
         );
-        random_walk(
+        random_walk_medium(
             light_ray,
             lambda,
             sp1 as u16,
@@ -241,13 +364,34 @@ impl GenericIntegrator for BDPTIntegrator {
 
         let (eye_vertex_count, light_vertex_count) = (eye_path.len(), light_path.len());
 
+        // emission from mediums is only picked up by the eye path's collision estimator, which makes it the s = 0 strategy
+        // for paths that end in a medium. light subpaths never start in a medium, so it's the only strategy that can sample
+        // those paths and its mis weight is 1.
+        let medium_emission = additional_contribution.unwrap_or(SingleEnergy::ZERO) / (sampled.3).0;
+
         static MIS_ENABLED: bool = true;
         let russian_roulette_threshold = 0.005;
         if let IntegratorKind::BDPT {
             selected_pair: Some((s, t)),
         } = settings.integrator
         {
+            let medium_emission = if s == 0 {
+                medium_emission
+            } else {
+                SingleEnergy::ZERO
+            };
             if s <= light_vertex_count && t <= eye_vertex_count {
+                // the light is at the end of the eye path when it hit it, and at the start of the light path otherwise
+                let light_vertex = if s == 0 {
+                    eye_path[t - 1]
+                } else {
+                    light_path[0]
+                };
+                let light_group = light_group_of_vertex(
+                    &self.world,
+                    light_vertex.vertex_type,
+                    light_vertex.instance_id,
+                );
                 let res = eval_unweighted_contribution(
                     &self.world,
                     &light_path,
@@ -262,7 +406,7 @@ impl GenericIntegrator for BDPTIntegrator {
                 match res {
                     SampleKind::Sampled((factor, g)) => {
                         if g == 0.0 || factor == SingleEnergy::ZERO {
-                            return XYZColor::from(SingleWavelength::BLACK);
+                            return XYZColor::from(SingleWavelength::new(lambda, medium_emission));
                         }
                         let weight = if MIS_ENABLED {
                             eval_mis(
@@ -277,9 +421,17 @@ impl GenericIntegrator for BDPTIntegrator {
                         } else {
                             1.0 / ((s + t) as f32)
                         } / (sampled.3).0;
+                        if let Some(group_color) =
+                            light_group.and_then(|group| light_groups.get_mut(group))
+                        {
+                            *group_color += XYZColor::from(SingleWavelength::new(
+                                lambda,
+                                weight * factor / (sampled.3).0,
+                            ));
+                        }
                         return XYZColor::from(SingleWavelength::new(
                             lambda,
-                            weight * factor / (sampled.3).0,
+                            weight * factor / (sampled.3).0 + medium_emission,
                         ));
                     }
 
@@ -327,6 +479,7 @@ impl GenericIntegrator for BDPTIntegrator {
                                 let sample = Sample::LightSample(
                                     XYZColor::from(SingleWavelength::new(lambda, contribution)),
                                     pixel_uv,
+                                    light_group,
                                 );
                                 samples.push((sample, camera_id as usize));
                             } else {
@@ -337,11 +490,10 @@ impl GenericIntegrator for BDPTIntegrator {
                     }
                 }
             }
-            return XYZColor::from(SingleWavelength::BLACK);
+            return XYZColor::from(SingleWavelength::new(lambda, medium_emission));
         }
 
-        let mut sum = SingleEnergy::ZERO;
-        // sum += additional_contribution.unwrap_or(SingleEnergy::ZERO);
+        let mut sum = medium_emission;
         for path_length in 1..(1 + self.max_bounces as usize) {
             let path_vertex_count = path_length + 1;
             for s in 0..(path_vertex_count as usize) {
@@ -392,6 +544,17 @@ impl GenericIntegrator for BDPTIntegrator {
                 if weight == 0.0 {
                     continue;
                 }
+                // the light is at the end of the eye path when it hit it, and at the start of the light path otherwise
+                let light_vertex = if s == 0 {
+                    eye_path[t - 1]
+                } else {
+                    light_path[0]
+                };
+                let light_group = light_group_of_vertex(
+                    &self.world,
+                    light_vertex.vertex_type,
+                    light_vertex.instance_id,
+                );
                 if calculate_splat {
                     // println!("should be splatting2");
                     let contribution = weight * factor;
@@ -420,6 +583,7 @@ impl GenericIntegrator for BDPTIntegrator {
                             let sample = Sample::LightSample(
                                 XYZColor::from(SingleWavelength::new(lambda, contribution)),
                                 pixel_uv,
+                                light_group,
                             );
                             samples.push((sample, camera_id as usize));
                         } else {
@@ -429,6 +593,12 @@ impl GenericIntegrator for BDPTIntegrator {
                 } else {
                     sum += weight * factor;
                     debug_assert!(sum.0.is_finite(), "{:?} {:?}", weight, factor);
+                    if let Some(group_color) =
+                        light_group.and_then(|group| light_groups.get_mut(group))
+                    {
+                        *group_color +=
+                            XYZColor::from(SingleWavelength::new(lambda, weight * factor));
+                    }
                 }
             }
         }
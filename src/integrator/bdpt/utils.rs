@@ -1,11 +1,11 @@
 // use crate::config::Settings;
 // use crate::camera::*;
-use crate::hittable::HitRecord;
+use crate::hittable::{HitRecord, Hittable};
 use crate::integrator::utils::*;
 use crate::materials::{Material, MaterialId};
 use crate::math::*;
 use crate::profile::Profile;
-use crate::world::World;
+use crate::world::{LightRef, TransportMode, World};
 
 use std::ops::Index;
 use std::sync::Arc;
@@ -15,6 +15,235 @@ pub enum SampleKind {
     Splatted((SingleEnergy, f32)),
 }
 
+// samples the starting vertex of a light subpath, either on an emissive instance, a punctual light or on the environment.
+// also returns the sampled emission ray, wavelength, directional pdf and wavelength pdf.
+pub fn sample_light_vertex(
+    world: &Arc<World>,
+    wavelength_bounds: Bounds1D,
+    sampler: &mut Box<dyn Sampler>,
+) -> (SurfaceVertex, (Ray, SingleWavelength, PDF, PDF)) {
+    let wavelength_sample = sampler.draw_1d();
+    let light_pick_sample = sampler.draw_1d();
+    let env_sampling_probability = world.get_env_sampling_probability();
+
+    let mut sampled;
+
+    let mut start_light_vertex;
+    let (light_pick_sample, sample_env) =
+        light_pick_sample.choose(env_sampling_probability, true, false);
+    if !sample_env {
+        let (light, light_pick_pdf) = world.pick_random_light(light_pick_sample).unwrap();
+        let light = match light {
+            LightRef::Instance(instance) => instance,
+            LightRef::Punctual(index, punctual) => {
+                let sampled = punctual.sample_emission(
+                    world.get_world_radius(),
+                    world.get_center(),
+                    sampler.draw_2d(),
+                    sampler.draw_2d(),
+                    wavelength_bounds,
+                    wavelength_sample,
+                );
+                // like the environment, directional lights store the direction towards the light as the normal.
+                // point and spot lights have no surface, so the normal is just the emitted direction
+                let normal = if punctual.is_directional() {
+                    -sampled.0.direction
+                } else {
+                    sampled.0.direction
+                };
+                // pdf_forward is the delta light's directional pdf, and pdf_backward only has the pick probability since there's no area to sample
+                let mut start_light_vertex = SurfaceVertex::new(
+                    VertexType::LightSource(LightSourceType::Punctual),
+                    0.0,
+                    sampled.1.lambda,
+                    Vec3::ZERO,
+                    sampled.0.origin,
+                    normal,
+                    (0.0, 0.0),
+                    MaterialId::Light(0),
+                    index,
+                    sampled.1.energy,
+                    (sampled.2).0,
+                    light_pick_pdf.0 * (1.0 - env_sampling_probability),
+                    1.0,
+                );
+                start_light_vertex.medium_id = world.world_medium;
+                return (start_light_vertex, sampled);
+            }
+        };
+
+        // if we picked a light
+        let (light_surface_point, light_surface_normal, light_surface_uv, area_pdf) =
+            world.sample_light_surface(light, sampler.draw_2d());
+
+        let mat_id = light.get_material_id();
+        let material = world.get_material(mat_id);
+        // println!("sampled light emission in instance light branch");
+        let maybe_sampled = material.sample_emission(
+            light_surface_point,
+            light_surface_normal,
+            light_surface_uv,
+            wavelength_bounds,
+            sampler.draw_2d(),
+            wavelength_sample,
+        );
+        // if the light couldn't be sampled, start a path that carries nothing along the normal instead
+        sampled = maybe_sampled.unwrap_or_else(|| {
+            (
+                Ray::new(light_surface_point, light_surface_normal),
+                SingleWavelength::new_from_range(wavelength_sample.x, wavelength_bounds),
+                PDF::from(1.0),
+                PDF::from(1.0),
+            )
+        });
+        if area_pdf.0 == 0.0 {
+            // the point fell outside of the emitting part of a textured light, so the path carries nothing
+            sampled.1.energy = SingleEnergy::ZERO;
+        }
+
+        let directional_pdf = sampled.2;
+        // if delta light, the pdf_forward is only directional_pdf
+        let pdf_forward: PDF =
+            directional_pdf / (light_surface_normal * (&sampled.0).direction).abs();
+        // includes the probability of picking this light over the others and over the environment
+        let pdf_backward: PDF =
+            light_pick_pdf * PDF::from(1.0 - env_sampling_probability) * area_pdf;
+        debug_assert!(
+            pdf_forward.0.is_finite(),
+            "pdf_forward was not finite {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
+            pdf_forward,
+            pdf_backward,
+            sampled.0,
+            material.get_name(),
+            directional_pdf,
+            light_surface_point,
+            light_surface_normal,
+            sampled.1.energy
+        );
+        debug_assert!(
+            pdf_backward.0.is_finite(),
+            "pdf_backward was not finite {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
+            pdf_backward,
+            pdf_forward,
+            material.get_name(),
+            directional_pdf,
+            light_surface_point,
+            light_surface_normal,
+            sampled.1.energy
+        );
+
+        start_light_vertex = SurfaceVertex::new(
+            VertexType::LightSource(LightSourceType::Instance),
+            0.0,
+            sampled.1.lambda,
+            Vec3::ZERO,
+            light_surface_point,
+            light_surface_normal,
+            light_surface_uv,
+            mat_id,
+            light.get_instance_id(),
+            sampled.1.energy,
+            pdf_forward.into(),
+            pdf_backward.into(),
+            1.0,
+        );
+    } else {
+        // sample world env
+        sampled = world.sample_environment_emission(
+            sampler.draw_2d(),
+            sampler.draw_2d(),
+            wavelength_bounds,
+            wavelength_sample,
+        );
+        let light_g_term = 1.0;
+        let directional_pdf = sampled.2;
+        start_light_vertex = SurfaceVertex::new(
+            VertexType::LightSource(LightSourceType::Environment),
+            0.0,
+            sampled.1.lambda,
+            Vec3::ZERO,
+            sampled.0.origin,
+            -sampled.0.direction,
+            (0.0, 0.0),
+            MaterialId::Light(0),
+            0,
+            sampled.1.energy,
+            directional_pdf.0,
+            env_sampling_probability,
+            light_g_term,
+        );
+    };
+    // lights are assumed to sit in the world medium
+    start_light_vertex.medium_id = world.world_medium;
+    (start_light_vertex, sampled)
+}
+
+// the light group of the light at the start of a light path or at the end of an eye path that hit a light.
+// emission from mediums isn't part of any group
+pub fn light_group_of_vertex(
+    world: &World,
+    vertex_type: VertexType,
+    instance_id: usize,
+) -> Option<usize> {
+    match vertex_type {
+        VertexType::LightSource(LightSourceType::Environment) => {
+            Some(world.environment_light_group())
+        }
+        VertexType::LightSource(LightSourceType::Punctual) => Some(world.light_group_of(
+            LightRef::Punctual(instance_id, &world.punctual_lights[instance_id]),
+        )),
+        _ => world.light_group_of_instance(instance_id),
+    }
+}
+
+// moves a directional light vertex so that it lies along the light's direction as seen from eye_point, like pt's sample_li does.
+// every other light vertex is returned unchanged
+pub fn resample_light_vertex_towards(
+    world: &World,
+    light_vertex: SurfaceVertex,
+    eye_point: Point3,
+) -> SurfaceVertex {
+    let mut light_vertex = light_vertex;
+    if light_vertex.vertex_type == VertexType::LightSource(LightSourceType::Punctual) {
+        let light = &world.punctual_lights[light_vertex.instance_id];
+        if light.is_directional() {
+            let (point_on_light, _, _) =
+                light.sample_li(eye_point, light_vertex.lambda, world.get_world_radius());
+            light_vertex.point = point_on_light;
+        }
+    }
+    light_vertex
+}
+
+// evaluates the emission leaving a light vertex from sample_light_vertex along direction, at a different wavelength than the one it was sampled with
+pub fn eval_light_vertex_emission(
+    world: &Arc<World>,
+    vertex: &SurfaceVertex,
+    direction: Vec3,
+    lambda: f32,
+) -> SingleEnergy {
+    match vertex.vertex_type {
+        VertexType::LightSource(LightSourceType::Environment) => world
+            .environment
+            .emission(direction_to_uv(vertex.normal), lambda),
+        VertexType::LightSource(LightSourceType::Punctual) => {
+            world.punctual_lights[vertex.instance_id].emission(direction, lambda)
+        }
+        // see sample_light_vertex, light vertices that can't be sampled don't emit
+        _ if vertex.pdf_backward == 0.0 => SingleEnergy::ZERO,
+        _ => {
+            let material = world.get_material(vertex.material_id);
+            let frame = TangentFrame::from_normal(vertex.normal);
+            material.emission(
+                lambda,
+                vertex.uv,
+                TransportMode::Radiance,
+                frame.to_local(&direction).normalized(),
+            )
+        }
+    }
+}
+
 pub fn eval_unweighted_contribution(
     world: &Arc<World>,
     light_path: &Vec<SurfaceVertex>,
@@ -54,7 +283,7 @@ pub fn eval_unweighted_contribution(
             let uv = direction_to_uv(wo);
             let emission = world.environment.emission(uv, last_eye_vertex.lambda);
             cst = emission;
-            let cos_o = (wo * second_to_last_eye_vertex.normal).abs();
+            let cos_o = second_to_last_eye_vertex.cos(wo);
             g = cos_o;
         } else {
             let hit_light_material = world.get_material(last_eye_vertex.material_id);
@@ -65,7 +294,7 @@ pub fn eval_unweighted_contribution(
             debug_assert!(wi.0.is_finite().all(), "{:?}", eye_path);
             let (cos_i, cos_o) = (
                 (wi * normal).abs(), // these are cosines relative to their surface normals btw.
-                (wi * second_to_last_eye_vertex.normal).abs(), // i.e. eye_to_light.dot(eye_vertex_normal) and light_to_eye.dot(light_vertex_normal)
+                second_to_last_eye_vertex.cos(wi), // i.e. eye_to_light.dot(eye_vertex_normal) and light_to_eye.dot(light_vertex_normal)
             );
             g = veach_g(
                 last_eye_vertex.point,
@@ -91,7 +320,6 @@ pub fn eval_unweighted_contribution(
                 camera
                     .eval_we(
                         lambda,
-                        last_light_vertex.normal,
                         last_light_vertex.point,
                         second_to_last_light_vertex.point,
                     )
@@ -100,7 +328,7 @@ pub fn eval_unweighted_contribution(
             sample = SampleKind::Splatted((SingleEnergy::ONE, 0.0));
 
             let (cos_i, cos_o) = (
-                (direction * second_to_last_light_vertex.normal).abs(), // these are cosines relative to their surface normals btw.
+                second_to_last_light_vertex.cos(direction), // these are cosines relative to their surface normals btw.
                 (direction * last_light_vertex.normal).abs(), // i.e. eye_to_light.dot(eye_vertex_normal) and light_to_eye.dot(light_vertex_normal)
             );
             g = veach_g(
@@ -117,9 +345,17 @@ pub fn eval_unweighted_contribution(
         // also assume that camera_path[0] throughput is set to the so called We value, which is a measure of the importance of the given camera ray and wavelength sample
 
         // a valid connection can be made.
-        let last_light_vertex = light_path[s - 1]; // s_end_v
         let last_eye_vertex = eye_path[t - 1]; // t_end_v
+
+        // s_end_v. when it's on a directional light, it's resampled to be in the light's direction from the eye vertex
+        let last_light_vertex = if s == 1 {
+            resample_light_vertex_towards(world, light_path[0], last_eye_vertex.point)
+        } else {
+            light_path[s - 1]
+        };
         let mut ignore_distance_and_cos_o = false;
+        // point and spot lights emit intensity, so only the distance and the cosine at the eye vertex matter
+        let mut ignore_cos_o = false;
 
         let light_to_eye_vec = last_eye_vertex.point - last_light_vertex.point;
         let light_to_eye_direction = light_to_eye_vec.normalized();
@@ -155,6 +391,23 @@ pub fn eval_unweighted_contribution(
                     ignore_distance_and_cos_o = true;
                     emission
                 }
+            } else if last_light_vertex.vertex_type
+                == VertexType::LightSource(LightSourceType::Punctual)
+            {
+                let light = &world.punctual_lights[last_light_vertex.instance_id];
+                if last_eye_vertex.vertex_type
+                    == VertexType::LightSource(LightSourceType::Environment)
+                {
+                    SingleEnergy::ZERO
+                } else {
+                    if light.is_directional() {
+                        // the light vertex was moved along the light's direction, so like the environment only the cosine at the eye vertex matters
+                        ignore_distance_and_cos_o = true;
+                    } else {
+                        ignore_cos_o = true;
+                    }
+                    light.emission(llv_world_light_to_eye, last_eye_vertex.lambda)
+                }
             } else {
                 if last_eye_vertex.vertex_type
                     == VertexType::LightSource(LightSourceType::Environment)
@@ -181,17 +434,7 @@ pub fn eval_unweighted_contribution(
             } else {
                 let second_to_last_light_vertex = light_path[s - 2];
                 let wi = (second_to_last_light_vertex.point - last_light_vertex.point).normalized();
-                let hit_material = world.get_material(last_light_vertex.material_id);
-                let hit: HitRecord = last_light_vertex.into();
-                hit_material
-                    .bsdf(
-                        hit.lambda,
-                        hit.uv,
-                        hit.transport_mode,
-                        llv_frame.to_local(&wi).normalized(),
-                        llv_local_light_to_eye,
-                    )
-                    .0
+                eval_vertex_scattering(world, &last_light_vertex, wi, llv_world_light_to_eye).0
             }
         };
 
@@ -200,24 +443,18 @@ pub fn eval_unweighted_contribution(
         }
 
         // lev means Last Eye Vertex
-        let lev_normal = last_eye_vertex.normal;
-        let lev_frame = TangentFrame::from_normal(lev_normal);
         let lev_world_eye_to_light = -light_to_eye_direction;
-        let lev_local_eye_to_light = lev_frame.to_local(&lev_world_eye_to_light).normalized();
         let fse = if t == 1 {
             // connected to surface of camera
             if let MaterialId::Camera(camera_id) = last_eye_vertex.material_id {
                 let camera = world.get_camera(camera_id as usize);
                 sample = SampleKind::Splatted((SingleEnergy::ONE, 0.0));
+                // the point on the lens was sampled uniformly by area, so divide by that pdf
                 SingleEnergy(
                     camera
-                        .eval_we(
-                            lambda,
-                            lev_normal,
-                            last_eye_vertex.point,
-                            last_light_vertex.point,
-                        )
-                        .0,
+                        .eval_we(lambda, last_eye_vertex.point, last_light_vertex.point)
+                        .0
+                        * camera.lens_area(),
                 )
             } else {
                 SingleEnergy(0.0)
@@ -229,19 +466,7 @@ pub fn eval_unweighted_contribution(
             } else {
                 let second_to_last_eye_vertex = eye_path[t - 2];
                 let wi = (second_to_last_eye_vertex.point - last_eye_vertex.point).normalized();
-                // let wo = -light_to_eye;
-                let hit_material = world.get_material(last_eye_vertex.material_id);
-                let hit: HitRecord = last_eye_vertex.into();
-                let reflectance = hit_material
-                    .bsdf(
-                        hit.lambda,
-                        hit.uv,
-                        hit.transport_mode,
-                        lev_frame.to_local(&wi).normalized(),
-                        lev_local_eye_to_light,
-                    )
-                    .0;
-                reflectance
+                eval_vertex_scattering(world, &last_eye_vertex, wi, lev_world_eye_to_light).0
             }
         };
 
@@ -250,11 +475,13 @@ pub fn eval_unweighted_contribution(
         }
 
         let (cos_i, cos_o) = (
-            lev_local_eye_to_light.z().abs(), // these are cosines relative to their surface normals btw.
-            llv_local_light_to_eye.z().abs(), // i.e. eye_to_light.dot(eye_vertex_normal) and light_to_eye.dot(light_vertex_normal)
+            last_eye_vertex.cos(lev_world_eye_to_light), // these are cosines relative to their surface normals btw.
+            last_light_vertex.cos(llv_world_light_to_eye), // i.e. eye_to_light.dot(eye_vertex_normal) and light_to_eye.dot(light_vertex_normal)
         );
         if ignore_distance_and_cos_o {
             g = cos_i;
+        } else if ignore_cos_o {
+            g = veach_g(last_eye_vertex.point, cos_i, last_light_vertex.point, 1.0);
         } else {
             g = veach_g(last_eye_vertex.point, cos_i, last_light_vertex.point, cos_o);
         }
@@ -267,11 +494,20 @@ pub fn eval_unweighted_contribution(
         let russian_roulette_probability = (cst.0 / russian_roulette_threshold).min(1.0);
         if sample < russian_roulette_probability {
             profile.shadow_rays += 1;
-            if !veach_v(world, last_eye_vertex.point, last_light_vertex.point) {
+            // visibility, and transmittance through any mediums in between
+            let transmittance = eval_transmittance(
+                world,
+                lambda,
+                last_light_vertex.point,
+                medium_towards(world, &last_light_vertex, llv_world_light_to_eye),
+                last_eye_vertex.point,
+                _sampler.draw_1d(),
+            );
+            if transmittance == 0.0 {
                 // not visible
                 return SampleKind::Sampled((SingleEnergy::ZERO, 0.0));
             } else {
-                cst *= 1.0 / russian_roulette_probability;
+                cst *= transmittance / russian_roulette_probability;
             }
         } else {
             return SampleKind::Sampled((SingleEnergy::ZERO, 0.0));
@@ -300,6 +536,8 @@ pub struct CombinedPath<'a> {
     pub light_vertex_pdf_backward: f32,
     pub eye_vertex_pdf_forward: f32,
     pub eye_vertex_pdf_backward: f32,
+    // whether light_path[0] is on a directional light, whose rays are parallel
+    pub light_is_directional: bool,
 }
 
 impl<'a> CombinedPath<'a> {
@@ -326,7 +564,12 @@ impl<'a> CombinedPath<'a> {
             self.connecting_g
         } else if vidx1 < self.s {
             // if vidx1 is less than s, which is to say <= s-1
-            self.light_path[vidx1].veach_g
+            if vidx1 == 1 && self.light_is_directional {
+                // the distance from the disk the ray started on doesn't matter, like for the s = 1 connection
+                self.light_path[1].cos(self.light_path[0].normal)
+            } else {
+                self.light_path[vidx1].veach_g
+            }
         } else {
             // vidx1 must be > connection_index
             assert!(
@@ -426,6 +669,27 @@ pub fn eval_mis<F>(
 where
     F: FnOnce(&Vec<f32>) -> f32,
 {
+    if s + t == 2 {
+        return 1.0;
+    }
+    let (ps, _path) = eval_path_probabilities(world, light_path, s, eye_path, t, connecting_g);
+    let result = mis_function(&ps);
+    debug_assert!(result.is_finite());
+    result
+}
+
+// computes the probabilities of generating the path determined by s and t with every other strategy, relative to strategy s.
+// ps[i] is the relative probability of the strategy with i light vertices.
+// also returns the combined path, with the pdfs around the connection filled in.
+#[allow(unused)]
+pub fn eval_path_probabilities<'a>(
+    world: &Arc<World>,
+    light_path: &'a Vec<SurfaceVertex>,
+    s: usize,
+    eye_path: &'a Vec<SurfaceVertex>,
+    t: usize,
+    connecting_g: f32,
+) -> (Vec<f32>, CombinedPath<'a>) {
     let lambda = light_path[0].lambda;
     // computes the mis weight of generating the path determined by s and t
     // path index is i = s
@@ -435,11 +699,30 @@ where
     // the scaling factor towards the light root is p_i+1 / p_i
     // refer to veach (1997) page 306 equation 10.9
 
+    let light_is_directional = light_path[0].vertex_type
+        == VertexType::LightSource(LightSourceType::Punctual)
+        && world.punctual_lights[light_path[0].instance_id].is_directional();
+
     let k = s + t - 1; // for 2,0 case, k is 1
     let k1 = k + 1; // k1 is 2
 
     if s + t == 2 {
-        return 1.0;
+        // only one strategy is considered for paths this short
+        let mut ps: Vec<f32> = vec![0.0; s + t + 2];
+        ps[s] = 1.0;
+        let path = CombinedPath {
+            light_path,
+            eye_path,
+            s,
+            t,
+            connecting_g,
+            light_vertex_pdf_forward: 1.0,
+            light_vertex_pdf_backward: 1.0,
+            eye_vertex_pdf_forward: 1.0,
+            eye_vertex_pdf_backward: 1.0,
+            light_is_directional,
+        };
+        return (ps, path);
     }
 
     // general notes:
@@ -466,10 +749,19 @@ where
 
     // need to compute the connecting pdfs.
 
-    let last_light_vertex = if s > 0 { Some(light_path[s - 1]) } else { None };
+    let last_eye_vertex = if t > 0 { Some(eye_path[t - 1]) } else { None };
+    // match the resampled light vertex from eval_unweighted_contribution
+    let last_light_vertex = match (s, last_eye_vertex) {
+        (0, _) => None,
+        (1, Some(lev)) => Some(resample_light_vertex_towards(
+            world,
+            light_path[0],
+            lev.point,
+        )),
+        _ => Some(light_path[s - 1]),
+    };
     let second_to_last_light_vertex = if s > 1 { Some(light_path[s - 2]) } else { None };
 
-    let last_eye_vertex = if t > 0 { Some(eye_path[t - 1]) } else { None };
     let second_to_last_eye_vertex = if t > 1 { Some(eye_path[t - 2]) } else { None };
 
     // compute forward pdfs, which is lev to llv pdf and llv to lev pdf
@@ -505,32 +797,11 @@ where
 
         if let Some(sllv) = second_to_last_light_vertex {
             let wi = (sllv.point - llv.point).normalized();
-            let hit_material = world.get_material(llv.material_id);
-            // let g = veach_g(
-            //     lev.point,
-            //     lev_local_eye_to_light.z().abs(),
-            //     llv.point,
-            //     llv_local_light_to_eye.z().abs(),
-            // );
-            let hit: HitRecord = llv.into();
-            llv_forward_pdf = hit_material
-                .bsdf(
-                    hit.lambda,
-                    hit.uv,
-                    hit.transport_mode,
-                    llv_frame.to_local(&wi).normalized(),
-                    llv_local_light_to_eye,
-                )
+            // the phase function for medium vertices
+            llv_forward_pdf = eval_vertex_scattering(world, &llv, wi, llv_world_light_to_eye)
                 .1
                  .0;
-            llv_backward_pdf = hit_material
-                .bsdf(
-                    hit.lambda,
-                    hit.uv,
-                    hit.transport_mode,
-                    llv_local_light_to_eye,
-                    llv_frame.to_local(&wi).normalized(),
-                )
+            llv_backward_pdf = eval_vertex_scattering(world, &llv, llv_world_light_to_eye, wi)
                 .1
                  .0;
         } else {
@@ -549,6 +820,12 @@ where
 
                 llv_forward_pdf = llv.pdf_forward;
                 llv_backward_pdf = 0.0; // what to do here? since env probability would be expressed in solid angle space.
+            } else if llv.vertex_type == VertexType::LightSource(LightSourceType::Punctual) {
+                let light = &world.punctual_lights[llv.instance_id];
+                llv_forward_pdf = light
+                    .emission_pdf(llv_world_light_to_eye, world.get_world_radius())
+                    .0;
+                llv_backward_pdf = 1.0;
             } else {
                 let hit_light_material = world.get_material(llv.material_id);
 
@@ -581,33 +858,11 @@ where
 
         if let Some(slev) = second_to_last_eye_vertex {
             let wi = (slev.point - lev.point).normalized();
-            let hit_material = world.get_material(lev.material_id);
-            let llv_local_wi = lev_frame.to_local(&wi).normalized();
-            // let g = veach_g(
-            //     lev.point,
-            //     lev_local_eye_to_light.z().abs(),
-            //     llv.point,
-            //     llv_local_light_to_eye.z().abs(),
-            // );
-            let hit: HitRecord = lev.into();
-            lev_forward_pdf = hit_material
-                .bsdf(
-                    hit.lambda,
-                    hit.uv,
-                    hit.transport_mode,
-                    llv_local_wi,
-                    llv_local_light_to_eye,
-                )
+            // the phase function for medium vertices
+            lev_forward_pdf = eval_vertex_scattering(world, &lev, wi, lev_world_eye_to_light)
                 .1
                  .0;
-            lev_backward_pdf = hit_material
-                .bsdf(
-                    hit.lambda,
-                    hit.uv,
-                    hit.transport_mode,
-                    llv_local_light_to_eye,
-                    llv_local_wi,
-                )
+            lev_backward_pdf = eval_vertex_scattering(world, &lev, lev_world_eye_to_light, wi)
                 .1
                  .0;
         } else {
@@ -622,7 +877,7 @@ where
                     llv_local_light_to_eye.z().abs(),
                 );
                 let camera = world.get_camera(camera_id as usize);
-                lev_forward_pdf = (camera.eval_we(lambda, lev_normal, lev.point, llv.point).1).0;
+                lev_forward_pdf = (camera.eval_we(lambda, lev.point, llv.point).1).0;
                 lev_backward_pdf = 1.0; // do camera area sampling?
             } else {
                 lev_forward_pdf = 0.0;
@@ -676,7 +931,18 @@ where
                         frame.to_local(&wi).normalized(),
                     )
                     .0;
-                llv_forward_pdf = 1.0; // do light area sampling pdf
+                // same as the pdf_backward of a light vertex from sample_light_vertex, which samples uniformly by area
+                // over the emissive part of the light unless its emission is textured
+                let light = world.get_primitive(last_eye_vertex.instance_id);
+                let area = light.light_area();
+                llv_forward_pdf = if area > 0.0 {
+                    world.light_pick_pdf(last_eye_vertex.instance_id).0
+                        * (1.0 - world.get_env_sampling_probability())
+                        * world.light_uv_pdf_scale(light, last_eye_vertex.uv)
+                        / area
+                } else {
+                    0.0
+                };
                 lev_backward_pdf = 0.0;
                 lev_forward_pdf = 0.0;
             }
@@ -699,7 +965,6 @@ where
                 llv_forward_pdf = (camera
                     .eval_we(
                         lambda,
-                        last_light_vertex.normal,
                         last_light_vertex.point,
                         second_to_last_light_vertex.point,
                     )
@@ -727,6 +992,7 @@ where
         light_vertex_pdf_backward: llv_backward_pdf,
         eye_vertex_pdf_forward: lev_forward_pdf,
         eye_vertex_pdf_backward: lev_backward_pdf,
+        light_is_directional,
     };
 
     let mut ps: Vec<f32> = vec![0.0; s + t + 2];
@@ -864,12 +1130,15 @@ where
         }
     }
 
+    if s > 0 && light_path[0].vertex_type == VertexType::LightSource(LightSourceType::Punctual) {
+        // eye paths can never hit a delta light
+        ps[0] = 0.0;
+    }
+
     for p in ps.iter() {
         debug_assert!(p.is_finite() && !p.is_nan(), "{:?}", ps);
     }
-    let result = mis_function(&ps);
-    debug_assert!(result.is_finite());
-    result
+    (ps, path)
 }
 /*
 #[cfg(test)]
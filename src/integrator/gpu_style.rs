@@ -6,8 +6,9 @@ use crate::hittable::Hittable;
 use crate::materials::*;
 use crate::math::*;
 // use crate::profile::Profile;
+use crate::sampler::SamplerFactory;
 use crate::world::World;
-use crate::world::{MaterialId, TransportMode};
+use crate::world::{LightRef, MaterialId, TransportMode};
 
 // use std::collections::HashMap;
 // use std::io::Write;
@@ -95,6 +96,8 @@ impl IntersectionBuffer {
 #[derive(Copy, Clone, Debug)]
 pub enum ConnectionData {
     Intersection(IntersectionData),
+    // the light arriving from a punctual light, if nothing is in the way
+    Punctual(SingleEnergy),
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -129,16 +132,27 @@ impl ShadingResultBuffer {
     }
 }
 
+// russian roulette, light pick, light surface and bsdf samples
+const DIMENSIONS_PER_BOUNCE: usize = 6;
+
 pub struct SampleBounceBuffer {
     pub sample_count: Vec<(usize, usize)>,
+    // the samples for every bounce of the current sample, drawn when the path starts. see primary_ray_pass
+    pub samples: Vec<Vec<f32>>,
 }
 
 impl SampleBounceBuffer {
     pub fn new(width: usize, height: usize) -> Self {
         SampleBounceBuffer {
             sample_count: vec![(0, 0); width * height],
+            samples: vec![Vec::new(); width * height],
         }
     }
+    // the samples for the current bounce of the path at buffer_idx, in the order of DIMENSIONS_PER_BOUNCE
+    pub fn bounce_samples(&self, buffer_idx: usize) -> &[f32] {
+        let start = self.sample_count[buffer_idx].1 * DIMENSIONS_PER_BOUNCE;
+        &self.samples[buffer_idx][start..start + DIMENSIONS_PER_BOUNCE]
+    }
 }
 
 pub fn intersection_cmp(a: &IntersectionData, b: &IntersectionData) -> Ordering {
@@ -223,6 +237,7 @@ pub struct GPUStylePTIntegrator {
     pub light_samples: u16,
     pub only_direct: bool,
     pub wavelength_bounds: Bounds1D,
+    pub sampler_factory: SamplerFactory,
 }
 
 impl GPUStylePTIntegrator {
@@ -234,6 +249,7 @@ impl GPUStylePTIntegrator {
         light_samples: u16,
         only_direct: bool,
         wavelength_bounds: Bounds1D,
+        sampler_factory: SamplerFactory,
     ) -> Self {
         GPUStylePTIntegrator {
             min_bounces,
@@ -243,16 +259,19 @@ impl GPUStylePTIntegrator {
             light_samples,
             only_direct,
             wavelength_bounds,
+            sampler_factory,
         }
     }
+    // tile_offset is the pixel the tile starts at, so that every pixel gets its own sampler
     pub fn primary_ray_pass(
         &self,
         buffer: &mut PrimaryRayBuffer,
-        sample_buffer: &SampleBounceBuffer,
+        sample_buffer: &mut SampleBounceBuffer,
         width: usize,
         height: usize,
         max_samples: usize,
         bounds: Bounds2D,
+        tile_offset: (usize, usize),
         cam_id: CameraId,
         camera: &Camera,
     ) -> Status {
@@ -310,8 +329,6 @@ impl GPUStylePTIntegrator {
         let mut buffer_idx = 0;
         let mut any_remaining = false;
         let buffer_size = buffer.rays.len();
-        let sampler = RandomSampler::new();
-        let mut boxed: Box<dyn Sampler> = Box::new(sampler);
         for index in first_empty..buffer_size {
             loop {
                 if buffer_idx >= buffer_size {
@@ -337,16 +354,44 @@ impl GPUStylePTIntegrator {
             }
 
             // ready to fire from camera
-            let tile_u = (buffer_idx % width) as f32 / width as f32; // + pixel_offset.0;
-            let tile_v = (buffer_idx / width) as f32 / height as f32; // + pixel_offset.1;
+            // every sample of every pixel gets its own sampler, same as the naive renderer. the passes below run over the buffer in parallel,
+            // so the samples for every bounce are drawn up front, in the same order regardless of how long the path ends up being
+            let pixel = (
+                tile_offset.0 + buffer_idx % width,
+                tile_offset.1 + buffer_idx / width,
+            );
+            let sample_index = sample_buffer.sample_count[buffer_idx].0;
+            let mut sampler = self.sampler_factory.sampler_for(pixel, sample_index);
+            let film_sample = sampler.draw_2d();
+            let tile_u = ((buffer_idx % width) as f32 + film_sample.x) / width as f32; // + pixel_offset.0;
+            let tile_v = ((buffer_idx / width) as f32 + film_sample.y) / height as f32; // + pixel_offset.1;
             let tile_width = bounds.x.span();
             let tile_height = bounds.y.span();
             let (px, py) = (
                 bounds.x.lower + (tile_u as f32) * tile_width,
                 bounds.y.lower + (tile_v as f32) * tile_height,
             );
-            let lambda = self.wavelength_bounds.sample(boxed.draw_1d().x);
-            let (ray, tau) = camera.get_ray(&mut boxed, lambda, px, py);
+            let lambda = self.wavelength_bounds.sample(sampler.draw_1d().x);
+            let (ray, tau) = camera.get_ray(&mut sampler, lambda, px, py);
+            let samples = &mut sample_buffer.samples[buffer_idx];
+            samples.clear();
+            // the last bounce only needs its russian roulette sample, but every bounce gets the same layout
+            for _ in 0..=self.max_bounces {
+                let (rr_sample, light_pick_sample, light_sample, scatter_sample) = (
+                    sampler.draw_1d(),
+                    sampler.draw_1d(),
+                    sampler.draw_2d(),
+                    sampler.draw_2d(),
+                );
+                samples.extend_from_slice(&[
+                    rr_sample.x,
+                    light_pick_sample.x,
+                    light_sample.x,
+                    light_sample.y,
+                    scatter_sample.x,
+                    scatter_sample.y,
+                ]);
+            }
             buffer.rays[index] = Some(PrimaryRay {
                 ray,
                 lambda,
@@ -382,7 +427,7 @@ impl GPUStylePTIntegrator {
                     const RR_THRESHOLD: f32 = 0.05;
                     if bounce_count > self.min_bounces as usize && primary.throughput < RR_THRESHOLD
                     {
-                        let x = Sample1D::new_random_sample().x;
+                        let x = sample_buffer.bounce_samples(primary.buffer_idx)[0];
                         if x < primary.throughput {
                             mult /= RR_THRESHOLD;
                         } else {
@@ -417,7 +462,7 @@ impl GPUStylePTIntegrator {
                                         primary.cos_o,
                                         primary.ray.origin,
                                         hit.point,
-                                    );
+                                    ) * self.world.light_pick_pdf(hit.instance_id);
                                     let weight = power_heuristic(primary.bsdf_pdf, pdf.0);
                                     emission.0 *= weight;
                                 }
@@ -458,33 +503,68 @@ impl GPUStylePTIntegrator {
     pub fn nee_pass(
         &self,
         _light_samples: usize,
+        ray_buffer: &PrimaryRayBuffer,
+        sample_buffer: &SampleBounceBuffer,
         intersection_buffer: &IntersectionBuffer,
         shadow_buffer: &mut ShadowRayBuffer,
     ) {
-        // pick a single light to try and sample?
-        let (light, _light_pick_pdf) = self
-            .world
-            .pick_random_light(Sample1D::new_random_sample())
-            .unwrap();
-        // queues up NEE shadow rays
+        // queues up NEE shadow rays, picking a light for each one
         shadow_buffer
             .rays
             .par_iter_mut()
             .zip(intersection_buffer.intersections.par_iter())
-            .for_each(|(maybe_shadow, isect)| {
-                if let IntersectionData::Surface {
-                    lambda,
-                    point,
-                    normal,
-                    ..
-                } = isect
+            .zip(ray_buffer.rays.par_iter())
+            .for_each(|((maybe_shadow, isect), primary)| {
+                *maybe_shadow = None;
+                if let (
+                    IntersectionData::Surface {
+                        lambda,
+                        point,
+                        normal,
+                        ..
+                    },
+                    Some(primary),
+                ) = (isect, primary)
                 {
-                    let (wo, psa_pdf) = light.sample(Sample2D::new_random_sample(), *point);
+                    let samples = sample_buffer.bounce_samples(primary.buffer_idx);
+                    let (light, light_pick_pdf) =
+                        match self.world.pick_random_light(Sample1D { x: samples[1] }) {
+                            Some(picked) => picked,
+                            None => return,
+                        };
+                    let light = match light {
+                        LightRef::Instance(instance) => instance,
+                        LightRef::Punctual(_, punctual) => {
+                            let (point_on_light, direction, incoming) =
+                                punctual.sample_li(*point, *lambda, self.world.get_world_radius());
+                            if incoming.0 == 0.0 {
+                                return;
+                            }
+                            let tmax = (point_on_light - *point).norm() * 0.99;
+                            *maybe_shadow = Some((
+                                PrimaryRay {
+                                    ray: Ray::new_with_time_and_tmax(*point, direction, 0.0, tmax),
+                                    // punctual lights can't be hit, so there's no mis and the only pdf is the pick pdf
+                                    throughput: light_pick_pdf.0,
+                                    cam_and_pixel_id: None,
+                                    buffer_idx: 0,
+                                    bsdf_pdf: 0.0,
+                                    cos_o: (*normal * direction).abs(),
+                                    transport_mode: TransportMode::Importance,
+                                    lambda: *lambda,
+                                },
+                                VisibilityTestStatus::Unchecked,
+                                Some(ConnectionData::Punctual(incoming)),
+                            ));
+                            return;
+                        }
+                    };
+                    let (wo, psa_pdf) = light.sample(Sample2D::new(samples[2], samples[3]), *point);
                     debug_assert!(psa_pdf.0.is_finite(), "{:?} {:?}", point, wo);
                     *maybe_shadow = Some((
                         PrimaryRay {
                             ray: Ray::new(*point, wo),
-                            throughput: psa_pdf.0,
+                            throughput: psa_pdf.0 * light_pick_pdf.0,
                             cam_and_pixel_id: None,
                             buffer_idx: 0,
                             bsdf_pdf: 0.0,
@@ -495,8 +575,6 @@ impl GPUStylePTIntegrator {
                         VisibilityTestStatus::Unchecked,
                         None,
                     ));
-                } else {
-                    *maybe_shadow = None;
                 }
             });
     }
@@ -510,6 +588,16 @@ impl GPUStylePTIntegrator {
             let primary = local_ray.0;
             let ray = primary.ray;
             let maybe_hit = self.world.hit(ray, 0.0001, ray.tmax);
+            if let Some(ConnectionData::Punctual(_)) = local_ray.2 {
+                // the shadow ray stops short of the light, so anything it hits is in the way
+                let status = if maybe_hit.is_some() {
+                    VisibilityTestStatus::Blocked
+                } else {
+                    VisibilityTestStatus::Visible
+                };
+                *shadow = Some((primary, status, local_ray.2));
+                return;
+            }
             match maybe_hit {
                 Some(hit) => {
                     let frame = TangentFrame::from_normal(hit.normal);
@@ -568,6 +656,8 @@ impl GPUStylePTIntegrator {
         // performs the scatter, pdf, and shading calculations, putting the results in the shading result buffer and back into the primary ray buffer (for bounces)
 
         // perform attenuation and scatter
+        let ray_slots = &rays.rays;
+        let samples_ref: &SampleBounceBuffer = sample_buffer;
         buffer
             .data
             .par_iter_mut()
@@ -586,6 +676,11 @@ impl GPUStylePTIntegrator {
                     transport_mode,
                     ..
                 } => {
+                    // surface intersections always come from the ray in the same slot, and unused slots are skipped below
+                    let samples = match ray_slots[index] {
+                        Some(primary) => samples_ref.bounce_samples(primary.buffer_idx),
+                        None => return,
+                    };
                     // get material and regenerate tangentframe
                     let material = self.world.get_material(*material);
                     let frame = TangentFrame::from_normal(*normal);
@@ -595,16 +690,13 @@ impl GPUStylePTIntegrator {
                     let mut nee_contibution = SingleEnergy::ZERO;
                     if let Some((shadow_primary, status, data)) = nee_data {
                         match status {
-                            VisibilityTestStatus::Visible => {
-                                if let Some(ConnectionData::Intersection(
-                                    IntersectionData::Surface {
-                                        emission,
-                                        // normal: light_surface_normal,
-                                        // local_wi: light_local_wi,
-                                        ..
-                                    },
-                                )) = data
-                                {
+                            VisibilityTestStatus::Visible => match data {
+                                Some(ConnectionData::Intersection(IntersectionData::Surface {
+                                    emission,
+                                    // normal: light_surface_normal,
+                                    // local_wi: light_local_wi,
+                                    ..
+                                })) => {
                                     // since we assigned the throughput as the pdf earlier, when doing the NEE intersection pass
                                     let light_psa_pdf = shadow_primary.throughput;
                                     let wo = frame.to_local(&shadow_primary.ray.direction);
@@ -619,9 +711,21 @@ impl GPUStylePTIntegrator {
                                     } else {
                                         SingleEnergy::ZERO
                                     };
-                                } else {
                                 }
-                            }
+                                Some(ConnectionData::Punctual(incoming)) => {
+                                    // same as above, the throughput is the light pick pdf
+                                    let light_pick_pdf = shadow_primary.throughput;
+                                    let wo = frame.to_local(&shadow_primary.ray.direction);
+                                    let (f, _) =
+                                        material.bsdf(*lambda, *uv, *transport_mode, *local_wi, wo);
+                                    nee_contibution = if light_pick_pdf > 0.0 {
+                                        wo.z().abs() * incoming * f / light_pick_pdf
+                                    } else {
+                                        SingleEnergy::ZERO
+                                    };
+                                }
+                                _ => {}
+                            },
                             _ => {}
                         }
                     }
@@ -631,7 +735,7 @@ impl GPUStylePTIntegrator {
                         *lambda,
                         *uv,
                         *transport_mode,
-                        Sample2D::new_random_sample(),
+                        Sample2D::new(samples[4], samples[5]),
                         *local_wi,
                     );
 
@@ -0,0 +1,307 @@
+use crate::integrator::utils::{LightSourceType, SurfaceVertex, VertexType};
+use crate::materials::MaterialEnum;
+use crate::math::*;
+use crate::world::World;
+
+// light path expressions, for splitting a render into passes by the kind of path the light took, like in OSL.
+// a path is written as one symbol per vertex, going from the camera to the light:
+//   C  the camera
+//   D  diffuse reflection
+//   G  glossy reflection
+//   S  specular reflection, off of surfaces smoother than SPECULAR_ROUGHNESS
+//   T  transmission through a surface, of any roughness
+//   V  scattering in a medium
+//   L  a light, either an emissive surface or a punctual light
+//   B  the environment, i.e. the background
+// expressions are regular expressions over these symbols that have to match the whole path.
+// they support `.` for any symbol, classes like `[DG]` and `[^S]`, groups, `|`, and the `*`, `+` and `?` repetitions.
+// whitespace is ignored, so for example direct diffuse is "C D L", indirect diffuse is "C D .+ [LB]",
+// and caustics seen on diffuse surfaces are "C D [ST]+ [LB]".
+
+pub const CAMERA: u8 = b'C';
+pub const LIGHT: u8 = b'L';
+pub const BACKGROUND: u8 = b'B';
+const SYMBOLS: &[u8] = b"CDGSTVLB";
+
+// ggx surfaces with a roughness below this count as specular
+pub const SPECULAR_ROUGHNESS: f32 = 0.01;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    // matches a single symbol that's in symbols, or that isn't if negated
+    Symbol {
+        symbols: Vec<u8>,
+        negated: bool,
+    },
+    Sequence(Vec<Node>),
+    Alternation(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+    },
+}
+
+impl Node {
+    // starts marks the positions in path that matching can start at.
+    // returns the positions that matching can end at, from any of the starts.
+    fn step(&self, path: &[u8], starts: &[bool]) -> Vec<bool> {
+        let mut ends = vec![false; path.len() + 1];
+        match self {
+            Node::Symbol { symbols, negated } => {
+                for (i, symbol) in path.iter().enumerate() {
+                    if starts[i] && symbols.contains(symbol) != *negated {
+                        ends[i + 1] = true;
+                    }
+                }
+            }
+            Node::Sequence(nodes) => {
+                ends.copy_from_slice(starts);
+                for node in nodes.iter() {
+                    ends = node.step(path, &ends);
+                }
+            }
+            Node::Alternation(nodes) => {
+                for node in nodes.iter() {
+                    for (end, reached) in ends.iter_mut().zip(node.step(path, starts)) {
+                        *end |= reached;
+                    }
+                }
+            }
+            Node::Repeat { node, min, max } => {
+                let mut current = starts.to_vec();
+                for _ in 0..*min {
+                    current = node.step(path, &current);
+                }
+                ends.copy_from_slice(&current);
+                let mut count = *min;
+                while max.map_or(true, |max| count < max) {
+                    current = node.step(path, &current);
+                    // once a repetition doesn't reach anything new, no later one can either
+                    let mut changed = false;
+                    for (end, reached) in ends.iter_mut().zip(current.iter()) {
+                        if *reached && !*end {
+                            *end = true;
+                            changed = true;
+                        }
+                    }
+                    if !changed {
+                        break;
+                    }
+                    count += 1;
+                }
+            }
+        }
+        ends
+    }
+}
+
+// recursive descent parser, where alternation binds loosest, then sequences, then repetitions
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).cloned()
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("{} at position {}", message, self.position)
+    }
+
+    fn symbol(&mut self) -> Result<u8, String> {
+        match self.peek() {
+            Some(c) if c.is_ascii() && SYMBOLS.contains(&(c as u8)) => {
+                self.position += 1;
+                Ok(c as u8)
+            }
+            Some(c) => Err(self.error(&format!("unknown symbol {:?}", c))),
+            None => Err(self.error("expected a symbol")),
+        }
+    }
+
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut options = vec![self.sequence()?];
+        while self.peek() == Some('|') {
+            self.position += 1;
+            options.push(self.sequence()?);
+        }
+        Ok(if options.len() == 1 {
+            options.pop().unwrap()
+        } else {
+            Node::Alternation(options)
+        })
+    }
+
+    fn sequence(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            nodes.push(self.repeat()?);
+        }
+        Ok(if nodes.len() == 1 {
+            nodes.pop().unwrap()
+        } else {
+            Node::Sequence(nodes)
+        })
+    }
+
+    fn repeat(&mut self) -> Result<Node, String> {
+        let mut node = self.atom()?;
+        while let Some(c) = self.peek() {
+            let (min, max) = match c {
+                '*' => (0, None),
+                '+' => (1, None),
+                '?' => (0, Some(1)),
+                _ => break,
+            };
+            self.position += 1;
+            node = Node::Repeat {
+                node: Box::new(node),
+                min,
+                max,
+            };
+        }
+        Ok(node)
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        match self.peek() {
+            Some('.') => {
+                self.position += 1;
+                Ok(Node::Symbol {
+                    symbols: Vec::new(),
+                    negated: true,
+                })
+            }
+            Some('[') => {
+                self.position += 1;
+                let negated = self.peek() == Some('^');
+                if negated {
+                    self.position += 1;
+                }
+                let mut symbols = Vec::new();
+                while self.peek() != Some(']') {
+                    symbols.push(self.symbol()?);
+                }
+                self.position += 1;
+                Ok(Node::Symbol { symbols, negated })
+            }
+            Some('(') => {
+                self.position += 1;
+                let node = self.alternation()?;
+                if self.peek() != Some(')') {
+                    return Err(self.error("expected )"));
+                }
+                self.position += 1;
+                Ok(node)
+            }
+            _ => Ok(Node::Symbol {
+                symbols: vec![self.symbol()?],
+                negated: false,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LightPathExpression {
+    pub expression: String,
+    root: Node,
+}
+
+impl LightPathExpression {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            chars: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+            position: 0,
+        };
+        let root = parser.alternation()?;
+        if parser.position != parser.chars.len() {
+            return Err(parser.error("unexpected )"));
+        }
+        Ok(LightPathExpression {
+            expression: expression.to_string(),
+            root,
+        })
+    }
+
+    pub fn matches(&self, path: &[u8]) -> bool {
+        let mut starts = vec![false; path.len() + 1];
+        starts[0] = true;
+        self.root.step(path, &starts)[path.len()]
+    }
+}
+
+// the symbol for scattering at a surface or medium vertex, towards wo if it's known.
+// wo is in world space, pointing away from the vertex. without it, surface scattering is classified as a reflection.
+pub fn scattering_symbol(world: &World, vertex: &SurfaceVertex, wo: Option<Vec3>) -> u8 {
+    if vertex.is_medium() {
+        return b'V';
+    }
+    if let Some(wo) = wo {
+        // local_wi points back towards the previous vertex
+        if (vertex.normal * wo) * (vertex.normal * vertex.local_wi) < 0.0 {
+            return b'T';
+        }
+    }
+    match world.get_material(vertex.material_id) {
+        MaterialEnum::GGX(ggx) if ggx.alpha < SPECULAR_ROUGHNESS => b'S',
+        MaterialEnum::GGX(_) => b'G',
+        MaterialEnum::Layered(layered) if !layered.scatters_diffusely() => b'G',
+        _ => b'D',
+    }
+}
+
+// the symbol of each vertex of a camera path, where the symbol of a scattering vertex is for the event that led to the vertex after it.
+// the last vertex doesn't lead anywhere, so it's classified without a direction.
+pub fn path_symbols(world: &World, path: &[SurfaceVertex]) -> Vec<u8> {
+    (0..path.len())
+        .map(|i| {
+            if i == 0 {
+                return CAMERA;
+            }
+            let wo = path.get(i + 1).map(|next| match next.vertex_type {
+                // the environment vertex stores the direction the path left in
+                VertexType::LightSource(LightSourceType::Environment) => next.local_wi,
+                _ => -next.local_wi,
+            });
+            scattering_symbol(world, &path[i], wo)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_light_path_expressions() {
+        let matches = |expression: &str, path: &str| {
+            LightPathExpression::parse(expression)
+                .unwrap()
+                .matches(path.as_bytes())
+        };
+        assert!(matches("C D L", "CDL"));
+        assert!(!matches("CDL", "CDDL"));
+        assert!(matches("C D .+ [LB]", "CDGTB"));
+        assert!(!matches("C D .+ [LB]", "CDL"));
+        assert!(matches("C D [ST]+ [LB]", "CDSTTL"));
+        assert!(!matches("C D [ST]+ [LB]", "CDSDL"));
+        assert!(matches("C [^V]* L", "CDGSL"));
+        assert!(!matches("C [^V]* L", "CDVSL"));
+        assert!(matches("C .* V .* [LB]", "CDVSB"));
+        assert!(matches("C (D|G)? (TT)+ L", "CTTTTL"));
+        assert!(!matches("C (D|G)? (TT)+ L", "CDTTTL"));
+        assert!(matches("C (D*)* L", "CL"));
+
+        assert!(LightPathExpression::parse("C X L").is_err());
+        assert!(LightPathExpression::parse("C (D L").is_err());
+        assert!(LightPathExpression::parse("C D) L").is_err());
+        assert!(LightPathExpression::parse("C [D").is_err());
+    }
+}
@@ -1,25 +1,28 @@
-use crate::world::World;
+use crate::world::{LightRef, World};
 // use crate::config::Settings;
-use crate::hittable::{HitRecord, Hittable};
+use crate::hittable::Hittable;
 use crate::integrator::utils::*;
 use crate::integrator::*;
-use crate::materials::{Material, MaterialEnum, MaterialId};
+use crate::materials::{Material, MaterialId};
 use crate::math::*;
 
+use crate::integrator::pt_hwss::generate_hero;
 use crate::world::TransportMode;
 
+use packed_simd::f32x4;
 use std::sync::Arc;
 
 fn evaluate_direct_importance(
     world: &Arc<World>,
     camera_pick: Sample1D,
     lens_sample: Sample2D,
+    transmittance_sample: Sample1D,
     lambda: f32,
     beta: SingleEnergy,
-    material: &MaterialEnum,
-    wi: Vec3,
-    hit: &HitRecord,
-    frame: &TangentFrame,
+    vertex: &SurfaceVertex,
+    dir_to_prev: Vec3,
+    emitting: bool,
+    light_group: Option<usize>,
     samples: &mut Vec<(Sample, CameraId)>,
     profile: &mut Profile,
 ) {
@@ -27,20 +30,34 @@ fn evaluate_direct_importance(
         .pick_random_camera(camera_pick)
         .expect("camera pick failed");
     if let Some(camera_surface) = camera.get_surface() {
-        let (point_on_lens, _lens_normal, pdf) = camera_surface.sample_surface(lens_sample);
+        let (point_on_lens, lens_normal, _, pdf) = camera_surface.sample_surface(lens_sample);
         let camera_pdf = pdf * camera_pick_pdf;
         if camera_pdf.0 == 0.0 {
             // go to next pick
             return;
         }
-        let direction = (point_on_lens - hit.point).normalized();
+        let to_lens = point_on_lens - vertex.point;
+        let direction = to_lens.normalized();
+        let (we, _) = camera.eval_we(lambda, point_on_lens, vertex.point);
+        if we == 0.0 {
+            return;
+        }
+        // the importance arriving at vertex, converted from the area measure on the lens
+        let importance = we * (lens_normal * direction).abs() / to_lens.norm_squared();
 
         // this should be the same as the other method, but maybe not.
         // camera_surface.material_id
-        let camera_wo = frame.to_local(&direction);
-        let (reflectance, scatter_pdf_into_camera) =
-            material.bsdf(hit.lambda, hit.uv, hit.transport_mode, wi, camera_wo);
-        let dropoff = camera_wo.z().max(0.0);
+        // an emitting vertex has its emission folded into beta, and can't be reached by the light path itself
+        let (reflectance, scatter_pdf_into_camera) = if emitting {
+            (SingleEnergy::ONE, PDF::from(0.0))
+        } else {
+            eval_vertex_scattering(world, vertex, dir_to_prev, direction)
+        };
+        let dropoff = if vertex.is_medium() {
+            1.0
+        } else {
+            (vertex.normal * direction).max(0.0)
+        };
         if dropoff == 0.0 {
             return;
         }
@@ -48,7 +65,15 @@ fn evaluate_direct_importance(
         // generate point on camera, then see if it can be connected to.
         // println!("hit {:?}", &hit);
         profile.shadow_rays += 1;
-        if veach_v(&world, point_on_lens, hit.point) {
+        let transmittance = eval_transmittance(
+            world,
+            lambda,
+            vertex.point,
+            medium_towards(world, vertex, direction),
+            point_on_lens,
+            transmittance_sample,
+        );
+        if transmittance > 0.0 {
             let weight = power_heuristic(camera_pdf.0, scatter_pdf_into_camera.0);
 
             // correctly connected.
@@ -65,10 +90,14 @@ fn evaluate_direct_importance(
                     camera_pdf,
                     weight
                 );
-                let energy = reflectance * beta * dropoff * weight / camera_pdf.0;
+                let energy = reflectance * beta * dropoff * transmittance * importance * weight
+                    / camera_pdf.0;
                 debug_assert!(energy.0.is_finite());
                 let sample = XYZColor::from(SingleWavelength::new(lambda, energy));
-                let ret = (Sample::LightSample(sample, uv), camera_id as CameraId);
+                let ret = (
+                    Sample::LightSample(sample, uv, light_group),
+                    camera_id as CameraId,
+                );
                 // println!("adding camera sample to splatting list");
                 samples.push(ret);
             }
@@ -76,41 +105,66 @@ fn evaluate_direct_importance(
     }
 }
 
+// what a light path was emitted from, so that the emission can be evaluated at other wavelengths
+#[derive(Copy, Clone, Debug)]
+enum Emitter {
+    // the emitting material, and the surface normal and uv at the emission point
+    Surface(MaterialId, Vec3, (f32, f32)),
+    Environment,
+    // index into the world's punctual lights
+    Punctual(usize),
+}
+
 pub struct LightTracingIntegrator {
     pub max_bounces: u16,
     pub world: Arc<World>,
     pub russian_roulette: bool,
     pub camera_samples: u16,
     pub wavelength_bounds: Bounds1D,
+    pub hwss: bool,
 }
 
-impl GenericIntegrator for LightTracingIntegrator {
-    fn color(
+impl LightTracingIntegrator {
+    // samples an emission ray from either a light or the environment.
+    // also returns the g term at the light, what the ray was emitted from and its light group
+    fn sample_light(
         &self,
+        wavelength_sample: Sample1D,
         sampler: &mut Box<dyn Sampler>,
-        _settings: &RenderSettings,
-        _camera_sample: ((f32, f32), CameraId),
-        _sample_id: usize,
-        mut samples: &mut Vec<(Sample, CameraId)>,
-        mut profile: &mut Profile,
-    ) -> XYZColor {
-        // setup: decide light, decide wavelength, emit ray from light, connect light ray vertices to camera.
-        let wavelength_sample = sampler.draw_1d();
+    ) -> ((Ray, SingleWavelength, PDF, PDF), f32, Emitter, usize) {
         let light_pick_sample = sampler.draw_1d();
 
         let env_sampling_probability = self.world.get_env_sampling_probability();
 
-        let sampled;
-        let light_g_term: f32;
-
         let (light_pick_sample, sample_world) =
             light_pick_sample.choose(env_sampling_probability, true, false);
         if !sample_world {
             let (light, pick_pdf) = self.world.pick_random_light(light_pick_sample).unwrap();
+            let light_group = self.world.light_group_of(light);
+            let light = match light {
+                LightRef::Instance(instance) => instance,
+                LightRef::Punctual(index, punctual) => {
+                    // there's no surface, so the g term at the light is 1
+                    let sampled = punctual.sample_emission(
+                        self.world.get_world_radius(),
+                        self.world.get_center(),
+                        sampler.draw_2d(),
+                        sampler.draw_2d(),
+                        self.wavelength_bounds,
+                        wavelength_sample,
+                    );
+                    return (
+                        (sampled.0, sampled.1, sampled.2 * pick_pdf, sampled.3),
+                        1.0,
+                        Emitter::Punctual(index),
+                        light_group,
+                    );
+                }
+            };
 
             // if we picked a light
-            let (light_surface_point, light_surface_normal, area_pdf) =
-                light.sample_surface(sampler.draw_2d());
+            let (light_surface_point, light_surface_normal, light_surface_uv, area_pdf) =
+                self.world.sample_light_surface(light, sampler.draw_2d());
 
             let mat_id = light.get_material_id();
             let material = self.world.get_material(mat_id);
@@ -119,6 +173,7 @@ impl GenericIntegrator for LightTracingIntegrator {
                 .sample_emission(
                     light_surface_point,
                     light_surface_normal,
+                    light_surface_uv,
                     self.wavelength_bounds,
                     sampler.draw_2d(),
                     wavelength_sample,
@@ -127,38 +182,318 @@ impl GenericIntegrator for LightTracingIntegrator {
                     "emission sample failed, light is {:?} material is {:?}",
                     light, mat_id
                 ));
-            light_g_term = (light_surface_normal * (&tmp_sampled.0).direction).abs();
-            sampled = (
-                tmp_sampled.0,
-                tmp_sampled.1,
-                tmp_sampled.2 * pick_pdf * area_pdf,
-                tmp_sampled.3,
-            );
+            let light_g_term = (light_surface_normal * (&tmp_sampled.0).direction).abs();
+            (
+                (
+                    tmp_sampled.0,
+                    tmp_sampled.1,
+                    tmp_sampled.2 * pick_pdf * area_pdf,
+                    tmp_sampled.3,
+                ),
+                light_g_term,
+                Emitter::Surface(mat_id, light_surface_normal, light_surface_uv),
+                light_group,
+            )
         } else {
             // sample world env
             // println!("sampled light emission in world light branch");
             // println!("sampling world, world radius is {}", world_radius);
-            let world_radius = self.world.get_world_radius();
-            let world_center = self.world.get_center();
-            sampled = self.world.environment.sample_emission(
-                world_radius,
-                world_center,
+            let sampled = self.world.sample_environment_emission(
                 sampler.draw_2d(),
                 sampler.draw_2d(),
                 self.wavelength_bounds,
                 wavelength_sample,
             );
-            light_g_term = 1.0;
-            // sampled = (tmp_sampled.0, tmp_sampled.1, tmp_sampled.2);
-        };
+            (
+                sampled,
+                1.0,
+                Emitter::Environment,
+                self.world.environment_light_group(),
+            )
+        }
+    }
+
+    // light tracing from a point inside an emissive medium. the emission point is connected to the camera directly,
+    // and then the walk continues from there like any other light path.
+    fn trace_medium_emission(
+        &self,
+        light_pick_sample: Sample1D,
+        selection_pdf: f32,
+        sampler: &mut Box<dyn Sampler>,
+        samples: &mut Vec<(Sample, CameraId)>,
+        profile: &mut Profile,
+    ) -> XYZColor {
+        let sum = SingleWavelength::new_from_range(sampler.draw_1d().x, self.wavelength_bounds);
+        let lambda = sum.lambda;
+        let lambda_pdf = 1.0 / self.wavelength_bounds.span();
+        let (medium_id, pick_pdf) = self
+            .world
+            .pick_random_emissive_medium(light_pick_sample)
+            .expect("emissive medium pick failed");
+        let medium = &self.world.mediums[medium_id - 1];
+        let (point, direction, emission, point_pdf, direction_pdf) =
+            match medium.sample_emission(lambda, sampler.draw_3d(), sampler.draw_2d()) {
+                Some(sampled) => sampled,
+                None => return XYZColor::from(SingleWavelength::BLACK),
+            };
+        profile.light_rays += 1;
+        if emission.0 == 0.0 || point_pdf.0 == 0.0 || !self.world.medium_contains(medium_id, point)
+        {
+            return XYZColor::from(SingleWavelength::BLACK);
+        }
+
+        let beta = emission / (point_pdf.0 * pick_pdf.0 * selection_pdf * lambda_pdf);
+        let mut emission_vertex = SurfaceVertex::new(
+            VertexType::Medium(TransportMode::Radiance),
+            0.0,
+            lambda,
+            Vec3::ZERO,
+            point,
+            Vec3::ZERO,
+            (0.0, 0.0),
+            MaterialId::Material(0),
+            0,
+            beta,
+            direction_pdf.0,
+            0.0,
+            1.0,
+        );
+        emission_vertex.medium_id = medium_id;
+
+        for _ in 0..self.camera_samples {
+            evaluate_direct_importance(
+                &self.world,
+                sampler.draw_1d(),
+                sampler.draw_2d(),
+                sampler.draw_1d(),
+                lambda,
+                beta,
+                &emission_vertex,
+                Vec3::ZERO,
+                true,
+                None,
+                samples,
+                profile,
+            );
+        }
+
+        let mut path: Vec<SurfaceVertex> = Vec::with_capacity(1 + self.max_bounces as usize);
+        path.push(emission_vertex);
+        let _ = random_walk_medium_from(
+            Ray::new(point, direction),
+            lambda,
+            self.max_bounces,
+            beta / direction_pdf.0,
+            TransportMode::Radiance,
+            sampler,
+            &self.world,
+            &mut path,
+            0,
+            profile,
+            medium_id,
+        );
+
+        for (index, vertex) in path.iter().enumerate() {
+            if index == 0 {
+                continue;
+            }
+            if let VertexType::LightSource(_) = vertex.vertex_type {
+                continue;
+            }
+            let dir_to_prev = (path[index - 1].point - vertex.point).normalized();
+            for _ in 0..self.camera_samples {
+                evaluate_direct_importance(
+                    &self.world,
+                    sampler.draw_1d(),
+                    sampler.draw_2d(),
+                    sampler.draw_1d(),
+                    lambda,
+                    vertex.throughput,
+                    vertex,
+                    dir_to_prev,
+                    false,
+                    None,
+                    samples,
+                    profile,
+                );
+            }
+        }
+        XYZColor::from(SingleWavelength::BLACK)
+    }
+
+    // light tracing with hero wavelength spectral sampling. the path is traced once for four wavelengths,
+    // and each lane is weighted by spectral mis, which hands the whole estimate to the hero past a dispersive interface.
+    // selection_pdf is the probability of emitting from a light or the environment instead of a medium
+    fn color_hero(
+        &self,
+        selection_pdf: f32,
+        sampler: &mut Box<dyn Sampler>,
+        samples: &mut Vec<(Sample, CameraId)>,
+        profile: &mut Profile,
+    ) -> XYZColor {
+        let lambda = generate_hero(sampler.draw_1d().x, self.wavelength_bounds);
+        // every lane is uniformly distributed over the wavelength bounds
+        let wavelength_pdf = 1.0 / self.wavelength_bounds.span();
+        let (sampled, light_g_term, emitter, light_group) =
+            self.sample_light(Sample1D { x: 0.5 }, sampler);
+        profile.light_rays += 1;
+        let light_ray = sampled.0;
+        let light_pdf = sampled.2;
+
+        // sample_light only samples the ray, the emission is evaluated at every hero wavelength
+        let mut radiance = f32x4::splat(0.0);
+        for i in 0..4 {
+            let emission = match emitter {
+                Emitter::Surface(material_id, normal, uv) => {
+                    let frame = TangentFrame::from_normal(normal);
+                    self.world.get_material(material_id).emission(
+                        lambda.extract(i),
+                        uv,
+                        TransportMode::Radiance,
+                        frame.to_local(&light_ray.direction).normalized(),
+                    )
+                }
+                Emitter::Environment => self
+                    .world
+                    .environment
+                    .emission(direction_to_uv(-light_ray.direction), lambda.extract(i)),
+                Emitter::Punctual(index) => self.world.punctual_lights[index]
+                    .emission(light_ray.direction, lambda.extract(i)),
+            };
+            radiance = radiance.replace(i, emission.0);
+        }
+        // points sampled outside of the emitting part of a textured light have a pdf of 0
+        if radiance.eq(f32x4::splat(0.0)).all() || light_pdf.0 == 0.0 {
+            return XYZColor::from(SingleWavelength::BLACK);
+        }
+
+        let mut path: Vec<HeroSurfaceVertex> = Vec::with_capacity(1 + self.max_bounces as usize);
+        path.push(HeroSurfaceVertex::new(
+            VertexType::Camera,
+            light_ray.time,
+            lambda,
+            Vec3::ZERO,
+            light_ray.origin,
+            light_ray.direction,
+            (0.0, 0.0),
+            MaterialId::Camera(0),
+            0,
+            HeroEnergy(f32x4::splat(1.0)),
+            f32x4::splat(light_pdf.0),
+            f32x4::splat(0.0),
+            light_g_term,
+        ));
+        let _ = random_walk_hero(
+            light_ray,
+            lambda,
+            self.max_bounces,
+            radiance * light_pdf.0 / wavelength_pdf / selection_pdf,
+            TransportMode::Radiance,
+            sampler,
+            &self.world,
+            &mut path,
+            0,
+            profile,
+        );
+
+        let mut multiplier = 1.0;
+        // the probability of sampling the path so far with each wavelength as the hero, relative to the actual hero
+        let mut ratios = f32x4::splat(1.0);
+        for (index, vertex) in path.iter().enumerate() {
+            if index == 0 {
+                continue;
+            }
+            let prev_vertex = path[index - 1];
+            if index == 1 {
+                multiplier =
+                    vertex.local_wi.z() / (vertex.point - prev_vertex.point).norm_squared();
+            }
+            if let VertexType::LightSource(_) = vertex.vertex_type {
+                continue;
+            }
+            // balance heuristic over the wavelengths. the lanes get averaged when they're splatted, which cancels the usual factor of 4.
+            let ratio_sum = ratios.sum();
+            if ratio_sum > 0.0 && self.camera_samples > 0 {
+                let beta = vertex.throughput.0 * multiplier / ratio_sum;
+                let dir_to_prev = (prev_vertex.point - vertex.point).normalized();
+                for _ in 0..self.camera_samples {
+                    // every lane connects to the same point on the lens
+                    let camera_pick = sampler.draw_1d();
+                    let lens_sample = sampler.draw_2d();
+                    let transmittance_sample = sampler.draw_1d();
+                    for i in 0..4 {
+                        if beta.extract(i) == 0.0 {
+                            continue;
+                        }
+                        evaluate_direct_importance(
+                            &self.world,
+                            camera_pick,
+                            lens_sample,
+                            transmittance_sample,
+                            lambda.extract(i),
+                            SingleEnergy(beta.extract(i)),
+                            &vertex.lane(i),
+                            dir_to_prev,
+                            false,
+                            Some(light_group),
+                            samples,
+                            profile,
+                        );
+                    }
+                }
+            }
+            let hero_pdf = vertex.pdf_forward.extract(0);
+            if hero_pdf > 0.0 {
+                ratios *= vertex.pdf_forward / hero_pdf;
+            }
+        }
+        XYZColor::from(SingleWavelength::BLACK)
+    }
+}
+
+impl GenericIntegrator for LightTracingIntegrator {
+    fn color(
+        &self,
+        sampler: &mut Box<dyn Sampler>,
+        _settings: &RenderSettings,
+        _camera_sample: ((f32, f32), CameraId),
+        _sample_id: usize,
+        mut samples: &mut Vec<(Sample, CameraId)>,
+        mut profile: &mut Profile,
+    ) -> XYZColor {
+        // setup: decide light, decide wavelength, emit ray from light, connect light ray vertices to camera.
+        // only draw the extra sample when there are emissive mediums, so that the sample layout is otherwise unchanged.
+        // medium emission is traced with a single wavelength even with hwss, since random_walk_hero doesn't go through mediums
+        let medium_emission_probability = self.world.get_medium_emission_probability();
+        if medium_emission_probability > 0.0 {
+            let (light_pick_sample, sample_medium) =
+                sampler
+                    .draw_1d()
+                    .choose(medium_emission_probability, true, false);
+            if sample_medium {
+                return self.trace_medium_emission(
+                    light_pick_sample,
+                    medium_emission_probability,
+                    sampler,
+                    samples,
+                    profile,
+                );
+            }
+        }
+        if self.hwss {
+            return self.color_hero(1.0 - medium_emission_probability, sampler, samples, profile);
+        }
+        let wavelength_sample = sampler.draw_1d();
+        let (sampled, light_g_term, _emitter, light_group) =
+            self.sample_light(wavelength_sample, sampler);
         profile.light_rays += 1;
         let light_ray = sampled.0;
         let lambda = sampled.1.lambda;
         let radiance = sampled.1.energy;
-        if radiance.0 == 0.0 {
+        let light_pdf = sampled.2;
+        if radiance.0 == 0.0 || light_pdf.0 == 0.0 {
             return XYZColor::from(SingleWavelength::BLACK);
         }
-        let light_pdf = sampled.2;
         let lambda_pdf = sampled.3;
 
         // light loop here
@@ -179,11 +514,11 @@ impl GenericIntegrator for LightTracingIntegrator {
             0.0,
             light_g_term,
         ));
-        let _ = random_walk(
+        let _ = random_walk_medium(
             light_ray,
             lambda,
             self.max_bounces,
-            radiance * light_pdf.0 / lambda_pdf.0,
+            radiance * light_pdf.0 / lambda_pdf.0 / (1.0 - medium_emission_probability),
             TransportMode::Radiance,
             sampler,
             &self.world,
@@ -252,15 +587,10 @@ impl GenericIntegrator for LightTracingIntegrator {
                     // }
                 }
             } else {
-                let hit = HitRecord::from(*vertex);
-                let frame = TangentFrame::from_normal(hit.normal);
                 let dir_to_prev = (prev_vertex.point - vertex.point).normalized();
                 // let _maybe_dir_to_next = path
                 //     .get(index + 1)
                 //     .map(|v| (v.point - vertex.point).normalized());
-                let wi = frame.to_local(&dir_to_prev);
-                // let wo = maybe_dir_to_next.map(|dir| frame.to_local(&dir));
-                let material = self.world.get_material(vertex.material_id);
 
                 // let emission = material.emission(&hit, wi, wo);
 
@@ -287,12 +617,13 @@ impl GenericIntegrator for LightTracingIntegrator {
                             &self.world,
                             sampler.draw_1d(),
                             sampler.draw_2d(),
+                            sampler.draw_1d(),
                             vertex.lambda,
                             beta,
-                            material,
-                            wi,
-                            &hit,
-                            &frame,
+                            vertex,
+                            dir_to_prev,
+                            false,
+                            Some(light_group),
                             &mut samples,
                             &mut profile,
                         );
@@ -0,0 +1,309 @@
+use crate::integrator::bdpt::utils::*;
+use crate::integrator::utils::*;
+use crate::integrator::*;
+use crate::materials::MaterialId;
+use crate::math::*;
+use crate::sampler::{hash_combine, MLTSampler};
+use crate::world::TransportMode;
+use crate::world::World;
+
+use std::sync::Arc;
+
+use rayon::prelude::*;
+
+// primary sample streams. see MLTSampler for why they're kept separate.
+pub const CAMERA_STREAM: usize = 0;
+pub const LIGHT_STREAM: usize = 1;
+pub const CONNECTION_STREAM: usize = 2;
+pub const STREAM_COUNT: usize = 3;
+
+// multiplexed metropolis light transport, see Hachisuka et al. 2014 and pbrt-v3's MLTIntegrator.
+// each chain is restricted to a single path depth, and each iteration evaluates a single bdpt strategy (s, t)
+// for that depth, picked by the connection stream. contributions are weighted with the same mis weights as the bdpt integrator.
+pub struct MLTIntegrator {
+    pub max_bounces: u16,
+    pub world: Arc<World>,
+    pub wavelength_bounds: Bounds1D,
+    pub bootstrap_samples: usize,
+    pub chains: usize,
+    pub large_step_probability: f32,
+    pub sigma: f32,
+}
+
+impl MLTIntegrator {
+    // evaluates the path determined by the sampler's primary sample vector, for the given depth.
+    // returns the wavelength and the weighted contribution, and the film uv the contribution should be splatted to.
+    // film uvs use the same orientation as the light samples of the other integrators, i.e. y = height - v * height - 1.
+    pub fn eval_path(
+        &self,
+        mlt_sampler: &MLTSampler,
+        depth: usize,
+        camera_id: CameraId,
+        profile: &mut Profile,
+    ) -> (SingleWavelength, (f32, f32)) {
+        let mut sampler = mlt_sampler.boxed();
+        let zero = (SingleWavelength::BLACK, (0.0, 0.0));
+
+        mlt_sampler.start_stream(CONNECTION_STREAM);
+        // for a given depth, a path has depth + 1 vertices, and t ranges from 1 to depth + 1, same as the bdpt integrator.
+        let strategy_count = depth + 1;
+        let s = ((sampler.draw_1d().x * strategy_count as f32) as usize).min(strategy_count - 1);
+        let t = strategy_count - s;
+
+        mlt_sampler.start_stream(LIGHT_STREAM);
+        let (start_light_vertex, sampled) =
+            sample_light_vertex(&self.world, self.wavelength_bounds, &mut sampler);
+        let light_ray = sampled.0;
+        let lambda = sampled.1.lambda;
+        let radiance = sampled.1.energy;
+        let wavelength_pdf = (sampled.3).0;
+        if wavelength_pdf == 0.0 {
+            return zero;
+        }
+
+        let mut light_path: Vec<SurfaceVertex> = Vec::with_capacity(s.max(1));
+        light_path.push(start_light_vertex);
+        // russian roulette starts after the bounce limit, i.e. it never happens. path length is already fixed by the strategy.
+        if s > 1 {
+            random_walk_medium(
+                light_ray,
+                lambda,
+                (s - 1) as u16,
+                radiance,
+                TransportMode::Radiance,
+                &mut sampler,
+                &self.world,
+                &mut light_path,
+                s as u16,
+                profile,
+            );
+            profile.light_rays += 1;
+        }
+
+        mlt_sampler.start_stream(CAMERA_STREAM);
+        let film_sample = sampler.draw_2d();
+        let film_sample = Sample2D::new(
+            film_sample.x.clamp(0.0, 1.0 - std::f32::EPSILON),
+            film_sample.y.clamp(0.0, 1.0 - std::f32::EPSILON),
+        );
+        let camera = self.world.get_camera(camera_id);
+        let (camera_ray, lens_normal, _) = camera.sample_we(film_sample, &mut sampler, lambda);
+        // the eye path starts with the directional pdf of the camera ray, to match what eval_we gives when connecting to the lens
+        let camera_pdf = camera
+            .eval_we(
+                lambda,
+                camera_ray.origin,
+                camera_ray.point_at_parameter(1.0),
+            )
+            .1;
+
+        let mut eye_path: Vec<SurfaceVertex> = Vec::with_capacity(t);
+        eye_path.push(SurfaceVertex::new(
+            VertexType::Camera,
+            camera_ray.time,
+            lambda,
+            Vec3::ZERO,
+            camera_ray.origin,
+            lens_normal,
+            (0.0, 0.0),
+            MaterialId::Camera(camera_id as u16),
+            0,
+            SingleEnergy::ONE,
+            camera_pdf.0,
+            0.0,
+            1.0,
+        ));
+        let camera_medium = self.world.get_camera_medium(camera_id);
+        eye_path[0].medium_id = camera_medium;
+        if t > 1 {
+            random_walk_medium_from(
+                camera_ray,
+                lambda,
+                (t - 1) as u16,
+                SingleEnergy::ONE,
+                TransportMode::Importance,
+                &mut sampler,
+                &self.world,
+                &mut eye_path,
+                t as u16,
+                profile,
+                camera_medium,
+            );
+            profile.camera_rays += 1;
+        }
+
+        if light_path.len() < s || eye_path.len() < t {
+            // one of the subpaths terminated early, so this strategy can't produce a path of this depth
+            return zero;
+        }
+
+        let russian_roulette_threshold = 0.005;
+        let (factor, g, splatted) = match eval_unweighted_contribution(
+            &self.world,
+            &light_path,
+            s,
+            &eye_path,
+            t,
+            &mut sampler,
+            russian_roulette_threshold,
+            profile,
+        ) {
+            SampleKind::Sampled((factor, g)) => (factor, g, false),
+            SampleKind::Splatted((factor, g)) => (factor, g, true),
+        };
+        if g == 0.0 || factor == SingleEnergy::ZERO {
+            return zero;
+        }
+        let weight = eval_mis(
+            &self.world,
+            &light_path,
+            s,
+            &eye_path,
+            t,
+            g,
+            |weights: &Vec<f32>| -> f32 { 1.0 / weights.iter().map(|&v| v * v).sum::<f32>() },
+        );
+        if weight == 0.0 || !weight.is_finite() {
+            return zero;
+        }
+        // the strategy was picked uniformly, so divide by its probability
+        let contribution = (weight * strategy_count as f32 / wavelength_pdf) * factor;
+
+        let film_uv = if splatted {
+            // t == 1, the light subpath was connected to the lens, so find what pixel that connection went through
+            let ray = Ray::new(
+                eye_path[0].point,
+                (light_path[s - 1].point - eye_path[0].point).normalized(),
+            );
+            match camera.get_pixel_for_ray(ray, lambda) {
+                Some(uv) => uv,
+                None => return zero,
+            }
+        } else {
+            // light samples are flipped vertically relative to image samples
+            (film_sample.x, 1.0 - film_sample.y)
+        };
+        (SingleWavelength::new(lambda, contribution), film_uv)
+    }
+
+    // computes the importance of each bootstrap path. bootstrap path i has depth i % max_bounces + 1,
+    // and can be reconstructed by creating an MLTSampler with the same seed and index i.
+    pub fn bootstrap(&self, seed: u32, camera_id: CameraId, profile: &mut Profile) -> Vec<f32> {
+        let max_depth = self.max_bounces as usize;
+        let (weights, profiles): (Vec<f32>, Vec<Profile>) = (0..(self.bootstrap_samples
+            * max_depth))
+            .into_par_iter()
+            .map(|i| {
+                let mut profile = Profile::default();
+                let depth = i % max_depth + 1;
+                let mlt_sampler = MLTSampler::new(
+                    seed,
+                    i,
+                    self.sigma,
+                    self.large_step_probability,
+                    STREAM_COUNT,
+                );
+                let (sw, _) = self.eval_path(&mlt_sampler, depth, camera_id, &mut profile);
+                (XYZColor::from(sw).y().max(0.0), profile)
+            })
+            .unzip();
+        for other in profiles {
+            *profile = profile.combine(other);
+        }
+        weights
+    }
+}
+
+// a single markov chain, restricted to a single path depth
+pub struct MarkovChain {
+    pub sampler: MLTSampler,
+    pub depth: usize,
+    pub current: (SingleWavelength, (f32, f32)),
+    current_importance: f32,
+}
+
+impl MarkovChain {
+    // starts a chain at the bootstrap path with the given index, which reproduces that path exactly
+    pub fn new(
+        integrator: &MLTIntegrator,
+        seed: u32,
+        bootstrap_index: usize,
+        chain_index: usize,
+        camera_id: CameraId,
+        profile: &mut Profile,
+    ) -> Self {
+        let depth = bootstrap_index % integrator.max_bounces as usize + 1;
+        let sampler = MLTSampler::new(
+            seed,
+            bootstrap_index,
+            integrator.sigma,
+            integrator.large_step_probability,
+            STREAM_COUNT,
+        );
+        let current = integrator.eval_path(&sampler, depth, camera_id, profile);
+        // chains that start at the same bootstrap path still need to mutate independently
+        sampler.reseed(hash_combine(seed, 1), chain_index);
+        let current_importance = XYZColor::from(current.0).y().max(0.0);
+        MarkovChain {
+            sampler,
+            depth,
+            current,
+            current_importance,
+        }
+    }
+
+    // proposes a mutation and accepts or rejects it, pushing the expected value splats of both the current and proposed states
+    // as light samples for the given camera, same as the splats of the other integrators.
+    // splats still need to be scaled by the bootstrap normalization factor divided by the mutations per pixel.
+    pub fn mutate(
+        &mut self,
+        integrator: &MLTIntegrator,
+        camera_id: CameraId,
+        splats: &mut Vec<(Sample, CameraId)>,
+        profile: &mut Profile,
+    ) {
+        self.sampler.start_iteration();
+        let proposed = integrator.eval_path(&self.sampler, self.depth, camera_id, profile);
+        let proposed_importance = XYZColor::from(proposed.0).y().max(0.0);
+        let accept = if self.current_importance > 0.0 {
+            (proposed_importance / self.current_importance).min(1.0)
+        } else {
+            1.0
+        };
+        if accept > 0.0 && proposed_importance > 0.0 {
+            let scale = accept / proposed_importance;
+            splats.push((
+                Sample::LightSample(
+                    XYZColor::from(SingleWavelength::new(
+                        proposed.0.lambda,
+                        scale * proposed.0.energy,
+                    )),
+                    proposed.1,
+                    None,
+                ),
+                camera_id,
+            ));
+        }
+        if accept < 1.0 && self.current_importance > 0.0 {
+            let scale = (1.0 - accept) / self.current_importance;
+            splats.push((
+                Sample::LightSample(
+                    XYZColor::from(SingleWavelength::new(
+                        self.current.0.lambda,
+                        scale * self.current.0.energy,
+                    )),
+                    self.current.1,
+                    None,
+                ),
+                camera_id,
+            ));
+        }
+        if self.sampler.uniform() < accept {
+            self.current = proposed;
+            self.current_importance = proposed_importance;
+            self.sampler.accept();
+        } else {
+            self.sampler.reject();
+        }
+    }
+}
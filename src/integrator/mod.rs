@@ -1,10 +1,13 @@
 mod bdpt;
 pub mod gpu_style;
+pub mod lpe;
 mod lt;
+mod mlt;
 mod pt;
 mod pt_hwss;
 mod sppm;
 pub mod utils;
+mod vcm;
 
 pub use crate::camera::{Camera, CameraId};
 use crate::config::IntegratorKind;
@@ -15,23 +18,27 @@ use crate::world::World;
 use math::spectral::BOUNDED_VISIBLE_RANGE as VISIBLE_RANGE;
 
 pub use bdpt::BDPTIntegrator;
+pub use lpe::LightPathExpression;
 pub use lt::LightTracingIntegrator;
+pub use mlt::{MLTIntegrator, MarkovChain};
 pub use pt::PathTracingIntegrator;
 pub use pt_hwss::HWSSPathTracingIntegrator;
 pub use sppm::SPPMIntegrator;
+pub use vcm::VCMIntegrator;
 
 use std::hash::Hash;
 use std::sync::Arc;
 
 // pub type CameraId = u8;
 
-#[derive(Hash, Ord, PartialOrd, Eq, PartialEq, Debug)]
+#[derive(Copy, Clone, Hash, Ord, PartialOrd, Eq, PartialEq, Debug)]
 pub enum IntegratorType {
     PathTracing,
     LightTracing,
     BDPT,
     SPPM,
     MLT,
+    VCM,
 }
 
 impl IntegratorType {
@@ -42,6 +49,7 @@ impl IntegratorType {
             "BDPT" => IntegratorType::BDPT,
             "MLT" => IntegratorType::MLT,
             "SPPM" => IntegratorType::SPPM,
+            "VCM" => IntegratorType::VCM,
             _ => IntegratorType::PathTracing,
         }
     }
@@ -54,6 +62,8 @@ impl From<IntegratorKind> for IntegratorType {
             IntegratorKind::PT { .. } => IntegratorType::PathTracing,
             IntegratorKind::LT { .. } => IntegratorType::LightTracing,
             IntegratorKind::BDPT { .. } => IntegratorType::BDPT,
+            IntegratorKind::MLT { .. } => IntegratorType::MLT,
+            IntegratorKind::VCM { .. } => IntegratorType::VCM,
         }
     }
 }
@@ -64,6 +74,8 @@ pub enum Integrator {
     LightTracing(LightTracingIntegrator),
     BDPT(BDPTIntegrator),
     SPPM(SPPMIntegrator),
+    MLT(MLTIntegrator),
+    VCM(VCMIntegrator),
 }
 
 impl Integrator {
@@ -78,7 +90,8 @@ impl Integrator {
             .unwrap_or((VISIBLE_RANGE.lower, VISIBLE_RANGE.upper));
         let bounds = Bounds1D::new(lower, upper);
         assert!(lower < upper);
-        if settings.hwss {
+        // bdpt and lt handle hwss themselves
+        if settings.hwss && integrator_type == IntegratorType::PathTracing {
             println!("constructing and returning hwss pt integrator");
             Some(Integrator::HWSSPathTracing(HWSSPathTracingIntegrator {
                 inner: PathTracingIntegrator {
@@ -89,6 +102,7 @@ impl Integrator {
                     light_samples: 4,
                     only_direct: settings.only_direct.unwrap_or(false),
                     wavelength_bounds: bounds,
+                    passes: Vec::new(),
                 },
             }))
         } else {
@@ -97,16 +111,85 @@ impl Integrator {
                     max_bounces: settings.max_bounces.unwrap(),
                     world,
                     wavelength_bounds: bounds,
+                    hwss: settings.hwss,
                 })),
-                IntegratorType::SPPM => Some(Integrator::SPPM(SPPMIntegrator {
-                    max_bounces: settings.max_bounces.unwrap(),
-                    world,
-                    russian_roulette: settings.russian_roulette.unwrap_or(false),
-                    camera_samples: settings.min_samples,
-                    wavelength_bounds: bounds,
-                    photon_map: None,
-                    last_lambda: 0.0,
-                })),
+                IntegratorType::LightTracing => {
+                    Some(Integrator::LightTracing(LightTracingIntegrator {
+                        max_bounces: settings.max_bounces.unwrap(),
+                        world,
+                        russian_roulette: settings.russian_roulette.unwrap_or(true),
+                        camera_samples: 4,
+                        wavelength_bounds: bounds,
+                        hwss: settings.hwss,
+                    }))
+                }
+                IntegratorType::SPPM => {
+                    let (photons_per_iteration, alpha, initial_radius) = match settings.integrator {
+                        IntegratorKind::SPPM {
+                            photons_per_iteration,
+                            alpha,
+                            initial_radius,
+                            ..
+                        } => (photons_per_iteration, alpha, initial_radius),
+                        _ => (None, None, None),
+                    };
+                    let world_radius = world.get_world_radius();
+                    Some(Integrator::SPPM(SPPMIntegrator {
+                        max_bounces: settings.max_bounces.unwrap(),
+                        world,
+                        russian_roulette: settings.russian_roulette.unwrap_or(false),
+                        wavelength_bounds: bounds,
+                        photons_per_iteration: photons_per_iteration
+                            .unwrap_or(settings.resolution.width * settings.resolution.height),
+                        alpha: alpha.unwrap_or(2.0 / 3.0),
+                        initial_radius: initial_radius.unwrap_or(world_radius / 100.0),
+                        pixels: Vec::new(),
+                        iteration: 0,
+                    }))
+                }
+                IntegratorType::MLT => {
+                    let (bootstrap_samples, chains, large_step_probability, sigma) =
+                        match settings.integrator {
+                            IntegratorKind::MLT {
+                                bootstrap_samples,
+                                chains,
+                                large_step_probability,
+                                sigma,
+                            } => (bootstrap_samples, chains, large_step_probability, sigma),
+                            _ => (None, None, None, None),
+                        };
+                    Some(Integrator::MLT(MLTIntegrator {
+                        max_bounces: settings.max_bounces.unwrap(),
+                        world,
+                        wavelength_bounds: bounds,
+                        bootstrap_samples: bootstrap_samples.unwrap_or(100000),
+                        chains: chains.unwrap_or(1000),
+                        large_step_probability: large_step_probability.unwrap_or(0.3),
+                        sigma: sigma.unwrap_or(0.01),
+                    }))
+                }
+                IntegratorType::VCM => {
+                    let (initial_radius, alpha) = match settings.integrator {
+                        IntegratorKind::VCM {
+                            initial_radius,
+                            alpha,
+                            ..
+                        } => (initial_radius, alpha),
+                        _ => (None, None),
+                    };
+                    let world_radius = world.get_world_radius();
+                    Some(Integrator::VCM(VCMIntegrator {
+                        max_bounces: settings.max_bounces.unwrap(),
+                        min_bounces: settings.min_bounces.unwrap_or(3),
+                        world,
+                        wavelength_bounds: bounds,
+                        initial_radius: initial_radius.unwrap_or(world_radius / 100.0),
+                        alpha: alpha.unwrap_or(0.75),
+                        pixels: Vec::new(),
+                        light_pixels: Vec::new(),
+                        iteration: 0,
+                    }))
+                }
                 IntegratorType::PathTracing { .. } | _ => {
                     Some(Integrator::PathTracing(PathTracingIntegrator {
                         min_bounces: settings.min_bounces.unwrap_or(4),
@@ -116,6 +199,19 @@ impl Integrator {
                         light_samples: 4,
                         only_direct: settings.only_direct.unwrap_or(false),
                         wavelength_bounds: bounds,
+                        passes: settings
+                            .passes
+                            .iter()
+                            .flatten()
+                            .map(|pass| {
+                                LightPathExpression::parse(&pass.expression).unwrap_or_else(|e| {
+                                    panic!(
+                                        "failed to parse light path expression {:?} for pass {}: {}",
+                                        pass.expression, pass.name, e
+                                    )
+                                })
+                            })
+                            .collect(),
                     }))
                 }
             }
@@ -138,11 +234,37 @@ pub trait SamplerIntegrator: Sync + Send {
         sample_id: usize,
         profile: &mut Profile,
     ) -> XYZColor;
+    // same as color, but also adds how much of it came from each of the world's light groups to light_groups.
+    // integrators that don't keep track of which light a contribution came from leave light_groups untouched.
+    fn color_with_light_groups(
+        &self,
+        sampler: &mut Box<dyn Sampler>,
+        camera_sample: ((f32, f32), CameraId),
+        sample_id: usize,
+        _light_groups: &mut [XYZColor],
+        profile: &mut Profile,
+    ) -> XYZColor {
+        self.color(sampler, camera_sample, sample_id, profile)
+    }
+    // same as color_with_light_groups, but also adds how much came along paths matching each of the integrator's light path expressions to passes.
+    // integrators without light path expressions leave passes untouched.
+    fn color_with_passes(
+        &self,
+        sampler: &mut Box<dyn Sampler>,
+        camera_sample: ((f32, f32), CameraId),
+        sample_id: usize,
+        light_groups: &mut [XYZColor],
+        _passes: &mut [XYZColor],
+        profile: &mut Profile,
+    ) -> XYZColor {
+        self.color_with_light_groups(sampler, camera_sample, sample_id, light_groups, profile)
+    }
 }
 
 pub enum Sample {
     ImageSample(XYZColor, (f32, f32)),
-    LightSample(XYZColor, (f32, f32)),
+    // also has the light group the contribution came from, when it's known
+    LightSample(XYZColor, (f32, f32), Option<usize>),
 }
 
 #[allow(unused)]
@@ -163,4 +285,25 @@ pub trait GenericIntegrator: Send + Sync {
         samples: &mut Vec<(Sample, CameraId)>,
         profile: &mut Profile,
     ) -> XYZColor;
+    // same as color, but also adds how much of the returned color came from each of the world's light groups to light_groups.
+    // splats are tagged with their light group either way
+    fn color_with_light_groups(
+        &self,
+        sampler: &mut Box<dyn Sampler>,
+        settings: &RenderSettings,
+        camera_sample: ((f32, f32), CameraId),
+        sample_id: usize,
+        _light_groups: &mut [XYZColor],
+        samples: &mut Vec<(Sample, CameraId)>,
+        profile: &mut Profile,
+    ) -> XYZColor {
+        self.color(
+            sampler,
+            settings,
+            camera_sample,
+            sample_id,
+            samples,
+            profile,
+        )
+    }
 }
@@ -1,14 +1,17 @@
-use crate::world::World;
+use crate::world::{LightRef, PunctualLight, World};
 // use crate::config::Settings;
 use crate::hittable::{HitRecord, Hittable};
-use crate::integrator::utils::{random_walk, veach_v, LightSourceType, SurfaceVertex, VertexType};
+use crate::integrator::lpe::{self, LightPathExpression};
+use crate::integrator::utils::{
+    eval_transmittance, eval_vertex_scattering, medium_towards, random_walk_medium_from,
+    LightSourceType, SurfaceVertex, VertexType,
+};
 use crate::integrator::*;
-use crate::materials::{Material, MaterialEnum, MaterialId};
+use crate::materials::{Material, MaterialId};
 use crate::math::*;
 use crate::world::TransportMode;
 // use crate::world::EnvironmentMap;
 
-use std::f32::INFINITY;
 use std::sync::Arc;
 
 pub struct PathTracingIntegrator {
@@ -19,213 +22,247 @@ pub struct PathTracingIntegrator {
     pub light_samples: u16,
     pub only_direct: bool,
     pub wavelength_bounds: Bounds1D,
+    // expressions for the paths that count towards each pass
+    pub passes: Vec<LightPathExpression>,
 }
 
 impl PathTracingIntegrator {
+    // direct illumination at a path vertex, which can be either on a surface or in a medium.
+    // dir_to_prev is the world space direction towards the previous vertex, and light is the light that was picked for it.
     fn estimate_direct_illumination(
         &self,
-        hit: &HitRecord,
-        frame: &TangentFrame,
-        wi: Vec3,
-        material: &MaterialEnum,
+        vertex: &SurfaceVertex,
+        dir_to_prev: Vec3,
         throughput: SingleEnergy,
-        light_pick_sample: Sample1D,
+        light: LightRef,
+        light_pick_pdf: PDF,
         additional_light_sample: Sample2D,
+        transmittance_sample: Sample1D,
         profile: &mut Profile,
-    ) -> SingleEnergy {
-        if let Some((light, light_pick_pdf)) = self.world.pick_random_light(light_pick_sample) {
-            // determine pick pdf
-            // as of now the pick pdf is just num lights, however if it were to change this would be where it should change.
-            // sample the primitive from hit_point
-            // let (direction, light_pdf) = light.sample(sampler.draw_2d(), hit.point);
-            let (point_on_light, normal, light_area_pdf) =
-                light.sample_surface(additional_light_sample);
-            debug_assert!(light_area_pdf.0.is_finite());
-            if light_area_pdf.0 == 0.0 {
-                return SingleEnergy::ZERO;
-            }
-            // direction is from shading point to light
-            let direction = (point_on_light - hit.point).normalized();
-            // direction is already in world space.
-            // direction is also oriented away from the shading point already, so no need to negate directions until later.
-            let local_light_direction = frame.to_local(&direction);
-            let light_vertex_wi = TangentFrame::from_normal(normal).to_local(&(-direction));
-
-            let dropoff = light_vertex_wi.z().abs();
-            if dropoff == 0.0 {
-                return SingleEnergy::ZERO;
-            }
-            // since direction is already in world space, no need to call frame.to_world(direction) in the above line
-            let (reflectance, scatter_pdf_for_light_ray) = material.bsdf(
-                hit.lambda,
-                hit.uv,
-                hit.transport_mode,
-                wi,
-                local_light_direction,
-            );
-            // if reflectance.0 < 0.00001 {
-            //     // if reflectance is 0 for all components, skip this light sample
-            //     continue;
-            // }
-
-            let pdf = light.psa_pdf(
-                hit.normal * (point_on_light - hit.point).normalized(),
-                hit.point,
-                point_on_light,
-            );
-            let light_pdf = pdf * light_pick_pdf; // / light_vertex_wi.z().abs();
-            if light_pdf.0 == 0.0 {
-                // println!("light pdf was 0");
-                // go to next pick
-                return SingleEnergy::ZERO;
-            }
-
-            let light_material = self.world.get_material(light.get_material_id());
-            let emission =
-                light_material.emission(hit.lambda, hit.uv, hit.transport_mode, light_vertex_wi);
-            // this should be the same as the other method, but maybe not.
-            if emission.0 == 0.0 {
-                return SingleEnergy::ZERO;
-            }
-
-            profile.shadow_rays += 1;
-            if veach_v(&self.world, point_on_light, hit.point) {
-                let weight = power_heuristic(light_pdf.0, scatter_pdf_for_light_ray.0);
-
-                debug_assert!(emission.0 >= 0.0);
-                // successful_light_samples += 1;
-                let v = reflectance * throughput * dropoff * emission * weight / light_pdf.0;
-                debug_assert!(
-                    v.0.is_finite(),
-                    "{:?},{:?},{:?},{:?},{:?},{:?},",
-                    reflectance,
+    ) -> (SingleEnergy, Vec3) {
+        let light = match light {
+            LightRef::Instance(instance) => instance,
+            LightRef::Punctual(_, punctual) => {
+                return self.estimate_direct_illumination_from_punctual(
+                    vertex,
+                    dir_to_prev,
                     throughput,
-                    dropoff,
-                    emission,
-                    weight,
-                    light_pdf.0
+                    punctual,
+                    light_pick_pdf,
+                    transmittance_sample,
+                    profile,
                 );
-                return v;
-                // debug_assert!(
-                //     !light_contribution.0.is_nan(),
-                //     "l {:?} r {:?} b {:?} d {:?} s {:?} w {:?} p {:?} ",
-                //     light_contribution,
-                //     reflectance,
-                //     beta,
-                //     dropoff,
-                //     emission,
-                //     weight,
-                //     light_pdf
-                // );
             }
-        }
-        SingleEnergy::ZERO
-    }
-
-    fn estimate_direct_illumination_from_world(
-        &self,
-        lambda: f32,
-        hit: &HitRecord,
-        frame: &TangentFrame,
-        wi: Vec3,
-        material: &MaterialEnum,
-        throughput: SingleEnergy,
-        sample: Sample2D,
-        profile: &mut Profile,
-    ) -> SingleEnergy {
-        let (uv, light_pdf) = self
+        };
+        // the pick pdf depends on the light sampling strategy, and includes the probability of not sampling the environment instead.
+        // sample the primitive from hit_point
+        // let (direction, light_pdf) = light.sample(sampler.draw_2d(), hit.point);
+        let (point_on_light, normal, light_uv, light_area_pdf) = self
             .world
-            .environment
-            .sample_env_uv_given_wavelength(sample, lambda);
-        // direction is the direction to the sampled point on the environment
-        let direction = uv_to_direction(uv);
-        let local_wo = frame.to_local(&direction);
-
-        let local_dropoff = local_wo.z();
-
-        let (reflectance, scatter_pdf_for_light_ray) =
-            material.bsdf(hit.lambda, hit.uv, hit.transport_mode, wi, local_wo);
+            .sample_light_surface(light, additional_light_sample);
+        debug_assert!(light_area_pdf.0.is_finite());
+        if light_area_pdf.0 == 0.0 {
+            return (SingleEnergy::ZERO, Vec3::ZERO);
+        }
+        // direction is from shading point to light
+        let direction = (point_on_light - vertex.point).normalized();
+        // direction is already in world space.
+        // direction is also oriented away from the shading point already, so no need to negate directions until later.
+        let light_vertex_wi = TangentFrame::from_normal(normal).to_local(&(-direction));
 
-        profile.shadow_rays += 1;
-        // TODO: add support for passthrough material, such that it doesn't fully interfere with direct illumination
-        if let Some(mut _light_hit) =
-            self.world
-                .hit(Ray::new(hit.point, direction), 0.00001, INFINITY)
-        {
-            return 0.0.into();
-        /*
-        light_hit.lambda = lambda;
-        // handle case where we intended to hit the world but instead hit a light
-        let material = self.world.get_material(light_hit.material);
-
-        let point_on_light = light_hit.point;
-        let light_frame = TangentFrame::from_normal(light_hit.normal);
-        let light_wi = light_frame.to_local(&-direction);
-        let dropoff = light_wi.z().abs();
+        let dropoff = light_vertex_wi.z().abs();
         if dropoff == 0.0 {
-            return SingleEnergy::ZERO;
+            return (SingleEnergy::ZERO, direction);
         }
+        // the phase function for medium vertices
+        let (reflectance, scatter_pdf_for_light_ray) =
+            eval_vertex_scattering(&self.world, vertex, dir_to_prev, direction);
         // if reflectance.0 < 0.00001 {
         //     // if reflectance is 0 for all components, skip this light sample
         //     continue;
         // }
-        let emission = material.emission(
-            light_hit.lambda,
-            light_hit.uv,
-            light_hit.transport_mode,
-            light_wi,
-        );
-        if emission.0 > 0.0 {
-            let light = self.world.get_primitive(light_hit.instance_id);
-            let pdf = light.psa_pdf(
-                hit.normal * (point_on_light - hit.point).normalized(),
-                hit.point,
-                point_on_light,
-            );
 
-            if pdf.0 == 0.0 {
-                return SingleEnergy::ZERO;
-            }
-            let weight = power_heuristic(light_pdf.0, scatter_pdf_for_light_ray.0);
-            reflectance * throughput * dropoff * emission * weight / light_pdf.0
-        } else {
-            SingleEnergy::ZERO
-        } */
+        let cos_at_vertex = if vertex.is_medium() {
+            1.0
         } else {
-            // successfully hit nothing, which is to say, hit the world
-            let emission = self.world.environment.emission(uv, lambda);
+            vertex.normal * direction
+        };
+        let pdf = light.psa_pdf(cos_at_vertex, vertex.point, point_on_light)
+            * PDF::from(self.world.light_uv_pdf_scale(light, light_uv));
+        let light_pdf =
+            pdf * light_pick_pdf * PDF::from(1.0 - self.world.get_env_sampling_probability()); // / light_vertex_wi.z().abs();
+        if light_pdf.0 == 0.0 {
+            // println!("light pdf was 0");
+            // go to next pick
+            return (SingleEnergy::ZERO, direction);
+        }
 
+        let hit = HitRecord::from(*vertex);
+        let light_material = self.world.get_material(light.get_material_id());
+        let emission =
+            light_material.emission(hit.lambda, light_uv, hit.transport_mode, light_vertex_wi);
+        // this should be the same as the other method, but maybe not.
+        if emission.0 == 0.0 {
+            return (SingleEnergy::ZERO, direction);
+        }
+
+        profile.shadow_rays += 1;
+        // passes through passthrough surfaces, and includes transmittance through any mediums along the way
+        let transmittance = eval_transmittance(
+            &self.world,
+            vertex.lambda,
+            vertex.point,
+            medium_towards(&self.world, vertex, direction),
+            point_on_light,
+            transmittance_sample,
+        );
+        if transmittance > 0.0 {
             let weight = power_heuristic(light_pdf.0, scatter_pdf_for_light_ray.0);
-            let v =
-                reflectance * local_dropoff.abs() * throughput * emission * weight / light_pdf.0;
+
+            debug_assert!(emission.0 >= 0.0);
+            // successful_light_samples += 1;
+            let v = reflectance * throughput * dropoff * emission * transmittance * weight
+                / light_pdf.0;
             debug_assert!(
                 v.0.is_finite(),
                 "{:?},{:?},{:?},{:?},{:?},{:?},",
                 reflectance,
-                local_dropoff,
                 throughput,
+                dropoff,
                 emission,
                 weight,
                 light_pdf.0
             );
-            v
+            return (v, direction);
         }
+        (SingleEnergy::ZERO, direction)
     }
 
+    // punctual lights can only be reached through next event estimation, so there's nothing to weigh them against with mis
+    fn estimate_direct_illumination_from_punctual(
+        &self,
+        vertex: &SurfaceVertex,
+        dir_to_prev: Vec3,
+        throughput: SingleEnergy,
+        light: &PunctualLight,
+        light_pick_pdf: PDF,
+        transmittance_sample: Sample1D,
+        profile: &mut Profile,
+    ) -> (SingleEnergy, Vec3) {
+        let light_pdf = light_pick_pdf.0 * (1.0 - self.world.get_env_sampling_probability());
+        if light_pdf == 0.0 {
+            return (SingleEnergy::ZERO, Vec3::ZERO);
+        }
+        let (point_on_light, direction, incoming) =
+            light.sample_li(vertex.point, vertex.lambda, self.world.get_world_radius());
+        if incoming.0 == 0.0 {
+            return (SingleEnergy::ZERO, direction);
+        }
+        let (reflectance, _) = eval_vertex_scattering(&self.world, vertex, dir_to_prev, direction);
+        if reflectance.0 == 0.0 {
+            return (SingleEnergy::ZERO, direction);
+        }
+
+        profile.shadow_rays += 1;
+        let transmittance = eval_transmittance(
+            &self.world,
+            vertex.lambda,
+            vertex.point,
+            medium_towards(&self.world, vertex, direction),
+            point_on_light,
+            transmittance_sample,
+        );
+        if transmittance == 0.0 {
+            return (SingleEnergy::ZERO, direction);
+        }
+        let v =
+            reflectance * vertex.cos(direction) * throughput * incoming * transmittance / light_pdf;
+        debug_assert!(
+            v.0.is_finite(),
+            "{:?},{:?},{:?},{:?},{:?}",
+            reflectance,
+            direction,
+            throughput,
+            incoming,
+            light_pdf
+        );
+        (v, direction)
+    }
+
+    fn estimate_direct_illumination_from_world(
+        &self,
+        vertex: &SurfaceVertex,
+        dir_to_prev: Vec3,
+        throughput: SingleEnergy,
+        sample: Sample2D,
+        transmittance_sample: Sample1D,
+        profile: &mut Profile,
+    ) -> (SingleEnergy, Vec3) {
+        let lambda = vertex.lambda;
+        // direction is the direction to the sampled point on the environment, possibly through a portal
+        let (direction, env_pdf) = self
+            .world
+            .sample_environment_from(vertex.point, sample, lambda);
+        if env_pdf.0 == 0.0 {
+            return (SingleEnergy::ZERO, direction);
+        }
+        // include the probability of picking the environment over the other lights, so that it's consistent with the pdf used when hitting the environment
+        let light_pdf = env_pdf * PDF::from(self.world.get_env_sampling_probability());
+        let uv = direction_to_uv(direction);
+
+        let (reflectance, scatter_pdf_for_light_ray) =
+            eval_vertex_scattering(&self.world, vertex, dir_to_prev, direction);
+        if reflectance.0 == 0.0 {
+            return (SingleEnergy::ZERO, direction);
+        }
+
+        profile.shadow_rays += 1;
+        // anywhere outside the world sphere counts as reaching the environment
+        let transmittance = eval_transmittance(
+            &self.world,
+            lambda,
+            vertex.point,
+            medium_towards(&self.world, vertex, direction),
+            vertex.point + direction * 2.0 * self.world.get_world_radius(),
+            transmittance_sample,
+        );
+        if transmittance == 0.0 {
+            return (SingleEnergy::ZERO, direction);
+        }
+        // successfully hit nothing, which is to say, hit the world
+        let emission = self.world.environment.emission(uv, lambda);
+
+        let weight = power_heuristic(light_pdf.0, scatter_pdf_for_light_ray.0);
+        let v =
+            reflectance * vertex.cos(direction) * throughput * emission * transmittance * weight
+                / light_pdf.0;
+        debug_assert!(
+            v.0.is_finite(),
+            "{:?},{:?},{:?},{:?},{:?},{:?},",
+            reflectance,
+            direction,
+            throughput,
+            emission,
+            weight,
+            light_pdf.0
+        );
+        (v, direction)
+    }
+
+    // record is called with each sample's share of the averaged contribution, along with the light group it came from
+    // and the world space direction towards the light
     pub fn estimate_direct_illumination_with_loop(
         &self,
-        lambda: f32,
-        hit: &HitRecord,
-        frame: &TangentFrame,
-        wi: Vec3,
-        material: &MaterialEnum,
+        vertex: &SurfaceVertex,
+        dir_to_prev: Vec3,
         throughput: SingleEnergy,
         sampler: &mut Box<dyn Sampler>,
+        mut record: impl FnMut(usize, Vec3, SingleEnergy),
         mut profile: &mut Profile,
     ) -> SingleEnergy {
         let mut light_contribution = SingleEnergy::ZERO;
         let env_sampling_probability = self.world.get_env_sampling_probability();
-        if self.world.lights.len() == 0 && env_sampling_probability == 0.0 {
+        if self.world.light_count() == 0 && env_sampling_probability == 0.0 {
             return SingleEnergy::ZERO;
         }
         for _i in 0..self.light_samples {
@@ -234,53 +271,118 @@ impl PathTracingIntegrator {
                     .draw_1d()
                     .choose(env_sampling_probability, true, false);
             // decide whether to sample the lights or the world
-            if sample_world {
+            let ((contribution, direction), group) = if sample_world {
                 // light_contribution += self.world.environment.sample
-                light_contribution += self.estimate_direct_illumination_from_world(
-                    lambda,
-                    hit,
-                    frame,
-                    wi,
-                    material,
+                let contribution = self.estimate_direct_illumination_from_world(
+                    vertex,
+                    dir_to_prev,
                     throughput,
                     sampler.draw_2d(),
+                    sampler.draw_1d(),
                     &mut profile,
                 );
+                (contribution, self.world.environment_light_group())
             } else {
-                light_contribution += self.estimate_direct_illumination(
-                    &hit,
-                    &frame,
-                    wi,
-                    material,
-                    throughput,
-                    light_pick_sample,
-                    sampler.draw_2d(),
-                    &mut profile,
-                );
-            }
+                let additional_light_sample = sampler.draw_2d();
+                let transmittance_sample = sampler.draw_1d();
+                match self
+                    .world
+                    .pick_random_light_from(light_pick_sample, vertex.point)
+                {
+                    Some((light, light_pick_pdf)) => (
+                        self.estimate_direct_illumination(
+                            vertex,
+                            dir_to_prev,
+                            throughput,
+                            light,
+                            light_pick_pdf,
+                            additional_light_sample,
+                            transmittance_sample,
+                            &mut profile,
+                        ),
+                        self.world.light_group_of(light),
+                    ),
+                    None => continue,
+                }
+            };
+            light_contribution += contribution;
+            record(group, direction, contribution / (self.light_samples as f32));
             debug_assert!(
                 light_contribution.0.is_finite(),
-                "{:?}, {}, {:?}, {:?}, {:?}",
+                "{:?}, {}, {:?}, {:?}",
                 light_contribution,
                 sample_world,
-                hit.material,
-                material.get_name(),
-                wi,
+                vertex.material_id,
+                dir_to_prev,
             );
         }
         light_contribution
     }
+
+    // adds energy to every pass whose expression matches the path through the first index vertices, followed by last
+    fn add_to_passes(
+        &self,
+        pass_energy: &mut [SingleEnergy],
+        symbols: &[u8],
+        index: usize,
+        last: &[u8],
+        energy: SingleEnergy,
+    ) {
+        if pass_energy.is_empty() {
+            return;
+        }
+        let path: Vec<u8> = symbols[..index].iter().chain(last).cloned().collect();
+        for (expression, pass) in self.passes.iter().zip(pass_energy.iter_mut()) {
+            if expression.matches(&path) {
+                *pass += energy;
+            }
+        }
+    }
 }
 
 impl SamplerIntegrator for PathTracingIntegrator {
     fn color(
+        &self,
+        sampler: &mut Box<dyn Sampler>,
+        camera_sample: ((f32, f32), CameraId),
+        sample_id: usize,
+        profile: &mut Profile,
+    ) -> XYZColor {
+        self.color_with_passes(sampler, camera_sample, sample_id, &mut [], &mut [], profile)
+    }
+
+    fn color_with_light_groups(
+        &self,
+        sampler: &mut Box<dyn Sampler>,
+        camera_sample: ((f32, f32), CameraId),
+        sample_id: usize,
+        light_groups: &mut [XYZColor],
+        profile: &mut Profile,
+    ) -> XYZColor {
+        self.color_with_passes(
+            sampler,
+            camera_sample,
+            sample_id,
+            light_groups,
+            &mut [],
+            profile,
+        )
+    }
+
+    fn color_with_passes(
         &self,
         mut sampler: &mut Box<dyn Sampler>,
         camera_sample: ((f32, f32), CameraId),
         _sample_id: usize,
+        light_groups: &mut [XYZColor],
+        passes: &mut [XYZColor],
         mut profile: &mut Profile,
     ) -> XYZColor {
         profile.camera_rays += 1;
+        // the energy from each light group and pass, for the same wavelength as sum.
+        // emission from mediums isn't part of any group or pass
+        let mut group_energy = vec![SingleEnergy::ZERO; light_groups.len()];
+        let mut pass_energy = vec![SingleEnergy::ZERO; passes.len()];
 
         let mut sum = SingleWavelength::new_from_range(sampler.draw_1d().x, self.wavelength_bounds);
         let lambda = sum.lambda;
@@ -316,7 +418,9 @@ impl SamplerIntegrator for PathTracingIntegrator {
             0.0,
             1.0,
         ));
-        let _ = random_walk(
+        let camera_medium = self.world.get_camera_medium(camera_id as usize);
+        path[0].medium_id = camera_medium;
+        let medium_emission = random_walk_medium_from(
             camera_ray,
             lambda,
             self.max_bounces,
@@ -327,8 +431,20 @@ impl SamplerIntegrator for PathTracingIntegrator {
             &mut path,
             self.min_bounces,
             &mut profile,
+            camera_medium,
         );
 
+        if let Some(emission) = medium_emission {
+            sum.energy += emission;
+        }
+
+        // only needed for matching light path expressions
+        let symbols = if pass_energy.is_empty() {
+            Vec::new()
+        } else {
+            lpe::path_symbols(&self.world, &path)
+        };
+
         for (index, vertex) in path.iter().enumerate() {
             if index == 0 {
                 continue;
@@ -340,7 +456,31 @@ impl SamplerIntegrator for PathTracingIntegrator {
                     let wo = vertex.local_wi;
                     let uv = direction_to_uv(wo);
                     let emission = self.world.environment.emission(uv, lambda);
-                    sum.energy += emission * vertex.throughput;
+                    let contribution = if prev_vertex.pdf_forward <= 0.0 || self.light_samples == 0
+                    {
+                        emission * vertex.throughput
+                    } else {
+                        // mis against next event estimation of the environment
+                        let pdf = self
+                            .world
+                            .environment_pdf_from(prev_vertex.point, wo, lambda)
+                            * PDF::from(self.world.get_env_sampling_probability());
+                        let weight = power_heuristic(prev_vertex.pdf_forward, pdf.0);
+                        emission * vertex.throughput * weight
+                    };
+                    sum.energy += contribution;
+                    if let Some(group_contribution) =
+                        group_energy.get_mut(self.world.environment_light_group())
+                    {
+                        *group_contribution += contribution;
+                    }
+                    self.add_to_passes(
+                        &mut pass_energy,
+                        &symbols,
+                        index,
+                        &[lpe::BACKGROUND],
+                        contribution,
+                    );
                 } else {
                     let hit = HitRecord::from(*vertex);
                     let frame = TangentFrame::from_normal(hit.normal);
@@ -354,70 +494,141 @@ impl SamplerIntegrator for PathTracingIntegrator {
                     let emission = material.emission(hit.lambda, hit.uv, hit.transport_mode, wi);
 
                     if emission.0 > 0.0 {
-                        if prev_vertex.pdf_forward <= 0.0 || self.light_samples == 0 {
-                            sum.energy += vertex.throughput * emission;
-                            debug_assert!(!sum.energy.is_nan());
-                        } else {
-                            let hit_primitive = self.world.get_primitive(hit.instance_id);
-                            // // println!("{:?}", hit);
-                            let pdf = hit_primitive.psa_pdf(
-                                prev_vertex.normal * (hit.point - prev_vertex.point).normalized(),
-                                prev_vertex.point,
-                                hit.point,
-                            );
-                            let weight = power_heuristic(prev_vertex.pdf_forward, pdf.0);
-                            debug_assert!(
-                                !pdf.is_nan() && !weight.is_nan(),
-                                "{:?}, {}",
-                                pdf,
-                                weight
-                            );
-                            sum.energy += vertex.throughput * emission * weight;
-                            debug_assert!(!sum.energy.is_nan());
+                        let contribution =
+                            if prev_vertex.pdf_forward <= 0.0 || self.light_samples == 0 {
+                                vertex.throughput * emission
+                            } else {
+                                let hit_primitive = self.world.get_primitive(hit.instance_id);
+                                // // println!("{:?}", hit);
+                                let pdf = hit_primitive.psa_pdf(
+                                    prev_vertex.cos((hit.point - prev_vertex.point).normalized()),
+                                    prev_vertex.point,
+                                    hit.point,
+                                );
+                                // textured lights aren't sampled uniformly by area
+                                let uv_scale = self.world.light_uv_pdf_scale(hit_primitive, hit.uv);
+                                let pdf = pdf
+                                    * PDF::from(uv_scale)
+                                    * self
+                                        .world
+                                        .light_pick_pdf_from(hit.instance_id, prev_vertex.point)
+                                    * PDF::from(1.0 - self.world.get_env_sampling_probability());
+                                let weight = power_heuristic(prev_vertex.pdf_forward, pdf.0);
+                                debug_assert!(
+                                    !pdf.is_nan() && !weight.is_nan(),
+                                    "{:?}, {}",
+                                    pdf,
+                                    weight
+                                );
+                                vertex.throughput * emission * weight
+                            };
+                        sum.energy += contribution;
+                        debug_assert!(!sum.energy.is_nan());
+                        if let Some(group_contribution) = self
+                            .world
+                            .light_group_of_instance(hit.instance_id)
+                            .and_then(|group| group_energy.get_mut(group))
+                        {
+                            *group_contribution += contribution;
                         }
+                        self.add_to_passes(
+                            &mut pass_energy,
+                            &symbols,
+                            index,
+                            &[lpe::LIGHT],
+                            contribution,
+                        );
                     }
                 }
             } else {
-                let hit = HitRecord::from(*vertex);
-                let frame = TangentFrame::from_normal(hit.normal);
                 let dir_to_prev = (prev_vertex.point - vertex.point).normalized();
-                let _maybe_dir_to_next = path
-                    .get(index + 1)
-                    .map(|v| (v.point - vertex.point).normalized());
-                let wi = frame.to_local(&dir_to_prev);
-                let material = self.world.get_material(vertex.material_id);
-
-                let emission = material.emission(hit.lambda, hit.uv, hit.transport_mode, wi);
-
-                if emission.0 > 0.0 {
-                    // this will likely never get triggered, since hitting a light source is handled in the above branch
-                    if prev_vertex.pdf_forward <= 0.0 || self.light_samples == 0 {
-                        sum.energy += vertex.throughput * emission;
+                if !vertex.is_medium() {
+                    let hit = HitRecord::from(*vertex);
+                    let frame = TangentFrame::from_normal(hit.normal);
+                    let wi = frame.to_local(&dir_to_prev);
+                    let material = self.world.get_material(vertex.material_id);
+
+                    let emission = material.emission(hit.lambda, hit.uv, hit.transport_mode, wi);
+
+                    if emission.0 > 0.0 {
+                        // this will likely never get triggered, since hitting a light source is handled in the above branch
+                        let contribution =
+                            if prev_vertex.pdf_forward <= 0.0 || self.light_samples == 0 {
+                                vertex.throughput * emission
+                            } else {
+                                let hit_primitive = self.world.get_primitive(hit.instance_id);
+                                let pdf = hit_primitive.psa_pdf(
+                                    prev_vertex.cos((hit.point - prev_vertex.point).normalized()),
+                                    prev_vertex.point,
+                                    hit.point,
+                                );
+                                // textured lights aren't sampled uniformly by area
+                                let uv_scale = self.world.light_uv_pdf_scale(hit_primitive, hit.uv);
+                                let pdf = pdf
+                                    * PDF::from(uv_scale)
+                                    * self
+                                        .world
+                                        .light_pick_pdf_from(hit.instance_id, prev_vertex.point)
+                                    * PDF::from(1.0 - self.world.get_env_sampling_probability());
+                                let weight = power_heuristic(prev_vertex.pdf_forward, pdf.0);
+                                debug_assert!(
+                                    !pdf.is_nan() && !weight.is_nan(),
+                                    "{:?}, {}",
+                                    pdf,
+                                    weight
+                                );
+                                vertex.throughput * emission * weight
+                            };
+                        sum.energy += contribution;
                         debug_assert!(!sum.energy.is_nan());
-                    } else {
-                        let hit_primitive = self.world.get_primitive(hit.instance_id);
-                        // // println!("{:?}", hit);
-                        let pdf = hit_primitive.psa_pdf(
-                            prev_vertex.normal * (hit.point - prev_vertex.point).normalized(),
-                            prev_vertex.point,
-                            hit.point,
+                        if let Some(group_contribution) = self
+                            .world
+                            .light_group_of_instance(hit.instance_id)
+                            .and_then(|group| group_energy.get_mut(group))
+                        {
+                            *group_contribution += contribution;
+                        }
+                        self.add_to_passes(
+                            &mut pass_energy,
+                            &symbols,
+                            index,
+                            &[lpe::LIGHT],
+                            contribution,
                         );
-                        let weight = power_heuristic(prev_vertex.pdf_forward, pdf.0);
-                        debug_assert!(!pdf.is_nan() && !weight.is_nan(), "{:?}, {}", pdf, weight);
-                        sum.energy += vertex.throughput * emission * weight;
-                        debug_assert!(!sum.energy.is_nan());
                     }
                 }
 
+                // medium vertices get next event estimation as well, mis'd against the phase function
                 if self.light_samples > 0 {
+                    let environment_group = self.world.environment_light_group();
                     let light_contribution = self.estimate_direct_illumination_with_loop(
-                        sum.lambda,
-                        &hit,
-                        &frame,
-                        wi,
-                        material,
+                        vertex,
+                        dir_to_prev,
                         vertex.throughput,
                         sampler,
+                        |group, direction, contribution| {
+                            if let Some(group_contribution) = group_energy.get_mut(group) {
+                                *group_contribution += contribution;
+                            }
+                            let light = if group == environment_group {
+                                lpe::BACKGROUND
+                            } else {
+                                lpe::LIGHT
+                            };
+                            // the direction towards the light decides whether this was a reflection or a transmission
+                            let scattering = if pass_energy.is_empty() {
+                                0
+                            } else {
+                                lpe::scattering_symbol(&self.world, vertex, Some(direction))
+                            };
+                            self.add_to_passes(
+                                &mut pass_energy,
+                                &symbols,
+                                index,
+                                &[scattering, light],
+                                contribution,
+                            );
+                        },
                         &mut profile,
                     );
                     // println!("light contribution: {:?}", light_contribution);
@@ -435,6 +646,123 @@ impl SamplerIntegrator for PathTracingIntegrator {
             }
         }
 
+        // the wavelength was picked uniformly, so divide by its pdf to match the other integrators
+        sum.energy *= self.wavelength_bounds.span();
+        for (color, energy) in light_groups
+            .iter_mut()
+            .zip(group_energy)
+            .chain(passes.iter_mut().zip(pass_energy))
+        {
+            *color += XYZColor::from(SingleWavelength::new(
+                lambda,
+                energy * self.wavelength_bounds.span(),
+            ));
+        }
         XYZColor::from(sum)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curves;
+    use crate::mediums::{HomogeneousMedium, Medium, MediumEnum, PhaseFunction};
+    use crate::sampler::IndependentSampler;
+    use crate::world::{
+        AcceleratorType, Aggregate, EnvironmentMap, Instance, LightSamplingStrategy, Sphere,
+    };
+
+    #[test]
+    fn test_medium_direct_illumination() {
+        // a world filled with fog, lit by a point light. the sphere is only there so that the world has a size, and is out of the way
+        let world = Arc::new(World::new(
+            vec![Instance::new(
+                Aggregate::from(Sphere::new(0.5, Point3::new(0.0, 5.0, 0.0))),
+                None,
+                Some(MaterialId::Material(0)),
+                0,
+            )],
+            Vec::new(),
+            vec![MediumEnum::Homogeneous(HomogeneousMedium {
+                phase: PhaseFunction::Rayleigh,
+                sigma_t: curves::cie_e(0.5),
+                sigma_s: curves::cie_e(0.25),
+                emission: None,
+                bounds: None,
+                transform: None,
+            })],
+            1,
+            EnvironmentMap::Constant {
+                color: curves::cie_e(1.0).into(),
+                strength: 0.0,
+            },
+            vec![PunctualLight::Point {
+                position: Point3::new(0.0, 0.0, 2.0),
+                color: curves::cie_e(1.0).into(),
+                strength: 3.0,
+            }],
+            0.0,
+            LightSamplingStrategy::Uniform,
+            AcceleratorType::List,
+        ));
+        let integrator = PathTracingIntegrator {
+            min_bounces: 0,
+            max_bounces: 1,
+            world: world.clone(),
+            russian_roulette: false,
+            light_samples: 1,
+            only_direct: true,
+            wavelength_bounds: curves::EXTENDED_VISIBLE_RANGE,
+            passes: Vec::new(),
+        };
+
+        // scattered at the origin, after arriving along the x axis
+        let lambda = 550.0;
+        let dir_to_prev = -Vec3::X;
+        let mut vertex = SurfaceVertex::new(
+            VertexType::Medium(TransportMode::Importance),
+            0.0,
+            lambda,
+            dir_to_prev,
+            Point3::ORIGIN,
+            Vec3::ZERO,
+            (0.0, 0.0),
+            MaterialId::Material(0),
+            0,
+            SingleEnergy::ONE,
+            1.0,
+            1.0,
+            1.0,
+        );
+        vertex.medium_id = 1;
+
+        // single scattering towards the light, which is the phase function times the intensity,
+        // attenuated by the fog and the distance squared
+        let intensity = world.punctual_lights[0].emission(-Vec3::Z, lambda).0;
+        let expected = world.mediums[0].p(lambda, (0.0, 0.0, 0.0), dir_to_prev, Vec3::Z)
+            * intensity
+            * (-0.5f32 * 2.0).exp()
+            / 4.0;
+
+        let mut sampler: Box<dyn Sampler> = Box::new(IndependentSampler::new(0, 0));
+        let mut profile = Profile::default();
+        let mut recorded = Vec::new();
+        let estimate = integrator.estimate_direct_illumination_with_loop(
+            &vertex,
+            dir_to_prev,
+            vertex.throughput,
+            &mut sampler,
+            |group, direction, contribution| recorded.push((group, direction, contribution)),
+            &mut profile,
+        );
+        assert!(
+            (estimate.0 - expected).abs() < 0.001 * expected,
+            "{:?} {}",
+            estimate,
+            expected
+        );
+        // the direction it was recorded with points at the light
+        assert!(recorded.len() == 1);
+        assert!((recorded[0].1 - Vec3::Z).norm() < 0.001, "{:?}", recorded);
+    }
+}
@@ -1,9 +1,11 @@
-use crate::world::World;
+use crate::world::{LightRef, World};
 // use crate::config::Settings;
 use crate::hittable::Hittable;
-use crate::integrator::utils::{HeroEnergy, HeroSurfaceVertex, LightSourceType, VertexType};
+use crate::integrator::utils::{
+    eval_transmittance, HeroEnergy, HeroSurfaceVertex, LightSourceType, VertexType,
+};
 use crate::integrator::*;
-use crate::materials::{Material, MaterialEnum, MaterialId};
+use crate::materials::{Material, MaterialId};
 use crate::math::*;
 use crate::mediums::{Medium, MediumEnum};
 use crate::world::TransportMode;
@@ -11,7 +13,6 @@ use crate::world::TransportMode;
 
 use crate::integrator::pt::PathTracingIntegrator;
 use packed_simd::f32x4;
-use std::f32::INFINITY;
 use std::sync::Arc;
 use utils::{random_walk_medium_hero, HeroMediumVertex, HeroVertex};
 
@@ -30,47 +31,46 @@ pub fn generate_hero(x: f32, bounds: Bounds1D) -> f32x4 {
     wavelengths - sub
 }
 
-// TODO: finish this function
-#[allow(dead_code)]
+// next event estimation from a point inside a medium, mis'd against sampling the phase function.
+// shadow rays pass through passthrough surfaces and are attenuated by the transmittance of every medium along the way.
 pub fn medium_direct_illumination(
     world: &Arc<World>,
     sample_world: bool,
     medium: &MediumEnum,
     lambda: f32x4,
     vertex: &HeroMediumVertex,
-    _mediums: &[usize],
     light_pick_sample: Sample1D,
     additional_light_sample: Sample2D,
+    transmittance_sample: Sample1D,
     profile: &mut Profile,
 ) -> HeroEnergy {
     if sample_world {
         // light_contribution += direct_illumination_from_world;
-        let (uv, light_pdf) = world
-            .environment
-            .sample_env_uv_given_wavelength(additional_light_sample, lambda.extract(0));
-        // direction is the direction to the sampled point on the environment
-        let wo = uv_to_direction(uv);
+        // wo is the direction to the sampled point on the environment, possibly through a portal
+        let (wo, env_pdf) =
+            world.sample_environment_from(vertex.point, additional_light_sample, lambda.extract(0));
+        let light_pdf = env_pdf * PDF::from(world.get_env_sampling_probability());
+        if light_pdf.0 == 0.0 {
+            return HeroEnergy::ZERO;
+        }
+        let uv = direction_to_uv(wo);
+        // anywhere outside the world sphere counts as reaching the environment
+        let point_on_env = vertex.point + wo * 2.0 * world.get_world_radius();
 
         profile.shadow_rays += 1;
-        let mut point = vertex.point;
-        loop {
-            if let Some(mut _light_hit) = world.hit(Ray::new(point, wo), 0.00001, INFINITY) {
-                // handle case where we intended to hit the world but instead hit a light?
-                match world.get_material(_light_hit.material) {
-                    MaterialEnum::PassthroughFilter(_) => {
-                        // need to update mediums for this step, and update transmittance.
-                        point = _light_hit.point + 0.00001 * wo;
-                        continue;
-                    }
-                    _ => {
-                        return HeroEnergy::ZERO;
-                    }
-                }
-            }
-            break;
-        }
         let mut contribution = f32x4::splat(0.0);
         for i in 0..4 {
+            let transmittance = eval_transmittance(
+                world,
+                lambda.extract(i),
+                vertex.point,
+                vertex.medium_id,
+                point_on_env,
+                transmittance_sample,
+            );
+            if transmittance == 0.0 {
+                continue;
+            }
             let f_and_pdf = medium.p(lambda.extract(i), vertex.uvw, vertex.wi, wo);
 
             // successfully hit nothing, which is to say, hit the world
@@ -79,7 +79,8 @@ pub fn medium_direct_illumination(
             let weight = power_heuristic(light_pdf.0, f_and_pdf);
             contribution = contribution.replace(
                 i,
-                f_and_pdf * vertex.throughput.0.extract(i) * emission.0 * weight / light_pdf.0,
+                f_and_pdf * vertex.throughput.0.extract(i) * emission.0 * transmittance * weight
+                    / light_pdf.0,
             );
 
             debug_assert!(
@@ -97,13 +98,55 @@ pub fn medium_direct_illumination(
         HeroEnergy(contribution)
     } else {
         // light_contribution += direct_illumination_from_light;
-        if let Some((light, light_pick_pdf)) = world.pick_random_light(light_pick_sample) {
-            // determine pick pdf
-            // as of now the pick pdf is just num lights, however if it were to change this would be where it should change.
+        if let Some((light, light_pick_pdf)) =
+            world.pick_random_light_from(light_pick_sample, vertex.point)
+        {
+            let light = match light {
+                LightRef::Instance(instance) => instance,
+                LightRef::Punctual(_, punctual) => {
+                    // delta lights can't be hit by phase function samples, so there's no mis weight
+                    let light_pdf = light_pick_pdf.0 * (1.0 - world.get_env_sampling_probability());
+                    if light_pdf == 0.0 {
+                        return HeroEnergy::ZERO;
+                    }
+                    profile.shadow_rays += 1;
+                    let mut contribution = f32x4::splat(0.0);
+                    for i in 0..4 {
+                        let (point_on_light, wo, incoming) = punctual.sample_li(
+                            vertex.point,
+                            lambda.extract(i),
+                            world.get_world_radius(),
+                        );
+                        if incoming.0 == 0.0 {
+                            continue;
+                        }
+                        let transmittance = eval_transmittance(
+                            world,
+                            lambda.extract(i),
+                            vertex.point,
+                            vertex.medium_id,
+                            point_on_light,
+                            transmittance_sample,
+                        );
+                        if transmittance == 0.0 {
+                            continue;
+                        }
+                        let f_and_pdf = medium.p(lambda.extract(i), vertex.uvw, vertex.wi, wo);
+                        contribution = contribution.replace(
+                            i,
+                            f_and_pdf * vertex.throughput.0.extract(i) * incoming.0 * transmittance
+                                / light_pdf,
+                        );
+                    }
+                    debug_assert!(contribution.is_finite().all(), "{:?}", contribution);
+                    return HeroEnergy(contribution);
+                }
+            };
+            // the pick pdf depends on the light sampling strategy, and includes the probability of not sampling the environment instead.
             // sample the primitive from hit_point
             // let (direction, light_pdf) = light.sample(additional_light_sample, hit.point);
-            let (point_on_light, normal, light_area_pdf) =
-                light.sample_surface(additional_light_sample);
+            let (point_on_light, normal, light_uv, light_area_pdf) =
+                world.sample_light_surface(light, additional_light_sample);
             debug_assert!(light_area_pdf.0.is_finite());
             if light_area_pdf.0 == 0.0 {
                 return HeroEnergy::ZERO;
@@ -120,67 +163,66 @@ pub fn medium_direct_illumination(
                 return HeroEnergy::ZERO;
             }
 
-            let pdf = light.psa_pdf(1.0, vertex.point, point_on_light);
-            let light_pdf = pdf * light_pick_pdf; // / light_vertex_wi.z().abs();
+            // there's no surface at a medium vertex, so the cosine there is 1
+            let pdf = light.psa_pdf(1.0, vertex.point, point_on_light)
+                * PDF::from(world.light_uv_pdf_scale(light, light_uv));
+            let light_pdf =
+                pdf * light_pick_pdf * PDF::from(1.0 - world.get_env_sampling_probability()); // / light_vertex_wi.z().abs();
             if light_pdf.0 == 0.0 {
                 // println!("light pdf was 0");
                 // go to next pick
                 return HeroEnergy::ZERO;
             }
             let light_material = world.get_material(light.get_material_id());
+            profile.shadow_rays += 1;
             let mut contribution = f32x4::splat(0.0);
             for i in 0..4 {
-                let f_and_pdf = medium.p(lambda.extract(i), vertex.uvw, vertex.wi, wo);
                 let emission = light_material.emission(
                     lambda.extract(i),
-                    (0.0, 0.0),
+                    light_uv,
                     vertex.transport_mode(),
                     light_vertex_wi,
                 );
                 // this should be the same as the other method, but maybe not.
                 if emission.0 == 0.0 {
-                    //
-                } else {
-                    profile.shadow_rays += 1;
-                    let mut point = vertex.point;
-                    let direction = (point_on_light - vertex.point).normalized();
-                    loop {
-                        if let Some(light_hit) =
-                            world.hit(Ray::new(point, direction), 0.00001, INFINITY)
-                        {
-                            match world.get_material(light_hit.material) {
-                                MaterialEnum::PassthroughFilter(_) => {
-                                    // need to update mediums for this step, and update transmittance.
-                                    point = light_hit.point + 0.00001 * direction;
-                                    continue;
-                                }
-                                _ => {
-                                    return HeroEnergy::ZERO;
-                                }
-                            }
-                        }
-                        break;
-                    }
-                    let weight = power_heuristic(light_pdf.0, f_and_pdf);
-
-                    debug_assert!(emission.0 >= 0.0);
-                    // successful_light_samples += 1;
-                    contribution = contribution.replace(
-                        i,
-                        f_and_pdf * vertex.throughput.0.extract(i) * dropoff * emission.0 * weight
-                            / light_pdf.0,
-                    );
-                    debug_assert!(
-                        contribution.is_finite().all(),
-                        "{:?}, {:?}, {:?}, {:?}, {:?}, {:?}",
-                        i,
-                        f_and_pdf,
-                        vertex.throughput,
-                        emission,
-                        weight,
-                        light_pdf
-                    );
+                    continue;
                 }
+                let transmittance = eval_transmittance(
+                    world,
+                    lambda.extract(i),
+                    vertex.point,
+                    vertex.medium_id,
+                    point_on_light,
+                    transmittance_sample,
+                );
+                if transmittance == 0.0 {
+                    continue;
+                }
+                let f_and_pdf = medium.p(lambda.extract(i), vertex.uvw, vertex.wi, wo);
+                let weight = power_heuristic(light_pdf.0, f_and_pdf);
+
+                debug_assert!(emission.0 >= 0.0);
+                // successful_light_samples += 1;
+                contribution = contribution.replace(
+                    i,
+                    f_and_pdf
+                        * vertex.throughput.0.extract(i)
+                        * dropoff
+                        * emission.0
+                        * transmittance
+                        * weight
+                        / light_pdf.0,
+                );
+                debug_assert!(
+                    contribution.is_finite().all(),
+                    "{:?}, {:?}, {:?}, {:?}, {:?}, {:?}",
+                    i,
+                    f_and_pdf,
+                    vertex.throughput,
+                    emission,
+                    weight,
+                    light_pdf
+                );
             }
             debug_assert!(contribution.is_finite().all(), "{:?}", contribution);
             HeroEnergy(contribution)
@@ -231,7 +273,7 @@ impl SamplerIntegrator for HWSSPathTracingIntegrator {
             f32x4::splat(0.0),
             1.0,
         )));
-        let _ = random_walk_medium_hero(
+        let medium_emission = random_walk_medium_hero(
             camera_ray,
             lambda,
             self.inner.max_bounces,
@@ -242,7 +284,11 @@ impl SamplerIntegrator for HWSSPathTracingIntegrator {
             &mut path,
             self.inner.min_bounces,
             &mut profile,
+            self.inner.world.get_camera_medium(camera_id as usize),
         );
+        if let Some(emission) = medium_emission {
+            sum += emission;
+        }
 
         for (index, vertex) in path.iter().enumerate() {
             if index == 0 {
@@ -269,7 +315,32 @@ impl SamplerIntegrator for HWSSPathTracingIntegrator {
                                         .0,
                                 );
                             }
-                            sum.0 += emission * vertex.throughput.0;
+                            if prev_vertex.pdf_forward().extract(0) <= 0.0
+                                || self.inner.light_samples == 0
+                            {
+                                sum.0 += emission * vertex.throughput.0;
+                            } else {
+                                // mis against next event estimation of the environment
+                                let env_sampling_probability =
+                                    self.inner.world.get_env_sampling_probability();
+                                let mut pdf = f32x4::splat(0.0);
+                                for i in 0..4 {
+                                    pdf = pdf.replace(
+                                        i,
+                                        self.inner
+                                            .world
+                                            .environment_pdf_from(
+                                                prev_vertex.point(),
+                                                wo,
+                                                lambda.extract(i),
+                                            )
+                                            .0
+                                            * env_sampling_probability,
+                                    );
+                                }
+                                let weight = power_heuristic_hero(prev_vertex.pdf_forward(), pdf);
+                                sum.0 += emission * vertex.throughput.0 * weight;
+                            }
                             debug_assert!(
                                 sum.0.is_finite().all(),
                                 "{:?} {:?}",
@@ -316,6 +387,11 @@ impl SamplerIntegrator for HWSSPathTracingIntegrator {
                                 } else {
                                     let hit_primitive =
                                         self.inner.world.get_primitive(vertex.instance_id);
+                                    // textured lights aren't sampled uniformly by area
+                                    let uv_scale = self
+                                        .inner
+                                        .world
+                                        .light_uv_pdf_scale(hit_primitive, vertex.uv);
                                     // // println!("{:?}", hit);
                                     let pdf = f32x4::splat(
                                         hit_primitive
@@ -327,7 +403,18 @@ impl SamplerIntegrator for HWSSPathTracingIntegrator {
                                                 prev_vertex.point(),
                                                 vertex.point,
                                             )
-                                            .0,
+                                            .0
+                                            * uv_scale
+                                            * self
+                                                .inner
+                                                .world
+                                                .light_pick_pdf_from(
+                                                    vertex.instance_id,
+                                                    prev_vertex.point(),
+                                                )
+                                                .0
+                                            * (1.0
+                                                - self.inner.world.get_env_sampling_probability()),
                                     );
                                     let weight =
                                         power_heuristic_hero(prev_vertex.pdf_forward(), pdf);
@@ -390,6 +477,11 @@ impl SamplerIntegrator for HWSSPathTracingIntegrator {
                             } else {
                                 let hit_primitive =
                                     self.inner.world.get_primitive(vertex.instance_id);
+                                // textured lights aren't sampled uniformly by area
+                                let uv_scale = self
+                                    .inner
+                                    .world
+                                    .light_uv_pdf_scale(hit_primitive, vertex.uv);
                                 // // println!("{:?}", hit);
                                 let pdf = f32x4::splat(
                                     hit_primitive
@@ -400,7 +492,17 @@ impl SamplerIntegrator for HWSSPathTracingIntegrator {
                                             prev_vertex.point(),
                                             vertex.point,
                                         )
-                                        .0,
+                                        .0
+                                        * uv_scale
+                                        * self
+                                            .inner
+                                            .world
+                                            .light_pick_pdf_from(
+                                                vertex.instance_id,
+                                                prev_vertex.point(),
+                                            )
+                                            .0
+                                        * (1.0 - self.inner.world.get_env_sampling_probability()),
                                 );
                                 let weight = power_heuristic_hero(prev_vertex.pdf_forward(), pdf);
                                 debug_assert!(
@@ -430,13 +532,11 @@ impl SamplerIntegrator for HWSSPathTracingIntegrator {
                                     i,
                                     self.inner
                                         .estimate_direct_illumination_with_loop(
-                                            lambda.extract(i),
-                                            &vertex.into_hit_w_lane(i),
-                                            &frame,
-                                            wi,
-                                            material,
+                                            &vertex.lane(i),
+                                            dir_to_prev,
                                             SingleEnergy(vertex.throughput.0.extract(i)),
                                             sampler,
+                                            |_, _, _| {},
                                             &mut profile,
                                         )
                                         .0,
@@ -469,68 +569,175 @@ impl SamplerIntegrator for HWSSPathTracingIntegrator {
                         VertexType::Eye | VertexType::Light => {
                             // main case
                             // evaluate direct illumination to the medium point.
-                            // let medium = &self.inner.world.mediums[vertex.medium_id - 1];
+                            if self.inner.light_samples == 0 {
+                                continue;
+                            }
+                            let medium = &self.inner.world.mediums[vertex.medium_id - 1];
 
-                            // let mut light_contribution = HeroEnergy(f32x4::splat(0.0));
-                            // let env_sampling_probability =
-                            //     self.inner.world.get_env_sampling_probability();
-                            // if self.inner.world.lights.len() == 0 && env_sampling_probability == 0.0
-                            // {
-                            //     // do nothing. direct illumination is 0 due to env sampling probability being 0
-                            // } else {
-                            //     for _i in 0..self.inner.light_samples {
-                            //         let (light_pick_sample, sample_world) = sampler
-                            //             .draw_1d()
-                            //             .choose(env_sampling_probability, true, false);
-                            //         // decide whether to sample the lights or the world
-                            //         let contribution = medium_direct_illumination(
-                            //             &self.inner.world,
-                            //             sample_world,
-                            //             medium,
-                            //             lambda,
-                            //             vertex,
-                            //             light_pick_sample,
-                            //             sampler.draw_2d(),
-                            //             &mut profile,
-                            //         );
-                            //         light_contribution += contribution;
-                            //     }
-                            // }
-                            // sum +=
-                            //     HeroEnergy(light_contribution.0 / self.inner.light_samples as f32);
-                            // debug_assert!(
-                            //     sum.0.is_finite().all(),
-                            //     "{:?} {:?}",
-                            //     light_contribution,
-                            //     self.inner.light_samples
-                            // );
+                            let mut light_contribution = HeroEnergy(f32x4::splat(0.0));
+                            let env_sampling_probability =
+                                self.inner.world.get_env_sampling_probability();
+                            if self.inner.world.light_count() == 0
+                                && env_sampling_probability == 0.0
+                            {
+                                // do nothing. direct illumination is 0 due to env sampling probability being 0
+                            } else {
+                                for _i in 0..self.inner.light_samples {
+                                    let (light_pick_sample, sample_world) = sampler
+                                        .draw_1d()
+                                        .choose(env_sampling_probability, true, false);
+                                    // decide whether to sample the lights or the world
+                                    let contribution = medium_direct_illumination(
+                                        &self.inner.world,
+                                        sample_world,
+                                        medium,
+                                        lambda,
+                                        vertex,
+                                        light_pick_sample,
+                                        sampler.draw_2d(),
+                                        sampler.draw_1d(),
+                                        &mut profile,
+                                    );
+                                    light_contribution.0 += contribution.0;
+                                }
+                            }
+                            sum.0 += light_contribution.0 / self.inner.light_samples as f32;
+                            debug_assert!(
+                                sum.0.is_finite().all(),
+                                "{:?} {:?}",
+                                light_contribution,
+                                self.inner.light_samples
+                            );
                         }
                         VertexType::Camera => {
                             // hit camera. since this is path tracing, we don't care. in fact, since the pt integrator version of the world doesn't even have a camera "surface" in it, this is impossible
                             panic!();
                         }
+                        VertexType::Medium(_) => {
+                            // hero walks mark medium vertices with HeroVertex::Medium and keep the transport mode as the vertex type
+                            panic!();
+                        }
                     }
                 }
             }
         }
 
+        // average the lanes, and divide by the pdf of the uniformly sampled wavelengths to match the other integrators
+        let scale = self.inner.wavelength_bounds.span() / 4.0;
         let mut c = XYZColor::from(SingleWavelength::new(
             lambda.extract(0),
-            SingleEnergy(sum.0.extract(0) / 4.0),
+            SingleEnergy(sum.0.extract(0) * scale),
         ));
         c += XYZColor::from(SingleWavelength::new(
             lambda.extract(1),
-            SingleEnergy(sum.0.extract(1) / 4.0),
+            SingleEnergy(sum.0.extract(1) * scale),
         ));
         c += XYZColor::from(SingleWavelength::new(
             lambda.extract(2),
-            SingleEnergy(sum.0.extract(2) / 4.0),
+            SingleEnergy(sum.0.extract(2) * scale),
         ));
         c += XYZColor::from(SingleWavelength::new(
             lambda.extract(3),
-            SingleEnergy(sum.0.extract(3) / 4.0),
+            SingleEnergy(sum.0.extract(3) * scale),
         ));
         debug_assert!(c.0.is_finite().all(), "{:?}", sum);
         c
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curves;
+    use crate::mediums::{HomogeneousMedium, PhaseFunction};
+    use crate::world::{
+        AcceleratorType, Aggregate, EnvironmentMap, Instance, LightSamplingStrategy, PunctualLight,
+        Sphere,
+    };
+
+    #[test]
+    fn test_medium_direct_illumination_per_lane() {
+        // forward scattering fog, lit by a point light
+        let world = Arc::new(World::new(
+            vec![Instance::new(
+                Aggregate::from(Sphere::new(0.5, Point3::new(0.0, 5.0, 0.0))),
+                None,
+                Some(MaterialId::Material(0)),
+                0,
+            )],
+            Vec::new(),
+            vec![MediumEnum::Homogeneous(HomogeneousMedium {
+                phase: PhaseFunction::HenyeyGreenstein {
+                    g: curves::cie_e(1.5),
+                },
+                sigma_t: curves::cie_e(0.5),
+                sigma_s: curves::cie_e(0.25),
+                emission: None,
+                bounds: None,
+                transform: None,
+            })],
+            1,
+            EnvironmentMap::Constant {
+                color: curves::cie_e(1.0).into(),
+                strength: 0.0,
+            },
+            vec![PunctualLight::Point {
+                position: Point3::new(0.0, 0.0, 2.0),
+                color: curves::cie_e(1.0).into(),
+                strength: 3.0,
+            }],
+            0.0,
+            LightSamplingStrategy::Uniform,
+            AcceleratorType::List,
+        ));
+
+        let lambda = f32x4::new(450.0, 500.0, 550.0, 650.0);
+        let wi = -Vec3::X;
+        let vertex = HeroMediumVertex::new(
+            VertexType::Eye,
+            0.0,
+            lambda,
+            wi,
+            Point3::ORIGIN,
+            (0.0, 0.0, 0.0),
+            1,
+            0,
+            HeroEnergy(f32x4::new(1.0, 0.5, 0.25, 2.0)),
+            f32x4::splat(1.0),
+            f32x4::splat(1.0),
+            1.0,
+        );
+        let medium = &world.mediums[0];
+        let mut profile = Profile::default();
+        let contribution = medium_direct_illumination(
+            &world,
+            false,
+            medium,
+            lambda,
+            &vertex,
+            Sample1D { x: 0.5 },
+            Sample2D::new(0.5, 0.5),
+            Sample1D { x: 0.5 },
+            &mut profile,
+        );
+        // every lane gets its own single scattering estimate, with its own throughput and phase function
+        for i in 0..4 {
+            let expected = medium.p(lambda.extract(i), vertex.uvw, wi, Vec3::Z)
+                * vertex.throughput.0.extract(i)
+                * world.punctual_lights[0]
+                    .emission(-Vec3::Z, lambda.extract(i))
+                    .0
+                * (-0.5f32 * 2.0).exp()
+                / 4.0;
+            let actual = contribution.0.extract(i);
+            assert!(
+                (actual - expected).abs() < 0.001 * expected,
+                "lane {}: {} {}",
+                i,
+                actual,
+                expected
+            );
+        }
+        assert!(profile.shadow_rays == 1);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::math::*;
+
+use rayon::prelude::*;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Photon {
+    pub point: Point3,
+    // world space direction the photon arrived from
+    pub wi: Vec3,
+    pub flux: SingleEnergy,
+}
+
+// anything that can be stored in a PhotonGrid
+pub trait GridPoint {
+    fn grid_point(&self) -> Point3;
+}
+
+impl GridPoint for Photon {
+    fn grid_point(&self) -> Point3 {
+        self.point
+    }
+}
+
+fn hash_cell(cell: (i32, i32, i32), table_size: usize) -> usize {
+    // large primes from Teschner et al. 2003, "Optimized Spatial Hashing for Collision Detection of Deformable Objects"
+    let h = (cell.0 as i64 as u64).wrapping_mul(73856093)
+        ^ (cell.1 as i64 as u64).wrapping_mul(19349663)
+        ^ (cell.2 as i64 as u64).wrapping_mul(83492791);
+    (h % table_size as u64) as usize
+}
+
+// hashed uniform grid over photons, or anything else that has a position. photons are sorted by bucket so that each bucket is a contiguous range,
+// and lookups check the 27 cells around the query point.
+// the cell size is the maximum gather radius, so any photon within the radius of a query point is in one of those cells.
+pub struct PhotonGrid<P = Photon> {
+    pub cell_size: f32,
+    bucket_starts: Vec<usize>,
+    photons: Vec<P>,
+}
+
+impl<P: GridPoint + Copy + Send + Sync> PhotonGrid<P> {
+    pub fn new(photons: Vec<P>, cell_size: f32) -> Self {
+        assert!(cell_size > 0.0);
+        let table_size = photons.len().max(1);
+        let buckets: Vec<usize> = photons
+            .par_iter()
+            .map(|photon| hash_cell(Self::cell_of(photon.grid_point(), cell_size), table_size))
+            .collect();
+
+        // counting sort by bucket, which keeps photons from the same bucket in their original order
+        let mut bucket_starts = vec![0usize; table_size + 1];
+        for &bucket in buckets.iter() {
+            bucket_starts[bucket + 1] += 1;
+        }
+        for i in 0..table_size {
+            bucket_starts[i + 1] += bucket_starts[i];
+        }
+        let mut offsets = bucket_starts.clone();
+        // every slot gets overwritten, so any photon works as the initial value
+        let mut sorted = match photons.first() {
+            Some(&first) => vec![first; photons.len()],
+            None => Vec::new(),
+        };
+        for (photon, &bucket) in photons.into_iter().zip(buckets.iter()) {
+            sorted[offsets[bucket]] = photon;
+            offsets[bucket] += 1;
+        }
+
+        PhotonGrid {
+            cell_size,
+            bucket_starts,
+            photons: sorted,
+        }
+    }
+
+    fn cell_of(point: Point3, cell_size: f32) -> (i32, i32, i32) {
+        (
+            (point.x() / cell_size).floor() as i32,
+            (point.y() / cell_size).floor() as i32,
+            (point.z() / cell_size).floor() as i32,
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    // calls f for every photon within radius of point. radius must not exceed the cell size.
+    pub fn for_each_within<F>(&self, point: Point3, radius: f32, mut f: F)
+    where
+        F: FnMut(&P),
+    {
+        debug_assert!(radius <= self.cell_size);
+        if self.photons.len() == 0 {
+            return;
+        }
+        let table_size = self.bucket_starts.len() - 1;
+        let center = Self::cell_of(point, self.cell_size);
+        // neighboring cells can hash to the same bucket, so dedup the buckets to avoid counting photons twice
+        let mut buckets: Vec<usize> = Vec::with_capacity(27);
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    buckets.push(hash_cell(
+                        (center.0 + dx, center.1 + dy, center.2 + dz),
+                        table_size,
+                    ));
+                }
+            }
+        }
+        buckets.sort_unstable();
+        buckets.dedup();
+
+        let radius_squared = radius * radius;
+        for bucket in buckets {
+            for photon in &self.photons[self.bucket_starts[bucket]..self.bucket_starts[bucket + 1]]
+            {
+                if (photon.grid_point() - point).norm_squared() < radius_squared {
+                    f(photon);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::IndependentSampler;
+
+    #[test]
+    fn test_grid_matches_brute_force() {
+        let mut sampler = IndependentSampler::new(0, 0);
+        let photons: Vec<Photon> = (0..5000)
+            .map(|_| Photon {
+                point: Point3::new(
+                    sampler.draw_1d().x * 4.0 - 2.0,
+                    sampler.draw_1d().x * 4.0 - 2.0,
+                    sampler.draw_1d().x * 4.0 - 2.0,
+                ),
+                wi: Vec3::Z,
+                flux: SingleEnergy::ONE,
+            })
+            .collect();
+        let radius = 0.3;
+        let grid = PhotonGrid::new(photons.clone(), radius);
+        assert!(grid.len() == photons.len());
+        for _ in 0..100 {
+            let query = Point3::new(
+                sampler.draw_1d().x * 4.0 - 2.0,
+                sampler.draw_1d().x * 4.0 - 2.0,
+                sampler.draw_1d().x * 4.0 - 2.0,
+            );
+            let brute_force = photons
+                .iter()
+                .filter(|p| (p.point - query).norm_squared() < radius * radius)
+                .count();
+            let mut count = 0;
+            grid.for_each_within(query, radius, |_| count += 1);
+            assert!(count == brute_force, "{} {}", count, brute_force);
+        }
+    }
+}
@@ -1,443 +1,408 @@
-use crate::hittable::{HitRecord, Hittable};
-use crate::integrator::utils::*;
+mod grid;
+
+pub use grid::{GridPoint, Photon, PhotonGrid};
+
+use crate::hittable::Hittable;
 use crate::integrator::*;
-use crate::materials::{Material, MaterialId};
+use crate::materials::{Material, MaterialEnum, MaterialId};
 use crate::math::*;
-use crate::world::TransportMode;
-use crate::world::World;
-use math::spectral::BOUNDED_VISIBLE_RANGE as VISIBLE_RANGE;
-// use crate::{INTERSECTION_TIME_OFFSET, NORMAL_OFFSET};
+use crate::sampler::SamplerFactory;
+use crate::world::{LightRef, TransportMode, NORMAL_OFFSET};
 
 use std::{f32::EPSILON, sync::Arc};
 
 use rayon::iter::ParallelIterator;
 use rayon::prelude::*;
 
-fn window_function(lambda1: f32, lambda2: f32) -> f32 {
-    let diff = (lambda1 - lambda2).abs();
-    1.0 / (1.0 + diff * diff)
+// materials rougher than this are treated as glossy, and get visible points and photons.
+// anything smoother is treated as specular, and is bounced through.
+const SPECULAR_ALPHA_THRESHOLD: f32 = 0.05;
+
+pub fn is_diffuse(material: &MaterialEnum) -> bool {
+    match material {
+        MaterialEnum::Lambertian(_) => true,
+        MaterialEnum::GGX(inner) => inner.alpha > SPECULAR_ALPHA_THRESHOLD,
+        // even a smooth coat has light scattering around underneath it
+        MaterialEnum::Layered(_) => true,
+        _ => false,
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct VisiblePoint {
+    pub point: Point3,
+    pub normal: Vec3,
+    // world space direction towards the camera
+    pub wi: Vec3,
+    pub uv: (f32, f32),
+    pub material_id: MaterialId,
+    pub beta: SingleEnergy,
 }
 
-pub struct PhotonMap {
-    pub photons: Vec<SurfaceVertex>,
+// per pixel state for progressive photon mapping, see Hachisuka and Jensen 2009, "Stochastic Progressive Photon Mapping"
+#[derive(Copy, Clone, Debug)]
+pub struct SPPMPixel {
+    pub radius: f32,
+    // accumulated photon count, after reduction
+    pub n: f32,
+    // accumulated flux, already scaled by the radius reduction
+    pub tau: XYZColor,
+    // emission seen directly or through specular bounces, summed over iterations
+    pub direct: XYZColor,
+    pub visible_point: Option<VisiblePoint>,
 }
 
+// every iteration renders a single wavelength, shared by the visible points and the photons.
+// since both ends of a photon connection have exactly the same wavelength, no kernel over wavelength is needed,
+// and radius reduction only ever scales flux that was gathered at matching wavelengths.
+// the per iteration estimates are converted to XYZ before being accumulated, which is fine since the radius reduction is linear.
 pub struct SPPMIntegrator {
     pub max_bounces: u16,
     pub world: Arc<World>,
     pub russian_roulette: bool,
-    pub camera_samples: u16,
     pub wavelength_bounds: Bounds1D,
-    pub photon_map: Option<PhotonMap>,
-    pub last_lambda: f32,
+    pub photons_per_iteration: usize,
+    pub alpha: f32,
+    pub initial_radius: f32,
+    pub pixels: Vec<SPPMPixel>,
+    pub iteration: usize,
 }
 
-impl SamplerIntegrator for SPPMIntegrator {
-    fn preprocess(
+impl SPPMIntegrator {
+    pub fn reset(&mut self, width: usize, height: usize) {
+        self.pixels = vec![
+            SPPMPixel {
+                radius: self.initial_radius,
+                n: 0.0,
+                tau: XYZColor::BLACK,
+                direct: XYZColor::BLACK,
+                visible_point: None,
+            };
+            width * height
+        ];
+        self.iteration = 0;
+    }
+
+    // runs one full iteration: finds visible points for every pixel, traces photons, then gathers and shrinks radii.
+    pub fn iterate(
         &mut self,
-        sampler: &mut Box<dyn Sampler>,
-        _settings: &Vec<RenderSettings>,
+        sampler_factory: &SamplerFactory,
+        camera_id: CameraId,
+        resolution: (usize, usize),
         profile: &mut Profile,
     ) {
-        let num_beams = 1000;
-
-        let wavelength_sample = sampler.draw_1d();
-        self.last_lambda = wavelength_sample.x;
-        println!("preprocessing and mapping beams");
-        let mut beams: Vec<Vec<SurfaceVertex>> = vec![Vec::new(); num_beams];
-        let beams_profile = beams
+        let (width, height) = resolution;
+        assert!(self.pixels.len() == width * height);
+        let iteration = self.iteration;
+
+        // the iteration wavelength gets its own sampler stream, so that wavelengths are stratified across iterations
+        let mut wavelength_sampler =
+            sampler_factory.sampler_for((usize::MAX, usize::MAX), iteration);
+        let lambda = self
+            .wavelength_bounds
+            .sample(wavelength_sampler.draw_1d().x);
+        let wavelength_pdf = 1.0 / (self.wavelength_bounds.upper - self.wavelength_bounds.lower);
+
+        // pass 1, generate visible points
+        let world = &self.world;
+        let max_bounces = self.max_bounces;
+        let camera_profile = self
+            .pixels
             .par_iter_mut()
-            .map(|beam| {
-                let mut sampler: Box<dyn Sampler> = Box::new(RandomSampler::new());
+            .enumerate()
+            .map(|(pixel_index, pixel)| {
                 let mut profile = Profile::default();
-
-                let env_sampling_probability = self.world.get_env_sampling_probability();
-
-                let sampled;
-                let start_light_vertex;
-                loop {
-                    let mut light_pick_sample = sampler.draw_1d();
-                    if light_pick_sample.x >= env_sampling_probability {
-                        light_pick_sample.x = ((light_pick_sample.x - env_sampling_probability)
-                            / (1.0 - env_sampling_probability))
-                            .clamp(0.0, 1.0);
-
-                        if self.world.lights.len() == 0 {
-                            continue;
+                let y: usize = pixel_index / width;
+                let x: usize = pixel_index - width * y;
+                let mut sampler = sampler_factory.sampler_for((x, y), iteration);
+                let film_sample = sampler.draw_2d();
+                let film_sample = Sample2D::new(
+                    ((x as f32 + film_sample.x) / width as f32).clamp(0.0, 1.0 - EPSILON),
+                    ((y as f32 + film_sample.y) / height as f32).clamp(0.0, 1.0 - EPSILON),
+                );
+                let camera = world.get_camera(camera_id);
+                let (mut ray, _lens_normal, _camera_pdf) =
+                    camera.sample_we(film_sample, &mut sampler, lambda);
+                profile.camera_rays += 1;
+
+                let mut beta = SingleEnergy::ONE;
+                let mut direct = SingleEnergy::ZERO;
+                pixel.visible_point = None;
+                for _ in 0..max_bounces {
+                    let scatter_sample = sampler.draw_2d();
+                    let hit = match world.hit(ray, 0.01, ray.tmax) {
+                        Some(hit) => hit,
+                        None => {
+                            let uv = direction_to_uv(ray.direction);
+                            direct += beta * world.environment.emission(uv, lambda);
+                            break;
                         }
-                        let (light, light_pick_pdf) =
-                            self.world.pick_random_light(light_pick_sample).unwrap();
-
-                        // if we picked a light
-                        let (light_surface_point, light_surface_normal, area_pdf) =
-                            light.sample_surface(sampler.draw_2d());
-
-                        let mat_id = light.get_material_id();
-                        let material = self.world.get_material(mat_id);
-                        // println!("sampled light emission in instance light branch");
-                        let maybe_sampled = material.sample_emission(
-                            light_surface_point,
-                            light_surface_normal,
-                            VISIBLE_RANGE,
-                            sampler.draw_2d(),
-                            wavelength_sample,
-                        );
-                        sampled = if let Some(data) = maybe_sampled {
-                            data
-                        } else {
-                            println!("failed to sample, material is {:?}", material.get_name());
-                            panic!();
-                        };
-
-                        let directional_pdf = sampled.2;
-                        // if delta light, the pdf_forward is only directional_pdf
-                        let pdf_forward: PDF =
-                            directional_pdf / (light_surface_normal * (&sampled.0).direction).abs();
-                        let pdf_backward: PDF = light_pick_pdf * area_pdf;
-                        debug_assert!(
-                            pdf_forward.0.is_finite(),
-                            "pdf_forward was not finite {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
-                            pdf_forward,
-                            pdf_backward,
-                            sampled.0,
-                            material.get_name(),
-                            directional_pdf,
-                            light_surface_point,
-                            light_surface_normal,
-                            sampled.1.energy
-                        );
-                        debug_assert!(
-                            pdf_backward.0.is_finite(),
-                            "pdf_backward was not finite {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
-                            pdf_backward,
-                            pdf_forward,
-                            material.get_name(),
-                            directional_pdf,
-                            light_surface_point,
-                            light_surface_normal,
-                            sampled.1.energy
-                        );
-
-                        start_light_vertex = SurfaceVertex::new(
-                            VertexType::LightSource(LightSourceType::Instance),
-                            0.0,
-                            sampled.1.lambda,
-                            Vec3::ZERO,
-                            light_surface_point,
-                            light_surface_normal,
-                            (0.0, 0.0),
-                            mat_id,
-                            light.get_instance_id(),
-                            sampled.1.energy,
-                            pdf_forward.into(),
-                            pdf_backward.into(),
-                            1.0,
-                        );
+                    };
+                    profile.bounce_rays += 1;
+                    if let MaterialId::Camera(_) = hit.material {
                         break;
-                    } else {
-                        // world
-                        light_pick_sample.x =
-                            (light_pick_sample.x / env_sampling_probability).clamp(0.0, 1.0);
-                        // sample world env
-                        let world_radius = self.world.get_world_radius();
-                        let world_center = self.world.get_center();
-                        sampled = self.world.environment.sample_emission(
-                            world_radius,
-                            world_center,
-                            sampler.draw_2d(),
-                            sampler.draw_2d(),
-                            VISIBLE_RANGE,
-                            wavelength_sample,
-                        );
-                        let light_g_term = 1.0;
-                        let directional_pdf = sampled.2;
-                        start_light_vertex = SurfaceVertex::new(
-                            VertexType::LightSource(LightSourceType::Environment),
-                            0.0,
-                            sampled.1.lambda,
-                            Vec3::ZERO,
-                            sampled.0.origin,
-                            sampled.0.direction,
-                            (0.0, 0.0),
-                            MaterialId::Light(0),
-                            0,
-                            sampled.1.energy,
-                            directional_pdf.0,
-                            1.0,
-                            light_g_term,
-                        );
+                    }
+                    let frame = TangentFrame::from_normal(hit.normal);
+                    let wi = frame.to_local(&-ray.direction).normalized();
+                    let material = world.get_material(hit.material);
+                    direct +=
+                        beta * material.emission(lambda, hit.uv, TransportMode::Importance, wi);
+
+                    if is_diffuse(material) {
+                        pixel.visible_point = Some(VisiblePoint {
+                            point: hit.point,
+                            normal: hit.normal,
+                            wi: -ray.direction,
+                            uv: hit.uv,
+                            material_id: hit.material,
+                            beta,
+                        });
                         break;
+                    }
+
+                    let wo = match material.generate(
+                        lambda,
+                        hit.uv,
+                        TransportMode::Importance,
+                        scatter_sample,
+                        wi,
+                    ) {
+                        Some(wo) => wo,
+                        None => break,
                     };
+                    let (f, pdf) = material.bsdf(lambda, hit.uv, TransportMode::Importance, wi, wo);
+                    if pdf.0 < 0.00000001 || pdf.is_nan() {
+                        break;
+                    }
+                    beta *= f * wo.z().abs() / pdf.0;
+                    ray = Ray::new(
+                        hit.point
+                            + hit.normal * NORMAL_OFFSET * if wo.z() > 0.0 { 1.0 } else { -1.0 },
+                        frame.to_world(&wo).normalized(),
+                    );
                 }
-
-                // println!("{:?}", start_light_vertex);
-
-                profile.light_rays += 1;
-                let light_ray = sampled.0;
-                let lambda = sampled.1.lambda;
-                assert!(
-                    (sampled.3).0 > 0.0,
-                    "{:?} {:?} {:?} {:?}",
-                    sampled.0,
-                    sampled.1,
-                    sampled.2,
-                    sampled.3
-                );
-                let radiance = sampled.1.energy;
-
-                *beam = Vec::with_capacity(self.max_bounces as usize);
-                beam.push(start_light_vertex);
-                let _ = random_walk(
-                    light_ray,
-                    lambda,
-                    self.max_bounces,
-                    radiance,
-                    TransportMode::Radiance,
-                    &mut sampler,
-                    &self.world,
-                    beam,
-                    4,
-                    &mut profile,
-                );
+                pixel.direct +=
+                    XYZColor::from(SingleWavelength::new(lambda, direct / wavelength_pdf));
                 profile
             })
             .reduce(|| Profile::default(), |a, b| a.combine(b));
-        *profile = profile.combine(beams_profile);
-        self.photon_map = Some(PhotonMap {
-            photons: beams.into_iter().flatten().collect(),
-        });
-        println!(
-            "stored {} photons in the photon map",
-            self.photon_map
-                .as_ref()
-                .map(|e| e.photons.len())
-                .unwrap_or(0)
-        );
-    }
-    fn color(
-        &self,
-        mut sampler: &mut Box<dyn Sampler>,
-        camera_sample: ((f32, f32), CameraId),
-        sample_id: usize,
-        mut profile: &mut Profile,
-    ) -> XYZColor {
-        // naive implementation of SPPM
-        // iterate through all deposited photons and add contributions based on if they are close to the eye vertex in question
+        *profile = profile.combine(camera_profile);
 
-        let camera_id = camera_sample.1;
-        let camera = self.world.get_camera(camera_id as usize);
-
-        let mut sum = XYZColor::ZERO;
-        // let (direction, camera_pdf) = camera_surface.sample(camera_direction_sample, hit.point);
-        // let direction = direction.normalized();
-        let film_sample = Sample2D::new(
-            (camera_sample.0).0.clamp(0.0, 1.0 - EPSILON),
-            (camera_sample.0).1.clamp(0.0, 1.0 - EPSILON),
-        );
-
-        let lambda = self.wavelength_bounds.sample(self.last_lambda);
-        let (camera_ray, _lens_normal, pdf) = camera.sample_we(film_sample, &mut sampler, lambda);
-        let _camera_pdf = pdf;
-
-        let mut path: Vec<SurfaceVertex> = vec![SurfaceVertex::new(
-            VertexType::Camera,
-            camera_ray.time,
-            lambda,
-            Vec3::ZERO,
-            camera_ray.origin,
-            camera_ray.direction,
-            (0.0, 0.0),
-            MaterialId::Camera(0),
-            0,
-            SingleEnergy::ONE,
-            0.0,
-            0.0,
-            1.0,
-        )];
-
-        let _ = random_walk(
-            camera_ray,
-            lambda,
-            self.max_bounces,
-            SingleEnergy::ONE,
-            TransportMode::Importance,
-            sampler,
-            &self.world,
-            &mut path,
-            1,
-            &mut profile,
-        );
-
-        profile.camera_rays += 1;
-        // camera random walk is now stored in path, with length limited to 1 (for now)
-        // let vertex_in_scene = path.last().unwrap();
-        let radius_squared = 0.005 / (1.0 + sample_id as f32);
-        let scaling_factor = PI * radius_squared;
-
-        for vertex_in_scene in path.iter().skip(1) {
-            let mut temp_sum = XYZColor::ZERO;
-            let mut n = 0;
-            // collect photons that are within a certain radius
-            for vert in self.photon_map.as_ref().unwrap().photons.iter() {
-                let point = vert.point;
+        // pass 2, trace photons, all at the iteration wavelength
+        let photon_results: Vec<(Vec<Photon>, Profile)> = (0..self.photons_per_iteration)
+            .into_par_iter()
+            .map(|photon_index| {
+                let mut profile = Profile::default();
+                let mut sampler =
+                    sampler_factory.sampler_for((photon_index, usize::MAX), iteration);
+                let photons = self.trace_photon(lambda, &mut sampler, &mut profile);
+                (photons, profile)
+            })
+            .collect();
+        let mut photons: Vec<Photon> = Vec::new();
+        for (path_photons, path_profile) in photon_results {
+            photons.extend(path_photons);
+            *profile = profile.combine(path_profile);
+        }
 
-                let vec_to_camera: Vec3 = camera_ray.origin - vertex_in_scene.point;
-                if vec_to_camera.norm_squared() < 0.001 {
-                    continue;
+        // pass 3, gather photons around every visible point and update the pixel statistics
+        let max_radius = self
+            .pixels
+            .iter()
+            .filter(|p| p.visible_point.is_some())
+            .fold(0.0f32, |a, p| a.max(p.radius));
+        if max_radius > 0.0 {
+            let grid = PhotonGrid::new(photons, max_radius);
+            let alpha = self.alpha;
+            self.pixels.par_iter_mut().for_each(|pixel| {
+                let vp = match pixel.visible_point {
+                    Some(vp) => vp,
+                    None => return,
+                };
+                let material = world.get_material(vp.material_id);
+                let frame = TangentFrame::from_normal(vp.normal);
+                let wi = frame.to_local(&vp.wi).normalized();
+                let mut phi = SingleEnergy::ZERO;
+                let mut m = 0usize;
+                grid.for_each_within(vp.point, pixel.radius, |photon| {
+                    let wo = frame.to_local(&photon.wi).normalized();
+                    let (f, _pdf) = material.bsdf(lambda, vp.uv, TransportMode::Importance, wi, wo);
+                    phi += f * photon.flux.0;
+                    m += 1;
+                });
+                if m > 0 {
+                    let n_new = pixel.n + alpha * m as f32;
+                    let radius_new = pixel.radius * (n_new / (pixel.n + m as f32)).sqrt();
+                    let flux = XYZColor::from(SingleWavelength::new(
+                        lambda,
+                        vp.beta.0 * phi / wavelength_pdf,
+                    ));
+                    let reduction = (radius_new * radius_new) / (pixel.radius * pixel.radius);
+                    pixel.tau = (pixel.tau + flux) * reduction;
+                    pixel.n = n_new;
+                    pixel.radius = radius_new;
                 }
-                let distance_squared = (point - vertex_in_scene.point).norm_squared();
-                if distance_squared < radius_squared {
-                    let wi_global: Vec3 = vert.local_wi;
-                    let wo_global: Vec3 = vec_to_camera.normalized();
+            });
+        }
+        self.iteration += 1;
+    }
 
-                    let normal = vert.normal;
+    fn trace_photon(
+        &self,
+        lambda: f32,
+        sampler: &mut Box<dyn Sampler>,
+        profile: &mut Profile,
+    ) -> Vec<Photon> {
+        let mut photons = Vec::new();
+        let env_sampling_probability = self.world.get_env_sampling_probability();
+        let (light_pick_sample, sample_env) =
+            sampler
+                .draw_1d()
+                .choose(env_sampling_probability, true, false);
+        let position_sample = sampler.draw_2d();
+        let direction_sample = sampler.draw_2d();
+        // sample_emission samples its own wavelength, so it's only used for the ray and pdfs,
+        // and the emission is evaluated at the iteration wavelength instead.
+        let (mut ray, mut beta) = if !sample_env {
+            let (light, light_pick_pdf) = match self.world.pick_random_light(light_pick_sample) {
+                Some(picked) => picked,
+                None => return photons,
+            };
+            match light {
+                LightRef::Instance(light) => {
+                    let (point, normal, uv, area_pdf) =
+                        self.world.sample_light_surface(light, position_sample);
+                    let material = self.world.get_material(light.get_material_id());
+                    let sampled = match material.sample_emission(
+                        point,
+                        normal,
+                        uv,
+                        self.wavelength_bounds,
+                        direction_sample,
+                        Sample1D { x: 0.5 },
+                    ) {
+                        Some(sampled) => sampled,
+                        None => return photons,
+                    };
                     let frame = TangentFrame::from_normal(normal);
-
-                    let wi = frame.to_local(&wi_global);
-                    let wo = frame.to_local(&wo_global);
-
-                    let material = self.world.get_material(vertex_in_scene.material_id);
-
-                    let hit: HitRecord = (*vertex_in_scene).into();
-                    let (f, pdf) = material.bsdf(hit.lambda, hit.uv, hit.transport_mode, wi, wo);
-
-                    if pdf.0 == 0.0 {
-                        continue;
+                    let local_wo = frame.to_local(&sampled.0.direction).normalized();
+                    let emission = material.emission(lambda, uv, TransportMode::Radiance, local_wo);
+                    let pdf = (1.0 - env_sampling_probability)
+                        * light_pick_pdf.0
+                        * area_pdf.0
+                        * (sampled.2).0;
+                    if pdf == 0.0 {
+                        return photons;
                     }
-
-                    temp_sum += XYZColor::from(SingleWavelength::new(
-                        lambda,
-                        vert.throughput.0 * f / pdf.0
-                            * window_function(
-                                0.1 * lambda * (1.0 + sample_id as f32),
-                                0.1 * vert.lambda * (1.0 + sample_id as f32),
-                            )
-                            / scaling_factor,
-                    ));
-                    n += 1;
+                    (sampled.0, emission * local_wo.z().abs() / pdf)
+                }
+                LightRef::Punctual(_, punctual) => {
+                    let sampled = punctual.sample_emission(
+                        self.world.get_world_radius(),
+                        self.world.get_center(),
+                        position_sample,
+                        direction_sample,
+                        self.wavelength_bounds,
+                        Sample1D { x: 0.5 },
+                    );
+                    let emission = punctual.emission(sampled.0.direction, lambda);
+                    // for directional lights, the pdf is over the disk the rays start from, which turns irradiance into power
+                    let pdf = (1.0 - env_sampling_probability) * light_pick_pdf.0 * (sampled.2).0;
+                    if pdf == 0.0 {
+                        return photons;
+                    }
+                    (sampled.0, emission / pdf)
                 }
             }
-            if n > 0 {
-                sum += temp_sum / n as f32;
+        } else {
+            let world_radius = self.world.get_world_radius();
+            let sampled = self.world.sample_environment_emission(
+                position_sample,
+                direction_sample,
+                self.wavelength_bounds,
+                Sample1D { x: 0.5 },
+            );
+            let emission = self
+                .world
+                .environment
+                .emission(direction_to_uv(-sampled.0.direction), lambda);
+            // rays start on a disk of radius world_radius, perpendicular to the sampled direction.
+            // with portals they don't, but the directional pdf accounts for that
+            let pdf = env_sampling_probability * (sampled.2).0 / (PI * world_radius * world_radius);
+            if pdf == 0.0 {
+                return photons;
             }
+            (sampled.0, emission / pdf)
+        };
+        profile.light_rays += 1;
+        if beta.0 == 0.0 {
+            return photons;
         }
 
-        sum
+        for bounce in 0..self.max_bounces {
+            // draw every sample for this bounce up front, same as random_walk
+            let scatter_sample = sampler.draw_2d();
+            let russian_roulette_sample = sampler.draw_1d();
+            let hit = match self.world.hit(ray, 0.01, ray.tmax) {
+                Some(hit) => hit,
+                None => break,
+            };
+            profile.bounce_rays += 1;
+            if let MaterialId::Camera(_) = hit.material {
+                break;
+            }
+            let material = self.world.get_material(hit.material);
+            if is_diffuse(material) {
+                photons.push(Photon {
+                    point: hit.point,
+                    wi: -ray.direction,
+                    flux: beta,
+                });
+            }
+            let frame = TangentFrame::from_normal(hit.normal);
+            let wi = frame.to_local(&-ray.direction).normalized();
+            let wo = match material.generate(
+                lambda,
+                hit.uv,
+                TransportMode::Radiance,
+                scatter_sample,
+                wi,
+            ) {
+                Some(wo) => wo,
+                None => break,
+            };
+            let (f, pdf) = material.bsdf(lambda, hit.uv, TransportMode::Radiance, wi, wo);
+            if pdf.0 < 0.00000001 || pdf.is_nan() {
+                break;
+            }
+            let new_beta = beta * f * wo.z().abs() / pdf.0;
+            if self.russian_roulette && bounce >= 3 {
+                // continue with probability proportional to how much of the throughput survived the bounce
+                let continue_probability = (new_beta.0 / beta.0).min(1.0);
+                if !(russian_roulette_sample.x < continue_probability) {
+                    break;
+                }
+                beta = new_beta / continue_probability;
+            } else {
+                beta = new_beta;
+            }
+            ray = Ray::new(
+                hit.point + hit.normal * NORMAL_OFFSET * if wo.z() > 0.0 { 1.0 } else { -1.0 },
+                frame.to_world(&wo).normalized(),
+            );
+        }
+        photons
     }
-}
-
-// impl SPPMIntegrator {
-//     fn color_given_lambda(
-//         &self,
-//         sampler: &mut Box<dyn Sampler>,
-//         camera_sample: ((f32, f32), CameraId),
-//         sample_id: usize,
-//         mut profile: &mut Profile,
-//         lambda: f32,
-//     ) -> XYZColor {
-//         // naive implementation of SPPM
-//         // iterate through all deposited photons and add contributions based on if they are close to the eye vertex in question
-
-//         let camera_id = camera_sample.1;
-//         let camera = self.world.get_camera(camera_id as usize);
-
-//         let mut sum = XYZColor::ZERO;
-//         // let (direction, camera_pdf) = camera_surface.sample(camera_direction_sample, hit.point);
-//         // let direction = direction.normalized();
-//         let film_sample = Sample2D::new(
-//             (camera_sample.0).0.clamp(0.0, 1.0 - EPSILON),
-//             (camera_sample.0).1.clamp(0.0, 1.0 - EPSILON),
-//         );
-
-//         let lambda = self.wavelength_bounds.sample(sampler.draw_1d().x);
-//         let aperture_sample = sampler.draw_2d();
-//         let (camera_ray, _lens_normal, pdf) =
-//             camera.sample_we(film_sample, aperture_sample, lambda);
-//         let _camera_pdf = pdf;
-
-//         let mut path: Vec<SurfaceVertex> = vec![SurfaceVertex::new(
-//             VertexType::Camera,
-//             camera_ray.time,
-//             lambda,
-//             Vec3::ZERO,
-//             camera_ray.origin,
-//             camera_ray.direction,
-//             (0.0, 0.0),
-//             MaterialId::Camera(0),
-//             0,
-//             SingleEnergy::ONE,
-//             0.0,
-//             0.0,
-//             1.0,
-//         )];
-
-//         let _ = random_walk(
-//             camera_ray,
-//             lambda,
-//             self.max_bounces,
-//             SingleEnergy::ONE,
-//             TransportMode::Importance,
-//             sampler,
-//             &self.world,
-//             &mut path,
-//             1,
-//             &mut profile,
-//         );
-
-//         profile.camera_rays += 1;
-//         // camera random walk is now stored in path, with length limited to 1 (for now)
-//         // let vertex_in_scene = path.last().unwrap();
-//         let radius_squared = 0.005 / (1.0 + sample_id as f32);
-//         let scaling_factor = PI * radius_squared;
-
-//         for vertex_in_scene in path.iter().skip(1) {
-//             let mut temp_sum = XYZColor::ZERO;
-//             let mut n = 0;
-//             // collect photons that are within a certain radius
-//             for vert in self.photon_map.as_ref().unwrap().photons.iter() {
-//                 let point = vert.point;
 
-//                 let vec_to_camera: Vec3 = camera_ray.origin - vertex_in_scene.point;
-//                 if vec_to_camera.norm_squared() < 0.001 {
-//                     continue;
-//                 }
-//                 let distance_squared = (point - vertex_in_scene.point).norm_squared();
-//                 if distance_squared < radius_squared {
-//                     let wi_global: Vec3 = vert.local_wi;
-//                     let wo_global: Vec3 = vec_to_camera.normalized();
-
-//                     let normal = vert.normal;
-//                     let frame = TangentFrame::from_normal(normal);
-
-//                     let wi = frame.to_local(&wi_global);
-//                     let wo = frame.to_local(&wo_global);
-
-//                     let material = self.world.get_material(vertex_in_scene.material_id);
-
-//                     let hit: HitRecord = (*vertex_in_scene).into();
-//                     let (f, pdf) = material.bsdf(hit.lambda, hit.uv, hit.transport_mode, wi, wo);
-
-//                     if pdf.0 == 0.0 {
-//                         continue;
-//                     }
-
-//                     temp_sum += XYZColor::from(SingleWavelength::new(
-//                         lambda,
-//                         vert.throughput.0 * f / pdf.0
-//                             * window_function(
-//                                 0.1 * lambda * (1.0 + sample_id as f32),
-//                                 0.1 * vert.lambda * (1.0 + sample_id as f32),
-//                             )
-//                             / scaling_factor,
-//                     ));
-//                     n += 1;
-//                 }
-//             }
-//             if n > 0 {
-//                 sum += temp_sum / n as f32;
-//             }
-//         }
-
-//         sum
-//     }
-// }
+    // the current estimate for a pixel. photon flux is normalized by the total number of photon paths traced so far.
+    pub fn estimate(&self, pixel_index: usize) -> XYZColor {
+        let pixel = &self.pixels[pixel_index];
+        let iterations = self.iteration.max(1) as f32;
+        let photon_paths = iterations * self.photons_per_iteration as f32;
+        let mut color = pixel.direct / iterations;
+        if pixel.n > 0.0 {
+            color += pixel.tau / (photon_paths * PI * pixel.radius * pixel.radius);
+        }
+        color
+    }
+}
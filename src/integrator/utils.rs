@@ -1,7 +1,7 @@
 use crate::hittable::HitRecord;
-use crate::materials::{Material, MaterialId};
+use crate::materials::{Material, MaterialEnum, MaterialId};
 use crate::math::*;
-use crate::mediums::Medium;
+use crate::mediums::{Medium, MediumStack};
 use crate::profile::Profile;
 use crate::world::World;
 use crate::world::INTERSECTION_TIME_OFFSET;
@@ -13,6 +13,8 @@ use std::{ops::AddAssign, sync::Arc};
 pub enum LightSourceType {
     Instance,
     Environment,
+    // point, spot and directional lights, which can't be hit by rays
+    Punctual,
 }
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum VertexType {
@@ -20,6 +22,8 @@ pub enum VertexType {
     Light,
     Eye,
     Camera,
+    // a point inside a medium, which has no surface. keeps the transport mode of the path it's on
+    Medium(TransportMode),
 }
 
 impl From<TransportMode> for VertexType {
@@ -41,6 +45,9 @@ pub struct SurfaceVertex {
     pub normal: Vec3,
     pub uv: (f32, f32),
     pub material_id: MaterialId,
+    // the medium the path was travelling through when it arrived at this vertex, or the medium that scattered it for medium vertices.
+    // 0 is vacuum, otherwise it's an index into world.mediums offset by 1.
+    pub medium_id: usize,
     pub instance_id: usize,
     pub throughput: SingleEnergy,
     pub pdf_forward: f32,
@@ -73,6 +80,7 @@ impl SurfaceVertex {
             normal,
             uv,
             material_id,
+            medium_id: 0,
             instance_id,
             throughput,
             pdf_forward,
@@ -81,6 +89,22 @@ impl SurfaceVertex {
         }
     }
 
+    pub fn is_medium(&self) -> bool {
+        match self.vertex_type {
+            VertexType::Medium(_) => true,
+            _ => false,
+        }
+    }
+
+    // cosine between the normal and direction, which is always 1 for medium vertices
+    pub fn cos(&self, direction: Vec3) -> f32 {
+        if self.is_medium() {
+            1.0
+        } else {
+            (self.normal * direction).abs()
+        }
+    }
+
     pub fn default() -> Self {
         SurfaceVertex::new(
             VertexType::Eye,
@@ -105,6 +129,7 @@ impl From<SurfaceVertex> for HitRecord {
         let transport_mode = match data.vertex_type {
             VertexType::Light | VertexType::LightSource(_) => TransportMode::Radiance,
             VertexType::Camera | VertexType::Eye => TransportMode::Importance,
+            VertexType::Medium(transport_mode) => transport_mode,
         };
         HitRecord::new(
             data.time,
@@ -159,7 +184,12 @@ pub fn random_walk(
     // let mut last_bsdf_pdf = PDF::from(0.0);
     let mut additional_contribution = SingleEnergy::ZERO;
     // additional contributions from emission from hit objects that support bsdf sampling? review veach paper.
+    let mut bounces_taken = 0;
     for bounce in 0..bounce_limit {
+        // draw every sample for this bounce up front, so that each bounce consumes the same dimensions no matter what gets hit
+        let scatter_sample = sampler.draw_2d();
+        let russian_roulette_sample = sampler.draw_1d();
+        bounces_taken += 1;
         if let Some(mut hit) = world.hit(ray, 0.01, ray.tmax) {
             hit.lambda = lambda;
             hit.transport_mode = trace_type;
@@ -197,13 +227,8 @@ pub fn random_walk(
             let material = world.get_material(hit.material);
 
             // consider accumulating emission in some other form for trace_type == TransportMode::Importance situations, as mentioned in veach.
-            let maybe_wo: Option<Vec3> = material.generate(
-                hit.lambda,
-                hit.uv,
-                hit.transport_mode,
-                sampler.draw_2d(),
-                wi,
-            );
+            let maybe_wo: Option<Vec3> =
+                material.generate(hit.lambda, hit.uv, hit.transport_mode, scatter_sample, wi);
 
             // what to do in this situation, where there is a wo and there's also emission?
             let emission = material.emission(hit.lambda, hit.uv, hit.transport_mode, wi);
@@ -229,7 +254,6 @@ pub fn random_walk(
                 } else {
                     1.0
                 };
-                let russian_roulette_sample = sampler.draw_1d();
                 if russian_roulette_sample.x > rr_continue_prob {
                     break;
                 }
@@ -322,6 +346,11 @@ pub fn random_walk(
             break;
         }
     }
+    // pad out the bounces that weren't taken, so that anything drawn after the walk starts on the same dimension for every path
+    for _ in bounces_taken..bounce_limit {
+        let _ = sampler.draw_2d();
+        let _ = sampler.draw_1d();
+    }
     profile.bounce_rays += vertices.len();
 
     if additional_contribution.0 > 0.0 {
@@ -330,6 +359,380 @@ pub fn random_walk(
         None
     }
 }
+// the outer medium, inner medium and nested dielectric priority of a surface that bounds a volume,
+// or None if crossing it doesn't move a path into a different medium.
+pub fn volume_interface(material: &MaterialEnum, uv: (f32, f32)) -> Option<(usize, usize, u32)> {
+    let (outer, inner) = (material.outer_medium_id(uv), material.inner_medium_id(uv));
+    if outer == inner {
+        return None;
+    }
+    material
+        .interface_priority(uv)
+        .map(|priority| (outer, inner, priority))
+}
+
+// the medium on the side of vertex that direction points into
+pub fn medium_towards(world: &Arc<World>, vertex: &SurfaceVertex, direction: Vec3) -> usize {
+    if vertex.is_medium() || vertex.local_wi == Vec3::ZERO {
+        // medium vertices and the first vertex of a subpath don't have sides
+        return vertex.medium_id;
+    }
+    if let MaterialId::Material(_) = vertex.material_id {
+        let same_side = (vertex.normal * direction) * (vertex.normal * vertex.local_wi) > 0.0;
+        if !same_side {
+            let material = world.get_material(vertex.material_id);
+            let (outer, inner) = (
+                material.outer_medium_id(vertex.uv),
+                material.inner_medium_id(vertex.uv),
+            );
+            if outer != inner {
+                return if vertex.normal * direction > 0.0 {
+                    outer
+                } else {
+                    inner
+                };
+            }
+        }
+    }
+    vertex.medium_id
+}
+
+// the fraction of light at lambda that makes it from p0 to p1, starting out in medium_id.
+// passthrough surfaces are null interfaces that filter the light and move it into another medium, anything else blocks it.
+// this is veach_v for paths that go through mediums.
+pub fn eval_transmittance(
+    world: &Arc<World>,
+    lambda: f32,
+    p0: Point3,
+    medium_id: usize,
+    p1: Point3,
+    s: Sample1D,
+) -> f32 {
+    let mut point = p0;
+    let mut medium_id = medium_id;
+    let mut transmittance = 1.0;
+    loop {
+        let diff = p1 - point;
+        let norm = diff.norm();
+        let direction = diff / norm;
+        let tmax = norm * 0.99;
+        let ray = Ray::new_with_time_and_tmax(point, direction, 0.0, tmax);
+        let maybe_hit = world.hit(ray, INTERSECTION_TIME_OFFSET, tmax);
+        let segment_end = maybe_hit.as_ref().map(|hit| hit.point).unwrap_or(p1);
+        if medium_id != 0 {
+            transmittance *= world.mediums[medium_id - 1].tr(lambda, point, segment_end, s);
+        }
+        let hit = match maybe_hit {
+            Some(hit) => hit,
+            None => return transmittance,
+        };
+        if let MaterialId::Material(_) = hit.material {
+            if let MaterialEnum::PassthroughFilter(filter) = world.get_material(hit.material) {
+                transmittance *= filter.color.evaluate(lambda);
+                if transmittance == 0.0 {
+                    return 0.0;
+                }
+                if filter.outer_medium_id != filter.inner_medium_id {
+                    medium_id = if direction * hit.normal < 0.0 {
+                        filter.inner_medium_id
+                    } else {
+                        filter.outer_medium_id
+                    };
+                }
+                point = hit.point + direction * NORMAL_OFFSET;
+                continue;
+            }
+        }
+        return 0.0;
+    }
+}
+
+// evaluates the bsdf, or the phase function for medium vertices, along with its pdf.
+// wi and wo are world space directions pointing away from the vertex.
+pub fn eval_vertex_scattering(
+    world: &Arc<World>,
+    vertex: &SurfaceVertex,
+    wi: Vec3,
+    wo: Vec3,
+) -> (SingleEnergy, PDF) {
+    if vertex.is_medium() {
+        let medium = &world.mediums[vertex.medium_id - 1];
+        let phase = medium.p(vertex.lambda, vertex.point.as_tuple(), wi, wo);
+        (SingleEnergy(phase), phase.into())
+    } else {
+        let material = world.get_material(vertex.material_id);
+        let frame = TangentFrame::from_normal(vertex.normal);
+        let hit: HitRecord = (*vertex).into();
+        material.bsdf(
+            hit.lambda,
+            hit.uv,
+            hit.transport_mode,
+            frame.to_local(&wi).normalized(),
+            frame.to_local(&wo).normalized(),
+        )
+    }
+}
+
+// random_walk, but through participating media. medium vertices are recorded in between the surface vertices,
+// and passthrough surfaces are null interfaces that only move the path from one medium to another, so they never become vertices.
+// overlapping volumes are resolved with a MediumStack, so interfaces inside of higher priority volumes are skipped.
+// the walk starts in the world medium.
+// for camera paths, returns the emission picked up from emissive mediums along the way, if there was any.
+pub fn random_walk_medium(
+    ray: Ray,
+    lambda: f32,
+    bounce_limit: u16,
+    start_throughput: SingleEnergy,
+    trace_type: TransportMode,
+    sampler: &mut Box<dyn Sampler>,
+    world: &Arc<World>,
+    vertices: &mut Vec<SurfaceVertex>,
+    russian_roulette_start_index: u16,
+    profile: &mut Profile,
+) -> Option<SingleEnergy> {
+    random_walk_medium_from(
+        ray,
+        lambda,
+        bounce_limit,
+        start_throughput,
+        trace_type,
+        sampler,
+        world,
+        vertices,
+        russian_roulette_start_index,
+        profile,
+        world.world_medium,
+    )
+}
+
+// random_walk_medium, but starting inside the medium with id initial_medium_id, or in vacuum if it's 0
+#[allow(unused_mut)]
+pub fn random_walk_medium_from(
+    mut ray: Ray,
+    lambda: f32,
+    bounce_limit: u16,
+    start_throughput: SingleEnergy,
+    trace_type: TransportMode,
+    sampler: &mut Box<dyn Sampler>,
+    world: &Arc<World>,
+    vertices: &mut Vec<SurfaceVertex>,
+    russian_roulette_start_index: u16,
+    profile: &mut Profile,
+    initial_medium_id: usize,
+) -> Option<SingleEnergy> {
+    let mut beta = start_throughput;
+    let mut emitted = SingleEnergy::ZERO;
+    let mut medium_stack = MediumStack::starting_in(world.world_medium, initial_medium_id);
+    let mut bounces_taken = 0;
+    for bounce in 0..bounce_limit {
+        // draw every sample for this bounce up front, see random_walk
+        let medium_sample = sampler.draw_1d();
+        let phase_sample = sampler.draw_2d();
+        let scatter_sample = sampler.draw_2d();
+        let russian_roulette_sample = sampler.draw_1d();
+        bounces_taken += 1;
+        let medium_id = medium_stack.current();
+        let maybe_hit = world.hit(ray, 0.01, ray.tmax);
+        if medium_id != 0 {
+            let medium = &world.mediums[medium_id - 1];
+            let tmax = maybe_hit.as_ref().map(|hit| hit.time).unwrap_or(ray.tmax);
+            let (point, weight, scattered) = medium.sample(
+                lambda,
+                Ray::new_with_time_and_tmax(ray.origin, ray.direction, ray.time, tmax),
+                medium_sample,
+            );
+            if scattered && trace_type == TransportMode::Importance {
+                // collision estimator for the emission, see Novak et al. 2018 "Monte Carlo Methods for Volumetric Light Transport Simulation"
+                emitted += beta * medium.emission(lambda, -ray.direction, point.as_tuple());
+            }
+            // weight is tr * sigma_s / pdf when a scattering point was sampled, and tr / pdf when the surface was reached
+            beta = beta * weight;
+            if scattered && weight == 0.0 {
+                // purely absorbing, nothing else can contribute
+                break;
+            }
+            if scattered {
+                let wi = -ray.direction;
+                let (wo, phase_pdf) = medium.sample_p(lambda, point.as_tuple(), wi, phase_sample);
+                if phase_pdf < 0.00000001 || !phase_pdf.is_finite() {
+                    break;
+                }
+                let mut vertex = SurfaceVertex::new(
+                    VertexType::Medium(trace_type),
+                    ray.time,
+                    lambda,
+                    wi,
+                    point,
+                    Vec3::ZERO,
+                    (0.0, 0.0),
+                    MaterialId::Material(0),
+                    0,
+                    beta,
+                    phase_pdf,
+                    medium.p(lambda, point.as_tuple(), wo, wi),
+                    veach_g(point, 1.0, ray.origin, 1.0),
+                );
+                vertex.medium_id = medium_id;
+                vertices.push(vertex);
+                // the phase function is sampled exactly, so beta is unchanged
+                ray = Ray::new(point, wo);
+                continue;
+            }
+        }
+        if let Some(mut hit) = maybe_hit {
+            hit.lambda = lambda;
+            hit.transport_mode = trace_type;
+            let mut vertex = SurfaceVertex::new(
+                trace_type.into(),
+                hit.time,
+                hit.lambda,
+                -ray.direction,
+                hit.point,
+                hit.normal,
+                hit.uv,
+                hit.material,
+                hit.instance_id,
+                beta,
+                1.0,
+                1.0,
+                1.0,
+            );
+            vertex.medium_id = medium_id;
+
+            let frame = TangentFrame::from_normal(hit.normal);
+            let wi = frame.to_local(&-ray.direction).normalized();
+
+            if let MaterialId::Camera(_camera_id) = hit.material {
+                if trace_type == TransportMode::Radiance {
+                    // if hit camera directly while tracing a light path
+                    vertex.vertex_type = VertexType::Camera;
+                    vertices.push(vertex);
+                }
+                break;
+            }
+
+            let material = world.get_material(hit.material);
+
+            let interface = volume_interface(material, hit.uv);
+            let is_false_interface = interface
+                .map(|(_, _, priority)| medium_stack.is_false_interface(priority))
+                .unwrap_or(false);
+            let is_passthrough = if let MaterialEnum::PassthroughFilter(_) = material {
+                true
+            } else {
+                false
+            };
+            if is_false_interface || is_passthrough {
+                // null interface. crossing it still uses up a bounce, same as random_walk_medium_hero.
+                // false interfaces are hidden by a higher priority volume, so they don't filter anything either.
+                if let Some((outer, inner, priority)) = interface {
+                    medium_stack.cross(outer, inner, priority, wi.z() > 0.0);
+                }
+                if let (MaterialEnum::PassthroughFilter(filter), false) =
+                    (material, is_false_interface)
+                {
+                    beta = beta * filter.color.evaluate(lambda);
+                }
+                ray = Ray::new(hit.point + ray.direction * NORMAL_OFFSET, ray.direction);
+                continue;
+            }
+
+            let maybe_wo: Option<Vec3> =
+                material.generate(hit.lambda, hit.uv, hit.transport_mode, scatter_sample, wi);
+
+            let emission = material.emission(hit.lambda, hit.uv, hit.transport_mode, wi);
+
+            if let Some(wo) = maybe_wo {
+                let (f, pdf) = material.bsdf(hit.lambda, hit.uv, hit.transport_mode, wi, wo);
+                let cos_i = wo.z().abs();
+                let cos_o = wi.z().abs();
+                vertex.veach_g = veach_g(hit.point, cos_i, ray.origin, cos_o);
+
+                debug_assert!(pdf.0 >= 0.0, "pdf was less than 0 {:?}", pdf);
+                if pdf.0 < 0.00000001 || pdf.is_nan() {
+                    break;
+                }
+                let rr_continue_prob = if bounce >= russian_roulette_start_index {
+                    (f.0 / pdf.0).min(1.0)
+                } else {
+                    1.0
+                };
+                if russian_roulette_sample.x > rr_continue_prob {
+                    break;
+                }
+                beta *= f * cos_i.abs() / (rr_continue_prob * pdf.0);
+                vertex.pdf_forward = rr_continue_prob * pdf.0 / cos_i;
+                vertex.pdf_backward = rr_continue_prob
+                    * material
+                        .bsdf(hit.lambda, hit.uv, hit.transport_mode, wo, wi)
+                        .1
+                         .0
+                    / cos_o;
+
+                vertices.push(vertex);
+
+                debug_assert!(!beta.0.is_nan(), "{:?} {:?} {} {:?}", beta.0, f, cos_i, pdf);
+
+                if let (Some((outer, inner, priority)), true) = (interface, wi.z() * wo.z() < 0.0) {
+                    // transmitted, so the path moved into a different medium
+                    medium_stack.cross(outer, inner, priority, wo.z() < 0.0);
+                }
+
+                ray = Ray::new(
+                    hit.point + hit.normal * NORMAL_OFFSET * if wo.z() > 0.0 { 1.0 } else { -1.0 },
+                    frame.to_world(&wo).normalized(),
+                );
+            } else {
+                // hit a surface and didn't bounce.
+                if emission.0 > 0.0 {
+                    vertex.vertex_type = VertexType::LightSource(LightSourceType::Instance);
+                    vertex.pdf_forward = 0.0;
+                    vertex.pdf_backward = 1.0;
+                    vertex.veach_g = veach_g(hit.point, wi.z().abs(), ray.origin, 1.0);
+                    vertices.push(vertex);
+                }
+                break;
+            }
+        } else {
+            // add a vertex when a camera ray hits the environment
+            if trace_type == TransportMode::Importance {
+                let world_radius = world.get_world_radius();
+                let at_env = ray.direction * world_radius;
+                let mut vertex = SurfaceVertex::new(
+                    VertexType::LightSource(LightSourceType::Environment),
+                    ray.time,
+                    lambda,
+                    ray.direction,
+                    Point3::from(at_env),
+                    ray.direction,
+                    (0.0, 0.0),
+                    MaterialId::Light(0),
+                    0,
+                    beta,
+                    0.0,
+                    1.0 / (4.0 * PI),
+                    1.0,
+                );
+                vertex.medium_id = medium_id;
+                vertices.push(vertex);
+            }
+            break;
+        }
+    }
+    for _ in bounces_taken..bounce_limit {
+        let _ = sampler.draw_1d();
+        let _ = sampler.draw_2d();
+        let _ = sampler.draw_2d();
+        let _ = sampler.draw_1d();
+    }
+    profile.bounce_rays += vertices.len();
+    if emitted.0 > 0.0 {
+        Some(emitted)
+    } else {
+        None
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct HeroEnergy(pub f32x4);
 
@@ -418,8 +821,27 @@ impl HeroSurfaceVertex {
         match self.vertex_type {
             VertexType::Light | VertexType::LightSource(_) => TransportMode::Radiance,
             VertexType::Camera | VertexType::Eye => TransportMode::Importance,
+            VertexType::Medium(transport_mode) => transport_mode,
         }
     }
+    // the single wavelength vertex for one of the lanes, so that single wavelength connection and mis code can be reused
+    pub fn lane(&self, lane: usize) -> SurfaceVertex {
+        SurfaceVertex::new(
+            self.vertex_type,
+            self.time,
+            self.lambda.extract(lane),
+            self.local_wi,
+            self.point,
+            self.normal,
+            self.uv,
+            self.material_id,
+            self.instance_id,
+            SingleEnergy(self.throughput.0.extract(lane)),
+            self.pdf_forward.extract(lane),
+            self.pdf_backward.extract(lane),
+            self.veach_g,
+        )
+    }
     pub fn into_hit_w_lane(&self, lane: usize) -> HitRecord {
         HitRecord::new(
             self.time,
@@ -451,7 +873,12 @@ pub fn random_walk_hero(
     // let mut last_bsdf_pdf = PDF::from(0.0);
     let mut additional_contribution = HeroEnergy::ZERO;
     // additional contributions from emission from hit objects that support bsdf sampling? review veach paper.
+    let mut bounces_taken = 0;
     for bounce in 0..bounce_limit {
+        // draw every sample for this bounce up front, so that each bounce consumes the same dimensions no matter what gets hit
+        let scatter_sample = sampler.draw_2d();
+        let russian_roulette_sample = sampler.draw_1d();
+        bounces_taken += 1;
         if let Some(mut hit) = world.hit(ray, 0.01, ray.tmax) {
             hit.lambda = lambda.extract(0);
             hit.transport_mode = trace_type;
@@ -488,14 +915,21 @@ pub fn random_walk_hero(
 
             let material = world.get_material(hit.material);
 
+            if let MaterialEnum::PassthroughFilter(filter) = material {
+                // null interface, like in random_walk_medium, so that bdpt connections through it are consistent with the walk.
+                for i in 0..4 {
+                    beta = beta.replace(
+                        i,
+                        beta.extract(i) * filter.color.evaluate(lambda.extract(i)),
+                    );
+                }
+                ray = Ray::new(hit.point + ray.direction * NORMAL_OFFSET, ray.direction);
+                continue;
+            }
+
             // consider accumulating emission in some other form for trace_type == TransportMode::Importance situations, as mentioned in veach.
-            let maybe_wo: Option<Vec3> = material.generate(
-                hit.lambda,
-                hit.uv,
-                hit.transport_mode,
-                sampler.draw_2d(),
-                wi,
-            );
+            let maybe_wo: Option<Vec3> =
+                material.generate(hit.lambda, hit.uv, hit.transport_mode, scatter_sample, wi);
 
             // what to do in this situation, where there is a wo and there's also emission?
             let multi_emission = HeroEnergy(f32x4::new(
@@ -517,7 +951,7 @@ pub fn random_walk_hero(
 
             if let Some(wo) = maybe_wo {
                 // NOTE! cos_i and cos_o seem to have somewhat reversed names.
-                let (multi_f, multi_pdf) = {
+                let (mut multi_f, mut multi_pdf) = {
                     let (f0, pdf0) =
                         material.bsdf(lambda.extract(0), hit.uv, hit.transport_mode, wi, wo);
                     let (f1, pdf1) =
@@ -545,6 +979,13 @@ pub fn random_walk_hero(
                         f32x4::new(pdf0.0, pdf1.0, pdf2.0, pdf3.0),
                     )
                 };
+                if wo.z() * wi.z() < 0.0 && material.is_dispersive(lambda) {
+                    // the refracted direction depends on the wavelength, so only the hero wavelength can follow it.
+                    // the other wavelengths are terminated, and spectral mis weights give the hero their share.
+                    let hero_only = f32x4::new(1.0, 0.0, 0.0, 0.0);
+                    multi_f *= hero_only;
+                    multi_pdf *= hero_only;
+                }
                 let cos_i = wo.z().abs();
                 let cos_o = wi.z().abs();
                 vertex.veach_g = veach_g(hit.point, cos_i, ray.origin, cos_o);
@@ -563,7 +1004,6 @@ pub fn random_walk_hero(
                 } else {
                     1.0
                 };
-                let russian_roulette_sample = sampler.draw_1d();
                 if russian_roulette_sample.x > rr_continue_prob {
                     break;
                 }
@@ -658,6 +1098,11 @@ pub fn random_walk_hero(
             break;
         }
     }
+    // pad out the bounces that weren't taken, so that anything drawn after the walk starts on the same dimension for every path
+    for _ in bounces_taken..bounce_limit {
+        let _ = sampler.draw_2d();
+        let _ = sampler.draw_1d();
+    }
     profile.bounce_rays += vertices.len();
 
     if additional_contribution.0.gt(f32x4::splat(0.0)).any() {
@@ -734,6 +1179,7 @@ impl HeroMediumVertex {
         match self.vertex_type {
             VertexType::Light | VertexType::LightSource(_) => TransportMode::Radiance,
             VertexType::Camera | VertexType::Eye => TransportMode::Importance,
+            VertexType::Medium(transport_mode) => transport_mode,
         }
     }
 }
@@ -765,6 +1211,29 @@ impl HeroVertex {
     }
 }
 
+// the vertex for a camera path that escaped to the environment
+fn hero_env_vertex(world: &Arc<World>, ray: Ray, lambda: f32x4, beta: f32x4) -> HeroSurfaceVertex {
+    let world_radius = world.get_world_radius();
+    let at_env = ray.direction * world_radius;
+    let vertex = HeroSurfaceVertex::new(
+        VertexType::LightSource(LightSourceType::Environment),
+        ray.time,
+        lambda,
+        ray.direction,
+        Point3::from(at_env),
+        ray.direction,
+        (0.0, 0.0),
+        MaterialId::Light(0),
+        0,
+        HeroEnergy(beta),
+        f32x4::splat(0.0),
+        f32x4::splat(1.0 / (4.0 * PI)),
+        1.0,
+    );
+    debug_assert!(vertex.point.0.is_finite().all());
+    vertex
+}
+
 #[allow(unused_mut)]
 pub fn random_walk_medium_hero(
     mut ray: Ray,
@@ -777,14 +1246,53 @@ pub fn random_walk_medium_hero(
     vertices: &mut Vec<HeroVertex>,
     russian_roulette_start_index: u16,
     profile: &mut Profile,
+    initial_medium_id: usize,
 ) -> Option<HeroEnergy> {
     let mut beta = start_throughput;
     // let mut last_bsdf_pdf = PDF::from(0.0);
     let mut additional_contribution = HeroEnergy::ZERO;
     // additional contributions from emission from hit objects that support bsdf sampling? review veach paper.
-    let mut tracked_mediums: Vec<usize> = Vec::new();
+    let mut medium_stack = MediumStack::starting_in(world.world_medium, initial_medium_id);
+    // whether a dispersive refraction already left only the hero wavelength
+    let mut lanes_terminated = false;
+    let mut bounces_taken = 0;
     for bounce in 0..bounce_limit {
-        if let Some(mut hit) = world.hit(ray, 0.001, ray.tmax) {
+        // draw every sample for this bounce up front, see random_walk
+        let medium_sample = sampler.draw_1d();
+        let phase_sample = sampler.draw_2d();
+        let scatter_sample = sampler.draw_2d();
+        let russian_roulette_sample = sampler.draw_1d();
+        bounces_taken += 1;
+        // only the highest priority medium the path is in affects it
+        let current_medium = medium_stack.current();
+        let active_mediums = [current_medium];
+        let tracked_mediums: &[usize] = if current_medium == 0 {
+            &[]
+        } else {
+            &active_mediums
+        };
+        let maybe_hit = world.hit(ray, 0.001, ray.tmax);
+        // a path that misses everything while inside a medium can still scatter before it escapes,
+        // so treat the miss as a hit just past the edge of the world until the medium has been sampled.
+        let escaped = maybe_hit.is_none();
+        let maybe_hit = if escaped && current_medium != 0 {
+            Some({
+                let far = 2.0 * world.get_world_radius();
+                HitRecord::new(
+                    far,
+                    ray.point_at_parameter(far),
+                    (0.0, 0.0),
+                    lambda.extract(0),
+                    -ray.direction,
+                    MaterialId::Light(0),
+                    0,
+                    Some(trace_type),
+                )
+            })
+        } else {
+            maybe_hit
+        };
+        if let Some(mut hit) = maybe_hit {
             hit.lambda = lambda.extract(0);
             hit.transport_mode = trace_type;
             let mut surface_vertex = HeroSurfaceVertex::new(
@@ -822,17 +1330,23 @@ pub fn random_walk_medium_hero(
 
             let mut hero_weight = 1.0;
             let mut hero_tr = 1.0;
-            for medium_id in tracked_mediums.iter() {
+            for (i, medium_id) in tracked_mediums.iter().enumerate() {
                 let medium = &world.mediums[*medium_id - 1];
-                let (p, tr, scatter) =
-                    medium.sample(lambda.extract(0), ray, Sample1D::new_random_sample());
+                // overlapping mediums each get a rotated copy of the same distance sample
+                let (p, tr, scatter) = medium.sample(
+                    lambda.extract(0),
+                    ray,
+                    Sample1D {
+                        x: (medium_sample.x + i as f32 * 0.618034).fract(),
+                    },
+                );
                 if scatter {
                     let t = (p - ray.origin).norm();
                     if t < medium_vertex.time {
                         medium_vertex.time = t;
                         medium_vertex.point = p;
                         hero_weight = tr;
-                        hero_tr = medium.tr(lambda.extract(0), ray.origin, p);
+                        hero_tr = medium.tr(lambda.extract(0), ray.origin, p, medium_sample);
                         medium_vertex.medium_id = *medium_id;
                         // println!(
                         //     "overrode surface vertex with medium vertex, p = {:?}",
@@ -842,6 +1356,23 @@ pub fn random_walk_medium_hero(
                     }
                 }
             }
+            if let HeroVertex::Medium(medium_vertex) = vertex {
+                if trace_type == TransportMode::Importance {
+                    // collision estimator for emissive mediums, see random_walk_medium
+                    let medium = &world.mediums[medium_vertex.medium_id - 1];
+                    for i in 0..4 {
+                        let emission = medium.emission(
+                            lambda.extract(i),
+                            -ray.direction,
+                            medium_vertex.point.as_tuple(),
+                        );
+                        additional_contribution.0 = additional_contribution.0.replace(
+                            i,
+                            additional_contribution.0.extract(i) + beta.extract(i) * emission.0,
+                        );
+                    }
+                }
+            }
             // multiply in hero weight, since it includes some of the hero pdf information and that would be lost if unaccounted for.
             // hero weight also includes tr.
             beta *= hero_weight;
@@ -853,8 +1384,12 @@ pub fn random_walk_medium_hero(
                         continue;
                     }
                     let medium = &world.mediums[*medium_id - 1];
-                    combined_throughput *=
-                        medium.tr(lambda.extract(i), ray.origin, medium_vertex.point);
+                    combined_throughput *= medium.tr(
+                        lambda.extract(i),
+                        ray.origin,
+                        medium_vertex.point,
+                        medium_sample,
+                    );
                 }
 
                 // divide out hero_tr for all wavelengths, since it was included in overall beta mult.
@@ -864,6 +1399,14 @@ pub fn random_walk_medium_hero(
             beta = beta.replace(0, beta.extract(0) * hero_tr);
 
             match vertex {
+                HeroVertex::Surface(_) if escaped => {
+                    if trace_type == TransportMode::Importance {
+                        vertices.push(HeroVertex::Surface(hero_env_vertex(
+                            world, ray, lambda, beta,
+                        )));
+                    }
+                    break;
+                }
                 HeroVertex::Surface(mut vertex) => {
                     let frame = TangentFrame::from_normal(hit.normal);
                     let wi = frame.to_local(&-ray.direction).normalized();
@@ -885,12 +1428,23 @@ pub fn random_walk_medium_hero(
                     vertex.outer_medium_id = material.outer_medium_id(hit.uv);
                     vertex.inner_medium_id = material.inner_medium_id(hit.uv);
 
+                    let interface = volume_interface(material, hit.uv);
+                    if let Some((outer, inner, priority)) = interface {
+                        if medium_stack.is_false_interface(priority) {
+                            // hidden by a higher priority volume, so pass straight through it
+                            medium_stack.cross(outer, inner, priority, wi.z() > 0.0);
+                            ray =
+                                Ray::new(hit.point + ray.direction * NORMAL_OFFSET, ray.direction);
+                            continue;
+                        }
+                    }
+
                     // consider accumulating emission in some other form for trace_type == TransportMode::Importance situations, as mentioned in veach.
                     let maybe_wo: Option<Vec3> = material.generate(
                         hit.lambda,
                         hit.uv,
                         hit.transport_mode,
-                        sampler.draw_2d(),
+                        scatter_sample,
                         wi,
                     );
 
@@ -914,7 +1468,7 @@ pub fn random_walk_medium_hero(
 
                     if let Some(wo) = maybe_wo {
                         // NOTE! cos_i and cos_o seem to have somewhat reversed names.
-                        let (multi_f, multi_pdf) = {
+                        let (mut multi_f, mut multi_pdf) = {
                             let (f0, pdf0) = material.bsdf(
                                 lambda.extract(0),
                                 hit.uv,
@@ -982,6 +1536,19 @@ pub fn random_walk_medium_hero(
                                 f32x4::new(pdf0.0, pdf1.0, pdf2.0, pdf3.0),
                             )
                         };
+                        let mut hero_scale = 1.0;
+                        if wo.z() * wi.z() < 0.0 && material.is_dispersive(lambda) {
+                            // only the hero wavelength can follow a dispersive refraction, same as in random_walk_hero
+                            let hero_only = f32x4::new(1.0, 0.0, 0.0, 0.0);
+                            multi_f *= hero_only;
+                            multi_pdf *= hero_only;
+                            if !lanes_terminated {
+                                // there's no spectral mis to give the hero the other lanes' share when they're averaged, so do it here.
+                                // the hero wavelength is uniformly distributed, so it can stand in for all four on its own
+                                lanes_terminated = true;
+                                hero_scale = 4.0;
+                            }
+                        }
                         let cos_i = wo.z().abs();
                         let cos_o = wi.z().abs();
                         vertex.veach_g = veach_g(hit.point, cos_i, ray.origin, cos_o);
@@ -1000,11 +1567,10 @@ pub fn random_walk_medium_hero(
                         } else {
                             1.0
                         };
-                        let russian_roulette_sample = sampler.draw_1d();
                         if russian_roulette_sample.x > rr_continue_prob {
                             break;
                         }
-                        beta *= multi_f * cos_i.abs() / (rr_continue_prob * hero_pdf);
+                        beta *= multi_f * cos_i.abs() * hero_scale / (rr_continue_prob * hero_pdf);
                         debug_assert!(
                             beta.is_finite().all(),
                             "{:?}, {:?}, {:?}, {:?}, ",
@@ -1057,65 +1623,11 @@ pub fn random_walk_medium_hero(
                             multi_pdf
                         );
 
-                        let outer = vertex.outer_medium_id;
-                        let inner = vertex.inner_medium_id;
-                        if wi.z() * wo.z() > 0.0 {
-                            // scattering, so don't mess with volumes
-                            // println!("reflect, {}, {}", outer, inner);
-                        } else {
-                            // transmitting, so remove appropriate medium from list and add new one. only applicable if inner != outer
-
-                            if inner != outer {
-                                // println!(
-                                //     "transmit, {}, {}, {:?}, {:?}, {:?}",
-                                //     outer, inner, wo, vertex.normal, tracked_mediums
-                                // );
-                                // print!("{:?} ", vertex.material_id);
-                                if wo.z() < 0.0 {
-                                    // println!("wo.z < 0, wi: {:?}, wo: {:?}", wi, wo);
-                                    // transmitting from outer to inner. thus remove outer and add inner
-                                    if outer != 0 {
-                                        // only remove outer if it's not the Vacuum index.
-                                        match tracked_mediums.iter().position(|e| *e == outer) {
-                                            Some(index) => {
-                                                tracked_mediums.remove(index);
-                                            }
-                                            None => {
-                                                // println!(
-                                                //     "warning: attempted to transition out of a medium that was not being tracked. tracked mediums already was {:?}. transmit from {} to {}, {:?}, {:?}.",
-                                                //     tracked_mediums, outer, inner, wi, wo
-                                                // );
-                                            }
-                                        }
-                                    }
-                                    if inner != 0 {
-                                        // let insertion_index = tracked_mediums.binary_search(&inner);
-                                        tracked_mediums.push(inner);
-                                        tracked_mediums.sort_unstable();
-                                    }
-                                } else {
-                                    // println!("wo.z > 0, wi: {:?}, wo: {:?}", wi, wo);
-                                    // transmitting from inner to outer. thus remove inner and add outer, unless outer is vacuum.
-                                    // also don't do anything if inner is vacuum.
-                                    if inner != 0 {
-                                        match tracked_mediums.iter().position(|e| *e == inner) {
-                                            Some(index) => {
-                                                tracked_mediums.remove(index);
-                                            }
-                                            None => {
-                                                // println!(
-                                                //     "warning: attempted to transition out of a medium that was not being tracked. tracked mediums already was {:?}. transmit from {} to {}, {:?}, {:?}.",
-                                                //      tracked_mediums, inner,outer, wi, wo
-                                                // );
-                                            }
-                                        }
-                                    }
-                                    if outer != 0 {
-                                        tracked_mediums.push(outer);
-                                        tracked_mediums.sort_unstable();
-                                    }
-                                }
-                            }
+                        if let (Some((outer, inner, priority)), true) =
+                            (interface, wi.z() * wo.z() < 0.0)
+                        {
+                            // transmitted, so the path moved into a different medium
+                            medium_stack.cross(outer, inner, priority, wo.z() < 0.0);
                         }
 
                         // add normal to avoid self intersection
@@ -1148,60 +1660,61 @@ pub fn random_walk_medium_hero(
                         lambda.extract(0),
                         vertex.point.as_tuple(),
                         wi,
-                        Sample2D::new_random_sample(),
+                        phase_sample,
                     );
 
                     // do russian roulette?
 
-                    vertex.pdf_forward = f32x4::splat(f_and_pdf);
-                    vertex.pdf_backward = f32x4::splat(f_and_pdf); // TODO: fix this, probably incorrect.
-                    vertex.veach_g = veach_g(vertex.point, 1.0, ray.origin, 1.0);
-                    vertices.push(HeroVertex::Medium(vertex));
-                    // println!(
-                    //     "medium interaction {}, wi = {:?}, wo = {:?}",
-                    //     vertex.medium_id, wi, wo
-                    // );
+                    // the phase function is normalized, so it's also the pdf of sampling wo given wi in each lane,
+                    // and the pdf of sampling wi given wo when going in reverse.
+                    let mut pdf_forward = f32x4::splat(f_and_pdf);
+                    let mut pdf_backward = f32x4::splat(0.0);
                     beta /= f_and_pdf; // pre divide by hero pdf
                     beta = beta.replace(0, beta.extract(0) * f_and_pdf);
                     debug_assert!(beta.is_finite().all(), "{:?} {:?}", beta, f_and_pdf);
-                    for i in 1..4 {
+                    for i in 0..4 {
+                        pdf_backward = pdf_backward.replace(
+                            i,
+                            medium.p(lambda.extract(i), vertex.point.as_tuple(), wo, wi),
+                        );
+                        if i == 0 {
+                            continue;
+                        }
                         let f_and_pdf =
                             medium.p(lambda.extract(i), vertex.point.as_tuple(), wi, wo);
+                        pdf_forward = pdf_forward.replace(i, f_and_pdf);
                         beta = beta.replace(i, beta.extract(i) * f_and_pdf);
                         debug_assert!(beta.is_finite().all(), "{:?} {:?}", beta, f_and_pdf);
                     }
 
+                    vertex.pdf_forward = pdf_forward;
+                    vertex.pdf_backward = pdf_backward;
+                    vertex.veach_g = veach_g(vertex.point, 1.0, ray.origin, 1.0);
+                    vertices.push(HeroVertex::Medium(vertex));
+                    // println!(
+                    //     "medium interaction {}, wi = {:?}, wo = {:?}",
+                    //     vertex.medium_id, wi, wo
+                    // );
+
                     ray = Ray::new(vertex.point, wo);
                 }
             }
         } else {
             // add a vertex when a camera ray hits the environment
             if trace_type == TransportMode::Importance {
-                let ray_direction = ray.direction;
-                let world_radius = world.get_world_radius();
-                let at_env = ray_direction * world_radius;
-                let vertex = HeroSurfaceVertex::new(
-                    VertexType::LightSource(LightSourceType::Environment),
-                    ray.time,
-                    lambda,
-                    ray.direction,
-                    Point3::from(at_env),
-                    ray.direction,
-                    (0.0, 0.0),
-                    MaterialId::Light(0),
-                    0,
-                    HeroEnergy(beta),
-                    f32x4::splat(0.0),
-                    f32x4::splat(1.0 / (4.0 * PI)),
-                    1.0,
-                );
-                debug_assert!(vertex.point.0.is_finite().all());
-                // println!("sampling env and setting pdf_forward to 0");
-                vertices.push(HeroVertex::Surface(vertex));
+                vertices.push(HeroVertex::Surface(hero_env_vertex(
+                    world, ray, lambda, beta,
+                )));
             }
             break;
         }
     }
+    for _ in bounces_taken..bounce_limit {
+        let _ = sampler.draw_1d();
+        let _ = sampler.draw_2d();
+        let _ = sampler.draw_2d();
+        let _ = sampler.draw_1d();
+    }
     profile.bounce_rays += vertices.len();
 
     if additional_contribution.0.gt(f32x4::splat(0.0)).any() {
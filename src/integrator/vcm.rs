@@ -0,0 +1,448 @@
+use crate::integrator::bdpt::utils::*;
+use crate::integrator::sppm::{is_diffuse, GridPoint, PhotonGrid};
+use crate::integrator::utils::*;
+use crate::integrator::*;
+use crate::materials::{Material, MaterialId};
+use crate::math::*;
+use crate::sampler::SamplerFactory;
+use crate::world::TransportMode;
+
+use std::{f32::EPSILON, sync::Arc};
+
+use rayon::iter::ParallelIterator;
+use rayon::prelude::*;
+
+// a light subpath vertex that eye subpaths can merge with
+#[derive(Copy, Clone, Debug)]
+pub struct MergeVertex {
+    pub vertex: SurfaceVertex,
+    pub path_index: usize,
+    // index of the vertex within its light subpath, always at least 1
+    pub vertex_index: usize,
+}
+
+impl GridPoint for MergeVertex {
+    fn grid_point(&self) -> Point3 {
+        self.vertex.point
+    }
+}
+
+// the radius used for merging at the given iteration, see Georgiev et al. 2012 "Light Transport Simulation with Vertex Connection and Merging".
+// alpha = 1 keeps the radius constant, smaller values shrink it faster.
+pub fn merge_radius(initial_radius: f32, alpha: f32, iteration: usize) -> f32 {
+    initial_radius * ((iteration + 1) as f32).powf((alpha - 1.0) / 2.0)
+}
+
+// the probability density of starting a light subpath at its first vertex and in its first direction, the same one sppm's photons divide by.
+// for surface lights pdf_forward already has the cosine divided out, so the emission times the cosine over this matches trace_photon.
+// the environment starts on a disk of radius world_radius, perpendicular to the sampled direction.
+pub fn light_start_pdf(world: &Arc<World>, light_vertex: &SurfaceVertex) -> f32 {
+    let pdf = light_vertex.pdf_backward * light_vertex.pdf_forward;
+    match light_vertex.vertex_type {
+        VertexType::LightSource(LightSourceType::Environment) => {
+            let world_radius = world.get_world_radius();
+            pdf / (PI * world_radius * world_radius)
+        }
+        _ => pdf,
+    }
+}
+
+// power heuristic mis weight for vertex connection and merging.
+// the connection strategy probabilities come from the same recurrence as bdpt, and merging at combined path index i
+// is as likely as connecting with i light vertices, times the probability of the light subpath landing on vertex i,
+// times the merge disk area and the light subpath count, which merge_scale should be.
+// if merging is true, the weight is for merging at index s instead of connecting with s light vertices.
+pub fn eval_vcm_mis(
+    world: &Arc<World>,
+    light_path: &Vec<SurfaceVertex>,
+    s: usize,
+    eye_path: &Vec<SurfaceVertex>,
+    t: usize,
+    connecting_g: f32,
+    merge_scale: f32,
+    merging: bool,
+) -> f32 {
+    let (ps, path) = eval_path_probabilities(world, light_path, s, eye_path, t, connecting_g);
+    let k = s + t - 1;
+
+    let mut sum = 0.0;
+    for p in ps.iter() {
+        sum += p * p;
+    }
+    let mut current = if merging { 0.0 } else { 1.0 };
+    // merging only happens at interior vertices, where both subpaths have at least one more vertex before their endpoint
+    for i in 1..k {
+        let vertex = path[i];
+        match vertex.vertex_type {
+            VertexType::Eye | VertexType::Light => {}
+            _ => continue,
+        }
+        // merging is only done on diffuse surfaces
+        if !is_diffuse(world.get_material(vertex.material_id)) {
+            continue;
+        }
+        let p = ps[i] * path.pdf_forward(i - 1) * path.veach_g_between(i - 1, i) * merge_scale;
+        sum += p * p;
+        if merging && i == s {
+            current = p;
+        }
+    }
+    if sum == 0.0 || !sum.is_finite() {
+        return 0.0;
+    }
+    current * current / sum
+}
+
+// vertex connection and merging, also known as unified path sampling (Hachisuka et al. 2012).
+// every iteration traces one light subpath per pixel, and each pixel's eye subpath is connected to its own light subpath like bdpt,
+// and merged with the vertices of every light subpath like photon mapping. both kinds of strategies are weighted together.
+// like sppm, every iteration renders a single wavelength, so that merged vertices always match in wavelength.
+pub struct VCMIntegrator {
+    pub max_bounces: u16,
+    pub min_bounces: u16,
+    pub world: Arc<World>,
+    pub wavelength_bounds: Bounds1D,
+    pub initial_radius: f32,
+    pub alpha: f32,
+    // summed per iteration estimates, for eye subpaths and for splats onto the lens
+    pub pixels: Vec<XYZColor>,
+    pub light_pixels: Vec<XYZColor>,
+    pub iteration: usize,
+}
+
+impl VCMIntegrator {
+    pub fn reset(&mut self, width: usize, height: usize) {
+        self.pixels = vec![XYZColor::BLACK; width * height];
+        self.light_pixels = vec![XYZColor::BLACK; width * height];
+        self.iteration = 0;
+    }
+
+    fn trace_light_path(
+        &self,
+        lambda: f32,
+        sampler: &mut Box<dyn Sampler>,
+        profile: &mut Profile,
+    ) -> Vec<SurfaceVertex> {
+        let mut light_path: Vec<SurfaceVertex> = Vec::with_capacity(1 + self.max_bounces as usize);
+        let (mut start_light_vertex, sampled) =
+            sample_light_vertex(&self.world, self.wavelength_bounds, sampler);
+        // sample_light_vertex samples its own wavelength, so it's only used for the ray and pdfs,
+        // and the emission is evaluated at the iteration wavelength instead.
+        let emission = eval_light_vertex_emission(
+            &self.world,
+            &start_light_vertex,
+            sampled.0.direction,
+            lambda,
+        );
+        start_light_vertex.lambda = lambda;
+        start_light_vertex.throughput = emission;
+        light_path.push(start_light_vertex);
+        random_walk_medium(
+            sampled.0,
+            lambda,
+            self.max_bounces,
+            emission,
+            TransportMode::Radiance,
+            sampler,
+            &self.world,
+            &mut light_path,
+            self.min_bounces,
+            profile,
+        );
+        profile.light_rays += 1;
+        light_path
+    }
+
+    // runs one full iteration: traces the light subpaths, then traces, connects and merges an eye subpath for every pixel.
+    pub fn iterate(
+        &mut self,
+        sampler_factory: &SamplerFactory,
+        camera_id: CameraId,
+        resolution: (usize, usize),
+        profile: &mut Profile,
+    ) {
+        let (width, height) = resolution;
+        assert!(self.pixels.len() == width * height);
+        let iteration = self.iteration;
+        let mut wavelength_sampler =
+            sampler_factory.sampler_for((usize::MAX, usize::MAX), iteration);
+        let lambda = self
+            .wavelength_bounds
+            .sample(wavelength_sampler.draw_1d().x);
+        let wavelength_pdf = 1.0 / (self.wavelength_bounds.upper - self.wavelength_bounds.lower);
+
+        let radius = merge_radius(self.initial_radius, self.alpha, iteration);
+        let light_path_count = width * height;
+        let merge_scale = PI * radius * radius * light_path_count as f32;
+
+        // pass 1, trace light subpaths and gather their vertices for merging
+        let light_results: Vec<(Vec<SurfaceVertex>, Profile)> = (0..light_path_count)
+            .into_par_iter()
+            .map(|path_index| {
+                let mut profile = Profile::default();
+                let mut sampler = sampler_factory.sampler_for((path_index, usize::MAX), iteration);
+                let light_path = self.trace_light_path(lambda, &mut sampler, &mut profile);
+                (light_path, profile)
+            })
+            .collect();
+        let mut light_paths: Vec<Vec<SurfaceVertex>> = Vec::with_capacity(light_path_count);
+        let mut merge_vertices: Vec<MergeVertex> = Vec::new();
+        for (path_index, (light_path, path_profile)) in light_results.into_iter().enumerate() {
+            *profile = profile.combine(path_profile);
+            for (vertex_index, vertex) in light_path.iter().enumerate().skip(1) {
+                if vertex.vertex_type == VertexType::Light
+                    && is_diffuse(self.world.get_material(vertex.material_id))
+                {
+                    merge_vertices.push(MergeVertex {
+                        vertex: *vertex,
+                        path_index,
+                        vertex_index,
+                    });
+                }
+            }
+            light_paths.push(light_path);
+        }
+        let grid = PhotonGrid::new(merge_vertices, radius);
+
+        // pass 2, trace eye subpaths, and connect and merge them
+        let eye_results: Vec<(SingleEnergy, Vec<(SingleEnergy, (f32, f32))>, Profile)> = (0..width
+            * height)
+            .into_par_iter()
+            .map(|pixel_index| {
+                let mut profile = Profile::default();
+                let y: usize = pixel_index / width;
+                let x: usize = pixel_index - width * y;
+                let mut sampler = sampler_factory.sampler_for((x, y), iteration);
+                let film_sample = sampler.draw_2d();
+                let film_sample = Sample2D::new(
+                    ((x as f32 + film_sample.x) / width as f32).clamp(0.0, 1.0 - EPSILON),
+                    ((y as f32 + film_sample.y) / height as f32).clamp(0.0, 1.0 - EPSILON),
+                );
+                let mut splats = Vec::new();
+                let sum = self.eye_path_contribution(
+                    &mut sampler,
+                    film_sample,
+                    camera_id,
+                    lambda,
+                    &light_paths[pixel_index],
+                    &light_paths,
+                    &grid,
+                    merge_scale,
+                    &mut splats,
+                    &mut profile,
+                );
+                (sum, splats, profile)
+            })
+            .collect();
+
+        for (pixel_index, (sum, splats, pixel_profile)) in eye_results.into_iter().enumerate() {
+            *profile = profile.combine(pixel_profile);
+            self.pixels[pixel_index] +=
+                XYZColor::from(SingleWavelength::new(lambda, sum / wavelength_pdf));
+            for (energy, pixel_uv) in splats {
+                // light samples are flipped vertically relative to image samples
+                let x = ((pixel_uv.0 * width as f32) as usize).min(width - 1);
+                let y = height - ((pixel_uv.1 * height as f32) as usize).min(height - 1) - 1;
+                self.light_pixels[y * width + x] +=
+                    XYZColor::from(SingleWavelength::new(lambda, energy / wavelength_pdf));
+            }
+        }
+        self.iteration += 1;
+    }
+
+    fn eye_path_contribution(
+        &self,
+        sampler: &mut Box<dyn Sampler>,
+        film_sample: Sample2D,
+        camera_id: CameraId,
+        lambda: f32,
+        light_path: &Vec<SurfaceVertex>,
+        light_paths: &Vec<Vec<SurfaceVertex>>,
+        grid: &PhotonGrid<MergeVertex>,
+        merge_scale: f32,
+        splats: &mut Vec<(SingleEnergy, (f32, f32))>,
+        profile: &mut Profile,
+    ) -> SingleEnergy {
+        let camera = self.world.get_camera(camera_id);
+        let (camera_ray, lens_normal, camera_pdf) = camera.sample_we(film_sample, sampler, lambda);
+        let mut eye_path: Vec<SurfaceVertex> = Vec::with_capacity(1 + self.max_bounces as usize);
+        eye_path.push(SurfaceVertex::new(
+            VertexType::Camera,
+            camera_ray.time,
+            lambda,
+            Vec3::ZERO,
+            camera_ray.origin,
+            lens_normal,
+            (0.0, 0.0),
+            MaterialId::Camera(camera_id as u16),
+            0,
+            SingleEnergy::ONE,
+            camera_pdf.0,
+            0.0,
+            1.0,
+        ));
+        let camera_medium = self.world.get_camera_medium(camera_id);
+        eye_path[0].medium_id = camera_medium;
+        random_walk_medium_from(
+            camera_ray,
+            lambda,
+            self.max_bounces,
+            SingleEnergy::ONE,
+            TransportMode::Importance,
+            sampler,
+            &self.world,
+            &mut eye_path,
+            self.min_bounces,
+            profile,
+            camera_medium,
+        );
+        profile.camera_rays += 1;
+
+        let russian_roulette_threshold = 0.005;
+        let mut sum = SingleEnergy::ZERO;
+
+        // vertex connection, same strategies as bdpt
+        let (eye_vertex_count, light_vertex_count) = (eye_path.len(), light_path.len());
+        for path_length in 1..(1 + self.max_bounces as usize) {
+            let path_vertex_count = path_length + 1;
+            for s in 0..path_vertex_count {
+                let t = path_vertex_count - s;
+                if s > light_vertex_count || t > eye_vertex_count {
+                    continue;
+                }
+                if (s == 0 && t < 2) || (t == 0 && s < 2) || (s + t) < 2 {
+                    continue;
+                }
+                let (factor, g, splatted) = match eval_unweighted_contribution(
+                    &self.world,
+                    light_path,
+                    s,
+                    &eye_path,
+                    t,
+                    sampler,
+                    russian_roulette_threshold,
+                    profile,
+                ) {
+                    SampleKind::Sampled((factor, g)) => (factor, g, false),
+                    SampleKind::Splatted((factor, g)) => (factor, g, true),
+                };
+                if g == 0.0 || factor == SingleEnergy::ZERO {
+                    continue;
+                }
+                let weight = eval_vcm_mis(
+                    &self.world,
+                    light_path,
+                    s,
+                    &eye_path,
+                    t,
+                    g,
+                    merge_scale,
+                    false,
+                );
+                if weight == 0.0 {
+                    continue;
+                }
+                if splatted {
+                    let (vert_on_lens, vert_in_scene) = if t == 1 {
+                        (eye_path[0], light_path[s - 1])
+                    } else {
+                        (light_path[s - 1], light_path[s - 2])
+                    };
+                    let ray = Ray::new(
+                        vert_on_lens.point,
+                        (vert_in_scene.point - vert_on_lens.point).normalized(),
+                    );
+                    if let Some(pixel_uv) = camera.get_pixel_for_ray(ray, lambda) {
+                        splats.push((weight * factor, pixel_uv));
+                    }
+                } else {
+                    sum += weight * factor;
+                }
+            }
+        }
+
+        // vertex merging, at every diffuse eye vertex past the lens
+        for j in 1..eye_vertex_count {
+            let eye_vertex = eye_path[j];
+            match eye_vertex.vertex_type {
+                VertexType::Eye => {}
+                VertexType::Medium(_) => continue,
+                _ => break,
+            }
+            let material = self.world.get_material(eye_vertex.material_id);
+            if !is_diffuse(material) {
+                continue;
+            }
+            let frame = TangentFrame::from_normal(eye_vertex.normal);
+            let wi = frame.to_local(&eye_vertex.local_wi).normalized();
+            let mut merged = SingleEnergy::ZERO;
+            grid.for_each_within(eye_vertex.point, grid.cell_size, |merge_vertex| {
+                // merging at light vertex i with eye vertex j makes a path of length i + j
+                let i = merge_vertex.vertex_index;
+                if i + j > self.max_bounces as usize {
+                    return;
+                }
+                let wo = frame.to_local(&merge_vertex.vertex.local_wi).normalized();
+                let (f, _pdf) =
+                    material.bsdf(lambda, eye_vertex.uv, TransportMode::Importance, wi, wo);
+                if f == SingleEnergy::ZERO {
+                    return;
+                }
+                // weight as if the light subpath up to vertex i - 1 had been connected to this eye vertex
+                let merge_light_path = &light_paths[merge_vertex.path_index];
+                let last_light_vertex = merge_light_path[i - 1];
+                let connection = (eye_vertex.point - last_light_vertex.point).normalized();
+                let g = veach_g(
+                    eye_vertex.point,
+                    (connection * eye_vertex.normal).abs(),
+                    last_light_vertex.point,
+                    (connection * last_light_vertex.normal).abs(),
+                );
+                let weight = eval_vcm_mis(
+                    &self.world,
+                    merge_light_path,
+                    i,
+                    &eye_path,
+                    j + 1,
+                    g,
+                    merge_scale,
+                    true,
+                );
+                // like sppm, the merged light subpath is normalized by the pdf it was started with
+                let start_pdf = light_start_pdf(&self.world, &merge_light_path[0]);
+                if start_pdf == 0.0 {
+                    return;
+                }
+                merged += weight * f * merge_vertex.vertex.throughput.0 / start_pdf;
+            });
+            sum += eye_vertex.throughput * merged / merge_scale;
+        }
+        sum
+    }
+
+    // the current estimate for a pixel, averaged over iterations
+    pub fn estimate(&self, pixel_index: usize) -> XYZColor {
+        let iterations = self.iteration.max(1) as f32;
+        (self.pixels[pixel_index] + self.light_pixels[pixel_index]) / iterations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_radius_schedule() {
+        assert!(merge_radius(0.1, 1.0, 100) == 0.1);
+        let mut last = merge_radius(0.1, 0.75, 0);
+        assert!(last == 0.1);
+        for iteration in 1..100 {
+            let radius = merge_radius(0.1, 0.75, iteration);
+            assert!(radius < last && radius > 0.0);
+            last = radius;
+        }
+        // r_i^2 * i^(1 - alpha) stays constant
+        let r = merge_radius(0.1, 0.75, 15);
+        assert!((r * r * 16.0f32.powf(0.25) - 0.01).abs() < 1e-6);
+    }
+}
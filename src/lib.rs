@@ -23,6 +23,7 @@ pub mod mediums;
 pub mod parsing;
 pub mod profile;
 pub mod renderer;
+pub mod sampler;
 pub mod texture;
 pub mod tonemap;
 pub mod world;
@@ -1,16 +1,17 @@
 use crate::materials::Material;
 use crate::math::*;
+use crate::texture::TexStack;
 use crate::world::TransportMode;
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct DiffuseLight {
     // pub color: Box<dyn SpectralPowerDistribution>,
-    pub color: CDF,
+    pub color: TexStack,
     pub sidedness: Sidedness,
 }
 
 impl DiffuseLight {
-    pub fn new(color: CDF, sidedness: Sidedness) -> DiffuseLight {
+    pub fn new(color: TexStack, sidedness: Sidedness) -> DiffuseLight {
         DiffuseLight { color, sidedness }
     }
     pub const NAME: &'static str = "DiffuseLight";
@@ -21,6 +22,7 @@ impl Material for DiffuseLight {
         &self,
         point: Point3,
         normal: Vec3,
+        uv: (f32, f32),
         wavelength_range: Bounds1D,
         mut scatter_sample: Sample2D,
         wavelength_sample: Sample1D,
@@ -49,7 +51,7 @@ impl Material for DiffuseLight {
         debug_assert!(directional_pdf > 0.0, "{:?} {:?}", local_wo, object_wo);
         let (sw, pdf) = self
             .color
-            .sample_power_and_pdf(wavelength_range, wavelength_sample);
+            .sample_power_and_pdf(uv, wavelength_range, wavelength_sample);
         Some((
             Ray::new(point, object_wo),
             sw.with_energy(sw.energy / PI),
@@ -60,20 +62,20 @@ impl Material for DiffuseLight {
 
     fn sample_emission_spectra(
         &self,
-        _uv: (f32, f32),
+        uv: (f32, f32),
         wavelength_range: Bounds1D,
         wavelength_sample: Sample1D,
     ) -> Option<(f32, PDF)> {
         let (sw, pdf) = self
             .color
-            .sample_power_and_pdf(wavelength_range, wavelength_sample);
+            .sample_power_and_pdf(uv, wavelength_range, wavelength_sample);
         Some((sw.lambda, pdf))
     }
 
     fn emission(
         &self,
         lambda: f32,
-        _uv: (f32, f32),
+        uv: (f32, f32),
         _transport_mode: TransportMode,
         wi: Vec3,
     ) -> SingleEnergy {
@@ -83,7 +85,7 @@ impl Material for DiffuseLight {
             || self.sidedness == Sidedness::Dual
         {
             // if wi.z() > 0.0 {
-            SingleEnergy::new(self.color.evaluate_power(lambda) / PI)
+            SingleEnergy::new(self.color.eval_at(lambda, uv) / PI)
         } else {
             SingleEnergy::ZERO
         }
@@ -102,9 +104,48 @@ impl Material for DiffuseLight {
             || (cosine < 0.0 && self.sidedness == Sidedness::Reverse)
             || self.sidedness == Sidedness::Dual
         {
-            (cosine / PI).into()
+            // reverse and dual sided lights emit below the surface too, where the cosine is negative
+            (cosine.abs() / PI).into()
         } else {
             0.0.into()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curves;
+    use crate::sampler::IndependentSampler;
+
+    #[test]
+    fn test_reverse_emission_pdf() {
+        let light = DiffuseLight::new(CDF::from(curves::cie_e(1.0)).into(), Sidedness::Reverse);
+        let mut sampler = IndependentSampler::new(0, 0);
+        for _ in 0..100 {
+            let (ray, sw, pdf, _) = light
+                .sample_emission(
+                    Point3::ORIGIN,
+                    Vec3::Z,
+                    (0.0, 0.0),
+                    curves::EXTENDED_VISIBLE_RANGE,
+                    sampler.draw_2d(),
+                    sampler.draw_1d(),
+                )
+                .unwrap();
+            assert!(ray.direction.z() < 0.0);
+            let evaluated = light.emission_pdf(
+                sw.lambda,
+                (0.0, 0.0),
+                TransportMode::Radiance,
+                ray.direction,
+            );
+            assert!(
+                (pdf.0 - evaluated.0).abs() < 0.001,
+                "{:?} {:?}",
+                pdf,
+                evaluated
+            );
+        }
+    }
+}
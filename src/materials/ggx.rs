@@ -2,6 +2,8 @@ use crate::materials::Material;
 use crate::math::*;
 use crate::world::TransportMode;
 
+use packed_simd::f32x4;
+
 pub fn reflect(wi: Vec3, normal: Vec3) -> Vec3 {
     let wi = -wi;
     (wi - 2.0 * (wi * normal) * normal).normalized()
@@ -189,6 +191,9 @@ pub struct GGX {
     pub kappa: SPD,
     pub permeability: f32,
     pub outer_medium_id: usize,
+    pub inner_medium_id: usize,
+    // nested dielectric priority, see MediumStack
+    pub priority: u32,
 }
 
 impl GGX {
@@ -208,7 +213,28 @@ impl GGX {
             kappa,
             permeability,
             outer_medium_id,
+            inner_medium_id: 0,
+            priority: 0,
+        }
+    }
+    pub fn with_inner_medium_id(mut self, inner_medium_id: usize) -> Self {
+        self.inner_medium_id = inner_medium_id;
+        self
+    }
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    // only dielectrics refract, and they only disperse if eta varies across the given wavelengths
+    pub fn is_dispersive(&self, lambda: f32x4) -> bool {
+        if self.permeability == 0.0 {
+            return false;
         }
+        let hero_eta = self.eta.evaluate_power(lambda.extract(0));
+        (1..4).any(|i| {
+            (self.eta.evaluate_power(lambda.extract(i)) - hero_eta).abs() > 1e-5 * hero_eta
+        })
     }
 
     fn reflectance(&self, eta_inner: f32, kappa: f32, cos_theta_i: f32) -> f32 {
@@ -482,11 +508,19 @@ impl Material for GGX {
         self.eval_pdf(lambda, wi, wo, transport_mode)
     }
     fn inner_medium_id(&self, _uv: (f32, f32)) -> usize {
-        0
+        self.inner_medium_id
     }
     fn outer_medium_id(&self, _uv: (f32, f32)) -> usize {
         self.outer_medium_id
     }
+    fn interface_priority(&self, _uv: (f32, f32)) -> Option<u32> {
+        // only dielectrics bound a volume
+        if self.permeability > 0.0 {
+            Some(self.priority)
+        } else {
+            None
+        }
+    }
 }
 
 #[cfg(test)]
@@ -518,6 +552,39 @@ mod tests {
         println!("fr1 is {}, fr2 is {}", fr_1, fr_2);
     }
 
+    #[test]
+    fn test_is_dispersive() {
+        let lambda = f32x4::new(450.0, 520.0, 590.0, 660.0);
+        let prism = GGX::new(
+            0.00001,
+            curves::cauchy(1.5, 10000.0),
+            1.0,
+            curves::void(),
+            1.0,
+            0,
+        );
+        assert!(prism.is_dispersive(lambda));
+        let flat_glass = GGX::new(
+            0.00001,
+            curves::cauchy(1.5, 0.0),
+            1.0,
+            curves::void(),
+            1.0,
+            0,
+        );
+        assert!(!flat_glass.is_dispersive(lambda));
+        // conductors don't refract at all
+        let metal = GGX::new(
+            0.1,
+            curves::cauchy(1.5, 10000.0),
+            1.0,
+            curves::void(),
+            0.0,
+            0,
+        );
+        assert!(!metal.is_dispersive(lambda));
+    }
+
     #[test]
     fn test_ggx_functions() {
         let glass = curves::cauchy(1.5, 10000.0);
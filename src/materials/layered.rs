@@ -0,0 +1,796 @@
+use crate::materials::{Material, GGX};
+use crate::math::*;
+use crate::mediums::PhaseFunction;
+use crate::sampler::{mix_bits, IndependentSampler};
+use crate::texture::TexStack;
+use crate::world::TransportMode;
+
+use packed_simd::f32x4;
+
+// a stack of parallel interfaces with media between them, evaluated with position free monte carlo,
+// see Guo et al. 2018, "Position-Free Monte Carlo Simulation for Arbitrary Layered BSDFs".
+// the layers are infinitely wide, so a path through them only has to keep track of its depth and direction.
+// evaluating the bsdf does random walks into the stack from wi. every scattering event is connected to wo
+// through a chain of transmissions that's sampled backwards from wo, which is mis'd against continuing the walk
+// and evaluating the exit interface once it gets there.
+// that gives an unbiased but noisy estimate of the bsdf. the pdf on the other hand is exact, since it's the density of
+// the analytic mixture of lobes that directions are generated from, and integrators divide by it.
+
+fn flip(v: Vec3) -> Vec3 {
+    Vec3::new(v.x(), v.y(), -v.z())
+}
+
+fn reverse(transport_mode: TransportMode) -> TransportMode {
+    match transport_mode {
+        TransportMode::Radiance => TransportMode::Importance,
+        TransportMode::Importance => TransportMode::Radiance,
+    }
+}
+
+// what fills the space between a layer and the one above it. the coefficients are per unit of thickness
+#[derive(Clone)]
+pub struct LayerMedium {
+    pub thickness: f32,
+    pub sigma_t: SPD,
+    pub sigma_s: SPD,
+    pub phase: PhaseFunction,
+}
+
+#[derive(Clone)]
+pub enum LayerInterface {
+    Diffuse { color: TexStack },
+    GGX(GGX),
+}
+
+impl LayerInterface {
+    // light can't get past diffuse layers or conductors
+    pub fn is_opaque(&self) -> bool {
+        match self {
+            LayerInterface::Diffuse { .. } => true,
+            LayerInterface::GGX(ggx) => ggx.permeability == 0.0,
+        }
+    }
+
+    fn bsdf(
+        &self,
+        lambda: f32,
+        uv: (f32, f32),
+        transport_mode: TransportMode,
+        wi: Vec3,
+        wo: Vec3,
+    ) -> (f32, f32) {
+        match self {
+            LayerInterface::Diffuse { color } => {
+                // unlike Lambertian, this works from either side, since the base can be lit from above
+                if wi.z() * wo.z() > 0.0 {
+                    (color.eval_at(lambda, uv).min(1.0) / PI, wo.z().abs() / PI)
+                } else {
+                    (0.0, 0.0)
+                }
+            }
+            LayerInterface::GGX(ggx) => {
+                let (f, pdf) = ggx.bsdf(lambda, uv, transport_mode, wi, wo);
+                (f.0, pdf.0)
+            }
+        }
+    }
+
+    fn generate(
+        &self,
+        lambda: f32,
+        uv: (f32, f32),
+        transport_mode: TransportMode,
+        s: Sample2D,
+        wi: Vec3,
+    ) -> Option<Vec3> {
+        match self {
+            LayerInterface::Diffuse { .. } => {
+                let wo = random_cosine_direction(s);
+                Some(if wi.z() < 0.0 { flip(wo) } else { wo })
+            }
+            LayerInterface::GGX(ggx) => ggx.generate(lambda, uv, transport_mode, s, wi),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Layer {
+    pub interface: LayerInterface,
+    // between this layer and the next one up, if there's anything there
+    pub medium: Option<LayerMedium>,
+}
+
+// one link of the chain of transmissions from wo, as it is in one of the slabs between layers
+#[derive(Copy, Clone)]
+struct ChainLink {
+    // points away from the exit
+    direction: Vec3,
+    // throughput of the chain up to the slab, with transmittance through the slabs before it
+    weight: f32,
+    // pdfs of the directions of the chain, as sampled backwards from wo,
+    // and as the walk would sample them going forwards, excluding the scattering event that starts it
+    pdf_backward: f32,
+    pdf_forward: f32,
+}
+
+impl ChainLink {
+    // the contribution of a vertex that scatters towards -direction with value and pdf,
+    // where transmittance is from the vertex to the far side of its slab
+    fn connect(&self, value: f32, pdf: f32, transmittance: f32) -> f32 {
+        value
+            * transmittance
+            * self.weight
+            * power_heuristic(self.pdf_backward, pdf * self.pdf_forward)
+    }
+}
+
+#[derive(Clone)]
+pub struct Layered {
+    // 0 is the base layer, and the rest are stacked on top of it in order
+    pub layers: Vec<Layer>,
+    // how many random walks each evaluation averages
+    pub samples: usize,
+    pub bounce_limit: usize,
+    pub outer_medium_id: usize,
+    pub inner_medium_id: usize,
+    // a rough reflection standing in for the whole stack when generating directions, mirrored for transmission
+    lobe: Option<GGX>,
+    lobe_probability: f32,
+}
+
+impl Layered {
+    pub fn new(layers: Vec<Layer>, outer_medium_id: usize) -> Self {
+        assert!(
+            !layers.is_empty(),
+            "layered material needs at least one layer"
+        );
+        assert!(
+            layers[1..].iter().all(|layer| !layer.interface.is_opaque()),
+            "only the base layer of a layered material can be opaque"
+        );
+        assert!(
+            layers.last().unwrap().medium.is_none(),
+            "the top layer can't have a medium above it"
+        );
+        let lobe = if layers.len() > 1 {
+            // the roughness of light that makes it through the stack and back adds up along the way
+            let alpha = layers
+                .iter()
+                .map(|layer| match &layer.interface {
+                    LayerInterface::GGX(ggx) => ggx.alpha * ggx.alpha,
+                    _ => 0.0,
+                })
+                .sum::<f32>()
+                .sqrt()
+                .clamp(0.0001, 1.0);
+            let mut lobe = match &layers.last().unwrap().interface {
+                LayerInterface::GGX(ggx) => ggx.clone(),
+                _ => unreachable!(),
+            };
+            lobe.alpha = alpha;
+            // only reflects
+            lobe.permeability = 0.0;
+            Some(lobe)
+        } else {
+            None
+        };
+        let mut layered = Layered {
+            layers,
+            samples: 1,
+            bounce_limit: 32,
+            outer_medium_id,
+            inner_medium_id: 0,
+            lobe,
+            lobe_probability: 1.0,
+        };
+        if layered.scatters_diffusely() {
+            layered.lobe_probability = 0.5;
+        }
+        layered
+    }
+    pub fn with_inner_medium_id(mut self, inner_medium_id: usize) -> Self {
+        self.inner_medium_id = inner_medium_id;
+        self
+    }
+    pub fn with_samples(mut self, samples: usize) -> Self {
+        self.samples = samples.max(1);
+        self
+    }
+    pub fn with_bounce_limit(mut self, bounce_limit: usize) -> Self {
+        self.bounce_limit = bounce_limit;
+        self
+    }
+
+    pub fn is_dispersive(&self, lambda: f32x4) -> bool {
+        self.layers.iter().any(|layer| match &layer.interface {
+            LayerInterface::GGX(ggx) => ggx.is_dispersive(lambda),
+            _ => false,
+        })
+    }
+
+    // whether anything in the stack scatters light in all directions, rather than only reflecting and refracting it
+    pub fn scatters_diffusely(&self) -> bool {
+        self.layers.iter().any(|layer| {
+            layer.medium.is_some() || matches!(layer.interface, LayerInterface::Diffuse { .. })
+        })
+    }
+
+    // whether light can get through the base
+    pub fn is_transmissive(&self) -> bool {
+        !self.layers[0].interface.is_opaque()
+    }
+
+    // the space between layer slab and layer slab + 1
+    fn thickness(&self, slab: usize) -> f32 {
+        self.layers[slab]
+            .medium
+            .as_ref()
+            .map_or(0.0, |medium| medium.thickness)
+    }
+
+    // through depth units of thickness of the slab, along direction
+    fn transmittance(&self, lambda: f32, slab: usize, depth: f32, direction: Vec3) -> f32 {
+        match &self.layers[slab].medium {
+            Some(medium) => {
+                (-medium.sigma_t.evaluate_power(lambda) * depth / direction.z().abs()).exp()
+            }
+            None => 1.0,
+        }
+    }
+
+    fn sampling_pdf(
+        &self,
+        lambda: f32,
+        uv: (f32, f32),
+        transport_mode: TransportMode,
+        wi: Vec3,
+        wo: Vec3,
+    ) -> f32 {
+        let lobe = self.lobe.as_ref().unwrap();
+        let transmissive = self.is_transmissive();
+        // transmission is sampled as the mirror image of reflection
+        let reflected = if wi.z() * wo.z() > 0.0 {
+            wo
+        } else if transmissive {
+            flip(wo)
+        } else {
+            return 0.0;
+        };
+        let side_probability = if transmissive { 0.5 } else { 1.0 };
+        let (_, lobe_pdf) = lobe.bsdf(lambda, uv, transport_mode, wi, reflected);
+        side_probability
+            * (self.lobe_probability * lobe_pdf.0
+                + (1.0 - self.lobe_probability) * reflected.z().abs() / PI)
+    }
+
+    // samples the chain of transmissions from wo into the stack, as far as it gets. indexed by slab
+    fn sample_chain(
+        &self,
+        lambda: f32,
+        uv: (f32, f32),
+        transport_mode: TransportMode,
+        wo: Vec3,
+        sampler: &mut IndependentSampler,
+    ) -> Vec<Option<ChainLink>> {
+        let n = self.layers.len();
+        let reverse_mode = reverse(transport_mode);
+        let exit_top = wo.z() > 0.0;
+        let mut chain = vec![None; n - 1];
+
+        let exit = &self.layers[if exit_top { n - 1 } else { 0 }].interface;
+        let direction = match exit.generate(lambda, uv, reverse_mode, sampler.draw_2d(), wo) {
+            Some(direction) if direction.z() * wo.z() < 0.0 => direction,
+            _ => return chain,
+        };
+        let (f, pdf) = exit.bsdf(lambda, uv, reverse_mode, wo, direction);
+        if pdf == 0.0 {
+            return chain;
+        }
+        let mut link = ChainLink {
+            direction,
+            weight: f / pdf,
+            pdf_backward: pdf,
+            pdf_forward: 1.0,
+        };
+        let mut slab = if exit_top { n - 2 } else { 0 };
+        loop {
+            chain[slab] = Some(link);
+            let next = if exit_top {
+                slab.checked_sub(1)
+            } else if slab + 2 < n {
+                Some(slab + 1)
+            } else {
+                None
+            };
+            let next = match next {
+                Some(next) => next,
+                None => break,
+            };
+            // the interface on the far side of the slab
+            let interface = &self.layers[if exit_top { slab } else { slab + 1 }].interface;
+            let u = link.direction;
+            let v = match interface.generate(lambda, uv, reverse_mode, sampler.draw_2d(), -u) {
+                Some(v) if v.z() * u.z() > 0.0 => v,
+                _ => break,
+            };
+            let (f, pdf) = interface.bsdf(lambda, uv, reverse_mode, -u, v);
+            if pdf == 0.0 {
+                break;
+            }
+            let (_, pdf_forward) = interface.bsdf(lambda, uv, transport_mode, v, -u);
+            link = ChainLink {
+                direction: v,
+                weight: link.weight
+                    * self.transmittance(lambda, slab, self.thickness(slab), u)
+                    * f
+                    * u.z().abs()
+                    / pdf,
+                pdf_backward: link.pdf_backward * pdf,
+                pdf_forward: link.pdf_forward * pdf_forward,
+            };
+            slab = next;
+        }
+        chain
+    }
+
+    // a single random walk's estimate of the bsdf, for stacks of more than one layer
+    fn estimate(
+        &self,
+        lambda: f32,
+        uv: (f32, f32),
+        transport_mode: TransportMode,
+        wi: Vec3,
+        wo: Vec3,
+        sampler: &mut IndependentSampler,
+    ) -> f32 {
+        let n = self.layers.len();
+        let reverse_mode = reverse(transport_mode);
+        let entry_top = wi.z() > 0.0;
+        let exit_top = wo.z() > 0.0;
+        let entry = &self.layers[if entry_top { n - 1 } else { 0 }].interface;
+        let exit = &self.layers[if exit_top { n - 1 } else { 0 }].interface;
+        let exit_slab = if exit_top { n - 2 } else { 0 };
+
+        let toward_exit = |w: Vec3| (w.z() > 0.0) == exit_top;
+        // from depth z in the slab to its side that's closest to the exit
+        let to_exit_side = |slab: usize, z: f32| {
+            if exit_top {
+                self.thickness(slab) - z
+            } else {
+                z
+            }
+        };
+        // flying from depth z in the exit slab towards the exit and evaluating it,
+        // given the pdfs of the directions sampled since the last scattering event
+        let exit_contribution = |w: Vec3, z: f32, pdf_forward: f32, pdf_backward: f32| {
+            let (f, _) = exit.bsdf(lambda, uv, transport_mode, -w, wo);
+            if f == 0.0 {
+                return 0.0;
+            }
+            let (_, pdf_exit) = exit.bsdf(lambda, uv, reverse_mode, wo, -w);
+            f * self.transmittance(lambda, exit_slab, to_exit_side(exit_slab, z), w)
+                * power_heuristic(pdf_forward, pdf_backward * pdf_exit)
+        };
+
+        let chain = self.sample_chain(lambda, uv, transport_mode, wo, sampler);
+
+        let mut f = 0.0;
+        let mut slab = if entry_top { n - 2 } else { 0 };
+        if entry_top == exit_top {
+            // reflecting off of the stack without entering it
+            f += entry.bsdf(lambda, uv, transport_mode, wi, wo).0;
+        } else if let Some(link) = &chain[slab] {
+            // refracting straight into the chain
+            let c = -link.direction;
+            let (value, pdf) = entry.bsdf(lambda, uv, transport_mode, wi, c);
+            f += link.connect(
+                value * c.z().abs(),
+                pdf,
+                self.transmittance(lambda, slab, self.thickness(slab), c),
+            );
+        }
+
+        let mut w = match entry.generate(lambda, uv, transport_mode, sampler.draw_2d(), wi) {
+            Some(w) if w.z() * wi.z() < 0.0 => w,
+            _ => return f,
+        };
+        let (value, pdf) = entry.bsdf(lambda, uv, transport_mode, wi, w);
+        if pdf == 0.0 {
+            return f;
+        }
+        let mut beta = value * w.z().abs() / pdf;
+        let mut z = if entry_top { self.thickness(slab) } else { 0.0 };
+        // pdfs of the directions sampled since the last scattering event,
+        // forwards like the walk sampled them and backwards like the chain would have
+        let mut pdf_forward = pdf;
+        let mut pdf_backward = 1.0;
+        if toward_exit(w) && slab == exit_slab {
+            f += beta * exit_contribution(w, z, pdf_forward, pdf_backward);
+        }
+
+        for bounce in 0..self.bounce_limit {
+            if beta <= 0.0 {
+                break;
+            }
+            if bounce > 3 && beta < 1.0 {
+                // russian roulette
+                if sampler.draw_1d().x > beta {
+                    break;
+                }
+                beta = 1.0;
+            }
+
+            if let Some(medium) = &self.layers[slab].medium {
+                // fly through the slab, possibly scattering on the way
+                let sigma_t = medium.sigma_t.evaluate_power(lambda);
+                let distance = -(1.0 - sampler.draw_1d().x).ln() / sigma_t;
+                let depth = distance * w.z().abs();
+                let to_boundary = if w.z() > 0.0 { medium.thickness - z } else { z };
+                if depth < to_boundary {
+                    z += depth * w.z().signum();
+                    let albedo = medium.sigma_s.evaluate_power(lambda) / sigma_t;
+                    if let Some(link) = &chain[slab] {
+                        let c = -link.direction;
+                        let phase = medium.phase.p(lambda, -w, c);
+                        f += beta
+                            * link.connect(
+                                albedo * phase,
+                                phase,
+                                self.transmittance(lambda, slab, to_exit_side(slab, z), c),
+                            );
+                    }
+                    let (scattered, pdf) = medium.phase.sample_p(lambda, -w, sampler.draw_2d());
+                    if scattered.z() == 0.0 {
+                        break;
+                    }
+                    beta *= albedo;
+                    w = scattered;
+                    if toward_exit(w) {
+                        pdf_forward = pdf;
+                        pdf_backward = 1.0;
+                        if slab == exit_slab {
+                            f += beta * exit_contribution(w, z, pdf_forward, pdf_backward);
+                        }
+                    }
+                    continue;
+                }
+                z = if w.z() > 0.0 { medium.thickness } else { 0.0 };
+            }
+
+            // at the interface on the side of the slab the walk is heading towards
+            let index = if w.z() > 0.0 { slab + 1 } else { slab };
+            let interface = &self.layers[index].interface;
+            if !toward_exit(w) {
+                if let Some(link) = &chain[slab] {
+                    // reflecting back into the chain
+                    let c = -link.direction;
+                    let (value, pdf) = interface.bsdf(lambda, uv, transport_mode, -w, c);
+                    f += beta
+                        * link.connect(
+                            value * c.z().abs(),
+                            pdf,
+                            self.transmittance(lambda, slab, self.thickness(slab), c),
+                        );
+                }
+            }
+
+            let scattered =
+                match interface.generate(lambda, uv, transport_mode, sampler.draw_2d(), -w) {
+                    Some(scattered) if scattered.z() != 0.0 => scattered,
+                    _ => break,
+                };
+            let (value, pdf) = interface.bsdf(lambda, uv, transport_mode, -w, scattered);
+            if pdf == 0.0 {
+                break;
+            }
+            beta *= value * scattered.z().abs() / pdf;
+
+            if scattered.z() * w.z() > 0.0 {
+                // transmitted, either out of the stack, which is accounted for by the connections, or into the next slab
+                if (w.z() > 0.0 && index == n - 1) || (w.z() < 0.0 && index == 0) {
+                    break;
+                }
+                slab = if w.z() > 0.0 { slab + 1 } else { slab - 1 };
+                z = if w.z() > 0.0 {
+                    0.0
+                } else {
+                    self.thickness(slab)
+                };
+                if toward_exit(w) {
+                    // part of a chain to the exit, so it continues the one from the last scattering event
+                    pdf_forward *= pdf;
+                    pdf_backward *= interface.bsdf(lambda, uv, reverse_mode, scattered, -w).1;
+                    if slab == exit_slab {
+                        f += beta * exit_contribution(scattered, z, pdf_forward, pdf_backward);
+                    }
+                }
+            } else if toward_exit(scattered) {
+                pdf_forward = pdf;
+                pdf_backward = 1.0;
+                if slab == exit_slab {
+                    f += beta * exit_contribution(scattered, z, pdf_forward, pdf_backward);
+                }
+            }
+            w = scattered;
+        }
+        f
+    }
+
+    pub const NAME: &'static str = "Layered";
+}
+
+impl Material for Layered {
+    fn generate(
+        &self,
+        lambda: f32,
+        uv: (f32, f32),
+        transport_mode: TransportMode,
+        s: Sample2D,
+        wi: Vec3,
+    ) -> Option<Vec3> {
+        let lobe = match &self.lobe {
+            Some(lobe) => lobe,
+            None => {
+                return self.layers[0]
+                    .interface
+                    .generate(lambda, uv, transport_mode, s, wi)
+            }
+        };
+        let transmissive = self.is_transmissive();
+        if wi.z() < 0.0 && !transmissive {
+            return None;
+        }
+        let mut s = s;
+        let transmit = transmissive && s.x >= 0.5;
+        if transmissive {
+            s.x = if transmit { 2.0 * s.x - 1.0 } else { 2.0 * s.x };
+        }
+        let wo = if s.x < self.lobe_probability {
+            s.x /= self.lobe_probability;
+            lobe.generate(lambda, uv, transport_mode, s, wi)?
+        } else {
+            s.x = (s.x - self.lobe_probability) / (1.0 - self.lobe_probability);
+            let wo = random_cosine_direction(s);
+            if wi.z() < 0.0 {
+                flip(wo)
+            } else {
+                wo
+            }
+        };
+        Some(if transmit { flip(wo) } else { wo })
+    }
+
+    fn bsdf(
+        &self,
+        lambda: f32,
+        uv: (f32, f32),
+        transport_mode: TransportMode,
+        wi: Vec3,
+        wo: Vec3,
+    ) -> (SingleEnergy, PDF) {
+        if self.lobe.is_none() {
+            let (f, pdf) = self.layers[0]
+                .interface
+                .bsdf(lambda, uv, transport_mode, wi, wo);
+            return (SingleEnergy::new(f), pdf.into());
+        }
+        if wi.z() * wo.z() == 0.0 || (!self.is_transmissive() && (wi.z() < 0.0 || wo.z() < 0.0)) {
+            return (SingleEnergy::ZERO, 0.0.into());
+        }
+        let pdf = self.sampling_pdf(lambda, uv, transport_mode, wi, wo);
+        // the walks draw from a sampler seeded by the arguments, so that this stays a pure function of them
+        // like every other bsdf, and renders stay reproducible and within mlt's primary sample space
+        let mut seed = mix_bits(lambda.to_bits() as u64);
+        for v in [uv.0, uv.1, wi.x(), wi.y(), wi.z(), wo.x(), wo.y(), wo.z()].iter() {
+            seed = mix_bits(seed ^ v.to_bits() as u64);
+        }
+        let mut sampler = IndependentSampler { seed, dimension: 0 };
+        let mut f = 0.0;
+        for _ in 0..self.samples {
+            f += self.estimate(lambda, uv, transport_mode, wi, wo, &mut sampler);
+        }
+        (SingleEnergy::new(f / self.samples as f32), pdf.into())
+    }
+    fn outer_medium_id(&self, _uv: (f32, f32)) -> usize {
+        self.outer_medium_id
+    }
+    fn inner_medium_id(&self, _uv: (f32, f32)) -> usize {
+        self.inner_medium_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curves;
+
+    fn coat(alpha: f32) -> Layer {
+        Layer {
+            interface: LayerInterface::GGX(GGX::new(
+                alpha,
+                curves::cauchy(1.5, 0.0),
+                1.0,
+                curves::void(),
+                1.0,
+                0,
+            )),
+            medium: None,
+        }
+    }
+
+    fn white_base(medium: Option<LayerMedium>) -> Layer {
+        Layer {
+            interface: LayerInterface::Diffuse {
+                color: CDF::from(curves::cie_e(1.0)).into(),
+            },
+            medium,
+        }
+    }
+
+    fn slab(sigma_s: f32) -> Option<LayerMedium> {
+        Some(LayerMedium {
+            thickness: 1.0,
+            sigma_t: curves::cie_e(1.0),
+            sigma_s: curves::cie_e(sigma_s),
+            phase: PhaseFunction::HenyeyGreenstein {
+                g: curves::cie_e(1.0),
+            },
+        })
+    }
+
+    // the fraction of light coming from wi that's scattered, by sampling the material like an integrator would
+    fn albedo(material: &Layered, wi: Vec3) -> (f32, f32) {
+        let lambda = 550.0;
+        let samples = 20000;
+        let (mut reflected, mut transmitted) = (0.0, 0.0);
+        let mut sampler = IndependentSampler::new(0, 0);
+        for _ in 0..samples {
+            let wo = match material.generate(
+                lambda,
+                (0.0, 0.0),
+                TransportMode::Importance,
+                sampler.draw_2d(),
+                wi,
+            ) {
+                Some(wo) => wo,
+                None => continue,
+            };
+            let (f, pdf) = material.bsdf(lambda, (0.0, 0.0), TransportMode::Importance, wi, wo);
+            if pdf.0 == 0.0 {
+                continue;
+            }
+            let weight = f.0 * wo.z().abs() / pdf.0;
+            assert!(weight.is_finite() && weight >= 0.0, "{:?} {:?}", f, pdf);
+            if wo.z() * wi.z() > 0.0 {
+                reflected += weight;
+            } else {
+                transmitted += weight;
+            }
+        }
+        (reflected / samples as f32, transmitted / samples as f32)
+    }
+
+    #[test]
+    fn test_energy_conservation() {
+        let wi = Vec3::new(0.4, 0.1, 1.0).normalized();
+
+        // nothing's absorbed, so everything comes back out eventually, minus what the rough coat loses to single scattering
+        let coated = Layered::new(vec![white_base(None), coat(0.1)], 0);
+        let (reflected, transmitted) = albedo(&coated, wi);
+        println!("coated white diffuse reflects {}", reflected);
+        assert!(reflected > 0.8 && reflected < 1.05, "{}", reflected);
+        assert_eq!(transmitted, 0.0);
+
+        let scattering = Layered::new(vec![white_base(slab(1.0)), coat(0.1)], 0);
+        let (reflected, _) = albedo(&scattering, wi);
+        println!("coated white scattering slab reflects {}", reflected);
+        assert!(reflected > 0.8 && reflected < 1.05, "{}", reflected);
+
+        // an absorbing slab only lets through what doesn't get absorbed on the way down and back up
+        let absorbing = Layered::new(vec![white_base(slab(0.0)), coat(0.2)], 0);
+        let (reflected, _) = albedo(&absorbing, wi);
+        println!("coated white absorbing slab reflects {}", reflected);
+        assert!(reflected > 0.0 && reflected < 0.3, "{}", reflected);
+
+        // a sheet of glass in air, with two rough sides
+        let bottom = Layer {
+            interface: LayerInterface::GGX(GGX::new(
+                0.3,
+                curves::cauchy(1.0, 0.0),
+                1.5,
+                curves::void(),
+                1.0,
+                0,
+            )),
+            medium: None,
+        };
+        let sheet = Layered::new(vec![bottom, coat(0.3)], 0);
+        for wi in [wi, -wi].iter() {
+            let (reflected, transmitted) = albedo(&sheet, *wi);
+            println!(
+                "glass sheet reflects {} and transmits {}",
+                reflected, transmitted
+            );
+            assert!(transmitted > reflected, "{} {}", reflected, transmitted);
+            assert!(
+                reflected + transmitted > 0.75 && reflected + transmitted < 1.05,
+                "{} {}",
+                reflected,
+                transmitted
+            );
+        }
+    }
+
+    #[test]
+    fn test_sampling_pdf() {
+        let lambda = 550.0;
+        let wi = Vec3::new(0.4, 0.1, 1.0).normalized();
+        let materials = [
+            Layered::new(vec![white_base(slab(0.5)), coat(0.3)], 0),
+            Layered::new(
+                vec![
+                    Layer {
+                        interface: LayerInterface::GGX(GGX::new(
+                            0.3,
+                            curves::cauchy(1.0, 0.0),
+                            1.5,
+                            curves::void(),
+                            1.0,
+                            0,
+                        )),
+                        medium: None,
+                    },
+                    coat(0.3),
+                ],
+                0,
+            ),
+        ];
+        let mut sampler = IndependentSampler::new(0, 0);
+        for material in materials.iter() {
+            // the pdf should integrate to about 1 over the sphere, minus what the reflection lobe sends below the horizon
+            let samples = 100000;
+            let mut sum = 0.0;
+            for _ in 0..samples {
+                let wo = random_on_unit_sphere(sampler.draw_2d());
+                sum += material.sampling_pdf(lambda, (0.0, 0.0), TransportMode::Importance, wi, wo);
+            }
+            let integral = 4.0 * PI * sum / samples as f32;
+            println!("pdf integrates to {}", integral);
+            assert!(integral > 0.9 && integral < 1.05, "{}", integral);
+
+            // and whatever's generated should have a nonzero pdf
+            for _ in 0..1000 {
+                if let Some(wo) = material.generate(
+                    lambda,
+                    (0.0, 0.0),
+                    TransportMode::Importance,
+                    sampler.draw_2d(),
+                    wi,
+                ) {
+                    if wo.z() * wi.z() > 0.0 || material.is_transmissive() {
+                        let pdf = material.sampling_pdf(
+                            lambda,
+                            (0.0, 0.0),
+                            TransportMode::Importance,
+                            wi,
+                            wo,
+                        );
+                        assert!(pdf > 0.0, "{:?}", wo);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_deterministic() {
+        // the walks are seeded from the arguments, so evaluating the same bsdf twice gives the same answer
+        let material = Layered::new(vec![white_base(slab(0.5)), coat(0.3)], 0);
+        let wi = Vec3::new(0.4, 0.1, 1.0).normalized();
+        let wo = Vec3::new(-0.2, 0.3, 1.0).normalized();
+        let (f0, _) = material.bsdf(550.0, (0.3, 0.7), TransportMode::Importance, wi, wo);
+        let (f1, _) = material.bsdf(550.0, (0.3, 0.7), TransportMode::Importance, wi, wo);
+        assert_eq!(f0.0, f1.0);
+        assert!(f0.0 > 0.0);
+    }
+}
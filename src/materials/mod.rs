@@ -3,6 +3,8 @@ use crate::world::TransportMode;
 
 use std::marker::{Send, Sync};
 
+use packed_simd::f32x4;
+
 #[allow(unused_variables)]
 pub trait Material: Send + Sync {
     // provide default implementations
@@ -37,13 +39,19 @@ pub trait Material: Send + Sync {
     fn inner_medium_id(&self, uv: (f32, f32)) -> usize {
         0
     }
+    // the priority of the volume this surface bounds, for nested dielectrics.
+    // None for surfaces that don't bound a volume, which can never be skipped over.
+    fn interface_priority(&self, uv: (f32, f32)) -> Option<u32> {
+        None
+    }
 
-    // method to sample an emitted light ray with a wavelength and energy
+    // method to sample an emitted light ray with a wavelength and energy, from the given point, normal and uv on the surface
     // can fail when the material is not emissive
     fn sample_emission(
         &self,
         point: Point3,
         normal: Vec3,
+        uv: (f32, f32),
         wavelength_range: Bounds1D,
         scatter_sample: Sample2D,
         wavelength_sample: Sample1D,
@@ -87,13 +95,17 @@ pub trait Material: Send + Sync {
 mod diffuse_light;
 mod ggx;
 mod lambertian;
+mod layered;
 mod passthrough;
+mod photometric_light;
 mod sharp_light;
 
 pub use diffuse_light::DiffuseLight;
 pub use ggx::{reflect, refract, GGX};
 pub use lambertian::Lambertian;
+pub use layered::{Layer, LayerInterface, LayerMedium, Layered};
 pub use passthrough::PassthroughFilter;
+pub use photometric_light::{PhotometricLight, PhotometricProfile};
 pub use sharp_light::SharpLight;
 
 // type required for an id into the Material Table
@@ -131,9 +143,11 @@ impl From<MaterialId> for usize {
 pub enum MaterialEnum {
     GGX(GGX),
     Lambertian(Lambertian),
+    Layered(Layered),
     PassthroughFilter(PassthroughFilter),
     DiffuseLight(DiffuseLight),
     SharpLight(SharpLight),
+    PhotometricLight(PhotometricLight),
 }
 
 impl From<DiffuseLight> for MaterialEnum {
@@ -148,12 +162,24 @@ impl From<Lambertian> for MaterialEnum {
     }
 }
 
+impl From<Layered> for MaterialEnum {
+    fn from(value: Layered) -> Self {
+        MaterialEnum::Layered(value)
+    }
+}
+
 impl From<SharpLight> for MaterialEnum {
     fn from(value: SharpLight) -> Self {
         MaterialEnum::SharpLight(value)
     }
 }
 
+impl From<PhotometricLight> for MaterialEnum {
+    fn from(value: PhotometricLight) -> Self {
+        MaterialEnum::PhotometricLight(value)
+    }
+}
+
 impl From<GGX> for MaterialEnum {
     fn from(value: GGX) -> Self {
         MaterialEnum::GGX(value)
@@ -172,8 +198,19 @@ impl MaterialEnum {
             MaterialEnum::GGX(_inner) => GGX::NAME,
             MaterialEnum::PassthroughFilter(_inner) => PassthroughFilter::NAME,
             MaterialEnum::Lambertian(_inner) => Lambertian::NAME,
+            MaterialEnum::Layered(_inner) => Layered::NAME,
             MaterialEnum::SharpLight(_inner) => SharpLight::NAME,
             MaterialEnum::DiffuseLight(_inner) => DiffuseLight::NAME,
+            MaterialEnum::PhotometricLight(_inner) => PhotometricLight::NAME,
+        }
+    }
+
+    // whether refraction through this material sends the given wavelengths in different directions
+    pub fn is_dispersive(&self, lambda: f32x4) -> bool {
+        match self {
+            MaterialEnum::GGX(inner) => inner.is_dispersive(lambda),
+            MaterialEnum::Layered(inner) => inner.is_dispersive(lambda),
+            _ => false,
         }
     }
 }
@@ -194,14 +231,19 @@ impl Material for MaterialEnum {
                 inner.generate(lambda, uv, transport_mode, s, wi)
             }
             MaterialEnum::Lambertian(inner) => inner.generate(lambda, uv, transport_mode, s, wi),
+            MaterialEnum::Layered(inner) => inner.generate(lambda, uv, transport_mode, s, wi),
             MaterialEnum::SharpLight(inner) => inner.generate(lambda, uv, transport_mode, s, wi),
             MaterialEnum::DiffuseLight(inner) => inner.generate(lambda, uv, transport_mode, s, wi),
+            MaterialEnum::PhotometricLight(inner) => {
+                inner.generate(lambda, uv, transport_mode, s, wi)
+            }
         }
     }
     fn sample_emission(
         &self,
         point: Point3,
         normal: Vec3,
+        uv: (f32, f32),
         wavelength_range: Bounds1D,
         scatter_sample: Sample2D,
         wavelength_sample: Sample1D,
@@ -210,6 +252,7 @@ impl Material for MaterialEnum {
             MaterialEnum::GGX(inner) => inner.sample_emission(
                 point,
                 normal,
+                uv,
                 wavelength_range,
                 scatter_sample,
                 wavelength_sample,
@@ -217,6 +260,15 @@ impl Material for MaterialEnum {
             MaterialEnum::Lambertian(inner) => inner.sample_emission(
                 point,
                 normal,
+                uv,
+                wavelength_range,
+                scatter_sample,
+                wavelength_sample,
+            ),
+            MaterialEnum::Layered(inner) => inner.sample_emission(
+                point,
+                normal,
+                uv,
                 wavelength_range,
                 scatter_sample,
                 wavelength_sample,
@@ -224,6 +276,7 @@ impl Material for MaterialEnum {
             MaterialEnum::SharpLight(inner) => inner.sample_emission(
                 point,
                 normal,
+                uv,
                 wavelength_range,
                 scatter_sample,
                 wavelength_sample,
@@ -231,6 +284,7 @@ impl Material for MaterialEnum {
             MaterialEnum::DiffuseLight(inner) => inner.sample_emission(
                 point,
                 normal,
+                uv,
                 wavelength_range,
                 scatter_sample,
                 wavelength_sample,
@@ -238,6 +292,15 @@ impl Material for MaterialEnum {
             MaterialEnum::PassthroughFilter(inner) => inner.sample_emission(
                 point,
                 normal,
+                uv,
+                wavelength_range,
+                scatter_sample,
+                wavelength_sample,
+            ),
+            MaterialEnum::PhotometricLight(inner) => inner.sample_emission(
+                point,
+                normal,
+                uv,
                 wavelength_range,
                 scatter_sample,
                 wavelength_sample,
@@ -262,8 +325,10 @@ impl Material for MaterialEnum {
                 inner.bsdf(lambda, uv, transport_mode, wi, wo)
             }
             MaterialEnum::Lambertian(inner) => inner.bsdf(lambda, uv, transport_mode, wi, wo),
+            MaterialEnum::Layered(inner) => inner.bsdf(lambda, uv, transport_mode, wi, wo),
             MaterialEnum::SharpLight(inner) => inner.bsdf(lambda, uv, transport_mode, wi, wo),
             MaterialEnum::DiffuseLight(inner) => inner.bsdf(lambda, uv, transport_mode, wi, wo),
+            MaterialEnum::PhotometricLight(inner) => inner.bsdf(lambda, uv, transport_mode, wi, wo),
         }
     }
     fn emission(
@@ -280,8 +345,31 @@ impl Material for MaterialEnum {
                 inner.emission(lambda, uv, transport_mode, wi)
             }
             MaterialEnum::Lambertian(inner) => inner.emission(lambda, uv, transport_mode, wi),
+            MaterialEnum::Layered(inner) => inner.emission(lambda, uv, transport_mode, wi),
             MaterialEnum::SharpLight(inner) => inner.emission(lambda, uv, transport_mode, wi),
             MaterialEnum::DiffuseLight(inner) => inner.emission(lambda, uv, transport_mode, wi),
+            MaterialEnum::PhotometricLight(inner) => inner.emission(lambda, uv, transport_mode, wi),
+        }
+    }
+    fn emission_pdf(
+        &self,
+        lambda: f32,
+        uv: (f32, f32),
+        transport_mode: TransportMode,
+        wo: Vec3,
+    ) -> PDF {
+        match self {
+            MaterialEnum::GGX(inner) => inner.emission_pdf(lambda, uv, transport_mode, wo),
+            MaterialEnum::PassthroughFilter(inner) => {
+                inner.emission_pdf(lambda, uv, transport_mode, wo)
+            }
+            MaterialEnum::Lambertian(inner) => inner.emission_pdf(lambda, uv, transport_mode, wo),
+            MaterialEnum::Layered(inner) => inner.emission_pdf(lambda, uv, transport_mode, wo),
+            MaterialEnum::SharpLight(inner) => inner.emission_pdf(lambda, uv, transport_mode, wo),
+            MaterialEnum::DiffuseLight(inner) => inner.emission_pdf(lambda, uv, transport_mode, wo),
+            MaterialEnum::PhotometricLight(inner) => {
+                inner.emission_pdf(lambda, uv, transport_mode, wo)
+            }
         }
     }
     fn outer_medium_id(&self, uv: (f32, f32)) -> usize {
@@ -289,8 +377,10 @@ impl Material for MaterialEnum {
             MaterialEnum::GGX(inner) => inner.outer_medium_id(uv),
             MaterialEnum::PassthroughFilter(inner) => inner.outer_medium_id(uv),
             MaterialEnum::Lambertian(inner) => inner.outer_medium_id(uv),
+            MaterialEnum::Layered(inner) => inner.outer_medium_id(uv),
             MaterialEnum::SharpLight(inner) => inner.outer_medium_id(uv),
             MaterialEnum::DiffuseLight(inner) => inner.outer_medium_id(uv),
+            MaterialEnum::PhotometricLight(inner) => inner.outer_medium_id(uv),
         }
     }
     fn inner_medium_id(&self, uv: (f32, f32)) -> usize {
@@ -298,8 +388,21 @@ impl Material for MaterialEnum {
             MaterialEnum::GGX(inner) => inner.inner_medium_id(uv),
             MaterialEnum::PassthroughFilter(inner) => inner.inner_medium_id(uv),
             MaterialEnum::Lambertian(inner) => inner.inner_medium_id(uv),
+            MaterialEnum::Layered(inner) => inner.inner_medium_id(uv),
             MaterialEnum::SharpLight(inner) => inner.inner_medium_id(uv),
             MaterialEnum::DiffuseLight(inner) => inner.inner_medium_id(uv),
+            MaterialEnum::PhotometricLight(inner) => inner.inner_medium_id(uv),
+        }
+    }
+    fn interface_priority(&self, uv: (f32, f32)) -> Option<u32> {
+        match self {
+            MaterialEnum::GGX(inner) => inner.interface_priority(uv),
+            MaterialEnum::PassthroughFilter(inner) => inner.interface_priority(uv),
+            MaterialEnum::Lambertian(inner) => inner.interface_priority(uv),
+            MaterialEnum::Layered(inner) => inner.interface_priority(uv),
+            MaterialEnum::SharpLight(inner) => inner.interface_priority(uv),
+            MaterialEnum::DiffuseLight(inner) => inner.interface_priority(uv),
+            MaterialEnum::PhotometricLight(inner) => inner.interface_priority(uv),
         }
     }
     fn sample_emission_spectra(
@@ -318,12 +421,18 @@ impl Material for MaterialEnum {
             MaterialEnum::Lambertian(inner) => {
                 inner.sample_emission_spectra(uv, wavelength_range, wavelength_sample)
             }
+            MaterialEnum::Layered(inner) => {
+                inner.sample_emission_spectra(uv, wavelength_range, wavelength_sample)
+            }
             MaterialEnum::SharpLight(inner) => {
                 inner.sample_emission_spectra(uv, wavelength_range, wavelength_sample)
             }
             MaterialEnum::DiffuseLight(inner) => {
                 inner.sample_emission_spectra(uv, wavelength_range, wavelength_sample)
             }
+            MaterialEnum::PhotometricLight(inner) => {
+                inner.sample_emission_spectra(uv, wavelength_range, wavelength_sample)
+            }
         }
     }
 }
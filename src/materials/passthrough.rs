@@ -7,6 +7,7 @@ pub struct PassthroughFilter {
     pub color: SPD,
     pub outer_medium_id: usize,
     pub inner_medium_id: usize,
+    pub priority: u32,
 }
 
 impl PassthroughFilter {
@@ -15,8 +16,13 @@ impl PassthroughFilter {
             color,
             outer_medium_id,
             inner_medium_id,
+            priority: 0,
         }
     }
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
     pub const NAME: &'static str = "PassthroughFilter";
 }
 
@@ -51,6 +57,9 @@ impl Material for PassthroughFilter {
     fn inner_medium_id(&self, _uv: (f32, f32)) -> usize {
         self.inner_medium_id
     }
+    fn interface_priority(&self, _uv: (f32, f32)) -> Option<u32> {
+        Some(self.priority)
+    }
 }
 
 unsafe impl Send for PassthroughFilter {}
@@ -0,0 +1,394 @@
+use crate::curves::{radiometric_and_photometric_integrals, LUMINOUS_EFFICACY};
+use crate::materials::Material;
+use crate::math::*;
+use crate::world::{Distribution2D, TransportMode};
+
+// resolution of the grid used to importance sample the profile
+const SAMPLING_ROWS: usize = 180;
+const SAMPLING_COLUMNS: usize = 180;
+// fraction of the peak intensity that every cell of the sampling grid gets at least,
+// so that no direction the profile emits in can be missed
+const SAMPLING_FLOOR: f32 = 0.001;
+
+// tabulated luminous intensity of a luminaire, as measured in ies and eulumdat files.
+// angles are in the type C convention, where vertical angles go from 0 at the nadir to 180 at the zenith,
+// and horizontal angles go around the vertical axis. intensities are in candela.
+#[derive(Clone, Debug)]
+pub struct PhotometricProfile {
+    // in degrees
+    vertical_angles: Vec<f32>,
+    // in degrees, always covering a full turn starting at the first angle
+    horizontal_angles: Vec<f32>,
+    // one row of vertical_angles.len() intensities per horizontal angle
+    intensities: Vec<f32>,
+    peak: f32,
+}
+
+impl PhotometricProfile {
+    // takes the angles and candela values as they're laid out in the files, with one row of intensities per horizontal angle.
+    // the horizontal angles can cover a quadrant, half or all of the luminaire, and the rest is filled in by symmetry.
+    pub fn new(
+        vertical_angles: Vec<f32>,
+        horizontal_angles: Vec<f32>,
+        intensities: Vec<f32>,
+    ) -> Self {
+        let columns = vertical_angles.len();
+        assert!(columns > 0 && !horizontal_angles.is_empty());
+        assert_eq!(intensities.len(), columns * horizontal_angles.len());
+        let mut rows: Vec<(f32, Vec<f32>)> = horizontal_angles
+            .iter()
+            .zip(intensities.chunks_exact(columns))
+            .map(|(angle, row)| (*angle, row.to_vec()))
+            .collect();
+
+        let first = rows[0].0;
+        let last = rows[rows.len() - 1].0;
+        if rows.len() == 1 {
+            // rotationally symmetric
+            let row = rows[0].1.clone();
+            rows.push((first + 360.0, row));
+        } else {
+            // mirror across the plane through the last angle until a full turn is covered,
+            // which handles quadrant symmetry (0 to 90), bilateral symmetry (0 to 180, or 90 to 270) and full data
+            let mut span = last - first;
+            while span < 180.0 - 0.01 {
+                let mirror = rows[rows.len() - 1].0;
+                let mirrored: Vec<(f32, Vec<f32>)> = rows
+                    .iter()
+                    .rev()
+                    .skip(1)
+                    .map(|(angle, row)| (2.0 * mirror - angle, row.clone()))
+                    .collect();
+                rows.extend(mirrored);
+                span = rows[rows.len() - 1].0 - first;
+            }
+            if (span - 180.0).abs() < 0.01 {
+                let mirror = rows[rows.len() - 1].0;
+                let mirrored: Vec<(f32, Vec<f32>)> = rows
+                    .iter()
+                    .rev()
+                    .skip(1)
+                    .map(|(angle, row)| (2.0 * mirror - angle, row.clone()))
+                    .collect();
+                rows.extend(mirrored);
+            } else if span < 360.0 - 0.01 {
+                // full data that doesn't repeat the first plane at the end, so wrap around
+                let row = rows[0].1.clone();
+                rows.push((first + 360.0, row));
+            }
+        }
+
+        let peak = rows
+            .iter()
+            .flat_map(|(_, row)| row.iter())
+            .fold(0.0f32, |a, b| a.max(*b));
+        PhotometricProfile {
+            vertical_angles,
+            horizontal_angles: rows.iter().map(|(angle, _)| *angle).collect(),
+            intensities: rows
+                .iter()
+                .flat_map(|(_, row)| row.iter().map(|v| v.max(0.0)))
+                .collect(),
+            peak,
+        }
+    }
+
+    // intensity in the brightest direction, in candela
+    pub fn peak(&self) -> f32 {
+        self.peak
+    }
+
+    // the intensity integrated over the sphere, which is the luminous flux in lumens
+    pub fn lumens(&self) -> f32 {
+        const STEPS: usize = 180;
+        let mut sum = 0.0;
+        for row in 0..STEPS {
+            let vertical0 = 180.0 * row as f32 / STEPS as f32;
+            let vertical1 = 180.0 * (row + 1) as f32 / STEPS as f32;
+            let band = vertical0.to_radians().cos() - vertical1.to_radians().cos();
+            for column in 0..2 * STEPS {
+                let horizontal = 360.0 * (column as f32 + 0.5) / (2 * STEPS) as f32;
+                sum += self.evaluate_angles((vertical0 + vertical1) / 2.0, horizontal) * band;
+            }
+        }
+        // each band covers 2 pi times its difference in cosines, split between the columns
+        sum * 2.0 * PI / (2 * STEPS) as f32
+    }
+
+    // finds the segment of angles that angle is in, and how far along it is
+    fn locate(angles: &[f32], angle: f32) -> (usize, f32) {
+        if angles.len() == 1 {
+            return (0, 0.0);
+        }
+        let index = (angles.partition_point(|a| *a <= angle).max(1) - 1).min(angles.len() - 2);
+        let width = angles[index + 1] - angles[index];
+        let t = if width > 0.0 {
+            ((angle - angles[index]) / width).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        (index, t)
+    }
+
+    // intensity in candela at the given angles in degrees
+    pub fn evaluate_angles(&self, vertical: f32, horizontal: f32) -> f32 {
+        let first_vertical = self.vertical_angles[0];
+        let last_vertical = self.vertical_angles[self.vertical_angles.len() - 1];
+        if vertical < first_vertical - 0.001 || vertical > last_vertical + 0.001 {
+            return 0.0;
+        }
+        let first_horizontal = self.horizontal_angles[0];
+        let horizontal = first_horizontal + (horizontal - first_horizontal).rem_euclid(360.0);
+
+        let columns = self.vertical_angles.len();
+        let (v, vt) = PhotometricProfile::locate(&self.vertical_angles, vertical);
+        let (h, ht) = PhotometricProfile::locate(&self.horizontal_angles, horizontal);
+        let at = |h: usize, v: usize| self.intensities[h * columns + v.min(columns - 1)];
+        let row0 = at(h, v) * (1.0 - vt) + at(h, v + 1) * vt;
+        let row1 = at(h + 1, v) * (1.0 - vt) + at(h + 1, v + 1) * vt;
+        row0 * (1.0 - ht) + row1 * ht
+    }
+
+    // intensity in candela in a local direction, where +z is the nadir and +x is the 0 degree horizontal plane
+    pub fn evaluate(&self, local: Vec3) -> f32 {
+        let vertical = local.z().clamp(-1.0, 1.0).acos().to_degrees();
+        let horizontal = local.y().atan2(local.x()).to_degrees();
+        self.evaluate_angles(vertical, horizontal)
+    }
+}
+
+// a light with the angular distribution of a real luminaire, for use on small emitters like disks and rects.
+// the luminaire points along the surface normal, i.e. the normal is the nadir direction,
+// and the intensity in each direction is the profile's, in candela, spread over the emitting area.
+#[derive(Clone, Debug)]
+pub struct PhotometricLight {
+    pub color: CDF,
+    pub profile: PhotometricProfile,
+    // what the color is multiplied by so that one candela of the profile is one candela of light
+    pub scale: f32,
+    // the area of everything that emits with this light, in square meters. bound by the world
+    area: f32,
+    // over (horizontal angle / 360, vertical angle / 180)
+    distribution: Distribution2D,
+}
+
+impl PhotometricLight {
+    pub fn new(color: CDF, profile: PhotometricProfile) -> PhotometricLight {
+        let (_, photometric) = radiometric_and_photometric_integrals(
+            |lambda| color.evaluate_power(lambda),
+            crate::curves::EXTENDED_VISIBLE_RANGE,
+        );
+        let scale = if photometric > 0.0 {
+            1.0 / (LUMINOUS_EFFICACY * photometric)
+        } else {
+            0.0
+        };
+        let profile_peak = profile.peak();
+        let mut values = Vec::with_capacity(SAMPLING_ROWS * SAMPLING_COLUMNS);
+        for row in 0..SAMPLING_ROWS {
+            let vertical0 = 180.0 * row as f32 / SAMPLING_ROWS as f32;
+            let vertical1 = 180.0 * (row + 1) as f32 / SAMPLING_ROWS as f32;
+            // proportional to the solid angle of the band of directions in this row
+            let band = vertical0.to_radians().cos() - vertical1.to_radians().cos();
+            for column in 0..SAMPLING_COLUMNS {
+                let horizontal0 = 360.0 * column as f32 / SAMPLING_COLUMNS as f32;
+                let horizontal1 = 360.0 * (column + 1) as f32 / SAMPLING_COLUMNS as f32;
+                // conservatively take the largest intensity in the cell
+                let mut peak = 0.0f32;
+                for i in 0..3 {
+                    for j in 0..3 {
+                        let vertical = vertical0 + (vertical1 - vertical0) * i as f32 / 2.0;
+                        let horizontal = horizontal0 + (horizontal1 - horizontal0) * j as f32 / 2.0;
+                        peak = peak.max(profile.evaluate_angles(vertical, horizontal));
+                    }
+                }
+                let relative = if profile_peak > 0.0 {
+                    peak / profile_peak
+                } else {
+                    0.0
+                };
+                values.push(relative.max(SAMPLING_FLOOR) * band);
+            }
+        }
+        PhotometricLight {
+            color,
+            profile,
+            scale,
+            area: 1.0,
+            distribution: Distribution2D::new(&values, SAMPLING_COLUMNS, SAMPLING_ROWS),
+        }
+    }
+    pub const NAME: &'static str = "PhotometricLight";
+
+    pub fn bind(&mut self, area: f32) {
+        self.area = area;
+    }
+
+    // what the color is multiplied by to get the radiance leaving in the local direction.
+    // the emitter's projected area shrinks with the cosine, so the radiance grows to keep the intensity the profile's
+    fn radiance_factor(&self, local: Vec3) -> f32 {
+        let cos_theta = local.z().abs();
+        if cos_theta == 0.0 {
+            return 0.0;
+        }
+        self.scale * self.profile.evaluate(local) / (self.area * cos_theta)
+    }
+
+    // solid angle pdf of sampling the local direction
+    fn directional_pdf(&self, local: Vec3) -> f32 {
+        let cos_theta = local.z().clamp(-1.0, 1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+        let u = (local.y().atan2(local.x()) / (2.0 * PI)).rem_euclid(1.0);
+        let v = cos_theta.acos() / PI;
+        self.distribution.pdf((u, v)).0 / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+impl Material for PhotometricLight {
+    fn sample_emission(
+        &self,
+        point: Point3,
+        normal: Vec3,
+        _uv: (f32, f32),
+        wavelength_range: Bounds1D,
+        scatter_sample: Sample2D,
+        wavelength_sample: Sample1D,
+    ) -> Option<(Ray, SingleWavelength, PDF, PDF)> {
+        let ((u, v), _) = self.distribution.sample(scatter_sample);
+        let (phi, theta) = (u * 2.0 * PI, v * PI);
+        let local_wo = Vec3::new(
+            theta.sin() * phi.cos(),
+            theta.sin() * phi.sin(),
+            theta.cos(),
+        );
+        let directional_pdf = self.directional_pdf(local_wo);
+        if directional_pdf == 0.0 {
+            return None;
+        }
+        let frame = TangentFrame::from_normal(normal);
+        let object_wo = frame.to_world(&local_wo).normalized();
+        let (sw, pdf) = self
+            .color
+            .sample_power_and_pdf(wavelength_range, wavelength_sample);
+        Some((
+            Ray::new(point, object_wo),
+            sw.with_energy(sw.energy * self.radiance_factor(local_wo)),
+            PDF::from(directional_pdf),
+            pdf,
+        ))
+    }
+
+    fn sample_emission_spectra(
+        &self,
+        _uv: (f32, f32),
+        wavelength_range: Bounds1D,
+        wavelength_sample: Sample1D,
+    ) -> Option<(f32, PDF)> {
+        let (sw, pdf) = self
+            .color
+            .sample_power_and_pdf(wavelength_range, wavelength_sample);
+        Some((sw.lambda, pdf))
+    }
+
+    fn emission(
+        &self,
+        lambda: f32,
+        _uv: (f32, f32),
+        _transport_mode: TransportMode,
+        wi: Vec3,
+    ) -> SingleEnergy {
+        SingleEnergy::new(self.color.evaluate_power(lambda) * self.radiance_factor(wi))
+    }
+
+    fn emission_pdf(
+        &self,
+        _lambda: f32,
+        _uv: (f32, f32),
+        _transport_mode: TransportMode,
+        wo: Vec3,
+    ) -> PDF {
+        self.directional_pdf(wo).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curves;
+    use crate::sampler::IndependentSampler;
+
+    #[test]
+    fn test_photometric_light_sampling() {
+        // a downlight that's symmetric around the nadir, with no light going up
+        let profile = PhotometricProfile::new(
+            vec![0.0, 30.0, 60.0, 90.0],
+            vec![0.0],
+            vec![1000.0, 800.0, 200.0, 0.0],
+        );
+        assert!((profile.evaluate(Vec3::Z) - 1000.0).abs() < 0.01);
+        assert!((profile.evaluate_angles(45.0, 123.0) - 500.0).abs() < 0.01);
+        assert_eq!(profile.evaluate(-Vec3::Z), 0.0);
+        // 2 pi times the integral of the piecewise linear falloff against sin(theta), from 0 to 90 degrees
+        let lumens = profile.lumens();
+        assert!((lumens - 2126.3).abs() < 1.0, "{}", lumens);
+
+        let mut light = PhotometricLight::new(curves::cie_e(1.0).into(), profile);
+        light.bind(0.01);
+        let mut sampler = IndependentSampler::new(0, 0);
+        let n = 100000;
+        let mut integral = 0.0;
+        for _ in 0..n {
+            let wo = random_on_unit_sphere(sampler.draw_2d());
+            integral += light.directional_pdf(wo) * 4.0 * PI;
+        }
+        integral /= n as f32;
+        assert!((integral - 1.0).abs() < 0.02, "{}", integral);
+
+        for _ in 0..1000 {
+            let (ray, sw, pdf, _) = light
+                .sample_emission(
+                    Point3::ORIGIN,
+                    Vec3::Z,
+                    (0.0, 0.0),
+                    curves::EXTENDED_VISIBLE_RANGE,
+                    sampler.draw_2d(),
+                    sampler.draw_1d(),
+                )
+                .unwrap();
+            let evaluated = light.emission_pdf(
+                sw.lambda,
+                (0.0, 0.0),
+                TransportMode::Radiance,
+                ray.direction,
+            );
+            assert!(
+                (pdf.0 - evaluated.0).abs() < 0.001 * pdf.0,
+                "{:?} {:?}",
+                pdf,
+                evaluated
+            );
+        }
+
+        // the emitted luminous intensity is the profile's, no matter the area
+        let wo = Vec3::new(0.6, 0.0, 0.8);
+        let (_, photometric) = radiometric_and_photometric_integrals(
+            |lambda| {
+                light
+                    .emission(lambda, (0.0, 0.0), TransportMode::Importance, wo)
+                    .0
+            },
+            curves::EXTENDED_VISIBLE_RANGE,
+        );
+        let candela = LUMINOUS_EFFICACY * photometric * 0.01 * 0.8;
+        let expected = light.profile.evaluate(wo);
+        assert!(
+            (candela - expected).abs() < 0.01 * expected,
+            "{} {}",
+            candela,
+            expected
+        );
+    }
+}
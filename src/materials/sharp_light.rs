@@ -1,17 +1,18 @@
 use crate::materials::Material;
 use crate::math::*;
+use crate::texture::TexStack;
 use crate::world::TransportMode;
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct SharpLight {
     // pub color: Box<dyn SpectralPowerDistribution>,
-    pub color: CDF,
+    pub color: TexStack,
     pub sharpness: f32,
     pub sidedness: Sidedness,
 }
 
 impl SharpLight {
-    pub fn new(color: CDF, sharpness: f32, sidedness: Sidedness) -> SharpLight {
+    pub fn new(color: TexStack, sharpness: f32, sidedness: Sidedness) -> SharpLight {
         SharpLight {
             color,
             sharpness: 1.0 + sharpness,
@@ -19,6 +20,27 @@ impl SharpLight {
         }
     }
     pub const NAME: &'static str = "SharpLight";
+
+    // what the color is multiplied by to get the radiance straight out of the surface
+    pub fn peak(&self) -> f32 {
+        evaluate(Vec3::Z, self.sharpness)
+    }
+
+    // the lobe integrated against the cosine over the hemisphere,
+    // which is what the color is multiplied by to get the exitance on each emitting side
+    pub fn projected_solid_angle(&self) -> f32 {
+        const STEPS: usize = 256;
+        // only directions inside the lobe get any emission
+        let min_z = (1.0 - self.sharpness.powi(2).recip()).sqrt();
+        let width = (1.0 - min_z) / STEPS as f32;
+        let mut sum = 0.0;
+        for i in 0..STEPS {
+            let cos_phi = min_z + (i as f32 + 0.5) * width;
+            let sin_phi = (1.0 - cos_phi * cos_phi).sqrt();
+            sum += evaluate(Vec3::new(sin_phi, 0.0, cos_phi), self.sharpness) * cos_phi;
+        }
+        2.0 * PI * sum * width
+    }
 }
 
 fn evaluate(vec: Vec3, sharpness: f32) -> f32 {
@@ -58,6 +80,7 @@ impl Material for SharpLight {
         &self,
         point: Point3,
         normal: Vec3,
+        uv: (f32, f32),
         wavelength_range: Bounds1D,
         mut scatter_sample: Sample2D,
         wavelength_sample: Sample1D,
@@ -89,7 +112,12 @@ impl Material for SharpLight {
             non_normalized_local_wo = -non_normalized_local_wo;
         }
 
-        assert!(fac.is_finite(), "{:?}, {:?}", self, non_normalized_local_wo);
+        assert!(
+            fac.is_finite(),
+            "{:?}, {:?}",
+            self.sharpness,
+            non_normalized_local_wo
+        );
         // needs to be converted to object space in a way that respects the surface normal
         let frame = TangentFrame::from_normal(normal);
         let object_wo = frame
@@ -99,7 +127,7 @@ impl Material for SharpLight {
         // debug_assert!(directional_pdf > 0.0, "{:?} {:?}", local_wo, object_wo);
         let (sw, pdf) = self
             .color
-            .sample_power_and_pdf(wavelength_range, wavelength_sample);
+            .sample_power_and_pdf(uv, wavelength_range, wavelength_sample);
         // fac both affects the power of the emitted light and the pdf.
         Some((
             Ray::new(point, object_wo),
@@ -111,20 +139,20 @@ impl Material for SharpLight {
 
     fn sample_emission_spectra(
         &self,
-        _uv: (f32, f32),
+        uv: (f32, f32),
         wavelength_range: Bounds1D,
         wavelength_sample: Sample1D,
     ) -> Option<(f32, PDF)> {
         let (sw, pdf) = self
             .color
-            .sample_power_and_pdf(wavelength_range, wavelength_sample);
+            .sample_power_and_pdf(uv, wavelength_range, wavelength_sample);
         Some((sw.lambda, pdf))
     }
 
     fn emission(
         &self,
         lambda: f32,
-        _uv: (f32, f32),
+        uv: (f32, f32),
         _transport_mode: TransportMode,
         wi: Vec3,
     ) -> SingleEnergy {
@@ -139,7 +167,7 @@ impl Material for SharpLight {
             if cosine > min_z {
                 // could have been generated
                 let fac = evaluate(wi, self.sharpness);
-                SingleEnergy::new(fac * self.color.evaluate_power(lambda))
+                SingleEnergy::new(fac * self.color.eval_at(lambda, uv))
             } else {
                 SingleEnergy::ZERO
             }
@@ -174,21 +202,30 @@ impl Material for SharpLight {
     }
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     use crate::curves;
-//     #[test]
-//     fn test_integral() {
-//         let light = SharpLight::new(curves::void(), 4.0, Sidedness::Forward);
-//         for _ in 0..10000 {
-//             let generated = light.sample_emission(
-//                 Point3::ORIGIN,
-//                 Vec3::Z,
-//                 curves::EXTENDED_VISIBLE_RANGE,
-//                 Sample2D::new_random_sample(),
-//                 Sample1D::new_random_sample(),
-//             );
-//         }
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curves;
+
+    #[test]
+    fn test_integral() {
+        // with no sharpness, the lobe is the cosine distribution
+        let light = SharpLight::new(
+            CDF::from(curves::cie_e(1.0)).into(),
+            0.0,
+            Sidedness::Forward,
+        );
+        assert!((light.peak() - 1.0 / PI).abs() < 0.0001);
+        let projected = light.projected_solid_angle();
+        assert!((projected - 2.0 / 3.0).abs() < 0.001, "{}", projected);
+
+        // sharper lobes keep the same radiance straight out of the surface, but emit into a narrower cone
+        let sharp = SharpLight::new(
+            CDF::from(curves::cie_e(1.0)).into(),
+            4.0,
+            Sidedness::Forward,
+        );
+        assert!((sharp.peak() - light.peak()).abs() < 0.0001);
+        assert!(sharp.projected_solid_angle() < projected);
+    }
+}
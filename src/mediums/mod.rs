@@ -1,8 +1,19 @@
+use crate::aabb::AABB;
 use crate::math::*;
 
+mod grid;
+mod phase;
+mod stack;
+
+pub use grid::{GridEmission, GridMedium, VoxelGrid};
+pub use phase::{PhaseFunction, TabulatedPhase};
+pub use stack::MediumStack;
+
 use std::marker::{Send, Sync};
 
 pub trait Medium {
+    // the phase function, normalized over the sphere. sigma_s is accounted for by sample, not here.
+    // wi and wo both point away from the scattering point.
     fn p(&self, lambda: f32, uvw: (f32, f32, f32), wi: Vec3, wo: Vec3) -> f32;
     fn sample_p(
         &self,
@@ -12,12 +23,25 @@ pub trait Medium {
         sample: Sample2D,
     ) -> (Vec3, f32);
     fn sample(&self, lambda: f32, ray: Ray, s: Sample1D) -> (Point3, f32, bool);
-    fn tr(&self, lambda: f32, p0: Point3, p1: Point3) -> f32;
+    // s seeds the estimate for mediums that can't evaluate the transmittance exactly,
+    // so that it comes from the integrator's sampler like everything else
+    fn tr(&self, lambda: f32, p0: Point3, p1: Point3, s: Sample1D) -> f32;
+    // emitted radiance per unit extinction, sigma_a * Le / sigma_t, at the world space point uvw.
+    // this is what gets added at every real collision sampled by `sample`, weighted by the throughput before that collision.
     fn emission(&self, _lambda: f32, _wo: Vec3, _uvw: (f32, f32, f32)) -> SingleEnergy {
         0.0.into()
     }
-    fn sample_emission(&self, _lambda: f32, _uvw: (f32, f32, f32)) -> (Vec3, SingleEnergy) {
-        (Vec3::ZERO, 0.0.into())
+    // samples a point and direction for emission, for light tracing.
+    // returns the point, direction, emitted radiance per unit length sigma_a * Le,
+    // and the pdfs of the point (per unit volume) and the direction (per unit solid angle).
+    // only mediums with known bounds can do this.
+    fn sample_emission(
+        &self,
+        _lambda: f32,
+        _point_sample: Sample3D,
+        _direction_sample: Sample2D,
+    ) -> Option<(Point3, Vec3, SingleEnergy, PDF, PDF)> {
+        None
     }
 }
 
@@ -32,61 +56,45 @@ pub fn phase_hg(cos_theta: f32, g: f32) -> f32 {
     );
     (1.0 - g * g) / (denom * denom.sqrt() * 2.0 * std::f32::consts::TAU)
 }
+
+// uniformly samples a point within local space bounds, returning it in local and world space
+// along with the world space volume of the bounds
+fn sample_bounds(
+    bounds: &AABB,
+    transform: &Option<Transform3>,
+    s: Sample3D,
+) -> (Point3, Point3, f32) {
+    let extent = bounds.max - bounds.min;
+    let local_point = bounds.min + Vec3::new(extent.x() * s.x, extent.y() * s.y, extent.z() * s.z);
+    let mut volume = extent.x() * extent.y() * extent.z();
+    let point = match transform {
+        Some(transform) => {
+            let transformed_axes = transform.axis_transform();
+            volume *=
+                transformed_axes.0.norm() * transformed_axes.1.norm() * transformed_axes.2.norm();
+            transform.to_world(local_point)
+        }
+        None => local_point,
+    };
+    (local_point, point, volume)
+}
 #[derive(Clone)]
-pub struct HenyeyGreensteinHomogeneous {
-    pub g: SPD,       // valid range: 0.0 to 2.0. actual g == g.evaluate_power(lambda) - 1.0
-    pub sigma_t: SPD, // transmittance attenuation
-    pub sigma_s: SPD, // scattering attenuation
+pub struct HomogeneousMedium {
+    pub phase: PhaseFunction,
+    pub sigma_t: SPD,          // transmittance attenuation
+    pub sigma_s: SPD,          // scattering attenuation
+    pub emission: Option<SPD>, // emitted radiance, Le
+    // where the medium is, see MediumEnum::bind. only needed for sampling the emission
+    pub bounds: Option<AABB>,
+    pub transform: Option<Transform3>,
 }
 
-impl Medium for HenyeyGreensteinHomogeneous {
+impl Medium for HomogeneousMedium {
     fn p(&self, lambda: f32, _uvw: (f32, f32, f32), wi: Vec3, wo: Vec3) -> f32 {
-        let cos_theta = wi * wo;
-
-        let g = self.g.evaluate_power(lambda) + 0.001 - 1.0;
-        let phase = phase_hg(cos_theta, g);
-        let sigma_s = self.sigma_s.evaluate_power(lambda);
-
-        let v = sigma_s * phase;
-        debug_assert!(
-            v.is_finite(),
-            "{:?}, {:?}, {:?}, {:?}",
-            sigma_s,
-            phase,
-            g,
-            cos_theta
-        );
-        v
+        self.phase.p(lambda, wi, wo)
     }
     fn sample_p(&self, lambda: f32, _uvw: (f32, f32, f32), wi: Vec3, s: Sample2D) -> (Vec3, f32) {
-        // just do isomorphic as a test
-        let g = self.g.evaluate_power(lambda) + 0.001 - 1.0;
-        let cos_theta = if g.abs() < 0.001 {
-            1.0 - 2.0 * s.x
-        } else {
-            let sqr = (1.0 - g * g) / (1.0 + g - 2.0 * g * s.x);
-            -(1.0 + g * g - sqr * sqr) / (2.0 * g)
-        };
-        // println!("{} {}", cos_theta, g);
-
-        let sin_theta = (0.0f32).max(1.0 - cos_theta * cos_theta).sqrt();
-        let phi = std::f32::consts::TAU * s.y;
-        let frame = TangentFrame::from_normal(wi);
-        let (sin_phi, cos_phi) = phi.sin_cos();
-        let wo = Vec3::new(sin_theta * cos_phi, sin_theta * sin_phi, cos_theta);
-        let phase = phase_hg(cos_theta, g);
-        let sigma_s = self.sigma_s.evaluate_power(lambda);
-        let v = sigma_s * phase;
-        debug_assert!(
-            v.is_finite(),
-            "{:?}, {:?}, {:?}, {:?}",
-            sigma_s,
-            phase,
-            g,
-            cos_theta
-        );
-
-        (frame.to_world(&wo), v)
+        self.phase.sample_p(lambda, wi, s)
     }
     fn sample(&self, lambda: f32, ray: Ray, s: Sample1D) -> (Point3, f32, bool) {
         let sigma_t = self.sigma_t.evaluate_power(lambda);
@@ -95,7 +103,7 @@ impl Medium for HenyeyGreensteinHomogeneous {
         let sampled_medium = t < ray.tmax;
 
         let point = ray.point_at_parameter(t);
-        let tr = self.tr(lambda, ray.origin, point);
+        let tr = self.tr(lambda, ray.origin, point, s);
         // could add HWSS here.
         let density = if sampled_medium { sigma_t * tr } else { tr };
         let pdf = density;
@@ -105,46 +113,121 @@ impl Medium for HenyeyGreensteinHomogeneous {
             (point, tr / pdf, false)
         }
     }
-    fn tr(&self, lambda: f32, p0: Point3, p1: Point3) -> f32 {
+    fn tr(&self, lambda: f32, p0: Point3, p1: Point3, _s: Sample1D) -> f32 {
         let sigma_t = self.sigma_t.evaluate_power(lambda);
         (-sigma_t * (p1 - p0).norm()).exp()
     }
+    fn emission(&self, lambda: f32, _wo: Vec3, _uvw: (f32, f32, f32)) -> SingleEnergy {
+        match &self.emission {
+            Some(emission) => {
+                let sigma_t = self.sigma_t.evaluate_power(lambda);
+                if sigma_t <= 0.0 {
+                    return 0.0.into();
+                }
+                let sigma_a = (sigma_t - self.sigma_s.evaluate_power(lambda)).max(0.0);
+                (emission.evaluate_power(lambda) * sigma_a / sigma_t).into()
+            }
+            None => 0.0.into(),
+        }
+    }
+    // uniformly samples a point within the bounds and a direction on the sphere.
+    // the bounds are a box around the instance the medium is inside of, so the caller has to reject points outside of it
+    fn sample_emission(
+        &self,
+        lambda: f32,
+        point_sample: Sample3D,
+        direction_sample: Sample2D,
+    ) -> Option<(Point3, Vec3, SingleEnergy, PDF, PDF)> {
+        let (emission, bounds) = match (&self.emission, &self.bounds) {
+            (Some(emission), Some(bounds)) => (emission, bounds),
+            _ => return None,
+        };
+        let (_, point, volume) = sample_bounds(bounds, &self.transform, point_sample);
+        let sigma_a =
+            (self.sigma_t.evaluate_power(lambda) - self.sigma_s.evaluate_power(lambda)).max(0.0);
+        Some((
+            point,
+            random_on_unit_sphere(direction_sample),
+            (sigma_a * emission.evaluate_power(lambda)).into(),
+            (1.0 / volume).into(),
+            (1.0 / (4.0 * PI)).into(),
+        ))
+    }
 }
 
 #[derive(Clone)]
 pub enum MediumEnum {
-    HenyeyGreensteinHomogeneous(HenyeyGreensteinHomogeneous),
+    Homogeneous(HomogeneousMedium),
+    Grid(GridMedium),
 }
 
 impl Medium for MediumEnum {
     fn p(&self, lambda: f32, uvw: (f32, f32, f32), wi: Vec3, wo: Vec3) -> f32 {
         match self {
-            MediumEnum::HenyeyGreensteinHomogeneous(inner) => inner.p(lambda, uvw, wi, wo),
+            MediumEnum::Homogeneous(inner) => inner.p(lambda, uvw, wi, wo),
+            MediumEnum::Grid(inner) => inner.p(lambda, uvw, wi, wo),
         }
     }
     fn sample_p(&self, lambda: f32, uvw: (f32, f32, f32), wi: Vec3, s: Sample2D) -> (Vec3, f32) {
         match self {
-            MediumEnum::HenyeyGreensteinHomogeneous(inner) => inner.sample_p(lambda, uvw, wi, s),
+            MediumEnum::Homogeneous(inner) => inner.sample_p(lambda, uvw, wi, s),
+            MediumEnum::Grid(inner) => inner.sample_p(lambda, uvw, wi, s),
         }
     }
     fn sample(&self, lambda: f32, ray: Ray, s: Sample1D) -> (Point3, f32, bool) {
         match self {
-            MediumEnum::HenyeyGreensteinHomogeneous(inner) => inner.sample(lambda, ray, s),
+            MediumEnum::Homogeneous(inner) => inner.sample(lambda, ray, s),
+            MediumEnum::Grid(inner) => inner.sample(lambda, ray, s),
         }
     }
-    fn tr(&self, lambda: f32, p0: Point3, p1: Point3) -> f32 {
+    fn tr(&self, lambda: f32, p0: Point3, p1: Point3, s: Sample1D) -> f32 {
         match self {
-            MediumEnum::HenyeyGreensteinHomogeneous(inner) => inner.tr(lambda, p0, p1),
+            MediumEnum::Homogeneous(inner) => inner.tr(lambda, p0, p1, s),
+            MediumEnum::Grid(inner) => inner.tr(lambda, p0, p1, s),
         }
     }
     fn emission(&self, lambda: f32, wo: Vec3, uvw: (f32, f32, f32)) -> SingleEnergy {
         match self {
-            MediumEnum::HenyeyGreensteinHomogeneous(inner) => inner.emission(lambda, wo, uvw),
+            MediumEnum::Homogeneous(inner) => inner.emission(lambda, wo, uvw),
+            MediumEnum::Grid(inner) => inner.emission(lambda, wo, uvw),
         }
     }
-    fn sample_emission(&self, lambda: f32, uvw: (f32, f32, f32)) -> (Vec3, SingleEnergy) {
+    fn sample_emission(
+        &self,
+        lambda: f32,
+        point_sample: Sample3D,
+        direction_sample: Sample2D,
+    ) -> Option<(Point3, Vec3, SingleEnergy, PDF, PDF)> {
         match self {
-            MediumEnum::HenyeyGreensteinHomogeneous(inner) => inner.sample_emission(lambda, uvw),
+            MediumEnum::Homogeneous(inner) => {
+                inner.sample_emission(lambda, point_sample, direction_sample)
+            }
+            MediumEnum::Grid(inner) => {
+                inner.sample_emission(lambda, point_sample, direction_sample)
+            }
+        }
+    }
+}
+
+impl MediumEnum {
+    // places the medium in the world, filling the local space bounds of the instance it's inside of
+    pub fn bind(&mut self, bounds: AABB, transform: Option<Transform3>) {
+        match self {
+            MediumEnum::Homogeneous(inner) => {
+                inner.bounds = Some(bounds);
+                inner.transform = transform;
+            }
+            MediumEnum::Grid(inner) => {
+                inner.bounds = bounds;
+                inner.transform = transform;
+            }
+        }
+    }
+    // whether this medium can be used as a light source, see Medium::sample_emission
+    pub fn has_sampleable_emission(&self) -> bool {
+        match self {
+            MediumEnum::Homogeneous(inner) => inner.emission.is_some() && inner.bounds.is_some(),
+            MediumEnum::Grid(inner) => inner.emission.is_some(),
         }
     }
 }
@@ -153,3 +236,74 @@ unsafe impl Send for MediumEnum {}
 unsafe impl Sync for MediumEnum {}
 
 pub type MediumTable = Vec<MediumEnum>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curves;
+
+    #[test]
+    fn test_homogeneous_sampling_weight() {
+        let medium = HomogeneousMedium {
+            phase: PhaseFunction::Rayleigh,
+            sigma_t: curves::cie_e(1.0),
+            sigma_s: curves::cie_e(0.5),
+            emission: Some(curves::cie_e(2.0)),
+            bounds: None,
+            transform: None,
+        };
+        let ray = Ray::new_with_time_and_tmax(Point3::ORIGIN, Vec3::Z, 0.0, 100.0);
+        // scattering events are weighted by the single scattering albedo
+        let (_point, weight, scattered) = medium.sample(550.0, ray, Sample1D { x: 0.5 });
+        assert!(scattered);
+        assert!((weight - 0.5).abs() < 0.001, "{}", weight);
+        // and passing through is weighted by 1
+        let short = Ray::new_with_time_and_tmax(Point3::ORIGIN, Vec3::Z, 0.0, 0.01);
+        let (_point, weight, scattered) = medium.sample(550.0, short, Sample1D { x: 0.5 });
+        assert!(!scattered);
+        assert!((weight - 1.0).abs() < 0.001, "{}", weight);
+
+        // half of the extinction is absorption, so half of Le is emitted per unit extinction
+        let emission = medium.emission(550.0, Vec3::Z, (0.0, 0.0, 0.0));
+        assert!((emission.0 - 1.0).abs() < 0.001, "{:?}", emission);
+    }
+
+    #[test]
+    fn test_homogeneous_emission_sampling() {
+        let mut medium = MediumEnum::Homogeneous(HomogeneousMedium {
+            phase: PhaseFunction::Rayleigh,
+            sigma_t: curves::cie_e(1.0),
+            sigma_s: curves::cie_e(0.5),
+            emission: Some(curves::cie_e(2.0)),
+            bounds: None,
+            transform: None,
+        });
+        // it has to know where it is to be sampled
+        assert!(!medium.has_sampleable_emission());
+        medium.bind(
+            AABB::new(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0)),
+            Some(Transform3::from_scale(Vec3::new(2.0, 2.0, 2.0))),
+        );
+        assert!(medium.has_sampleable_emission());
+        let (point, _, emission, point_pdf, direction_pdf) = medium
+            .sample_emission(
+                550.0,
+                Sample3D {
+                    x: 0.75,
+                    y: 0.5,
+                    z: 0.25,
+                },
+                Sample2D::new(0.5, 0.5),
+            )
+            .unwrap();
+        assert!(
+            (point - Point3::new(1.0, 0.0, -1.0)).norm() < 0.001,
+            "{:?}",
+            point
+        );
+        // sigma_a * Le per unit length, over the 4x4x4 box
+        assert!((emission.0 - 1.0).abs() < 0.001, "{:?}", emission);
+        assert!((point_pdf.0 - 1.0 / 64.0).abs() < 0.0001, "{:?}", point_pdf);
+        assert!((direction_pdf.0 - 1.0 / (4.0 * PI)).abs() < 0.0001);
+    }
+}
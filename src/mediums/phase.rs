@@ -0,0 +1,339 @@
+use super::phase_hg;
+use crate::math::*;
+
+// all phase functions here are normalized over the sphere, and are importance sampled exactly,
+// so the pdf of sample_p is always the same as p.
+// like phase_hg, cos_theta is wi * wo with both pointing away from the scattering point,
+// so forward scattering is cos_theta == -1.
+
+// g is stored as an SPD with the same convention as the HG medium, g == g.evaluate_power(lambda) - 1.0
+fn g_at(g: &SPD, lambda: f32) -> f32 {
+    g.evaluate_power(lambda) + 0.001 - 1.0
+}
+
+// inverts the cdf of HG, returning cos_theta
+fn sample_hg(g: f32, x: f32) -> f32 {
+    if g.abs() < 0.001 {
+        1.0 - 2.0 * x
+    } else {
+        let sqr = (1.0 - g * g) / (1.0 + g - 2.0 * g * x);
+        -(1.0 + g * g - sqr * sqr) / (2.0 * g)
+    }
+}
+
+// (1 + alpha * mu^2) / (4pi (1 + alpha / 3)), which is rayleigh when alpha == 1
+fn phase_polynomial(mu: f32, alpha: f32) -> f32 {
+    (1.0 + alpha * mu * mu) / (4.0 * PI * (1.0 + alpha / 3.0))
+}
+
+// inverts the cdf of phase_polynomial by solving the depressed cubic mu^3 + p mu + q == 0 with cardano's formula
+fn sample_polynomial(alpha: f32, x: f32) -> f32 {
+    let p = 3.0 / alpha;
+    let q = p * (1.0 + alpha / 3.0) * (1.0 - 2.0 * x);
+    let d = (q * q / 4.0 + p * p * p / 27.0).sqrt();
+    ((-q / 2.0 + d).cbrt() + (-q / 2.0 - d).cbrt())
+        .max(-1.0)
+        .min(1.0)
+}
+
+// see Draine 2003, "Scattering by Interstellar Dust Grains", with mu being the cosine of the scattering angle
+fn phase_draine(mu: f32, g: f32, alpha: f32) -> f32 {
+    let denom = 1.0 + g * g - 2.0 * g * mu;
+    (1.0 - g * g) / (denom * denom.sqrt()) * (1.0 + alpha * mu * mu)
+        / (4.0 * PI * (1.0 + alpha * (1.0 + 2.0 * g * g) / 3.0))
+}
+
+// closed form inversion of the cdf of the draine phase function,
+// see Jendersie and d'Eon 2023, "An Approximate Mie Scattering Function for Fog and Cloud Rendering"
+fn sample_draine(g: f32, alpha: f32, x: f32) -> f32 {
+    if alpha < 0.0001 {
+        return -sample_hg(g, x);
+    }
+    if g.abs() < 0.001 {
+        return sample_polynomial(alpha, x);
+    }
+    // done in f64, since the intermediate terms cancel catastrophically in f32
+    let (g, a, u) = (g as f64, alpha as f64, x as f64);
+    let g2 = g * g;
+    let g3 = g * g2;
+    let g4 = g2 * g2;
+    let g6 = g2 * g4;
+    let pgp1_2 = (1.0 + g2) * (1.0 + g2);
+    let t1a = -a + a * g4;
+    let t2 = -1296.0 * (-1.0 + g2) * (a - a * g2) * t1a * (4.0 * g2 + a * pgp1_2);
+    let t3 = 3.0 * g2 * (1.0 + g * (-1.0 + 2.0 * u))
+        + a * (2.0 + g2 + g3 * (1.0 + 2.0 * g2) * (-1.0 + 2.0 * u));
+    let t4a = 432.0 * t1a * t1a * t1a + t2 + 432.0 * (a - a * g2) * t3 * t3;
+    let t4b = -144.0 * a * g2 + 288.0 * a * g4 - 144.0 * a * g6;
+    let t4 = t4a + (-4.0 * t4b * t4b * t4b + t4a * t4a).sqrt();
+    let t4p3 = t4.cbrt();
+    let cbrt2 = 2.0f64.cbrt();
+    let t6 = (2.0 * t1a
+        + (48.0 * cbrt2 * (-(a * g2) + 2.0 * a * g4 - a * g6)) / t4p3
+        + t4p3 / (3.0 * cbrt2))
+        / (a - a * g2);
+    let t5 = 6.0 * (1.0 + g2) + t6;
+    let inner = -0.5 * t5.sqrt()
+        + (6.0 * (1.0 + g2) - (8.0 * t3) / (a * (-1.0 + g2) * t5.sqrt()) - t6).sqrt() / 2.0;
+    let mu = (1.0 + g2 - inner * inner) / (2.0 * g);
+    (mu as f32).max(-1.0).min(1.0)
+}
+
+// phase functions tabulated per wavelength, such as ones computed with lorenz-mie theory.
+// each table is piecewise constant over uniform bins of mu, the cosine of the scattering angle,
+// and the phase at wavelengths in between tables is linearly interpolated.
+#[derive(Clone)]
+pub struct TabulatedPhase {
+    pub wavelengths: Vec<f32>,
+    pub tables: Vec<Vec<f32>>,
+    cdfs: Vec<Vec<f32>>,
+}
+
+impl TabulatedPhase {
+    // curves are the phase as a function of the scattering angle in degrees, one for each wavelength.
+    // they don't need to be normalized.
+    pub fn new(wavelengths: Vec<f32>, curves: Vec<SPD>, resolution: usize) -> Self {
+        assert!(wavelengths.len() == curves.len() && !curves.is_empty());
+        assert!(wavelengths.windows(2).all(|w| w[0] < w[1]));
+        let mut tables = Vec::new();
+        let mut cdfs = Vec::new();
+        for curve in curves.iter() {
+            let mut table: Vec<f32> = (0..resolution)
+                .map(|i| {
+                    let mu = -1.0 + 2.0 * (i as f32 + 0.5) / resolution as f32;
+                    curve.evaluate(mu.acos().to_degrees()).max(0.0)
+                })
+                .collect();
+            let sum: f32 = table.iter().sum();
+            assert!(sum > 0.0, "tabulated phase function was 0 everywhere");
+            let mut cdf = Vec::with_capacity(resolution + 1);
+            cdf.push(0.0);
+            let mut acc = 0.0;
+            for value in table.iter_mut() {
+                acc += *value / sum;
+                cdf.push(acc);
+                // each bin covers a solid angle of 4pi / resolution
+                *value *= resolution as f32 / (4.0 * PI * sum);
+            }
+            tables.push(table);
+            cdfs.push(cdf);
+        }
+        TabulatedPhase {
+            wavelengths,
+            tables,
+            cdfs,
+        }
+    }
+
+    // the two tables that bracket lambda, and how far lambda is between them
+    fn bracket(&self, lambda: f32) -> (usize, usize, f32) {
+        let n = self.wavelengths.len();
+        if lambda <= self.wavelengths[0] {
+            return (0, 0, 0.0);
+        }
+        if lambda >= self.wavelengths[n - 1] {
+            return (n - 1, n - 1, 0.0);
+        }
+        let upper = self.wavelengths.iter().position(|&w| w > lambda).unwrap();
+        let lower = upper - 1;
+        let t = (lambda - self.wavelengths[lower])
+            / (self.wavelengths[upper] - self.wavelengths[lower]);
+        (lower, upper, t)
+    }
+
+    fn bin(&self, mu: f32) -> usize {
+        let resolution = self.tables[0].len();
+        (((mu + 1.0) / 2.0 * resolution as f32) as usize).min(resolution - 1)
+    }
+
+    pub fn eval(&self, lambda: f32, mu: f32) -> f32 {
+        let (lower, upper, t) = self.bracket(lambda);
+        let bin = self.bin(mu);
+        self.tables[lower][bin] * (1.0 - t) + self.tables[upper][bin] * t
+    }
+
+    // samples the mixture of the two bracketing tables, which is exactly the interpolated phase
+    pub fn sample(&self, lambda: f32, mut x: f32) -> f32 {
+        let (lower, upper, t) = self.bracket(lambda);
+        let table = if x < 1.0 - t {
+            x /= 1.0 - t;
+            lower
+        } else {
+            x = (x - (1.0 - t)) / t;
+            upper
+        };
+        let cdf = &self.cdfs[table];
+        let resolution = cdf.len() - 1;
+        let bin = cdf
+            .iter()
+            .position(|&c| c > x)
+            .unwrap_or(resolution)
+            .max(1)
+            .min(resolution)
+            - 1;
+        let width = cdf[bin + 1] - cdf[bin];
+        let offset = if width > 0.0 {
+            ((x - cdf[bin]) / width).max(0.0).min(1.0)
+        } else {
+            0.5
+        };
+        -1.0 + 2.0 * (bin as f32 + offset) / resolution as f32
+    }
+}
+
+#[derive(Clone)]
+pub enum PhaseFunction {
+    HenyeyGreenstein { g: SPD },
+    // weight is the probability of the first lobe
+    DoubleHenyeyGreenstein { g1: SPD, g2: SPD, weight: f32 },
+    Rayleigh,
+    Draine { g: SPD, alpha: f32 },
+    Tabulated(TabulatedPhase),
+}
+
+impl PhaseFunction {
+    pub fn p(&self, lambda: f32, wi: Vec3, wo: Vec3) -> f32 {
+        let cos_theta = wi * wo;
+        let phase = match self {
+            PhaseFunction::HenyeyGreenstein { g } => phase_hg(cos_theta, g_at(g, lambda)),
+            PhaseFunction::DoubleHenyeyGreenstein { g1, g2, weight } => {
+                weight * phase_hg(cos_theta, g_at(g1, lambda))
+                    + (1.0 - weight) * phase_hg(cos_theta, g_at(g2, lambda))
+            }
+            PhaseFunction::Rayleigh => phase_polynomial(cos_theta, 1.0),
+            PhaseFunction::Draine { g, alpha } => phase_draine(-cos_theta, g_at(g, lambda), *alpha),
+            PhaseFunction::Tabulated(table) => table.eval(lambda, -cos_theta),
+        };
+        debug_assert!(phase.is_finite(), "{:?}, {:?}", phase, cos_theta);
+        phase
+    }
+
+    pub fn sample_p(&self, lambda: f32, wi: Vec3, s: Sample2D) -> (Vec3, f32) {
+        let cos_theta = match self {
+            PhaseFunction::HenyeyGreenstein { g } => sample_hg(g_at(g, lambda), s.x),
+            PhaseFunction::DoubleHenyeyGreenstein { g1, g2, weight } => {
+                // pick a lobe and reuse the sample for it
+                if s.x < *weight {
+                    sample_hg(g_at(g1, lambda), s.x / weight)
+                } else {
+                    sample_hg(g_at(g2, lambda), (s.x - weight) / (1.0 - weight))
+                }
+            }
+            PhaseFunction::Rayleigh => sample_polynomial(1.0, s.x),
+            PhaseFunction::Draine { g, alpha } => -sample_draine(g_at(g, lambda), *alpha, s.x),
+            PhaseFunction::Tabulated(table) => -table.sample(lambda, s.x),
+        };
+
+        let sin_theta = (0.0f32).max(1.0 - cos_theta * cos_theta).sqrt();
+        let phi = std::f32::consts::TAU * s.y;
+        let frame = TangentFrame::from_normal(wi);
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        let wo = frame.to_world(&Vec3::new(
+            sin_theta * cos_phi,
+            sin_theta * sin_phi,
+            cos_theta,
+        ));
+        (wo, self.p(lambda, wi, wo))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curves;
+
+    fn test_phases() -> Vec<PhaseFunction> {
+        vec![
+            PhaseFunction::HenyeyGreenstein {
+                g: curves::cie_e(1.6),
+            },
+            PhaseFunction::DoubleHenyeyGreenstein {
+                g1: curves::cie_e(1.8),
+                g2: curves::cie_e(0.7),
+                weight: 0.7,
+            },
+            PhaseFunction::Rayleigh,
+            PhaseFunction::Draine {
+                g: curves::cie_e(1.6),
+                alpha: 0.5,
+            },
+            PhaseFunction::Draine {
+                g: curves::cie_e(1.0),
+                alpha: 1.0,
+            },
+            PhaseFunction::Tabulated(TabulatedPhase::new(
+                vec![450.0, 650.0],
+                vec![
+                    SPD::Linear {
+                        signal: vec![1.0],
+                        bounds: Bounds1D::new(0.0, 180.0),
+                        mode: InterpolationMode::Linear,
+                    },
+                    SPD::Linear {
+                        signal: vec![10.0, 1.0, 3.0],
+                        bounds: Bounds1D::new(0.0, 180.0),
+                        mode: InterpolationMode::Linear,
+                    },
+                ],
+                64,
+            )),
+        ]
+    }
+
+    #[test]
+    fn test_phase_functions_are_normalized() {
+        let wi = Vec3::Z;
+        for (index, phase) in test_phases().iter().enumerate() {
+            // the phase functions only depend on cos theta, so integrate over that
+            let n = 100000;
+            let mut integral = 0.0;
+            for i in 0..n {
+                let cos_theta = -1.0 + 2.0 * (i as f32 + 0.5) / n as f32;
+                let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                let wo = Vec3::new(sin_theta, 0.0, cos_theta);
+                integral += phase.p(550.0, wi, wo) * 2.0 / n as f32;
+            }
+            integral *= 2.0 * PI;
+            assert!((integral - 1.0).abs() < 0.01, "{} {}", index, integral);
+        }
+    }
+
+    #[test]
+    fn test_phase_sampling_matches_cdf() {
+        // the fraction of samples that land below some cos theta should match the integral of the phase function up to there
+        let wi = Vec3::Z;
+        for (index, phase) in test_phases().iter().enumerate() {
+            for &threshold in [-0.5f32, 0.0, 0.7].iter() {
+                let n = 20000;
+                let mut below = 0;
+                for i in 0..n {
+                    let s = Sample2D::new((i as f32 + 0.5) / n as f32, 0.3);
+                    let (wo, pdf) = phase.sample_p(550.0, wi, s);
+                    let p = phase.p(550.0, wi, wo);
+                    assert!((pdf - p).abs() <= 0.001 * p, "{} {} {}", index, pdf, p);
+                    if wo * wi < threshold {
+                        below += 1;
+                    }
+                }
+                let mut cdf = 0.0;
+                let m = 20000;
+                for i in 0..m {
+                    let cos_theta = -1.0 + (threshold + 1.0) * (i as f32 + 0.5) / m as f32;
+                    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                    let wo = Vec3::new(sin_theta, 0.0, cos_theta);
+                    cdf += phase.p(550.0, wi, wo) * (threshold + 1.0) / m as f32;
+                }
+                cdf *= 2.0 * PI;
+                let fraction = below as f32 / n as f32;
+                assert!(
+                    (fraction - cdf).abs() < 0.01,
+                    "{} {} {} {}",
+                    index,
+                    threshold,
+                    fraction,
+                    cdf
+                );
+            }
+        }
+    }
+}
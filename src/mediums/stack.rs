@@ -0,0 +1,133 @@
+// the set of volumes a path is inside of, used to resolve which medium it's in when volumes overlap or are nested.
+// see Schmidt and Budge 2002, "Simple Nested Dielectrics in Ray Traced Images".
+// each entry is a medium id along with the priority of the interface that was crossed to get into it.
+// the first entry is the world medium, which is never left.
+#[derive(Clone, Debug)]
+pub struct MediumStack {
+    entries: Vec<(usize, u32)>,
+}
+
+impl MediumStack {
+    pub fn new(world_medium_id: usize) -> Self {
+        MediumStack {
+            entries: vec![(world_medium_id, 0)],
+        }
+    }
+
+    // a stack for a path that starts inside medium_id without having crossed into it, like a camera placed underwater
+    pub fn starting_in(world_medium_id: usize, medium_id: usize) -> Self {
+        let mut stack = MediumStack::new(world_medium_id);
+        if medium_id != world_medium_id {
+            stack.entries.push((medium_id, 0));
+        }
+        stack
+    }
+
+    fn current_entry(&self) -> (usize, u32) {
+        // highest priority wins, and the most recently entered wins between equal priorities
+        let mut best = self.entries[0];
+        for entry in self.entries.iter().skip(1) {
+            if entry.1 >= best.1 {
+                best = *entry;
+            }
+        }
+        best
+    }
+
+    pub fn current(&self) -> usize {
+        self.current_entry().0
+    }
+
+    pub fn current_priority(&self) -> u32 {
+        self.current_entry().1
+    }
+
+    // an interface that's inside of a higher priority volume is a false intersection, and should be treated as if it wasn't there.
+    // the stack still needs to be updated when crossing it though.
+    pub fn is_false_interface(&self, priority: u32) -> bool {
+        priority < self.current_priority()
+    }
+
+    // moves the stack across an interface between outer_medium_id and inner_medium_id
+    pub fn cross(
+        &mut self,
+        outer_medium_id: usize,
+        inner_medium_id: usize,
+        priority: u32,
+        entering: bool,
+    ) {
+        if entering {
+            self.entries.push((inner_medium_id, priority));
+            return;
+        }
+        // leave the most recently entered matching volume, preferring one that was entered through an interface of the same priority
+        let position = self
+            .entries
+            .iter()
+            .skip(1)
+            .rposition(|e| *e == (inner_medium_id, priority))
+            .or_else(|| {
+                self.entries
+                    .iter()
+                    .skip(1)
+                    .rposition(|e| e.0 == inner_medium_id)
+            });
+        match position {
+            Some(index) => {
+                self.entries.remove(index + 1);
+            }
+            None => {
+                // left a volume that was never entered, so the only information about what's outside is the interface itself
+                if outer_medium_id != self.current() {
+                    self.entries.push((outer_medium_id, priority));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nested_priorities() {
+        // a glass (priority 2, vacuum inside) holding water (medium 2, priority 1), in a foggy room (medium 1)
+        let mut stack = MediumStack::new(1);
+        assert_eq!(stack.current(), 1);
+
+        // into the glass wall
+        assert!(!stack.is_false_interface(2));
+        stack.cross(1, 0, 2, true);
+        assert_eq!(stack.current(), 0);
+
+        // the water surface overlaps the glass wall, so it's hidden
+        assert!(stack.is_false_interface(1));
+        stack.cross(1, 2, 1, true);
+        assert_eq!(stack.current(), 0);
+
+        // out of the inner glass wall and into the water
+        assert!(!stack.is_false_interface(2));
+        stack.cross(1, 0, 2, false);
+        assert_eq!(stack.current(), 2);
+
+        // out through the water surface, back into the fog
+        assert!(!stack.is_false_interface(1));
+        stack.cross(1, 2, 1, false);
+        assert_eq!(stack.current(), 1);
+    }
+
+    #[test]
+    fn test_starting_inside() {
+        // an underwater camera looking up through the surface
+        let mut stack = MediumStack::starting_in(0, 2);
+        assert_eq!(stack.current(), 2);
+        stack.cross(0, 2, 0, false);
+        assert_eq!(stack.current(), 0);
+
+        // leaving a volume the path never entered falls back to the interface's outer medium
+        let mut stack = MediumStack::new(0);
+        stack.cross(3, 2, 0, false);
+        assert_eq!(stack.current(), 3);
+    }
+}
@@ -1,10 +1,20 @@
 use crate::parsing::curves::CurveData;
-use crate::parsing::Vec3Data;
+use crate::parsing::{Point3Data, Vec3Data};
 use serde::{Deserialize, Serialize};
 
 use crate::math::*;
 use crate::parsing::curves::parse_curve;
-use crate::world::EnvironmentMap;
+use crate::parsing::instance::{AxisAngleData, Transform3Data};
+use crate::parsing::texture::parse_hdr_rgb;
+use crate::world::{
+    sun_direction_from_location, EnvironmentMap, HosekWilkieDataset, HosekWilkieSky,
+    ImageEnvironment, Portal,
+};
+
+use math::spectral::InterpolationMode;
+
+use std::fs::File;
+use std::io::Read;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ConstantData {
@@ -20,11 +30,120 @@ pub struct SunData {
     pub sun_direction: Vec3Data,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LocationData {
+    pub latitude: f32,  // degrees, north is positive
+    pub longitude: f32, // degrees, east is positive
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: f32,             // local time, i.e. 13.5 is 1:30pm
+    pub timezone: Option<f32>, // hours offset from UTC, defaults to 0
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HosekWilkieData {
+    pub dataset: String, // path to ArHosekSkyModelData_Spectral.h from the reference implementation
+    pub turbidity: f32,  // 1 to 10
+    pub albedo: f32,     // ground albedo, 0 to 1
+    pub strength: f32,
+    pub sun_strength: Option<f32>,     // defaults to 1.0
+    pub angular_diameter: Option<f32>, // in radians, defaults to the sun's 0.0093
+    // one of these is required. x is east, y is north and z is up
+    pub sun_direction: Option<Vec3Data>,
+    pub location: Option<LocationData>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ImageEnvData {
+    pub filename: String, // equirectangular .exr or .hdr
+    pub strength: f32,
+    // spectral basis curves for the r, g and b channels. defaults to the simple spectral srgb basis
+    pub curves: Option<[CurveData; 3]>,
+    pub rotate: Option<Vec<AxisAngleData>>,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum EnvironmentData {
     Constant(ConstantData),
     Sun(SunData),
+    HosekWilkie(HosekWilkieData),
+    ImageEnv(ImageEnvData),
+}
+
+// a rect that the environment's light comes into the scene through, like a window. it's laid out like a Rect instance,
+// and the light comes in along the normal axis, or against it if flip is set.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PortalData {
+    pub size: (f32, f32),
+    pub normal: Axis,
+    pub origin: Point3Data,
+    pub flip: Option<bool>,
+    pub transform: Option<Transform3Data>,
+}
+
+fn default_rgb_basis() -> [CurveData; 3] {
+    let basis = |column| CurveData::TabulatedCSV {
+        filename: String::from("data/curves/basis/simple-spectral-srgb-1931.csv"),
+        column,
+        domain_mapping: None,
+        interpolation_mode: InterpolationMode::Cubic,
+    };
+    [basis(1), basis(2), basis(3)]
+}
+
+// finds the values of a c array named name, i.e. `static const double name[] = { ... };`
+fn parse_c_array(source: &str, name: &str) -> Vec<f32> {
+    let declaration = format!("{}[]", name);
+    let start = source
+        .find(&declaration)
+        .unwrap_or_else(|| panic!("couldn't find {} in sky dataset", name));
+    let open = start + source[start..].find('{').expect("malformed sky dataset");
+    let close = open + source[open..].find('}').expect("malformed sky dataset");
+    source[open + 1..close]
+        .split(',')
+        .map(|e| e.trim())
+        .filter(|e| !e.is_empty())
+        .map(|e| {
+            e.parse::<f32>()
+                .expect("failed to parse number in sky dataset")
+        })
+        .collect()
+}
+
+// reads the coefficients straight out of the reference implementation's header, which has
+// `datasets320` through `datasets720` and `datasetsRad320` through `datasetsRad720`
+pub fn load_hosek_wilkie_dataset(filename: &str) -> HosekWilkieDataset {
+    println!("loading sky dataset from {}", filename);
+    let mut file = File::open(filename).expect("failed to open sky dataset");
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .expect("failed to read sky dataset");
+    // strip comments, since they have numbers in them
+    let mut source = String::with_capacity(contents.len());
+    let mut rest = contents.as_str();
+    while !rest.is_empty() {
+        if rest.starts_with("//") {
+            rest = &rest[rest.find('\n').unwrap_or(rest.len())..];
+        } else if rest.starts_with("/*") {
+            rest = &rest[rest.find("*/").map(|i| i + 2).unwrap_or(rest.len())..];
+        } else {
+            let c = rest.chars().next().unwrap();
+            source.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    let mut config = Vec::new();
+    let mut radiance = Vec::new();
+    for wavelength in (320..=720).step_by(40) {
+        config.push(parse_c_array(&source, &format!("datasets{}", wavelength)));
+        radiance.push(parse_c_array(
+            &source,
+            &format!("datasetsRad{}", wavelength),
+        ));
+    }
+    HosekWilkieDataset::new(config, radiance)
 }
 
 pub fn parse_environment(env_data: EnvironmentData) -> EnvironmentMap {
@@ -39,5 +158,80 @@ pub fn parse_environment(env_data: EnvironmentData) -> EnvironmentMap {
             angular_diameter: data.angular_diameter,
             sun_direction: Vec3::from(data.sun_direction).normalized(),
         },
+        EnvironmentData::HosekWilkie(data) => {
+            println!("parsing HosekWilkie");
+            let sun_direction = match (data.sun_direction, data.location) {
+                (Some(direction), _) => Vec3::from(direction).normalized(),
+                (None, Some(location)) => sun_direction_from_location(
+                    location.latitude,
+                    location.longitude,
+                    location.year,
+                    location.month,
+                    location.day,
+                    location.hour,
+                    location.timezone.unwrap_or(0.0),
+                ),
+                (None, None) => panic!("HosekWilkie needs either a sun_direction or a location"),
+            };
+            println!("sun direction is {:?}", sun_direction);
+            let dataset = load_hosek_wilkie_dataset(&data.dataset);
+            EnvironmentMap::HosekWilkie(HosekWilkieSky::new(
+                &dataset,
+                sun_direction,
+                data.turbidity,
+                data.albedo,
+                data.angular_diameter.unwrap_or(0.0093),
+                data.strength,
+                data.sun_strength.unwrap_or(1.0),
+            ))
+        }
+        EnvironmentData::ImageEnv(data) => {
+            println!("parsing ImageEnv");
+            let [r, g, b] = data.curves.unwrap_or_else(default_rgb_basis);
+            let transform = data.rotate.map(|rotate| {
+                Transform3::from(Transform3Data {
+                    scale: None,
+                    rotate: Some(rotate),
+                    translate: None,
+                })
+            });
+            EnvironmentMap::ImageEnv(ImageEnvironment::new(
+                parse_hdr_rgb(&data.filename),
+                [
+                    parse_curve(r).into(),
+                    parse_curve(g).into(),
+                    parse_curve(b).into(),
+                ],
+                data.strength,
+                transform,
+            ))
+        }
+    }
+}
+
+pub fn parse_portal(data: PortalData) -> Portal {
+    let (width, height) = data.size;
+    // the same axes that AARect lays its size out along
+    let (edge0, edge1) = match data.normal {
+        Axis::X => (Vec3::new(0.0, 0.0, width), Vec3::new(0.0, height, 0.0)),
+        Axis::Y => (Vec3::new(width, 0.0, 0.0), Vec3::new(0.0, 0.0, height)),
+        Axis::Z => (Vec3::new(width, 0.0, 0.0), Vec3::new(0.0, height, 0.0)),
+    };
+    let mut inside = Vec3::from_axis(data.normal);
+    if data.flip.unwrap_or(false) {
+        inside = -inside;
+    }
+    let origin: Point3 = data.origin.into();
+    let corner = origin - (edge0 + edge1) / 2.0;
+    match data.transform {
+        Some(transform) => {
+            let transform: Transform3 = transform.into();
+            Portal::new(
+                transform.to_world(corner),
+                (transform.to_world(edge0), transform.to_world(edge1)),
+                transform.to_world(inside),
+            )
+        }
+        None => Portal::new(corner, (edge0, edge1), inside),
     }
 }
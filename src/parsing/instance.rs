@@ -34,6 +34,7 @@ pub struct InstanceData {
     pub aggregate: AggregateData,
     pub transform: Option<Transform3Data>,
     pub material_identifier: Option<String>,
+    pub light_group: Option<String>, // lights with the same group name are written to the same film when light groups are enabled
 }
 
 pub fn parse_instance(
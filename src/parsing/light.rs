@@ -0,0 +1,200 @@
+use crate::parsing::curves::{parse_curve, CurveData};
+use crate::parsing::{Point3Data, Vec3Data};
+use serde::{Deserialize, Serialize};
+
+use crate::curves::{
+    radiometric_and_photometric_integrals, EXTENDED_VISIBLE_RANGE, LUMINOUS_EFFICACY,
+};
+use crate::math::*;
+use crate::world::PunctualLight;
+
+// physical units for the strength of a light. without units, strength just scales the color.
+// watts and lumens are the total output of the light, or per square meter for area and directional lights,
+// and candela is the intensity at the light's peak, or per square meter (nits) for area lights.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum LightUnits {
+    Watts,
+    Lumens,
+    Candela,
+}
+
+impl LightUnits {
+    // what to scale a color by so that the light emits strength in these units.
+    // integrals are the color integrated over wavelength by itself and against y_bar,
+    // and solid_angle is what the light's intensity or radiance is integrated over to get its total output.
+    pub fn scale(self, strength: f32, integrals: (f32, f32), solid_angle: f32) -> f32 {
+        let (radiometric, photometric) = integrals;
+        let emitted = match self {
+            LightUnits::Watts => solid_angle * radiometric,
+            LightUnits::Lumens => solid_angle * LUMINOUS_EFFICACY * photometric,
+            LightUnits::Candela => LUMINOUS_EFFICACY * photometric,
+        };
+        if emitted > 0.0 {
+            strength / emitted
+        } else {
+            0.0
+        }
+    }
+}
+
+pub fn curve_integrals(color: &CDF) -> (f32, f32) {
+    radiometric_and_photometric_integrals(
+        |lambda| color.evaluate_power(lambda),
+        EXTENDED_VISIBLE_RANGE,
+    )
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum PunctualLightData {
+    Point {
+        position: Point3Data,
+        color: CurveData,
+        strength: f32,
+        units: Option<LightUnits>,
+        light_group: Option<String>,
+    },
+    Spot {
+        position: Point3Data,
+        direction: Vec3Data,
+        color: CurveData,
+        strength: f32,
+        cone_angle: f32, // degrees, from the center of the spot to its edge
+        falloff_start: Option<f32>, // degrees, where the falloff starts. defaults to cone_angle, which is a hard edge
+        units: Option<LightUnits>,
+        light_group: Option<String>,
+    },
+    Directional {
+        direction: Vec3Data, // the direction the light travels in
+        color: CurveData,
+        strength: f32,
+        units: Option<LightUnits>, // watts or lumens per square meter, i.e. irradiance or illuminance
+        light_group: Option<String>,
+    },
+}
+
+impl PunctualLightData {
+    pub fn light_group(&self) -> Option<String> {
+        match self {
+            PunctualLightData::Point { light_group, .. }
+            | PunctualLightData::Spot { light_group, .. }
+            | PunctualLightData::Directional { light_group, .. } => light_group.clone(),
+        }
+    }
+}
+
+pub fn parse_punctual_light(data: PunctualLightData) -> PunctualLight {
+    match data {
+        PunctualLightData::Point {
+            position,
+            color,
+            strength,
+            units,
+            ..
+        } => {
+            let color: CDF = parse_curve(color).into();
+            let strength = match units {
+                Some(units) => units.scale(strength, curve_integrals(&color), 4.0 * PI),
+                None => strength,
+            };
+            PunctualLight::Point {
+                position: position.into(),
+                color,
+                strength,
+            }
+        }
+        PunctualLightData::Spot {
+            position,
+            direction,
+            color,
+            strength,
+            cone_angle,
+            falloff_start,
+            units,
+            ..
+        } => {
+            let falloff_start = falloff_start.unwrap_or(cone_angle).min(cone_angle);
+            let (cos_cone, cos_falloff_start) = (
+                cone_angle.to_radians().cos(),
+                falloff_start.to_radians().cos(),
+            );
+            let color: CDF = parse_curve(color).into();
+            let strength = match units {
+                Some(units) => {
+                    // the solid angle of the cone, counting the smoothstep falloff as half
+                    let solid_angle = 2.0 * PI * (1.0 - 0.5 * (cos_cone + cos_falloff_start));
+                    units.scale(strength, curve_integrals(&color), solid_angle)
+                }
+                None => strength,
+            };
+            PunctualLight::Spot {
+                position: position.into(),
+                direction: Vec3::from(direction).normalized(),
+                color,
+                strength,
+                cos_cone,
+                cos_falloff_start,
+            }
+        }
+        PunctualLightData::Directional {
+            direction,
+            color,
+            strength,
+            units,
+            ..
+        } => {
+            let color: CDF = parse_curve(color).into();
+            let strength = match units {
+                Some(LightUnits::Candela) => {
+                    panic!("directional lights can't be given in candela, use watts or lumens per square meter")
+                }
+                Some(units) => units.scale(strength, curve_integrals(&color), 1.0),
+                None => strength,
+            };
+            PunctualLight::Directional {
+                direction: Vec3::from(direction).normalized(),
+                color,
+                strength,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curves;
+
+    #[test]
+    fn test_light_units() {
+        let color: CDF = curves::blackbody(5500.0, 1.0).into();
+        let integrals = curve_integrals(&color);
+        // a 100 watt point light
+        let scale = LightUnits::Watts.scale(100.0, integrals, 4.0 * PI);
+        let light = PunctualLight::Point {
+            position: Point3::ORIGIN,
+            color: color.clone(),
+            strength: scale,
+        };
+        let power = light.power(EXTENDED_VISIBLE_RANGE, 1.0);
+        assert!((power - 100.0).abs() < 1.0, "{}", power);
+
+        // a 1000 lumen point light
+        let scale = LightUnits::Lumens.scale(1000.0, integrals, 4.0 * PI);
+        let (_, luminous) = radiometric_and_photometric_integrals(
+            |lambda| color.evaluate_power(lambda) * scale,
+            EXTENDED_VISIBLE_RANGE,
+        );
+        let lumens = 4.0 * PI * LUMINOUS_EFFICACY * luminous;
+        assert!((lumens - 1000.0).abs() < 1.0, "{}", lumens);
+
+        // for a point light, the intensity in candela is the lumens spread over the whole sphere
+        let candela = LightUnits::Candela.scale(1000.0 / (4.0 * PI), integrals, 1.0);
+        assert!(
+            (candela - scale).abs() < 0.0001 * scale,
+            "{} {}",
+            candela,
+            scale
+        );
+    }
+}
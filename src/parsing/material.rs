@@ -1,11 +1,17 @@
+use crate::curves::{radiometric_and_photometric_integrals, EXTENDED_VISIBLE_RANGE};
 use crate::materials::*;
+use crate::mediums::PhaseFunction;
 use crate::parsing::curves::{parse_curve, CurveData};
+use crate::parsing::light::{curve_integrals, LightUnits};
+use crate::parsing::medium::{parse_phase_function, PhaseFunctionData};
+use crate::parsing::photometric::load_photometric_profile;
 use crate::texture::TexStack;
-use math::Sidedness;
+use math::{Sidedness, CDF};
 
 use serde::{Deserialize, Serialize};
 
 use std::collections::HashMap;
+use std::f32::consts::PI;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct LambertianData {
@@ -20,19 +26,83 @@ pub struct GGXData {
     pub kappa: CurveData,
     pub permeability: f32,
     pub outer_medium_id: Option<usize>,
+    pub inner_medium_id: Option<usize>,
+    pub priority: Option<u32>, // defaults to 0. higher priority volumes win where volumes overlap
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum LayerInterfaceData {
+    Diffuse {
+        color: String,
+    },
+    GGX {
+        alpha: f32,
+        eta_o: f32,
+        eta: CurveData,
+        kappa: CurveData,
+        permeability: f32,
+    },
+}
+
+// coefficients are per unit of thickness. phase defaults to isotropic
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LayerMediumData {
+    pub thickness: f32,
+    pub sigma_s: CurveData,
+    pub sigma_t: CurveData,
+    pub phase: Option<PhaseFunctionData>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LayerData {
+    pub interface: LayerInterfaceData,
+    // fills the space up to the next layer
+    pub medium: Option<LayerMediumData>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LayeredData {
+    // from the base up
+    pub layers: Vec<LayerData>,
+    pub samples: Option<usize>,
+    pub bounce_limit: Option<usize>,
+    pub outer_medium_id: Option<usize>,
+    pub inner_medium_id: Option<usize>,
+}
+
+// the color of an emissive material, either as a curve that's the same everywhere,
+// or as the name of a texture stack so that the emission varies over the surface
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum EmissionColorData {
+    Texture(String),
+    Curve(CurveData),
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct DiffuseLightData {
-    pub color: CurveData,
+    pub color: EmissionColorData,
     pub sidedness: Sidedness,
+    pub strength: Option<f32>, // defaults to 1, and scales the color if there are no units
+    pub units: Option<LightUnits>, // per square meter of surface, on each emitting side
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct SharpLightData {
-    pub color: CurveData,
+    pub color: EmissionColorData,
     pub sidedness: Sidedness,
     pub sharpness: f32,
+    pub strength: Option<f32>, // defaults to 1, and scales the color if there are no units
+    pub units: Option<LightUnits>, // like DiffuseLight, with candela being the luminance straight out of the surface
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PhotometricLightData {
+    pub color: CurveData,
+    pub filename: String,          // .ies or .ldt
+    pub strength: Option<f32>, // defaults to 1, and scales the file's intensities if there are no units
+    pub units: Option<LightUnits>, // the total output of the luminaire, or the intensity at its peak for candela
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -40,6 +110,7 @@ pub struct PassthroughFilterData {
     pub color: CurveData,
     pub outer_medium_id: usize,
     pub inner_medium_id: usize,
+    pub priority: Option<u32>, // defaults to 0
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -47,10 +118,107 @@ pub struct PassthroughFilterData {
 pub enum MaterialData {
     GGX(GGXData),
     Lambertian(LambertianData),
+    Layered(LayeredData),
     PassthroughFilter(PassthroughFilterData),
 
     DiffuseLight(DiffuseLightData),
     SharpLight(SharpLightData),
+    PhotometricLight(PhotometricLightData),
+}
+
+fn parse_emission_color(
+    data: EmissionColorData,
+    mapping: &HashMap<String, usize>,
+    texture_stacks: &Vec<TexStack>,
+) -> TexStack {
+    match data {
+        EmissionColorData::Texture(name) => {
+            let id = mapping
+                .get(&name)
+                .expect("didn't find texture stack id for texture name");
+            texture_stacks[*id].clone()
+        }
+        EmissionColorData::Curve(curve) => CDF::from(parse_curve(curve)).into(),
+    }
+}
+
+// scales an emission color so that an emitter with it gives off strength in the given units per square meter,
+// averaged over the surface in case the color is textured.
+// the emitter's radiance is the color times a lobe, which integrates to projected_solid_angle against the cosine and is peak straight out
+fn scale_to_units(
+    color: TexStack,
+    strength: f32,
+    units: LightUnits,
+    projected_solid_angle: f32,
+    peak: f32,
+) -> TexStack {
+    const UV_STEPS: usize = 8;
+    let integrals = radiometric_and_photometric_integrals(
+        |lambda| {
+            let mut sum = 0.0;
+            for u in 0..UV_STEPS {
+                for v in 0..UV_STEPS {
+                    let uv = (
+                        (u as f32 + 0.5) / UV_STEPS as f32,
+                        (v as f32 + 0.5) / UV_STEPS as f32,
+                    );
+                    sum += color.eval_at(lambda, uv);
+                }
+            }
+            sum / (UV_STEPS * UV_STEPS) as f32
+        },
+        EXTENDED_VISIBLE_RANGE,
+    );
+    // the radiance integrates to projected_solid_angle times the color over the hemisphere, which gives the exitance.
+    // candela per square meter are luminance, which is already per steradian
+    let mut scale = units.scale(strength, integrals, projected_solid_angle);
+    if units == LightUnits::Candela {
+        scale /= peak;
+    }
+    color.scaled(scale)
+}
+
+fn parse_layer(
+    data: LayerData,
+    mapping: &HashMap<String, usize>,
+    texture_stacks: &Vec<TexStack>,
+) -> Layer {
+    let interface = match data.interface {
+        LayerInterfaceData::Diffuse { color } => {
+            let id = mapping
+                .get(&color)
+                .expect("didn't find texture stack id for texture name");
+            LayerInterface::Diffuse {
+                color: texture_stacks[*id].clone(),
+            }
+        }
+        LayerInterfaceData::GGX {
+            alpha,
+            eta_o,
+            eta,
+            kappa,
+            permeability,
+        } => LayerInterface::GGX(GGX::new(
+            alpha,
+            parse_curve(eta),
+            eta_o,
+            parse_curve(kappa),
+            permeability,
+            0,
+        )),
+    };
+    let medium = data.medium.map(|medium| LayerMedium {
+        thickness: medium.thickness,
+        sigma_t: parse_curve(medium.sigma_t),
+        sigma_s: parse_curve(medium.sigma_s),
+        phase: match medium.phase {
+            Some(phase) => parse_phase_function(phase),
+            None => PhaseFunction::HenyeyGreenstein {
+                g: parse_curve(CurveData::Flat { strength: 1.0 }),
+            },
+        },
+    });
+    Layer { interface, medium }
 }
 
 pub fn parse_material(
@@ -63,14 +231,18 @@ pub fn parse_material(
             println!("parsing GGX");
             let eta = parse_curve(data.eta);
             let kappa = parse_curve(data.kappa);
-            MaterialEnum::GGX(GGX::new(
-                data.alpha,
-                eta,
-                data.eta_o,
-                kappa,
-                data.permeability,
-                data.outer_medium_id.unwrap_or(0),
-            ))
+            MaterialEnum::GGX(
+                GGX::new(
+                    data.alpha,
+                    eta,
+                    data.eta_o,
+                    kappa,
+                    data.permeability,
+                    data.outer_medium_id.unwrap_or(0),
+                )
+                .with_inner_medium_id(data.inner_medium_id.unwrap_or(0))
+                .with_priority(data.priority.unwrap_or(0)),
+            )
         }
         MaterialData::Lambertian(data) => {
             println!("parsing Lambertian");
@@ -79,28 +251,79 @@ pub fn parse_material(
                 .expect("didn't find texture stack id for texture name");
             MaterialEnum::Lambertian(Lambertian::new(texture_stacks[*id].clone()))
         }
+        MaterialData::Layered(data) => {
+            println!("parsing Layered");
+            let layers = data
+                .layers
+                .into_iter()
+                .map(|layer| parse_layer(layer, mapping, texture_stacks))
+                .collect();
+            let mut layered = Layered::new(layers, data.outer_medium_id.unwrap_or(0))
+                .with_inner_medium_id(data.inner_medium_id.unwrap_or(0))
+                .with_samples(data.samples.unwrap_or(1));
+            if let Some(bounce_limit) = data.bounce_limit {
+                layered = layered.with_bounce_limit(bounce_limit);
+            }
+            MaterialEnum::Layered(layered)
+        }
         MaterialData::SharpLight(data) => {
             println!("parsing SharpLight");
-            // let color = parse_texture_stack(data.color);
-            let color = parse_curve(data.color).into();
-            MaterialEnum::SharpLight(SharpLight::new(color, data.sharpness, data.sidedness))
+            let color = parse_emission_color(data.color, mapping, texture_stacks);
+            let mut light = SharpLight::new(color, data.sharpness, data.sidedness);
+            let strength = data.strength.unwrap_or(1.0);
+            light.color = match data.units {
+                Some(units) => scale_to_units(
+                    light.color.clone(),
+                    strength,
+                    units,
+                    light.projected_solid_angle(),
+                    light.peak(),
+                ),
+                None => light.color.clone().scaled(strength),
+            };
+            MaterialEnum::SharpLight(light)
         }
         MaterialData::PassthroughFilter(data) => {
             println!("parsing PassthroughFilter");
             // let color = parse_texture_stack(data.color);
             let color = parse_curve(data.color).into();
-            MaterialEnum::PassthroughFilter(PassthroughFilter::new(
-                color,
-                data.outer_medium_id,
-                data.inner_medium_id,
-            ))
+            MaterialEnum::PassthroughFilter(
+                PassthroughFilter::new(color, data.outer_medium_id, data.inner_medium_id)
+                    .with_priority(data.priority.unwrap_or(0)),
+            )
         }
         MaterialData::DiffuseLight(data) => {
             println!("parsing DiffuseLight");
-            // let color = parse_texture_stack(data.color);
-            let color = parse_curve(data.color).into();
+            let color = parse_emission_color(data.color, mapping, texture_stacks);
+            let strength = data.strength.unwrap_or(1.0);
+            let color = match data.units {
+                // constant radiance integrates to pi times itself over the hemisphere
+                Some(units) => scale_to_units(color, strength, units, PI, 1.0),
+                None => color.scaled(strength),
+            };
             MaterialEnum::DiffuseLight(DiffuseLight::new(color, data.sidedness))
         }
+        MaterialData::PhotometricLight(data) => {
+            println!("parsing PhotometricLight");
+            let color = parse_curve(data.color).into();
+            let profile = load_photometric_profile(&data.filename);
+            let mut light = PhotometricLight::new(color, profile);
+            let strength = data.strength.unwrap_or(1.0);
+            match data.units {
+                Some(units) => {
+                    // replaces the file's own scale, treating the profile as a shape with a peak of 1
+                    let peak = light.profile.peak();
+                    light.scale = if peak > 0.0 {
+                        let solid_angle = light.profile.lumens() / peak;
+                        units.scale(strength, curve_integrals(&light.color), solid_angle) / peak
+                    } else {
+                        0.0
+                    };
+                }
+                None => light.scale *= strength,
+            }
+            MaterialEnum::PhotometricLight(light)
+        }
     }
 }
 
@@ -1,35 +1,225 @@
+use crate::math::*;
 use crate::mediums::*;
-use crate::parsing::curves::{parse_curve, CurveData};
+use crate::parsing::curves::{load_multiple_csv_rows, parse_curve, CurveData};
 
 use serde::{Deserialize, Serialize};
 
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum PhaseFunctionData {
+    HG {
+        g: CurveData,
+    },
+    DoubleHG {
+        g1: CurveData,
+        g2: CurveData,
+        weight: f32,
+    },
+    Rayleigh,
+    Draine {
+        g: CurveData,
+        alpha: f32,
+    },
+    // csv where the first column is the scattering angle in degrees,
+    // and every other column is the phase at the corresponding wavelength
+    TabulatedMie {
+        filename: String,
+        wavelengths: Vec<f32>,
+        resolution: Option<usize>,
+    },
+}
+
+pub fn parse_phase_function(data: PhaseFunctionData) -> PhaseFunction {
+    match data {
+        PhaseFunctionData::HG { g } => PhaseFunction::HenyeyGreenstein { g: parse_curve(g) },
+        PhaseFunctionData::DoubleHG { g1, g2, weight } => PhaseFunction::DoubleHenyeyGreenstein {
+            g1: parse_curve(g1),
+            g2: parse_curve(g2),
+            weight,
+        },
+        PhaseFunctionData::Rayleigh => PhaseFunction::Rayleigh,
+        PhaseFunctionData::Draine { g, alpha } => PhaseFunction::Draine {
+            g: parse_curve(g),
+            alpha,
+        },
+        PhaseFunctionData::TabulatedMie {
+            filename,
+            wavelengths,
+            resolution,
+        } => {
+            let curves = load_multiple_csv_rows(
+                &filename,
+                wavelengths.len(),
+                InterpolationMode::Linear,
+                |x| x,
+                |y| y,
+            )
+            .expect(&format!(
+                "failed parsing tabulated phase function {}",
+                filename
+            ));
+            PhaseFunction::Tabulated(TabulatedPhase::new(
+                wavelengths,
+                curves,
+                resolution.unwrap_or(512),
+            ))
+        }
+    }
+}
+
+// phase defaults to HG with the given g, for backwards compatibility
+fn parse_phase_or_hg(phase: Option<PhaseFunctionData>, g: Option<CurveData>) -> PhaseFunction {
+    match (phase, g) {
+        (Some(phase), _) => parse_phase_function(phase),
+        (None, Some(g)) => PhaseFunction::HenyeyGreenstein { g: parse_curve(g) },
+        (None, None) => panic!("medium needs either a phase function or g"),
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct HGMediumData {
-    pub g: CurveData,
+    pub g: Option<CurveData>,
+    pub phase: Option<PhaseFunctionData>,
     pub sigma_s: CurveData,
     pub sigma_t: CurveData,
+    pub emission: Option<CurveData>,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub enum VoxelFormat {
+    // little endian f32s, x varying fastest. requires the resolution to be specified.
+    Raw,
+    // three little endian u32s for the resolution, followed by the same data as Raw.
+    RawWithHeader,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct VoxelGridData {
+    pub filename: String,
+    pub format: VoxelFormat,
+    pub resolution: Option<[usize; 3]>,
+    pub scale: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum GridEmissionData {
+    Constant { radiance: CurveData },
+    // blackbody at the temperature from the temperature grid, in kelvin
+    Blackbody { strength: f32 },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GridMediumData {
+    pub g: Option<CurveData>,
+    pub phase: Option<PhaseFunctionData>,
+    pub sigma_s: CurveData,
+    pub sigma_t: CurveData,
+    pub density: VoxelGridData,
+    pub temperature: Option<VoxelGridData>,
+    pub albedo: Option<VoxelGridData>,
+    pub emission: Option<GridEmissionData>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum MediumData {
+    #[serde(alias = "Homogeneous")]
     HG(HGMediumData),
+    Grid(GridMediumData),
 }
 
-pub fn parse_medium(data: MediumData) -> MediumEnum {
-    match data {
+pub fn load_voxel_grid(data: VoxelGridData) -> Result<VoxelGrid, Box<dyn Error>> {
+    println!("loading voxel grid from {}", data.filename);
+    let mut file = File::open(&data.filename)?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    let (resolution, body) = match data.format {
+        VoxelFormat::Raw => match data.resolution {
+            Some(resolution) => (resolution, &bytes[..]),
+            None => return Err("raw voxel grids need a resolution to be specified".into()),
+        },
+        VoxelFormat::RawWithHeader => {
+            if bytes.len() < 12 {
+                return Err(
+                    format!("{} is too short to have a resolution header", data.filename).into(),
+                );
+            }
+            let mut resolution = [0usize; 3];
+            for (i, chunk) in bytes[..12].chunks_exact(4).enumerate() {
+                resolution[i] =
+                    u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as usize;
+            }
+            (resolution, &bytes[12..])
+        }
+    };
+    if resolution.contains(&0) {
+        return Err(format!(
+            "voxel grid {} has a resolution of {:?}, which is empty",
+            data.filename, resolution
+        )
+        .into());
+    }
+    let scale = data.scale.unwrap_or(1.0);
+    let values: Vec<f32> = body
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) * scale)
+        .collect();
+    if values.len() != resolution[0] * resolution[1] * resolution[2] {
+        return Err(format!(
+            "voxel grid {} has {} values, but a resolution of {:?}",
+            data.filename,
+            values.len(),
+            resolution
+        )
+        .into());
+    }
+    Ok(VoxelGrid::new(
+        (resolution[0], resolution[1], resolution[2]),
+        values,
+    ))
+}
+
+pub fn parse_medium(data: MediumData) -> Result<MediumEnum, Box<dyn Error>> {
+    Ok(match data {
         MediumData::HG(data) => {
             println!("parsing HG");
-            let g = parse_curve(data.g);
+            let phase = parse_phase_or_hg(data.phase, data.g);
             let sigma_s = parse_curve(data.sigma_s);
             let sigma_t = parse_curve(data.sigma_t);
-            MediumEnum::HenyeyGreensteinHomogeneous(HenyeyGreensteinHomogeneous {
-                g,
+            MediumEnum::Homogeneous(HomogeneousMedium {
+                phase,
                 sigma_s,
                 sigma_t,
+                emission: data.emission.map(parse_curve),
+                bounds: None,
+                transform: None,
             })
         }
-    }
+        MediumData::Grid(data) => {
+            println!("parsing Grid");
+            MediumEnum::Grid(GridMedium::new(
+                parse_phase_or_hg(data.phase, data.g),
+                parse_curve(data.sigma_t),
+                parse_curve(data.sigma_s),
+                load_voxel_grid(data.density)?,
+                data.temperature.map(load_voxel_grid).transpose()?,
+                data.albedo.map(load_voxel_grid).transpose()?,
+                data.emission.map(|emission| match emission {
+                    GridEmissionData::Constant { radiance } => {
+                        GridEmission::Constant(parse_curve(radiance))
+                    }
+                    GridEmissionData::Blackbody { strength } => {
+                        GridEmission::Blackbody { strength }
+                    }
+                }),
+            ))
+        }
+    })
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -37,3 +227,37 @@ pub struct NamedMedium {
     pub data: MediumData,
     pub name: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    #[test]
+    fn test_parse_truncated_grid() {
+        // header says 2x2x2, but only 3 values follow
+        let filename = std::env::temp_dir().join("truncated_density.vol");
+        let mut bytes = Vec::new();
+        for v in [2u32, 2, 2].iter() {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        for v in [0.1f32, 0.2, 0.3].iter() {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        fs::write(&filename, bytes).unwrap();
+
+        let data: MediumData = toml::from_str(&format!(
+            r#"
+            type = "Grid"
+            g = {{ type = "Flat", strength = 0.0 }}
+            sigma_s = {{ type = "Flat", strength = 0.5 }}
+            sigma_t = {{ type = "Flat", strength = 1.0 }}
+            density = {{ filename = {:?}, format = "RawWithHeader" }}
+            "#,
+            filename.to_str().unwrap()
+        ))
+        .unwrap();
+        let result = parse_medium(data);
+        fs::remove_file(&filename).unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -3,14 +3,17 @@ extern crate serde;
 pub mod curves;
 pub mod environment;
 pub mod instance;
+pub mod light;
 pub mod material;
 pub mod medium;
+pub mod photometric;
 pub mod primitives;
 pub mod texture;
 
 // use curves::*;
-use environment::{parse_environment, EnvironmentData};
+use environment::{parse_environment, parse_portal, EnvironmentData, PortalData};
 use instance::*;
+use light::{parse_punctual_light, PunctualLightData};
 use material::*;
 use math::{Transform3, Vec3};
 use medium::*;
@@ -30,7 +33,7 @@ use crate::texture::*;
 // use crate::math::spectral::BOUNDED_VISIBLE_RANGE;
 // use crate::math::*;
 // use crate::world::EnvironmentMap;
-use crate::world::{AcceleratorType, World};
+use crate::world::{AcceleratorType, LightSamplingStrategy, World};
 
 use std::collections::HashMap;
 use std::f32::consts::PI;
@@ -50,7 +53,11 @@ pub struct Scene {
     pub mediums: Option<Vec<NamedMedium>>,
     pub instances: Vec<InstanceData>,
     pub environment: EnvironmentData,
+    pub portals: Option<Vec<PortalData>>, // rects the environment light comes in through, like windows
+    pub lights: Option<Vec<PunctualLightData>>, // point, spot and directional lights
     pub env_sampling_probability: Option<f32>,
+    pub world_medium: Option<usize>, // defaults to 0, vacuum
+    pub light_sampling: Option<LightSamplingStrategy>, // defaults to Power
 }
 
 impl From<Transform3Data> for Transform3 {
@@ -112,6 +119,8 @@ pub fn construct_world(config: &Config) -> World {
     let mut material_count: usize = 0;
     let mut medium_count: usize = 0;
     let mut texture_count: usize = 0;
+    // instance ids and punctual lights that were given a light group
+    let mut instance_light_groups: HashMap<usize, String> = HashMap::new();
     for tex in scene.textures {
         texture_count += 1;
         let tex_id = texture_count - 1;
@@ -121,7 +130,9 @@ pub fn construct_world(config: &Config) -> World {
 
     for material in scene.materials {
         let id = match material.data {
-            MaterialData::DiffuseLight(_) | MaterialData::SharpLight(_) => {
+            MaterialData::DiffuseLight(_)
+            | MaterialData::SharpLight(_)
+            | MaterialData::PhotometricLight(_) => {
                 material_count += 1;
                 MaterialId::Light((material_count - 1) as u16)
             }
@@ -141,8 +152,10 @@ pub fn construct_world(config: &Config) -> World {
         for medium in scene_mediums {
             medium_count += 1;
             let id = medium_count - 1;
+            let parsed = parse_medium(medium.data)
+                .unwrap_or_else(|e| panic!("failed to parse medium {}: {}", medium.name, e));
             medium_names_to_ids.insert(medium.name, id);
-            mediums.push(parse_medium(medium.data));
+            mediums.push(parsed);
         }
     }
     for instance in scene.instances {
@@ -152,6 +165,9 @@ pub fn construct_world(config: &Config) -> World {
                 let transform: Option<Transform3> = instance.transform.clone().map(|e| e.into());
                 for mut mesh in meshes {
                     let id = instances.len();
+                    if let Some(group) = &instance.light_group {
+                        instance_light_groups.insert(id, group.clone());
+                    }
                     mesh.init();
                     println!(
                         "pushing instance of mesh from meshbundle, with material id {:?} from {:?}",
@@ -174,18 +190,39 @@ pub fn construct_world(config: &Config) -> World {
             }
             _ => {
                 let id = instances.len();
+                if let Some(group) = &instance.light_group {
+                    instance_light_groups.insert(id, group.clone());
+                }
                 let instance = parse_instance(instance, &material_names_to_ids, id);
                 instances.push(instance);
             }
         }
     }
+    let punctual_lights = scene.lights.unwrap_or_default();
+    let punctual_light_groups: Vec<Option<String>> =
+        punctual_lights.iter().map(|e| e.light_group()).collect();
     let world = World::new(
         instances,
         materials,
         mediums,
+        scene.world_medium.unwrap_or(0),
         parse_environment(scene.environment),
+        punctual_lights
+            .into_iter()
+            .map(parse_punctual_light)
+            .collect(),
         scene.env_sampling_probability.unwrap_or(0.5),
+        scene.light_sampling.unwrap_or_default(),
         AcceleratorType::BVH,
+    )
+    .with_light_groups(&instance_light_groups, &punctual_light_groups)
+    .with_portals(
+        scene
+            .portals
+            .unwrap_or_default()
+            .into_iter()
+            .map(parse_portal)
+            .collect(),
     );
     world
 }
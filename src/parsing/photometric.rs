@@ -0,0 +1,179 @@
+use crate::materials::PhotometricProfile;
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+fn parse_numbers<'a>(tokens: impl Iterator<Item = &'a str>) -> Vec<f32> {
+    tokens
+        .map(|e| e.trim())
+        .filter(|e| !e.is_empty())
+        .map(|e| {
+            e.parse::<f32>()
+                .unwrap_or_else(|_| panic!("failed to parse number {:?} in photometric data", e))
+        })
+        .collect()
+}
+
+// parses an IES LM-63 file. everything up to the TILT line is keywords and is skipped,
+// and everything after it is whitespace or comma separated numbers.
+pub fn parse_ies(source: &str) -> PhotometricProfile {
+    let mut lines = source.lines();
+    let tilt = lines
+        .by_ref()
+        .map(|line| line.trim())
+        .find(|line| line.starts_with("TILT"))
+        .expect("couldn't find TILT line in ies file");
+    let rest: Vec<&str> = lines.collect();
+    let numbers = parse_numbers(
+        rest.iter()
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ',')),
+    );
+    let mut numbers = numbers.into_iter();
+    let mut next = || numbers.next().expect("ies file ended early");
+
+    if tilt.ends_with("INCLUDE") {
+        // lamp to luminaire geometry, then the tilt angles and multipliers, which aren't used
+        let _geometry = next();
+        let pairs = next() as usize;
+        for _ in 0..2 * pairs {
+            next();
+        }
+    }
+    let _lamps = next();
+    let _lumens_per_lamp = next();
+    let candela_multiplier = next();
+    let vertical_count = next() as usize;
+    let horizontal_count = next() as usize;
+    let photometric_type = next() as usize;
+    if photometric_type != 1 {
+        println!(
+            "warning: ies photometric type {} isn't supported, treating it as type C",
+            photometric_type
+        );
+    }
+    let _units = next();
+    let (_width, _length, _height) = (next(), next(), next());
+    let (ballast_factor, _future_use, _input_watts) = (next(), next(), next());
+    let vertical_angles: Vec<f32> = (0..vertical_count).map(|_| next()).collect();
+    let horizontal_angles: Vec<f32> = (0..horizontal_count).map(|_| next()).collect();
+    let candela: Vec<f32> = (0..vertical_count * horizontal_count)
+        .map(|_| next() * candela_multiplier * ballast_factor)
+        .collect();
+    PhotometricProfile::new(vertical_angles, horizontal_angles, candela)
+}
+
+// parses a EULUMDAT (.ldt) file, which has one value per line in a fixed order
+pub fn parse_eulumdat(source: &str) -> PhotometricProfile {
+    let lines: Vec<&str> = source.lines().map(|line| line.trim()).collect();
+    // some files use commas as the decimal separator
+    let number = |index: usize| -> f32 {
+        let line = lines.get(index).expect("eulumdat file ended early");
+        line.replace(',', ".").parse::<f32>().unwrap_or_else(|_| {
+            panic!(
+                "failed to parse {:?} on line {} of eulumdat file",
+                line,
+                index + 1
+            )
+        })
+    };
+    let symmetry = number(2) as usize;
+    let c_plane_count = number(3) as usize;
+    let gamma_count = number(5) as usize;
+    let conversion_factor = number(23);
+    let lamp_sets = number(25) as usize;
+    // intensities are given per 1000 lumens of lamp flux
+    let lamp_lumens: f32 = (0..lamp_sets).map(|i| number(26 + 6 * i + 2)).sum();
+    let scale = conversion_factor * lamp_lumens / 1000.0;
+    // 6 lines per set of lamps, then 10 lines of direct ratios
+    let c_angles_start = 26 + 6 * lamp_sets + 10;
+    let gamma_angles_start = c_angles_start + c_plane_count;
+    let intensities_start = gamma_angles_start + gamma_count;
+
+    let c_angles: Vec<f32> = (0..c_plane_count)
+        .map(|i| number(c_angles_start + i))
+        .collect();
+    let gamma_angles: Vec<f32> = (0..gamma_count)
+        .map(|i| number(gamma_angles_start + i))
+        .collect();
+    // only the c planes that can't be recovered by symmetry are stored
+    let (first_plane, plane_count) = match symmetry {
+        0 => (0, c_plane_count),
+        1 => (0, 1),
+        2 => (0, c_plane_count / 2 + 1),
+        3 => (c_plane_count / 4, c_plane_count / 2 + 1),
+        4 => (0, c_plane_count / 4 + 1),
+        _ => panic!("unknown eulumdat symmetry {}", symmetry),
+    };
+    let horizontal_angles = c_angles[first_plane..first_plane + plane_count].to_vec();
+    let intensities: Vec<f32> = (0..plane_count * gamma_count)
+        .map(|i| number(intensities_start + i) * scale)
+        .collect();
+    PhotometricProfile::new(gamma_angles, horizontal_angles, intensities)
+}
+
+// loads an .ies or .ldt file, depending on the extension
+pub fn load_photometric_profile(filename: &str) -> PhotometricProfile {
+    println!("loading photometric data from {}", filename);
+    let mut file = File::open(filename).expect("failed to open photometric data");
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .expect("failed to read photometric data");
+    let extension = Path::new(filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+    match extension.as_deref() {
+        Some("ldt") => parse_eulumdat(&contents),
+        _ => parse_ies(&contents),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ies() {
+        // quadrant symmetric, with more light towards the 0 degree plane
+        let source = "IESNA:LM-63-2002
+[TEST] test
+[MANUFAC] nobody
+TILT=NONE
+1 1000 2 3 2 1 2 0.5 0.5 0.0
+1.0 1.0 50
+0 45 90
+0 90
+100 80 0
+50, 40, 0
+";
+        let profile = parse_ies(source);
+        // with the candela multiplier of 2 applied
+        assert!((profile.evaluate_angles(0.0, 0.0) - 200.0).abs() < 0.001);
+        assert!((profile.evaluate_angles(45.0, 90.0) - 80.0).abs() < 0.001);
+        // mirrored into the other quadrants
+        assert!((profile.evaluate_angles(45.0, 180.0) - 160.0).abs() < 0.001);
+        assert!((profile.evaluate_angles(45.0, 270.0) - 80.0).abs() < 0.001);
+        assert!((profile.evaluate_angles(45.0, -45.0) - 120.0).abs() < 0.001);
+        assert_eq!(profile.evaluate_angles(120.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_parse_eulumdat() {
+        // rotationally symmetric, with 4 c planes and 3 gamma angles
+        let mut lines = vec![
+            "test", "1", "1", "4", "90", "3", "90", "report", "name", "number", "test.ldt", "date",
+            "100", "100", "10", "100", "100", "0", "0", "0", "0", "100", "80", "1", "0", "1", "1",
+            "lamp", "2000", "3000", "80", "10",
+        ];
+        lines.extend(vec!["0.5"; 10]);
+        lines.extend(vec!["0", "90", "180", "270"]);
+        lines.extend(vec!["0", "90", "180"]);
+        lines.extend(vec!["200", "100,0", "0"]);
+        let profile = parse_eulumdat(&lines.join("\r\n"));
+        // 2000 lumens of lamps, so twice the cd/klm values
+        assert!((profile.evaluate_angles(0.0, 0.0) - 400.0).abs() < 0.001);
+        assert!((profile.evaluate_angles(45.0, 200.0) - 300.0).abs() < 0.001);
+        assert_eq!(profile.evaluate_angles(180.0, 100.0), 0.0);
+    }
+}
@@ -6,6 +6,8 @@ use crate::texture::*;
 use packed_simd::f32x4;
 use serde::{Deserialize, Serialize};
 
+use std::fs::File;
+use std::io::BufReader;
 use std::path::Path;
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -50,6 +52,66 @@ pub fn parse_rgba(filepath: &str) -> Film<f32x4> {
     new_film
 }
 
+// loads a high dynamic range image, either openexr or radiance hdr, as linear rgb
+pub fn parse_hdr_rgb(filepath: &str) -> Film<f32x4> {
+    println!("parsing hdr image at {}", filepath);
+    let path = Path::new(filepath);
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+    match extension.as_deref() {
+        Some("exr") => {
+            use exr::prelude::rgba_image::*;
+            let (_info, film) = ImageInfo::read_pixels_from_file(
+                path,
+                read_options::high(),
+                |info: &ImageInfo| {
+                    Film::new(
+                        info.resolution.width(),
+                        info.resolution.height(),
+                        f32x4::splat(0.0),
+                    )
+                },
+                |film: &mut Film<f32x4>, position: Vec2<usize>, pixel: Pixel| {
+                    film.write_at(
+                        position.x(),
+                        position.y(),
+                        f32x4::new(
+                            pixel.red.to_f32(),
+                            pixel.green.to_f32(),
+                            pixel.blue.to_f32(),
+                            0.0,
+                        ),
+                    );
+                },
+            )
+            .expect("failed to read exr image");
+            film
+        }
+        Some("hdr") => {
+            let file = File::open(path).expect("failed to open hdr image");
+            let decoder = image::codecs::hdr::HdrDecoder::new(BufReader::new(file))
+                .expect("failed to read hdr header");
+            let metadata = decoder.metadata();
+            let (width, height) = (metadata.width as usize, metadata.height as usize);
+            let pixels = decoder.read_image_hdr().expect("failed to read hdr image");
+            Film {
+                buffer: pixels
+                    .iter()
+                    .map(|p| f32x4::new(p.0[0], p.0[1], p.0[2], 0.0))
+                    .collect(),
+                width,
+                height,
+            }
+        }
+        _ => panic!(
+            "unsupported hdr image format for {}, use .exr or .hdr",
+            filepath
+        ),
+    }
+}
+
 pub fn parse_bitmap(filepath: &str) -> Film<f32> {
     println!("parsing greyscale texture at {}", filepath);
     let path = Path::new(filepath);
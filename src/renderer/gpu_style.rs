@@ -1,12 +1,13 @@
 use super::{output_film, Film, Renderer};
 
 use crate::camera::Camera;
-use crate::config::{Config, IntegratorKind, RendererType, Resolution};
+use crate::config::{Config, IntegratorKind, RendererType, Resolution, SamplerKind};
 // use crate::hittable::Hittable;
 use crate::integrator::gpu_style::*;
 // use crate::materials::*;
 use crate::math::*;
 // use crate::profile::Profile;
+use crate::sampler::SamplerFactory;
 // use crate::spectral::BOUNDED_VISIBLE_RANGE as VISIBLE_RANGE;
 // use crate::tonemap::{sRGB, Tonemapper};
 use crate::world::World;
@@ -64,6 +65,7 @@ impl Renderer for GPUStyleRenderer {
                         render_settings
                             .wavelength_bounds
                             .map_or(Bounds1D::new(400.0, 780.0), |v| Bounds1D::new(v.0, v.1)),
+                        SamplerFactory::from_settings(&render_settings, SamplerKind::Random),
                     );
                     let Resolution { width, height } = render_settings.resolution;
                     let camera_id = render_settings.camera_id;
@@ -100,7 +102,7 @@ impl Renderer for GPUStyleRenderer {
                                 loop {
                                     let status = integrator.primary_ray_pass(
                                         &mut primary_ray_buffer,
-                                        &sample_buffer,
+                                        &mut sample_buffer,
                                         kernel_width,
                                         kernel_height,
                                         render_settings
@@ -108,6 +110,7 @@ impl Renderer for GPUStyleRenderer {
                                             .unwrap_or(render_settings.min_samples)
                                             as usize,
                                         cam_bounds,
+                                        (x * kernel_width, y * kernel_height),
                                         camera_id,
                                         &cameras[camera_id as usize],
                                     );
@@ -125,6 +128,8 @@ impl Renderer for GPUStyleRenderer {
 
                                     integrator.nee_pass(
                                         1,
+                                        &primary_ray_buffer,
+                                        &sample_buffer,
                                         &intersection_buffer,
                                         &mut shadow_ray_buffer,
                                     );
@@ -151,7 +156,7 @@ impl Renderer for GPUStyleRenderer {
                                 {
                                     integrator.primary_ray_pass(
                                         &mut primary_ray_buffer,
-                                        &sample_buffer,
+                                        &mut sample_buffer,
                                         kernel_width,
                                         kernel_height,
                                         render_settings
@@ -159,6 +164,7 @@ impl Renderer for GPUStyleRenderer {
                                             .unwrap_or(render_settings.min_samples)
                                             as usize,
                                         cam_bounds,
+                                        (x * kernel_width, y * kernel_height),
                                         camera_id,
                                         &cameras[camera_id as usize],
                                     );
@@ -171,6 +177,8 @@ impl Renderer for GPUStyleRenderer {
 
                                         integrator.nee_pass(
                                             1,
+                                            &primary_ray_buffer,
+                                            &sample_buffer,
                                             &intersection_buffer,
                                             &mut shadow_ray_buffer,
                                         );
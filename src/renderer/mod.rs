@@ -25,10 +25,57 @@ pub fn output_film(render_settings: &RenderSettings, film: &Film<XYZColor>) {
     let exr_filename = format!("output/{}.exr", filename_str);
     let png_filename = format!("output/{}.png", filename_str);
 
-    let srgb_tonemapper = sRGB::new(film, render_settings.exposure.unwrap_or(1.0));
+    let srgb_tonemapper = sRGB::for_settings(film, render_settings);
     srgb_tonemapper.write_to_files(film, &exr_filename, &png_filename);
 }
 
+// writes the film like output_film, plus a separate exr with the combined film and each light group's film as layers
+pub fn output_film_with_light_groups(
+    render_settings: &RenderSettings,
+    film: &Film<XYZColor>,
+    light_group_names: &[String],
+    light_group_films: &[Film<XYZColor>],
+) {
+    output_film(render_settings, film);
+    output_layers(
+        render_settings,
+        film,
+        "light_groups",
+        light_group_names,
+        light_group_films,
+    );
+}
+
+// writes a separate exr with the combined film and each pass's film as layers. the film itself is written by output_film
+pub fn output_passes(
+    render_settings: &RenderSettings,
+    film: &Film<XYZColor>,
+    pass_names: &[String],
+    pass_films: &[Film<XYZColor>],
+) {
+    output_layers(render_settings, film, "passes", pass_names, pass_films);
+}
+
+fn output_layers(
+    render_settings: &RenderSettings,
+    film: &Film<XYZColor>,
+    suffix: &str,
+    names: &[String],
+    films: &[Film<XYZColor>],
+) {
+    if films.is_empty() {
+        return;
+    }
+    let filename = render_settings.filename.as_ref();
+    let filename_str = filename.cloned().unwrap_or(String::from("output"));
+    let exr_filename = format!("output/{}_{}.exr", filename_str, suffix);
+
+    let mut layers = vec![("combined", film)];
+    layers.extend(names.iter().map(|name| name.as_str()).zip(films.iter()));
+    let srgb_tonemapper = sRGB::for_settings(film, render_settings);
+    srgb_tonemapper.write_layers_to_exr(&layers, &exr_filename);
+}
+
 pub fn parse_wavelength_bounds(config: &Vec<RenderSettings>, default: Bounds1D) -> Bounds1D {
     let mut wavelength_bounds: Option<Bounds1D> = None;
     for settings in config.iter() {
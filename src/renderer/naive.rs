@@ -1,27 +1,34 @@
-use super::{output_film, parse_wavelength_bounds, Film, Renderer};
+use super::{
+    output_film, output_film_with_light_groups, output_passes, parse_wavelength_bounds, Film,
+    Renderer, SPPMRenderer,
+};
 
 use crate::camera::{Camera, CameraId};
 use crate::config::*;
 use crate::integrator::*;
 use crate::math::*;
 use crate::profile::Profile;
+use crate::sampler::{hash_combine, IndependentSampler, SamplerFactory};
 use crate::world::World;
 
 use math::spectral::BOUNDED_VISIBLE_RANGE as VISIBLE_RANGE;
 
 use std::collections::HashMap;
 // use std::io::Write;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
 
-use crossbeam::channel::unbounded;
-// use crossbeam::channel::{bounded};
 use pbr::ProgressBar;
 use rayon::iter::ParallelIterator;
 use rayon::prelude::*;
 
+// number of film rows rendered before their splats are applied to the light films
+const SPLAT_CHUNK_ROWS: usize = 16;
+// number of mutations each metropolis chain makes before its splats are applied to the film
+const MLT_MUTATIONS_PER_ROUND: usize = 1024;
+
 pub struct NaiveRenderer {}
 
 impl NaiveRenderer {
@@ -29,11 +36,14 @@ impl NaiveRenderer {
         NaiveRenderer {}
     }
 
+    // also returns a film for each light group followed by a film for each pass, when there are any
     pub fn render_sampled<I: SamplerIntegrator>(
         mut integrator: I,
         settings: &RenderSettings,
         _camera: &Camera,
-    ) -> Film<XYZColor> {
+        light_group_count: usize,
+        pass_count: usize,
+    ) -> (Film<XYZColor>, Vec<Film<XYZColor>>) {
         let (width, height) = (settings.resolution.width, settings.resolution.height);
         println!("starting render with film resolution {}x{}", width, height);
         let min_camera_rays = width * height * settings.min_samples as usize;
@@ -49,7 +59,8 @@ impl NaiveRenderer {
 
         let mut pb = ProgressBar::new((width * height) as u64);
 
-        let mut presampler: Box<dyn Sampler> = Box::new(RandomSampler::new());
+        let sampler_factory = SamplerFactory::from_settings(settings, SamplerKind::Random);
+        let mut presampler: Box<dyn Sampler> = sampler_factory.pixel_sampler((width, height));
         let mut preprofile = Profile::default();
         integrator.preprocess(&mut presampler, &vec![settings.clone()], &mut preprofile);
 
@@ -69,11 +80,16 @@ impl NaiveRenderer {
         });
 
         let clone2 = pixel_count.clone();
+        let layer_count = light_group_count + pass_count;
+        // the layer colors of every pixel, one after the other. always has room for at least one layer,
+        // so that every pixel gets a chunk even when there are no layers
+        let mut layer_buffer = vec![XYZColor::BLACK; width * height * layer_count.max(1)];
         let stats: Profile = film
             .buffer
             .par_iter_mut()
+            .zip(layer_buffer.par_chunks_mut(layer_count.max(1)))
             .enumerate()
-            .map(|(pixel_index, pixel_ref)| {
+            .map(|(pixel_index, (pixel_ref, layer_colors))| {
                 let mut profile = Profile::default();
                 // let clone = pixel_count.clone();
                 let y: usize = pixel_index / width;
@@ -82,18 +98,26 @@ impl NaiveRenderer {
                 // let r: Ray = Ray::new(Point3::ZERO, Vec3::X);
                 // let mut temp_color = RGBColor::BLACK;
                 let mut temp_color = XYZColor::BLACK;
-                // let mut sampler: Box<dyn Sampler> = Box::new(StratifiedSampler::new(20, 20, 10));
-                let mut sampler: Box<dyn Sampler> = Box::new(RandomSampler::new());
+                let layer_colors = &mut layer_colors[..layer_count];
+                let mut sampler: Box<dyn Sampler> = sampler_factory.pixel_sampler((x, y));
                 // idea: use SPD::Tabulated to collect all the data for a single pixel as a SPD, then convert that whole thing to XYZ.
                 for s in 0..settings.min_samples {
+                    sampler_factory.start_sample(&mut sampler, (x, y), s as usize);
                     let sample = sampler.draw_2d();
 
                     let camera_uv = (
                         (x as f32 + sample.x) / (settings.resolution.width as f32),
                         (y as f32 + sample.y) / (settings.resolution.height as f32),
                     );
-                    temp_color +=
-                        integrator.color(&mut sampler, (camera_uv, 0), s as usize, &mut profile);
+                    let (light_groups, passes) = layer_colors.split_at_mut(light_group_count);
+                    temp_color += integrator.color_with_passes(
+                        &mut sampler,
+                        (camera_uv, 0),
+                        s as usize,
+                        light_groups,
+                        passes,
+                        &mut profile,
+                    );
                     // temp_color += RGBColor::from(integrator.color(&mut sampler, r));
                     debug_assert!(
                         temp_color.0.is_finite().all(),
@@ -114,9 +138,22 @@ impl NaiveRenderer {
                 // unsafe {
                 *pixel_ref = temp_color / (settings.min_samples as f32);
                 // }
+                for color in layer_colors.iter_mut() {
+                    *color = *color / (settings.min_samples as f32);
+                }
                 profile
             })
             .reduce(|| Profile::default(), |a, b| a.combine(b));
+        let mut layer_films = vec![Film::new(width, height, XYZColor::BLACK); layer_count];
+        for (layer_index, layer_film) in layer_films.iter_mut().enumerate() {
+            for (pixel_ref, colors) in layer_film
+                .buffer
+                .iter_mut()
+                .zip(layer_buffer.chunks(layer_count))
+            {
+                *pixel_ref = colors[layer_index];
+            }
+        }
 
         if let Err(panic) = thread.join() {
             println!(
@@ -124,23 +161,201 @@ impl NaiveRenderer {
                 panic
             );
         }
+        println!("");
+        let elapsed = (now.elapsed().as_millis() as f32) / 1000.0;
+        println!("took {}s", elapsed);
+        stats.pretty_print(elapsed, settings.threads.unwrap() as usize);
+        (film, layer_films)
+    }
+    pub fn render_mlt(
+        integrator: MLTIntegrator,
+        settings: &RenderSettings,
+        _camera: &Camera,
+    ) -> Film<XYZColor> {
+        let (width, height) = (settings.resolution.width, settings.resolution.height);
+        println!("starting render with film resolution {}x{}", width, height);
+        let now = Instant::now();
+        let seed = settings.seed.unwrap_or(0);
+        let camera_id = settings.camera_id as CameraId;
+
+        let mut film: Film<XYZColor> = Film::new(width, height, XYZColor::BLACK);
+
+        // bootstrap, to find the normalization factor and starting paths for the chains
+        let mut bootstrap_profile = Profile::default();
+        let bootstrap_weights = integrator.bootstrap(seed, camera_id, &mut bootstrap_profile);
+        let weight_sum: f32 = bootstrap_weights.iter().sum();
+        let normalization = weight_sum / integrator.bootstrap_samples as f32;
+        println!(
+            "bootstrapped {} paths, normalization factor is {}",
+            bootstrap_weights.len(),
+            normalization
+        );
+        if normalization == 0.0 {
+            println!("all bootstrap paths had zero contribution, returning black film");
+            return film;
+        }
+        let mut bootstrap_cdf: Vec<f32> = Vec::with_capacity(bootstrap_weights.len());
+        let mut running_sum = 0.0;
+        for weight in bootstrap_weights.iter() {
+            running_sum += weight / weight_sum;
+            bootstrap_cdf.push(running_sum);
+        }
+
+        let mutations_per_pixel = settings.min_samples as usize;
+        let total_mutations = mutations_per_pixel * width * height;
+        let chain_count = integrator.chains.min(total_mutations).max(1);
+        let mutations_per_chain = total_mutations / chain_count;
+        println!(
+            "running {} chains with {} mutations each",
+            chain_count, mutations_per_chain
+        );
+
+        let mut chains: Vec<(MarkovChain, Profile)> = (0..chain_count)
+            .into_par_iter()
+            .map(|chain_index| {
+                let mut profile = Profile::default();
+                // pick a starting path proportional to its importance
+                let u = IndependentSampler::new(hash_combine(seed, 2), chain_index)
+                    .draw_1d()
+                    .x;
+                let bootstrap_index =
+                    match bootstrap_cdf.binary_search_by(|v| v.partial_cmp(&u).unwrap()) {
+                        Ok(i) | Err(i) => i.min(bootstrap_cdf.len() - 1),
+                    };
+                let chain = MarkovChain::new(
+                    &integrator,
+                    seed,
+                    bootstrap_index,
+                    chain_index,
+                    camera_id,
+                    &mut profile,
+                );
+                (chain, profile)
+            })
+            .collect();
+
+        let mut pb = ProgressBar::new(mutations_per_chain as u64);
+        let mut mutations_done = 0;
+        while mutations_done < mutations_per_chain {
+            let round = MLT_MUTATIONS_PER_ROUND.min(mutations_per_chain - mutations_done);
+            // run every chain for a round in parallel, then apply the splats in chain order so that the film is bit reproducible
+            let round_splats: Vec<Vec<(Sample, CameraId)>> = chains
+                .par_iter_mut()
+                .map(|(chain, profile)| {
+                    let mut splats = Vec::with_capacity(2 * round);
+                    for _ in 0..round {
+                        chain.mutate(&integrator, camera_id, &mut splats, profile);
+                    }
+                    splats
+                })
+                .collect();
+            for splats in round_splats {
+                for (sample, film_id) in splats {
+                    match sample {
+                        Sample::LightSample(color, pixel, _) if film_id == camera_id => {
+                            let x = ((pixel.0 * width as f32) as usize).min(width - 1);
+                            let y =
+                                height - ((pixel.1 * height as f32) as usize).min(height - 1) - 1;
+                            film.buffer[y * width + x] += color;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            mutations_done += round;
+            pb.add(round as u64);
+        }
+        println!("");
+
+        film.buffer.par_iter_mut().for_each(|pixel_ref| {
+            *pixel_ref = *pixel_ref / (mutations_per_pixel as f32 / normalization);
+        });
+
+        let elapsed = (now.elapsed().as_millis() as f32) / 1000.0;
+        println!("took {}s", elapsed);
+        let stats = chains
+            .iter()
+            .fold(bootstrap_profile, |a, (_, b)| a.combine(*b));
+        stats.pretty_print(elapsed, settings.threads.unwrap() as usize);
+        film
+    }
+    // runs vcm iterations until either the iteration budget (max_samples, or min_samples if unset)
+    // or the time limit of the integrator settings is reached, then writes out the current estimate.
+    pub fn render_vcm(
+        mut integrator: VCMIntegrator,
+        settings: &RenderSettings,
+        _camera: &Camera,
+    ) -> Film<XYZColor> {
+        let (width, height) = (settings.resolution.width, settings.resolution.height);
+        println!(
+            "starting vcm render with film resolution {}x{}",
+            width, height
+        );
+        let max_iterations = settings.max_samples.unwrap_or(settings.min_samples) as usize;
+        let time_limit = match settings.integrator {
+            IntegratorKind::VCM { time_limit, .. } => time_limit,
+            _ => None,
+        };
+        println!(
+            "maximum iterations: {}, initial merge radius: {}, alpha: {}",
+            max_iterations, integrator.initial_radius, integrator.alpha
+        );
+        if let Some(limit) = time_limit {
+            println!("time limit: {}s", limit);
+        }
+
+        let now = Instant::now();
+
+        let mut film: Film<XYZColor> = Film::new(width, height, XYZColor::BLACK);
+        let sampler_factory = SamplerFactory::from_settings(settings, SamplerKind::Random);
+        integrator.reset(width, height);
+
+        let mut pb = ProgressBar::new(max_iterations as u64);
+        let mut stats: Profile = Profile::default();
+        while integrator.iteration < max_iterations {
+            integrator.iterate(&sampler_factory, 0, (width, height), &mut stats);
+            pb.inc();
+            if let Some(limit) = time_limit {
+                if now.elapsed().as_millis() as f32 / 1000.0 >= limit {
+                    println!(
+                        "\nreached time limit after {} iterations",
+                        integrator.iteration
+                    );
+                    break;
+                }
+            }
+        }
+
+        film.buffer
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(pixel_index, pixel_ref)| {
+                *pixel_ref = integrator.estimate(pixel_index);
+            });
+
         println!("");
         let elapsed = (now.elapsed().as_millis() as f32) / 1000.0;
         println!("took {}s", elapsed);
         stats.pretty_print(elapsed, settings.threads.unwrap() as usize);
         film
     }
+    // also returns a film for each light group with the combined films of renders that have light groups turned on,
+    // and no films otherwise
     pub fn render_splatted<I: GenericIntegrator>(
         mut integrator: I,
         renders: Vec<RenderSettings>,
         _cameras: Vec<Camera>,
-    ) -> Vec<(RenderSettings, Film<XYZColor>)> {
+        light_group_count: usize,
+    ) -> Vec<(RenderSettings, Film<XYZColor>, Vec<Film<XYZColor>>)> {
         let now = Instant::now();
 
         let mut total_camera_samples = 0;
         let mut total_pixels = 0;
         let mut films: Vec<(RenderSettings, Film<XYZColor>)> = Vec::new();
-        let light_films: Arc<Mutex<Vec<Film<XYZColor>>>> = Arc::new(Mutex::new(Vec::new()));
+        let mut light_films: Vec<Film<XYZColor>> = Vec::new();
+        // per camera, the image and light films of each light group
+        let mut group_films: Vec<Vec<Film<XYZColor>>> = Vec::new();
+        let mut group_light_films: Vec<Vec<Film<XYZColor>>> = Vec::new();
         for settings in renders.iter() {
             let (width, height) = (settings.resolution.width, settings.resolution.height);
             println!("starting render with film resolution {}x{}", width, height);
@@ -150,7 +365,14 @@ impl NaiveRenderer {
             let image_film: Film<XYZColor> = Film::new(width, height, XYZColor::BLACK);
             let light_film: Film<XYZColor> = Film::new(width, height, XYZColor::BLACK);
             films.push((settings.clone(), image_film));
-            light_films.lock().unwrap().push(light_film);
+            light_films.push(light_film);
+            let group_count = if settings.light_groups.unwrap_or(false) {
+                light_group_count
+            } else {
+                0
+            };
+            group_films.push(vec![Film::new(width, height, XYZColor::BLACK); group_count]);
+            group_light_films.push(vec![Film::new(width, height, XYZColor::BLACK); group_count]);
         }
         println!("total pixels: {}", total_pixels);
         println!("minimum total samples: {}", total_camera_samples);
@@ -163,7 +385,15 @@ impl NaiveRenderer {
 
         const SHOW_PROGRESS_BAR: bool = true;
 
-        let mut sampler: Box<dyn Sampler> = Box::new(StratifiedSampler::new(20, 20, 10));
+        let mut sampler: Box<dyn Sampler> = SamplerFactory::from_settings(
+            &renders[0],
+            SamplerKind::Stratified {
+                x: 20,
+                y: 20,
+                z: 10,
+            },
+        )
+        .pixel_sampler((renders[0].resolution.width, renders[0].resolution.height));
         let mut preprocess_profile = Profile::default();
         integrator.preprocess(&mut sampler, &renders, &mut preprocess_profile);
         let mut pb = ProgressBar::new(total_pixels as u64);
@@ -185,158 +415,164 @@ impl NaiveRenderer {
 
         let clone2 = pixel_count.clone();
 
-        let (tx, rx) = unbounded();
-        // let (tx, rx) = bounded(100000);
-
-        let total_splats = Arc::new(Mutex::new(0usize));
-        let stop_splatting = Arc::new(AtomicBool::new(false));
-
-        let light_films_ref = Arc::clone(&light_films);
-        let total_splats_ref = Arc::clone(&total_splats);
-        let stop_splatting_ref = Arc::clone(&stop_splatting);
-        let splatting_thread = thread::spawn(move || {
-            let films = &mut light_films_ref.lock().unwrap();
-            let mut local_total_splats = total_splats_ref.lock().unwrap();
-            let mut local_stop_splatting = false;
-            let mut remaining_iterations = 10;
-            loop {
-                // let mut samples: Vec<(Sample, u8)> = rx.try_iter().collect();
-                // samples.par_sort_unstable_by(|(a0, b0), (a1, b1)| {
-                //     // primary sort by camera id
-                //     match b0.cmp(b1) {
-                //         CmpOrdering::Equal => match (a0, a1) {
-                //             // if camera ids match, secondary sort by pixel
-                //             (
-                //                 Sample::LightSample(_, (p0x, p0y)),
-                //                 Sample::LightSample(_, (p1x, p1y)),
-                //             ) => {
-                //                 // let (p0x, p0y) = a0.1;
-                //                 // let (p1x, p1y) = a1.1;
-                //                 if p0y < p1y {
-                //                     CmpOrdering::Less
-                //                 } else if p0y > p1y {
-                //                     CmpOrdering::Greater
-                //                 } else {
-                //                     if p0x < p1x {
-                //                         CmpOrdering::Less
-                //                     } else if p0x > p1x {
-                //                         CmpOrdering::Greater
-                //                     } else {
-                //                         CmpOrdering::Equal
-                //                     }
-                //                 }
-                //             }
-                //             _ => CmpOrdering::Equal,
-                //         },
-                //         t => t,
-                //     }
-                // });
-                for v in rx.try_iter() {
-                    let (sample, film_id): (Sample, CameraId) = v;
-                    match sample {
-                        Sample::LightSample(sw, pixel) => {
-                            let film = &mut films[film_id as usize];
-                            let color = sw;
-                            let (x, y) = (
-                                (pixel.0 * film.width as f32) as usize,
-                                film.height - (pixel.1 * film.height as f32) as usize - 1,
-                            );
-
-                            film.buffer[y * film.width + x] += color;
-                            (*local_total_splats) += 1usize;
-                        }
-                        _ => {}
-                    }
-                }
-                if !local_stop_splatting && stop_splatting_ref.load(Ordering::Relaxed) {
-                    local_stop_splatting = true;
-                }
-                if local_stop_splatting {
-                    remaining_iterations -= 1;
-                }
-                thread::sleep(Duration::from_millis(100));
-                if remaining_iterations <= 0 {
-                    break;
+        // cameras render in parallel, and each one splats into its own copy of every light film, since splats can land on any camera's film.
+        // the copies are summed in camera order afterwards, which keeps the light films bit reproducible regardless of thread count.
+        let camera_results: Vec<(
+            Profile,
+            usize,
+            Vec<Film<XYZColor>>,
+            Vec<Vec<Film<XYZColor>>>,
+        )> = films
+            .par_iter_mut()
+            .zip(group_films.par_iter_mut())
+            .enumerate()
+            .map(|(camera_id, ((settings, film), camera_group_films))| {
+                if let IntegratorKind::BDPT {
+                    selected_pair: Some((s, t)),
+                } = settings.integrator
+                {
+                    println!("rendering specific pair {} {}", s, t);
                 }
-            }
-        });
+                let sampler_factory = SamplerFactory::from_settings(
+                    settings,
+                    SamplerKind::Stratified {
+                        x: 20,
+                        y: 20,
+                        z: 10,
+                    },
+                );
+                let width = settings.resolution.width;
+                let group_count = camera_group_films.len();
+                let mut profile = Profile::default();
+                let mut splat_count = 0usize;
+                let mut camera_light_films = light_films.clone();
+                let mut camera_group_light_films = group_light_films.clone();
+                // render a chunk of rows in parallel, then apply the splats from that chunk in pixel order.
+                // this keeps the light films bit reproducible regardless of thread count, since float addition order is fixed.
+                for (chunk_index, chunk) in
+                    film.buffer.chunks_mut(width * SPLAT_CHUNK_ROWS).enumerate()
+                {
+                    let chunk_results: Vec<(Profile, Vec<XYZColor>, Vec<(Sample, CameraId)>)> =
+                        chunk
+                            .par_iter_mut()
+                            .enumerate()
+                            .map(|(index_in_chunk, pixel_ref)| {
+                                let mut profile = Profile::default();
+                                let pixel_index =
+                                    chunk_index * width * SPLAT_CHUNK_ROWS + index_in_chunk;
+                                let y: usize = pixel_index / width;
+                                let x: usize = pixel_index - width * y;
 
-        let tx_arc = Arc::new(Mutex::new(tx));
-        // might need to rate limit based on the speed at which splatting is occurring, but for now don't limit.
-        // Light tracing will use an unbounded amount of memory though.
-        let per_splat_sleep_time = Duration::from_nanos(0);
+                                let mut temp_color = XYZColor::BLACK;
+                                let mut group_colors = vec![XYZColor::BLACK; group_count];
+                                let mut sampler: Box<dyn Sampler> =
+                                    sampler_factory.pixel_sampler((x, y));
+                                // idea: use SPD::Tabulated to collect all the data for a single pixel as a SPD, then convert that whole thing to XYZ.
+                                let mut local_additional_splats: Vec<(Sample, CameraId)> =
+                                    Vec::new();
+                                // use with capacity to preallocate
+                                for s in 0..settings.min_samples {
+                                    sampler_factory.start_sample(&mut sampler, (x, y), s as usize);
+                                    let sample = sampler.draw_2d();
+                                    let camera_uv = (
+                                        ((x as f32 + sample.x)
+                                            / (settings.resolution.width as f32))
+                                            .clamp(0.0, 1.0 - std::f32::EPSILON),
+                                        ((y as f32 + sample.y)
+                                            / (settings.resolution.height as f32))
+                                            .clamp(0.0, 1.0 - std::f32::EPSILON),
+                                    );
+                                    temp_color += integrator.color_with_light_groups(
+                                        &mut sampler,
+                                        settings,
+                                        (camera_uv, camera_id as CameraId),
+                                        s as usize,
+                                        &mut group_colors,
+                                        &mut local_additional_splats,
+                                        &mut profile,
+                                    );
 
-        let stats: Vec<Profile> = films
-            .par_iter_mut()
-            .enumerate()
-            .map(
-                |(camera_id, (settings, film)): (usize, &mut (RenderSettings, Film<XYZColor>))| {
-                    if let IntegratorKind::BDPT {
-                        selected_pair: Some((s, t)),
-                    } = settings.integrator
-                    {
-                        println!("rendering specific pair {} {}", s, t);
-                    }
+                                    debug_assert!(
+                                        temp_color.0.is_finite().all(),
+                                        "integrator returned {:?}",
+                                        temp_color
+                                    );
+                                }
 
-                    let profile: Profile = film
-                        .buffer
-                        .par_iter_mut()
-                        .enumerate()
-                        .map(|(pixel_index, pixel_ref)| {
-                            let mut profile = Profile::default();
-                            let tx1 = { tx_arc.lock().unwrap().clone() };
-                            let y: usize = pixel_index / settings.resolution.width;
-                            let x: usize = pixel_index - settings.resolution.width * y;
-
-                            let mut temp_color = XYZColor::BLACK;
-                            let mut sampler: Box<dyn Sampler> =
-                                Box::new(StratifiedSampler::new(20, 20, 10));
-                            // let mut sampler: Box<dyn Sampler> = Box::new(RandomSampler::new());
-                            // idea: use SPD::Tabulated to collect all the data for a single pixel as a SPD, then convert that whole thing to XYZ.
-                            let mut local_additional_splats: Vec<(Sample, CameraId)> = Vec::new();
-                            // use with capacity to preallocate
-                            for s in 0..settings.min_samples {
-                                let sample = sampler.draw_2d();
-                                let camera_uv = (
-                                    ((x as f32 + sample.x) / (settings.resolution.width as f32))
-                                        .clamp(0.0, 1.0 - std::f32::EPSILON),
-                                    ((y as f32 + sample.y) / (settings.resolution.height as f32))
-                                        .clamp(0.0, 1.0 - std::f32::EPSILON),
-                                );
-                                temp_color += integrator.color(
-                                    &mut sampler,
-                                    settings,
-                                    (camera_uv, camera_id as CameraId),
-                                    s as usize,
-                                    &mut local_additional_splats,
-                                    &mut profile,
-                                );
-
-                                debug_assert!(
-                                    temp_color.0.is_finite().all(),
-                                    "integrator returned {:?}",
-                                    temp_color
-                                );
-                            }
+                                *pixel_ref = temp_color / (settings.min_samples as f32);
+                                for color in group_colors.iter_mut() {
+                                    *color = *color / (settings.min_samples as f32);
+                                }
+                                clone2.fetch_add(1, Ordering::Relaxed);
+                                (profile, group_colors, local_additional_splats)
+                            })
+                            .collect();
 
-                            *pixel_ref = temp_color / (settings.min_samples as f32);
-                            clone2.fetch_add(1, Ordering::Relaxed);
-                            if per_splat_sleep_time.as_nanos() > 0 {
-                                thread::sleep(
-                                    per_splat_sleep_time * local_additional_splats.len() as u32,
-                                );
-                            }
-                            for splat in local_additional_splats {
-                                tx1.send(splat).unwrap();
+                    for (index_in_chunk, (pixel_profile, group_colors, splats)) in
+                        chunk_results.into_iter().enumerate()
+                    {
+                        profile = profile.combine(pixel_profile);
+                        let pixel_index = chunk_index * width * SPLAT_CHUNK_ROWS + index_in_chunk;
+                        for (group_film, color) in camera_group_films.iter_mut().zip(group_colors) {
+                            group_film.buffer[pixel_index] = color;
+                        }
+                        for (sample, film_id) in splats {
+                            match sample {
+                                Sample::LightSample(color, pixel, light_group) => {
+                                    let film = &mut camera_light_films[film_id as usize];
+                                    let (x, y) = (
+                                        (pixel.0 * film.width as f32) as usize,
+                                        film.height - (pixel.1 * film.height as f32) as usize - 1,
+                                    );
+
+                                    film.buffer[y * film.width + x] += color;
+                                    if let Some(group_film) = light_group.and_then(|group| {
+                                        camera_group_light_films[film_id as usize].get_mut(group)
+                                    }) {
+                                        group_film.buffer[y * film.width + x] += color;
+                                    }
+                                    splat_count += 1;
+                                }
+                                _ => {}
                             }
-                            profile
-                        })
-                        .reduce(|| Profile::default(), |a, b| a.combine(b));
-                    profile
-                },
-            )
+                        }
+                    }
+                }
+                (
+                    profile,
+                    splat_count,
+                    camera_light_films,
+                    camera_group_light_films,
+                )
+            })
             .collect();
 
+        let mut total_splats = 0usize;
+        let mut stats: Vec<Profile> = Vec::new();
+        for (profile, splat_count, camera_light_films, camera_group_light_films) in camera_results {
+            stats.push(profile);
+            total_splats += splat_count;
+            for (light_film, camera_light_film) in light_films.iter_mut().zip(camera_light_films) {
+                for (pixel, splatted) in light_film.buffer.iter_mut().zip(camera_light_film.buffer)
+                {
+                    *pixel += splatted;
+                }
+            }
+            for (group_light_film, camera_group_light_film) in group_light_films
+                .iter_mut()
+                .flatten()
+                .zip(camera_group_light_films.into_iter().flatten())
+            {
+                for (pixel, splatted) in group_light_film
+                    .buffer
+                    .iter_mut()
+                    .zip(camera_group_light_film.buffer)
+                {
+                    *pixel += splatted;
+                }
+            }
+        }
+
         if let Err(panic) = thread.join() {
             println!(
                 "progress bar incrementing thread threw an error {:?}",
@@ -344,18 +580,7 @@ impl NaiveRenderer {
             );
         }
 
-        let now2 = Instant::now();
-        stop_splatting.store(true, Ordering::Relaxed);
-
-        if let Err(panic) = splatting_thread.join() {
-            println!("panic occurred within thread: {:?}", panic);
-        }
-        let elapsed2 = (now2.elapsed().as_millis() as f32) / 1000.0;
-        println!(
-            "found {} splats, and took {}s to finish splatting them to film",
-            total_splats.lock().unwrap(),
-            elapsed2
-        );
+        println!("found and splatted {} splats", total_splats);
 
         let elapsed = now.elapsed().as_millis() as f32 / 1000.0;
 
@@ -367,8 +592,10 @@ impl NaiveRenderer {
 
         // TODO: do correct lightfilm + imagefilm combination, instead of outputting both
 
+        // the light group films of each entry in films, which only the combined films have
+        let mut layers: Vec<Vec<Film<XYZColor>>> = vec![Vec::new(); films.len()];
         let mut i = 0;
-        for light_film in light_films.lock().unwrap().iter() {
+        for light_film in light_films.iter() {
             let mut render_settings = films[i].0.clone();
             let mut image_film = films[i].1.clone();
             let new_filename = format!(
@@ -392,7 +619,26 @@ impl NaiveRenderer {
                     *pixel_ref = *pixel_ref + light_color / (render_settings.min_samples as f32);
                 });
 
+            // light groups are combined the same way
+            let combined_group_films = group_films[i]
+                .iter()
+                .zip(group_light_films[i].iter())
+                .map(|(group_film, group_light_film)| {
+                    let mut combined = group_film.clone();
+                    combined
+                        .buffer
+                        .par_iter_mut()
+                        .zip(group_light_film.buffer.par_iter())
+                        .for_each(|(pixel_ref, light_color)| {
+                            *pixel_ref =
+                                *pixel_ref + *light_color / (render_settings.min_samples as f32);
+                        });
+                    combined
+                })
+                .collect();
+
             films.push((render_settings, image_film));
+            layers.push(combined_group_films);
             println!(
                 "added combination film to films vec, films vec length is now {}",
                 films.len()
@@ -401,7 +647,7 @@ impl NaiveRenderer {
         }
 
         let mut i = 0;
-        for light_film in light_films.lock().unwrap().iter() {
+        for light_film in light_films.iter() {
             let mut render_settings = films[i].0.clone();
             let new_filename = format!(
                 "{}{}",
@@ -413,6 +659,7 @@ impl NaiveRenderer {
             println!("new filename is {}", new_filename);
             render_settings.filename = Some(new_filename);
             films.push((render_settings, light_film.clone()));
+            layers.push(Vec::new());
             println!(
                 "added light film to films vec, films vec length is now {}",
                 films.len()
@@ -426,6 +673,10 @@ impl NaiveRenderer {
         // let elapsed = (now.elapsed().as_millis() as f32) / 1000.0;
         // println!("\ntook {}s to merge films\n", elapsed,);
         films
+            .into_iter()
+            .zip(layers)
+            .map(|((settings, film), layers)| (settings, film, layers))
+            .collect()
     }
 }
 
@@ -436,12 +687,15 @@ impl Renderer for NaiveRenderer {
         let mut bundled_cameras: Vec<Camera> = Vec::new();
         // let mut films: Vec<(RenderSettings, Film<XYZColor>)> = Vec::new();
         let mut sampled_renders: Vec<(IntegratorType, RenderSettings)> = Vec::new();
+        // splatted renders are bundled by integrator and by whether they use hwss, since each bundle shares one integrator
         let mut splatting_renders_and_cameras: HashMap<
-            IntegratorType,
+            (IntegratorType, bool),
             Vec<(RenderSettings, Camera)>,
         > = HashMap::new();
-        splatting_renders_and_cameras.insert(IntegratorType::BDPT, Vec::new());
-        splatting_renders_and_cameras.insert(IntegratorType::LightTracing, Vec::new());
+        for hwss in [false, true].iter() {
+            splatting_renders_and_cameras.insert((IntegratorType::BDPT, *hwss), Vec::new());
+            splatting_renders_and_cameras.insert((IntegratorType::LightTracing, *hwss), Vec::new());
+        }
 
         // phase 1, gather and sort what renders need to be done
         for (_render_id, render_settings) in config.render_settings.iter().enumerate() {
@@ -457,6 +711,23 @@ impl Renderer for NaiveRenderer {
             let copied_camera = cameras[camera_id].with_aspect_ratio(aspect_ratio);
 
             let integrator_type: IntegratorType = IntegratorType::from(render_settings.integrator);
+            if render_settings.light_groups.unwrap_or(false)
+                && !matches!(
+                    integrator_type,
+                    IntegratorType::PathTracing
+                        | IntegratorType::BDPT
+                        | IntegratorType::LightTracing
+                )
+            {
+                println!(
+                    "warning: light groups are only supported by the path tracer, bdpt and light tracing, only writing the combined film"
+                );
+            }
+            if render_settings.passes.is_some() && integrator_type != IntegratorType::PathTracing {
+                println!(
+                    "warning: passes are only supported by the path tracer, only writing the combined film"
+                );
+            }
 
             match integrator_type {
                 IntegratorType::PathTracing => {
@@ -471,9 +742,23 @@ impl Renderer for NaiveRenderer {
                     bundled_cameras.push(copied_camera);
                     sampled_renders.push((IntegratorType::SPPM, updated_render_settings));
                 }
-                t if splatting_renders_and_cameras.contains_key(&t) => {
+                IntegratorType::MLT => {
+                    let mut updated_render_settings = render_settings.clone();
+                    updated_render_settings.camera_id = camera_id;
+                    bundled_cameras.push(copied_camera);
+                    sampled_renders.push((IntegratorType::MLT, updated_render_settings));
+                }
+                IntegratorType::VCM => {
+                    let mut updated_render_settings = render_settings.clone();
+                    updated_render_settings.camera_id = camera_id;
+                    bundled_cameras.push(copied_camera);
+                    sampled_renders.push((IntegratorType::VCM, updated_render_settings));
+                }
+                t if splatting_renders_and_cameras.contains_key(&(t, render_settings.hwss)) => {
                     // then determine new camera id
-                    let list = splatting_renders_and_cameras.get_mut(&t).unwrap();
+                    let list = splatting_renders_and_cameras
+                        .get_mut(&(t, render_settings.hwss))
+                        .unwrap();
                     let mut updated_render_settings = render_settings.clone();
                     updated_render_settings.camera_id = camera_id;
 
@@ -497,24 +782,54 @@ impl Renderer for NaiveRenderer {
                     ) {
                         Some(Integrator::PathTracing(integrator)) => {
                             println!("rendering with path tracing integrator");
-                            let (render_settings, film) = (
+                            let light_group_count = if render_settings.light_groups.unwrap_or(false)
+                            {
+                                arc_world.light_group_count()
+                            } else {
+                                0
+                            };
+                            let pass_count = integrator.passes.len();
+                            let (render_settings, (film, layer_films)) = (
                                 render_settings.clone(),
                                 NaiveRenderer::render_sampled(
                                     integrator,
                                     render_settings,
                                     &cameras[render_settings.camera_id],
+                                    light_group_count,
+                                    pass_count,
                                 ),
                             );
-                            output_film(&render_settings, &film);
+                            // render_sampled puts the light group films before the pass films
+                            let (group_films, pass_films) = layer_films.split_at(light_group_count);
+                            output_film_with_light_groups(
+                                &render_settings,
+                                &film,
+                                &arc_world.light_group_names,
+                                group_films,
+                            );
+                            let pass_names: Vec<String> = render_settings
+                                .passes
+                                .iter()
+                                .flatten()
+                                .map(|pass| pass.name.clone())
+                                .collect();
+                            output_passes(&render_settings, &film, &pass_names, pass_films);
                         }
                         Some(Integrator::HWSSPathTracing(integrator)) => {
                             println!("rendering with hwss path tracing integrator");
-                            let (render_settings, film) = (
+                            if render_settings.light_groups.unwrap_or(false)
+                                || render_settings.passes.is_some()
+                            {
+                                println!("warning: light groups and passes aren't supported with hwss, only writing the combined film");
+                            }
+                            let (render_settings, (film, _)) = (
                                 render_settings.clone(),
                                 NaiveRenderer::render_sampled(
                                     integrator,
                                     render_settings,
                                     &cameras[render_settings.camera_id],
+                                    0,
+                                    0,
                                 ),
                             );
                             output_film(&render_settings, &film);
@@ -535,7 +850,7 @@ impl Renderer for NaiveRenderer {
                             println!("rendering with sppm integrator");
                             let (render_settings, film) = (
                                 render_settings.clone(),
-                                NaiveRenderer::render_sampled(
+                                SPPMRenderer::render_progressive(
                                     integrator,
                                     render_settings,
                                     &cameras[render_settings.camera_id],
@@ -546,23 +861,77 @@ impl Renderer for NaiveRenderer {
                         _ => {}
                     }
                 }
+                IntegratorType::MLT => {
+                    // splats need the camera with the film's aspect ratio, and t == 1 connections need the lens in the scene
+                    let (width, height) = (
+                        render_settings.resolution.width,
+                        render_settings.resolution.height,
+                    );
+                    let camera = cameras[render_settings.camera_id]
+                        .with_aspect_ratio(width as f32 / height as f32);
+                    // keep every camera so that the world's camera ids match the render settings' camera_id
+                    let mut mlt_cameras = cameras.clone();
+                    mlt_cameras[render_settings.camera_id] = camera.clone();
+                    world.assign_cameras(mlt_cameras, true);
+                    let arc_world = Arc::new(world.clone());
+                    match Integrator::from_settings_and_world(
+                        arc_world.clone(),
+                        IntegratorType::MLT,
+                        &bundled_cameras,
+                        render_settings,
+                    ) {
+                        Some(Integrator::MLT(integrator)) => {
+                            println!("rendering with metropolis light transport integrator");
+                            let film =
+                                NaiveRenderer::render_mlt(integrator, render_settings, &camera);
+                            output_film(&render_settings, &film);
+                        }
+                        _ => {}
+                    }
+                }
+                IntegratorType::VCM => {
+                    // same as mlt, splats need the camera with the film's aspect ratio and the lens in the scene
+                    let (width, height) = (
+                        render_settings.resolution.width,
+                        render_settings.resolution.height,
+                    );
+                    let camera = cameras[render_settings.camera_id]
+                        .with_aspect_ratio(width as f32 / height as f32);
+                    world.assign_cameras(vec![camera.clone()], true);
+                    let arc_world = Arc::new(world.clone());
+                    match Integrator::from_settings_and_world(
+                        arc_world.clone(),
+                        IntegratorType::VCM,
+                        &bundled_cameras,
+                        render_settings,
+                    ) {
+                        Some(Integrator::VCM(integrator)) => {
+                            println!("rendering with vertex connection and merging integrator");
+                            let film =
+                                NaiveRenderer::render_vcm(integrator, render_settings, &camera);
+                            output_film(&render_settings, &film);
+                        }
+                        _ => {}
+                    }
+                }
                 _ => {}
             }
         }
 
         // phase 3, do renders where cameras can be combined, and output results as soon as they're finished
 
-        for integrator_type in splatting_renders_and_cameras.keys() {
-            if let Some(l) = splatting_renders_and_cameras.get(integrator_type) {
+        for bundle_key in splatting_renders_and_cameras.keys() {
+            if let Some(l) = splatting_renders_and_cameras.get(bundle_key) {
                 if l.len() == 0 {
                     continue;
                 }
             }
+            let (integrator_type, hwss) = bundle_key;
             match integrator_type {
                 IntegratorType::BDPT => {
                     let (bundled_settings, bundled_cameras): (Vec<RenderSettings>, Vec<Camera>) =
                         splatting_renders_and_cameras
-                            .get(integrator_type)
+                            .get(bundle_key)
                             .unwrap()
                             .iter()
                             .cloned()
@@ -580,6 +949,7 @@ impl Renderer for NaiveRenderer {
                         max_bounces,
                         world: arc_world.clone(),
                         wavelength_bounds,
+                        hwss: *hwss,
                     };
 
                     println!("rendering with bidirectional path tracing integrator");
@@ -587,6 +957,7 @@ impl Renderer for NaiveRenderer {
                         integrator,
                         bundled_settings.clone(),
                         bundled_cameras.clone(),
+                        arc_world.light_group_count(),
                     );
                     assert!(render_splatted_result.len() > 0);
                     // films.extend(
@@ -595,7 +966,7 @@ impl Renderer for NaiveRenderer {
                     //         .cloned()
                     //         .zip(render_splatted_result),
                     // );
-                    for (mut render_settings, film) in render_splatted_result {
+                    for (mut render_settings, film, group_films) in render_splatted_result {
                         // if selected pair, add the pair numbers to the filename automatically
                         if let IntegratorKind::BDPT {
                             selected_pair: Some((s, t)),
@@ -612,13 +983,18 @@ impl Renderer for NaiveRenderer {
                             println!("new filename is {}", new_filename);
                             render_settings.filename = Some(new_filename);
                         }
-                        output_film(&render_settings, &film);
+                        output_film_with_light_groups(
+                            &render_settings,
+                            &film,
+                            &arc_world.light_group_names,
+                            &group_films,
+                        );
                     }
                 }
                 IntegratorType::LightTracing => {
                     let (bundled_settings, bundled_cameras): (Vec<RenderSettings>, Vec<Camera>) =
                         splatting_renders_and_cameras
-                            .get(integrator_type)
+                            .get(bundle_key)
                             .unwrap()
                             .iter()
                             .cloned()
@@ -637,6 +1013,7 @@ impl Renderer for NaiveRenderer {
                         russian_roulette: true,
                         camera_samples: 4,
                         wavelength_bounds,
+                        hwss: *hwss,
                     };
 
                     println!("rendering with light tracing integrator");
@@ -644,6 +1021,7 @@ impl Renderer for NaiveRenderer {
                         integrator,
                         bundled_settings.clone(),
                         bundled_cameras.clone(),
+                        arc_world.light_group_count(),
                     );
                     assert!(render_splatted_result.len() > 0);
                     // films.extend(
@@ -652,8 +1030,13 @@ impl Renderer for NaiveRenderer {
                     //         .cloned()
                     //         .zip(render_splatted_result),
                     // );
-                    for (render_settings, film) in render_splatted_result {
-                        output_film(&render_settings, &film);
+                    for (render_settings, film, group_films) in render_splatted_result {
+                        output_film_with_light_groups(
+                            &render_settings,
+                            &film,
+                            &arc_world.light_group_names,
+                            &group_films,
+                        );
                     }
                 }
 
@@ -1,9 +1,10 @@
 use super::{output_film, Film, Renderer};
 
-use crate::config::{Config, IntegratorKind, RenderSettings, Resolution};
+use crate::config::{Config, IntegratorKind, RenderSettings, Resolution, SamplerKind};
 use crate::integrator::*;
 use crate::math::*;
 use crate::profile::Profile;
+use crate::sampler::SamplerFactory;
 use crate::tonemap::{sRGB, Tonemapper};
 use crate::world::World;
 use crate::{camera::Camera, config::RendererType};
@@ -101,8 +102,16 @@ impl Renderer for PreviewRenderer {
 
                     const SHOW_PROGRESS_BAR: bool = true;
 
+                    let sampler_factory = SamplerFactory::from_settings(
+                        &render_settings,
+                        SamplerKind::Stratified {
+                            x: 20,
+                            y: 20,
+                            z: 10,
+                        },
+                    );
                     let mut sampler: Box<dyn Sampler> =
-                        Box::new(StratifiedSampler::new(20, 20, 10));
+                        sampler_factory.pixel_sampler((width, height));
                     let mut preprocess_profile = Profile::default();
                     integrator.preprocess(
                         &mut sampler,
@@ -195,7 +204,7 @@ impl Renderer for PreviewRenderer {
                             for v in rx.try_iter() {
                                 let (sample, _film_id): (Sample, CameraId) = v;
                                 match sample {
-                                    Sample::LightSample(sw, pixel) => {
+                                    Sample::LightSample(sw, pixel, _) => {
                                         let color = XYZColor::from(sw);
                                         let (width, height) = (film.width, film.height);
                                         let (x, y) = (
@@ -209,8 +218,7 @@ impl Renderer for PreviewRenderer {
                                     _ => {}
                                 }
                             }
-                            let srgb_tonemapper =
-                                sRGB::new(&film, render_settings_copy.exposure.unwrap_or(1.0));
+                            let srgb_tonemapper = sRGB::for_settings(&film, &render_settings_copy);
                             {
                                 light_buffer_ref
                                     .lock()
@@ -274,8 +282,8 @@ impl Renderer for PreviewRenderer {
 
                                 let mut temp_color = XYZColor::BLACK;
                                 let mut sampler: Box<dyn Sampler> =
-                                    Box::new(StratifiedSampler::new(20, 20, 10));
-                                // let mut sampler: Box<dyn Sampler> = Box::new(RandomSampler::new());
+                                    sampler_factory.pixel_sampler((x, y));
+                                sampler_factory.start_sample(&mut sampler, (x, y), s as usize);
                                 // idea: use SPD::Tabulated to collect all the data for a single pixel as a SPD, then convert that whole thing to XYZ.
                                 let mut local_additional_splats: Vec<(Sample, CameraId)> =
                                     Vec::new();
@@ -316,7 +324,7 @@ impl Renderer for PreviewRenderer {
                                 }
                             });
                         let srgb_tonemapper =
-                            sRGB::new(&films[film_idx], render_settings.exposure.unwrap_or(1.0));
+                            sRGB::for_settings(&films[film_idx], &render_settings);
                         buffer
                             .par_iter_mut()
                             .enumerate()
@@ -397,6 +405,8 @@ impl Renderer for PreviewRenderer {
                         }
                     });
 
+                    let sampler_factory =
+                        SamplerFactory::from_settings(&render_settings, SamplerKind::Random);
                     let clone2 = pixel_count.clone();
                     for s in 0..render_settings.min_samples {
                         if !window.is_open() || window.is_key_down(Key::Escape) {
@@ -416,8 +426,9 @@ impl Renderer for PreviewRenderer {
                                 // let r: Ray = Ray::new(Point3::ZERO, Vec3::X);
                                 // let mut temp_color = RGBColor::BLACK;
                                 let mut temp_color = XYZColor::BLACK;
-                                // let mut sampler: Box<dyn Sampler> = Box::new(StratifiedSampler::new(20, 20, 10));
-                                let mut sampler: Box<dyn Sampler> = Box::new(RandomSampler::new());
+                                let mut sampler: Box<dyn Sampler> =
+                                    sampler_factory.pixel_sampler((x, y));
+                                sampler_factory.start_sample(&mut sampler, (x, y), s as usize);
                                 // idea: use SPD::Tabulated to collect all the data for a single pixel as a SPD, then convert that whole thing to XYZ.
 
                                 let sample = sampler.draw_2d();
@@ -461,7 +472,7 @@ impl Renderer for PreviewRenderer {
                         println!("took {}s", elapsed);
                         stats.pretty_print(elapsed, render_settings.threads.unwrap() as usize);
                         let srgb_tonemapper =
-                            sRGB::new(&films[film_idx], render_settings.exposure.unwrap_or(1.0));
+                            sRGB::for_settings(&films[film_idx], &render_settings);
                         buffer
                             .par_iter_mut()
                             .enumerate()
@@ -5,14 +5,13 @@ use crate::config::*;
 use crate::integrator::*;
 use crate::math::*;
 use crate::profile::Profile;
+use crate::sampler::SamplerFactory;
 use crate::world::World;
 
 use std::collections::HashMap;
 // use std::io::Write;
-use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::thread;
-use std::time::{Duration, Instant};
+use std::time::Instant;
 
 // use crossbeam::channel::{bounded};
 use pbr::ProgressBar;
@@ -26,7 +25,9 @@ impl SPPMRenderer {
         SPPMRenderer {}
     }
 
-    pub fn render_sampled(
+    // runs sppm iterations until either the iteration budget (max_samples, or min_samples if unset)
+    // or the time limit of the integrator settings is reached, then writes out the current estimate.
+    pub fn render_progressive(
         mut integrator: SPPMIntegrator,
         settings: &RenderSettings,
         _camera: &Camera,
@@ -36,98 +37,54 @@ impl SPPMRenderer {
             "starting sppm render with film resolution {}x{}",
             width, height
         );
-        let min_camera_rays = width * height * settings.min_samples as usize;
+        let max_iterations = settings.max_samples.unwrap_or(settings.min_samples) as usize;
+        let time_limit = match settings.integrator {
+            IntegratorKind::SPPM { time_limit, .. } => time_limit,
+            _ => None,
+        };
         println!(
-            "minimum samples per pixel: {}",
-            settings.min_samples as usize
+            "maximum iterations: {}, photons per iteration: {}",
+            max_iterations, integrator.photons_per_iteration
         );
-        println!("minimum total samples: {}", min_camera_rays);
+        if let Some(limit) = time_limit {
+            println!("time limit: {}s", limit);
+        }
 
         let now = Instant::now();
 
         let mut film: Film<XYZColor> = Film::new(width, height, XYZColor::BLACK);
+        let sampler_factory = SamplerFactory::from_settings(settings, SamplerKind::Random);
+        integrator.reset(width, height);
 
-        let mut pb = ProgressBar::new((width * height) as u64);
-
-        let total_pixels = width * height;
-
-        let pixel_count = Arc::new(AtomicUsize::new(0));
-        let clone1 = pixel_count.clone();
-        let thread = thread::spawn(move || {
-            let mut local_index = 0;
-            while local_index < total_pixels {
-                let pixels_to_increment = clone1.load(Ordering::Relaxed) - local_index;
-                pb.add(pixels_to_increment as u64);
-                local_index += pixels_to_increment;
-
-                thread::sleep(Duration::from_millis(250));
-            }
-        });
-
+        let mut pb = ProgressBar::new(max_iterations as u64);
         let mut stats: Profile = Profile::default();
-        let mut presampler: Box<dyn Sampler> = Box::new(RandomSampler::new());
-        let mut preprofile = Profile::default();
-
-        for s in 0..settings.min_samples {
-            integrator.preprocess(&mut presampler, &vec![settings.clone()], &mut preprofile);
-
-            let clone2 = pixel_count.clone();
-            let local_stats: Profile = film
-                .buffer
-                .par_iter_mut()
-                .enumerate()
-                .map(|(pixel_index, pixel_ref)| {
-                    let mut profile = Profile::default();
-                    // let clone = pixel_count.clone();
-                    let y: usize = pixel_index / width;
-                    let x: usize = pixel_index - width * y;
-                    // gen ray for pixel x, y
-                    // let r: Ray = Ray::new(Point3::ZERO, Vec3::X);
-                    // let mut temp_color = RGBColor::BLACK;
-                    let mut temp_color = XYZColor::BLACK;
-                    // let mut sampler: Box<dyn Sampler> = Box::new(StratifiedSampler::new(20, 20, 10));
-                    let mut sampler: Box<dyn Sampler> = Box::new(RandomSampler::new());
-                    // idea: use SPD::Tabulated to collect all the data for a single pixel as a SPD, then convert that whole thing to XYZ.
-
-                    let sample = sampler.draw_2d();
-
-                    let camera_uv = (
-                        (x as f32 + sample.x) / (settings.resolution.width as f32),
-                        (y as f32 + sample.y) / (settings.resolution.height as f32),
-                    );
-                    temp_color +=
-                        integrator.color(&mut sampler, (camera_uv, 0), s as usize, &mut profile);
-                    // temp_color += RGBColor::from(integrator.color(&mut sampler, r));
-                    debug_assert!(
-                        temp_color.0.is_finite().all(),
-                        "{:?} resulted in {:?}",
-                        camera_uv,
-                        temp_color
+        while integrator.iteration < max_iterations {
+            integrator.iterate(&sampler_factory, 0, (width, height), &mut stats);
+            pb.inc();
+            if let Some(limit) = time_limit {
+                if now.elapsed().as_millis() as f32 / 1000.0 >= limit {
+                    println!(
+                        "\nreached time limit after {} iterations",
+                        integrator.iteration
                     );
-
-                    clone2.fetch_add(1, Ordering::Relaxed);
-                    // if pixel_index % output_divisor == 0 {
-                    //     let stdout = std::io::stdout();
-                    //     let mut handle = stdout.lock();
-                    //     handle.write_all(b".").unwrap();
-                    //     std::io::stdout().flush().expect("some error message")
-                    // }
-                    // pb.inc();
-                    // unsafe {
-                    *pixel_ref = temp_color / (settings.min_samples as f32);
-                    // }
-                    profile
-                })
-                .reduce(|| Profile::default(), |a, b| a.combine(b));
-            stats = stats.combine(local_stats);
-        }
-        if let Err(panic) = thread.join() {
-            println!(
-                "progress bar incrememnting thread threw an error {:?}",
-                panic
-            );
+                    break;
+                }
+            }
         }
 
+        film.buffer
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(pixel_index, pixel_ref)| {
+                *pixel_ref = integrator.estimate(pixel_index);
+                debug_assert!(
+                    pixel_ref.0.is_finite().all(),
+                    "pixel {} resulted in {:?}",
+                    pixel_index,
+                    pixel_ref
+                );
+            });
+
         println!("");
         let elapsed = (now.elapsed().as_millis() as f32) / 1000.0;
         println!("took {}s", elapsed);
@@ -204,7 +161,7 @@ impl Renderer for SPPMRenderer {
                             println!("rendering with sppm integrator");
                             let (render_settings, film) = (
                                 render_settings.clone(),
-                                SPPMRenderer::render_sampled(
+                                SPPMRenderer::render_progressive(
                                     integrator,
                                     render_settings,
                                     &cameras[render_settings.camera_id],
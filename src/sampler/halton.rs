@@ -0,0 +1,73 @@
+use super::{hash_combine, mix_bits, u32_to_unit_float};
+use crate::math::*;
+
+const PRIMES: [u64; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+// radical inverse where every digit is randomly rotated, with the rotation depending on all the previous digits.
+// this is owen scrambling restricted to digit rotations, which keeps the halton stratification intact.
+pub fn owen_scrambled_radical_inverse(base: u64, mut index: u64, seed: u32) -> f32 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_m = 1.0f64;
+    let mut reversed_digits = 0u64;
+    // stop once the digits stop mattering at f32 precision
+    while inv_base_m > 1.0e-9 {
+        let next = index / base;
+        let digit = index - next * base;
+        let digit_hash = mix_bits((seed as u64) ^ (reversed_digits << 16) ^ inv_base_m.to_bits());
+        let digit = (digit + digit_hash % base) % base;
+        reversed_digits = reversed_digits * base + digit;
+        inv_base_m *= inv_base;
+        index = next;
+    }
+    ((reversed_digits as f64 * inv_base_m) as f32).min(1.0 - std::f32::EPSILON)
+}
+
+pub struct HaltonSampler {
+    pub seed: u32,
+    pub sample_index: u64,
+    pub dimension: u32,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u32, sample_index: usize) -> Self {
+        HaltonSampler {
+            seed,
+            sample_index: sample_index as u64,
+            dimension: 0,
+        }
+    }
+    fn sample_dimension(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        let seed = hash_combine(self.seed, dimension);
+        match PRIMES.get(dimension as usize) {
+            Some(&base) => owen_scrambled_radical_inverse(base, self.sample_index, seed),
+            // past the supported dimensions, fall back to hashed white noise
+            None => u32_to_unit_float(mix_bits(((seed as u64) << 32) ^ self.sample_index) as u32),
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn draw_1d(&mut self) -> Sample1D {
+        Sample1D {
+            x: self.sample_dimension(),
+        }
+    }
+    fn draw_2d(&mut self) -> Sample2D {
+        let x = self.sample_dimension();
+        let y = self.sample_dimension();
+        Sample2D { x, y }
+    }
+    fn draw_3d(&mut self) -> Sample3D {
+        let x = self.sample_dimension();
+        let y = self.sample_dimension();
+        let z = self.sample_dimension();
+        Sample3D { x, y, z }
+    }
+}
@@ -0,0 +1,231 @@
+use super::IndependentSampler;
+use crate::math::*;
+
+use std::sync::{Arc, Mutex};
+
+const ONE_MINUS_EPSILON: f32 = 1.0 - std::f32::EPSILON / 2.0;
+
+#[derive(Copy, Clone, Debug, Default)]
+struct PrimarySample {
+    value: f32,
+    last_modification_iteration: u64,
+    value_backup: f32,
+    modification_backup: u64,
+}
+
+impl PrimarySample {
+    fn backup(&mut self) {
+        self.value_backup = self.value;
+        self.modification_backup = self.last_modification_iteration;
+    }
+    fn restore(&mut self) {
+        self.value = self.value_backup;
+        self.last_modification_iteration = self.modification_backup;
+    }
+}
+
+struct MLTSamplerState {
+    x: Vec<PrimarySample>,
+    rng: IndependentSampler,
+    sigma: f32,
+    large_step_probability: f32,
+    stream_count: usize,
+    current_iteration: u64,
+    large_step: bool,
+    last_large_step_iteration: u64,
+    stream_index: usize,
+    sample_index: usize,
+}
+
+impl MLTSamplerState {
+    fn normal_sample(&mut self) -> f32 {
+        // box muller, always consumes two values so that the rng stream stays aligned
+        let u0 = (1.0 - self.rng.draw_1d().x).max(std::f32::MIN_POSITIVE);
+        let u1 = self.rng.draw_1d().x;
+        (-2.0 * u0.ln()).sqrt() * (2.0 * PI * u1).cos()
+    }
+
+    fn ensure_ready(&mut self, index: usize) {
+        if index >= self.x.len() {
+            self.x.resize(index + 1, PrimarySample::default());
+        }
+        let last_large_step_iteration = self.last_large_step_iteration;
+        // lazily apply any large steps that happened since this sample was last touched
+        if self.x[index].last_modification_iteration < last_large_step_iteration {
+            let value = self.rng.draw_1d().x;
+            let xi = &mut self.x[index];
+            xi.value = value;
+            xi.last_modification_iteration = last_large_step_iteration;
+        }
+
+        self.x[index].backup();
+        if self.large_step {
+            self.x[index].value = self.rng.draw_1d().x;
+        } else {
+            // apply all the small steps that were skipped at once, since the sum of gaussians is a gaussian
+            let small_steps =
+                (self.current_iteration - self.x[index].last_modification_iteration) as f32;
+            let effective_sigma = self.sigma * small_steps.sqrt();
+            let offset = self.normal_sample() * effective_sigma;
+            let xi = &mut self.x[index];
+            xi.value += offset;
+            xi.value -= xi.value.floor();
+            xi.value = xi.value.min(ONE_MINUS_EPSILON);
+        }
+        self.x[index].last_modification_iteration = self.current_iteration;
+    }
+
+    fn next(&mut self) -> f32 {
+        let index = self.stream_index + self.stream_count * self.sample_index;
+        self.sample_index += 1;
+        self.ensure_ready(index);
+        self.x[index].value
+    }
+}
+
+// primary sample space sampler for metropolis light transport, see Kelemen et al. 2002 and pbrt-v3's MLTSampler.
+// the state is shared between clones, so that the integrator can keep a handle to control mutations
+// while a boxed clone is handed to the path construction functions.
+// dimensions are interleaved between streams, so that the camera, light and connection dimensions
+// stay put when one of the subpaths changes length.
+#[derive(Clone)]
+pub struct MLTSampler {
+    state: Arc<Mutex<MLTSamplerState>>,
+}
+
+impl MLTSampler {
+    pub fn new(
+        seed: u32,
+        chain_index: usize,
+        sigma: f32,
+        large_step_probability: f32,
+        stream_count: usize,
+    ) -> Self {
+        MLTSampler {
+            state: Arc::new(Mutex::new(MLTSamplerState {
+                x: Vec::new(),
+                rng: IndependentSampler::new(seed, chain_index),
+                sigma,
+                large_step_probability,
+                stream_count,
+                current_iteration: 0,
+                large_step: true,
+                last_large_step_iteration: 0,
+                stream_index: 0,
+                sample_index: 0,
+            })),
+        }
+    }
+
+    // replaces the rng that drives mutations, without touching the current state
+    pub fn reseed(&self, seed: u32, chain_index: usize) {
+        self.state.lock().unwrap().rng = IndependentSampler::new(seed, chain_index);
+    }
+
+    pub fn start_iteration(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.current_iteration += 1;
+        state.large_step = state.rng.draw_1d().x < state.large_step_probability;
+    }
+
+    pub fn start_stream(&self, index: usize) {
+        let mut state = self.state.lock().unwrap();
+        debug_assert!(index < state.stream_count);
+        state.stream_index = index;
+        state.sample_index = 0;
+    }
+
+    pub fn is_large_step(&self) -> bool {
+        self.state.lock().unwrap().large_step
+    }
+
+    pub fn accept(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.large_step {
+            state.last_large_step_iteration = state.current_iteration;
+        }
+    }
+
+    pub fn reject(&self) {
+        let mut state = self.state.lock().unwrap();
+        let current_iteration = state.current_iteration;
+        for xi in state.x.iter_mut() {
+            if xi.last_modification_iteration == current_iteration {
+                xi.restore();
+            }
+        }
+        state.current_iteration -= 1;
+    }
+
+    // the next uniform value from the chain's own rng, for acceptance tests and the like
+    pub fn uniform(&self) -> f32 {
+        self.state.lock().unwrap().rng.draw_1d().x
+    }
+
+    pub fn boxed(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+impl Sampler for MLTSampler {
+    fn draw_1d(&mut self) -> Sample1D {
+        let mut state = self.state.lock().unwrap();
+        Sample1D { x: state.next() }
+    }
+    fn draw_2d(&mut self) -> Sample2D {
+        let mut state = self.state.lock().unwrap();
+        let x = state.next();
+        let y = state.next();
+        Sample2D { x, y }
+    }
+    fn draw_3d(&mut self) -> Sample3D {
+        let mut state = self.state.lock().unwrap();
+        let x = state.next();
+        let y = state.next();
+        let z = state.next();
+        Sample3D { x, y, z }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reject_restores_state() {
+        let mlt_sampler = MLTSampler::new(0, 0, 0.01, 0.3, 1);
+        let mut sampler = mlt_sampler.boxed();
+        let initial: Vec<f32> = (0..10).map(|_| sampler.draw_1d().x).collect();
+        for _ in 0..100 {
+            mlt_sampler.start_iteration();
+            mlt_sampler.start_stream(0);
+            let proposed: Vec<f32> = (0..10).map(|_| sampler.draw_1d().x).collect();
+            assert!(
+                proposed.iter().all(|&v| v >= 0.0 && v < 1.0),
+                "{:?}",
+                proposed
+            );
+            mlt_sampler.reject();
+        }
+        // every mutation was rejected, so the chain should still be at its initial state
+        let state = mlt_sampler.state.lock().unwrap();
+        assert!(state.current_iteration == 0);
+        for (xi, v) in state.x.iter().zip(initial.iter()) {
+            assert!(xi.value == *v);
+        }
+    }
+
+    #[test]
+    fn test_streams_interleave() {
+        let mlt_sampler = MLTSampler::new(0, 0, 0.01, 0.3, 3);
+        let mut sampler = mlt_sampler.boxed();
+        mlt_sampler.start_stream(1);
+        sampler.draw_1d();
+        mlt_sampler.start_stream(0);
+        sampler.draw_2d();
+        // stream 0 uses dimensions 0 and 3, stream 1 uses dimension 1
+        let state = mlt_sampler.state.lock().unwrap();
+        assert!(state.x.len() == 4);
+        assert!(state.x[2].last_modification_iteration == 0 && state.x[2].value == 0.0);
+    }
+}
@@ -0,0 +1,232 @@
+mod halton;
+mod mlt;
+mod pmj;
+mod random;
+mod sobol;
+
+pub use halton::HaltonSampler;
+pub use mlt::MLTSampler;
+pub use pmj::PMJ02Sampler;
+pub use random::{permutation_element, IndependentSampler, JitteredSampler};
+pub use sobol::SobolSampler;
+
+use crate::config::{RenderSettings, SamplerKind};
+use crate::math::*;
+
+use std::sync::Arc;
+
+// dimension layout, as consumed by the integrators. every sampler below hands out dimensions in call order,
+// so the integrators are responsible for drawing in a fixed order:
+// 0..2                     film position jitter (renderer)
+// 2                        wavelength
+// 3..5                     camera lens
+// 5                        shutter time
+// then per bounce          2d bsdf sample, 1d russian roulette sample. random_walk pads the remaining bounces so that
+//                          anything drawn after a walk always starts on the same dimension.
+// then per light sample    1d light pick, 2d light surface sample, 1d transmittance sample for the shadow ray
+
+// hash utilities, shared by all the low discrepancy samplers
+
+pub fn mix_bits(mut v: u64) -> u64 {
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5d329728ea185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81dadef4bc2dd44d);
+    v ^= v >> 33;
+    v
+}
+
+pub fn hash_combine(seed: u32, v: u32) -> u32 {
+    seed ^ (v
+        .wrapping_add(seed << 6)
+        .wrapping_add(seed >> 2)
+        .wrapping_add(0x9e3779b9))
+}
+
+pub fn hash_pixel(pixel: (usize, usize), seed: u32) -> u32 {
+    mix_bits(((pixel.0 as u64) << 32) ^ (pixel.1 as u64) ^ mix_bits(seed as u64 + 1)) as u32
+}
+
+// permutation that only propagates bits upward, see Burley 2020, "Practical Hash-based Owen Scrambling"
+pub fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x ^= x.wrapping_mul(0x3d20adea);
+    x = x.wrapping_add(seed);
+    x = x.wrapping_mul((seed >> 16) | 1);
+    x ^= x.wrapping_mul(0x05526c56);
+    x ^= x.wrapping_mul(0x53a22864);
+    x
+}
+
+// owen scrambling, expressed as a laine karras permutation on the reversed bits
+pub fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// second dimension of the sobol sequence. the first dimension is just the bit reversed index.
+pub fn sobol_dim1(mut index: u32) -> u32 {
+    let mut v = 1u32 << 31;
+    let mut result = 0u32;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+pub fn u32_to_unit_float(x: u32) -> f32 {
+    // only keep 24 bits so that the result is strictly less than 1.0
+    (x >> 8) as f32 / (1u32 << 24) as f32
+}
+
+// builds samplers for each pixel and each sample within a pixel.
+// every sampler is rebuilt at the start of each sample and depends only on the render seed, the pixel and the sample index,
+// so that renders are reproducible regardless of thread count or scheduling, and any single sample can be replayed.
+#[derive(Clone)]
+pub struct SamplerFactory {
+    pub kind: SamplerKind,
+    pub seed: u32,
+    pmj_tables: Option<Arc<Vec<Vec<(u32, u32)>>>>,
+}
+
+impl SamplerFactory {
+    pub fn new(kind: SamplerKind, samples_per_pixel: usize, seed: u32) -> Self {
+        let pmj_tables = match kind {
+            SamplerKind::PMJ02 => Some(Arc::new(pmj::generate_pmj02_tables(samples_per_pixel))),
+            _ => None,
+        };
+        SamplerFactory {
+            kind,
+            seed,
+            pmj_tables,
+        }
+    }
+
+    pub fn from_settings(settings: &RenderSettings, default: SamplerKind) -> Self {
+        let samples_per_pixel = settings.max_samples.unwrap_or(settings.min_samples) as usize;
+        SamplerFactory::new(
+            settings.sampler.unwrap_or(default),
+            samples_per_pixel,
+            settings.seed.unwrap_or(0),
+        )
+    }
+
+    pub fn pixel_sampler(&self, pixel: (usize, usize)) -> Box<dyn Sampler> {
+        self.sampler_for(pixel, 0)
+    }
+
+    pub fn start_sample(
+        &self,
+        sampler: &mut Box<dyn Sampler>,
+        pixel: (usize, usize),
+        sample_index: usize,
+    ) {
+        *sampler = self.sampler_for(pixel, sample_index);
+    }
+
+    pub fn sampler_for(&self, pixel: (usize, usize), sample_index: usize) -> Box<dyn Sampler> {
+        let pixel_seed = hash_pixel(pixel, self.seed);
+        match self.kind {
+            SamplerKind::Sobol => Box::new(SobolSampler::new(pixel_seed, sample_index)),
+            SamplerKind::Halton => Box::new(HaltonSampler::new(pixel_seed, sample_index)),
+            SamplerKind::PMJ02 => Box::new(PMJ02Sampler::new(
+                self.pmj_tables
+                    .clone()
+                    .expect("pmj02 tables were not generated"),
+                pixel_seed,
+                sample_index,
+            )),
+            SamplerKind::Random => Box::new(IndependentSampler::new(pixel_seed, sample_index)),
+            SamplerKind::Stratified { x, y, z } => {
+                Box::new(JitteredSampler::new((x, y, z), pixel_seed, sample_index))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_stratification(mut samplers: Vec<Box<dyn Sampler>>, n: usize) {
+        // for power of two sample counts, the first 2d sample should land in every 1/n interval exactly once, along both axes
+        let mut x_strata = vec![0usize; n];
+        let mut y_strata = vec![0usize; n];
+        for sampler in samplers.iter_mut() {
+            let sample = sampler.draw_2d();
+            assert!(sample.x >= 0.0 && sample.x < 1.0, "{:?}", sample.x);
+            assert!(sample.y >= 0.0 && sample.y < 1.0, "{:?}", sample.y);
+            x_strata[(sample.x * n as f32) as usize] += 1;
+            y_strata[(sample.y * n as f32) as usize] += 1;
+        }
+        assert!(x_strata.iter().all(|&c| c == 1), "{:?}", x_strata);
+        assert!(y_strata.iter().all(|&c| c == 1), "{:?}", y_strata);
+    }
+
+    #[test]
+    fn test_sobol_stratification() {
+        let n = 64;
+        let factory = SamplerFactory::new(SamplerKind::Sobol, n, 0);
+        let samplers = (0..n).map(|i| factory.sampler_for((3, 7), i)).collect();
+        check_stratification(samplers, n);
+    }
+
+    #[test]
+    fn test_pmj02_stratification() {
+        let n = 64;
+        let factory = SamplerFactory::new(SamplerKind::PMJ02, n, 0);
+        let samplers = (0..n).map(|i| factory.sampler_for((3, 7), i)).collect();
+        check_stratification(samplers, n);
+    }
+
+    #[test]
+    fn test_halton_range() {
+        let factory = SamplerFactory::new(SamplerKind::Halton, 16, 0);
+        for i in 0..256 {
+            let mut sampler = factory.sampler_for((5, 1), i);
+            for _ in 0..200 {
+                let sample = sampler.draw_1d();
+                assert!(sample.x >= 0.0 && sample.x < 1.0, "{:?}", sample.x);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pixel_decorrelation() {
+        for kind in vec![
+            SamplerKind::Sobol,
+            SamplerKind::Halton,
+            SamplerKind::PMJ02,
+            SamplerKind::Random,
+            SamplerKind::Stratified { x: 4, y: 4, z: 4 },
+        ] {
+            let factory = SamplerFactory::new(kind, 16, 0);
+            let mut a = factory.sampler_for((0, 0), 3);
+            let mut b = factory.sampler_for((1, 0), 3);
+            let (sa, sb) = (a.draw_2d(), b.draw_2d());
+            assert!(sa.x != sb.x || sa.y != sb.y);
+            // and the same dimension in the same pixel should be reproducible
+            let mut c = factory.sampler_for((0, 0), 3);
+            let sc = c.draw_2d();
+            assert!(sa.x == sc.x && sa.y == sc.y);
+        }
+    }
+
+    #[test]
+    fn test_seeded_reproducibility() {
+        let settings_seed = 12345;
+        let a = SamplerFactory::new(SamplerKind::Random, 16, settings_seed);
+        let b = SamplerFactory::new(SamplerKind::Random, 16, settings_seed);
+        let c = SamplerFactory::new(SamplerKind::Random, 16, settings_seed + 1);
+        let mut sa = a.sampler_for((10, 20), 7);
+        let mut sb = b.sampler_for((10, 20), 7);
+        let mut sc = c.sampler_for((10, 20), 7);
+        for _ in 0..100 {
+            let (x, y, z) = (sa.draw_1d().x, sb.draw_1d().x, sc.draw_1d().x);
+            assert!(x == y);
+            assert!(x != z);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use super::{hash_combine, mix_bits, nested_uniform_scramble, sobol_dim1, u32_to_unit_float};
+use crate::math::*;
+
+use std::sync::Arc;
+
+const NUM_PMJ02_TABLES: usize = 64;
+const MAX_PMJ02_TABLE_SIZE: usize = 1 << 16;
+
+// generates a set of independent progressive multi-jittered (0,2) sequences.
+// each table is an owen scrambled (0,2) sequence, which is a stochastic (0,2) sequence with the same distribution as pmj02.
+// see Helmer et al. 2021, "Stochastic Generation of (t, s) Sample Sequences"
+pub fn generate_pmj02_tables(samples_per_pixel: usize) -> Vec<Vec<(u32, u32)>> {
+    let table_size = samples_per_pixel
+        .max(1)
+        .next_power_of_two()
+        .min(MAX_PMJ02_TABLE_SIZE);
+    (0..NUM_PMJ02_TABLES)
+        .map(|table_index| {
+            let seed = mix_bits(table_index as u64 + 1) as u32;
+            (0..table_size as u32)
+                .map(|i| {
+                    (
+                        nested_uniform_scramble(i.reverse_bits(), hash_combine(seed, 0)),
+                        nested_uniform_scramble(sobol_dim1(i), hash_combine(seed, 1)),
+                    )
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// every pair of dimensions gets its own table, selected by hashing the pixel and dimension.
+// pixels are further decorrelated with a random digital shift, which keeps the (0,2) stratification intact.
+pub struct PMJ02Sampler {
+    pub tables: Arc<Vec<Vec<(u32, u32)>>>,
+    pub seed: u32,
+    pub sample_index: usize,
+    pub dimension: u32,
+}
+
+impl PMJ02Sampler {
+    pub fn new(tables: Arc<Vec<Vec<(u32, u32)>>>, seed: u32, sample_index: usize) -> Self {
+        PMJ02Sampler {
+            tables,
+            seed,
+            sample_index,
+            dimension: 0,
+        }
+    }
+
+    fn lookup(&mut self, dimensions: u32) -> (u32, u32) {
+        let table_size = self.tables[0].len();
+        // if we run past the end of the table, treat the next pass as a separate pixel
+        let pass = (self.sample_index / table_size) as u32;
+        let seed = hash_combine(hash_combine(self.seed, pass), self.dimension);
+        self.dimension += dimensions;
+
+        let table = &self.tables[seed as usize % self.tables.len()];
+        let (x, y) = table[self.sample_index % table_size];
+        let shift = mix_bits(seed as u64);
+        (x ^ (shift as u32), y ^ ((shift >> 32) as u32))
+    }
+}
+
+impl Sampler for PMJ02Sampler {
+    fn draw_1d(&mut self) -> Sample1D {
+        let (x, _) = self.lookup(1);
+        Sample1D {
+            x: u32_to_unit_float(x),
+        }
+    }
+    fn draw_2d(&mut self) -> Sample2D {
+        let (x, y) = self.lookup(2);
+        Sample2D {
+            x: u32_to_unit_float(x),
+            y: u32_to_unit_float(y),
+        }
+    }
+    fn draw_3d(&mut self) -> Sample3D {
+        let Sample2D { x, y } = self.draw_2d();
+        let Sample1D { x: z } = self.draw_1d();
+        Sample3D { x, y, z }
+    }
+}
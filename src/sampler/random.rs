@@ -0,0 +1,140 @@
+use super::{hash_combine, mix_bits, u32_to_unit_float};
+use crate::math::*;
+
+// counter based random sampler. every dimension is a hash of the seed and the dimension index,
+// so a sample can be reproduced exactly given only its pixel, sample index and render seed.
+pub struct IndependentSampler {
+    pub seed: u64,
+    pub dimension: u64,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u32, sample_index: usize) -> Self {
+        IndependentSampler {
+            seed: mix_bits(((seed as u64) << 32) ^ sample_index as u64),
+            dimension: 0,
+        }
+    }
+    fn next(&mut self) -> f32 {
+        let v = mix_bits(self.seed ^ mix_bits(self.dimension));
+        self.dimension += 1;
+        u32_to_unit_float(v as u32)
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn draw_1d(&mut self) -> Sample1D {
+        Sample1D { x: self.next() }
+    }
+    fn draw_2d(&mut self) -> Sample2D {
+        let x = self.next();
+        let y = self.next();
+        Sample2D { x, y }
+    }
+    fn draw_3d(&mut self) -> Sample3D {
+        let x = self.next();
+        let y = self.next();
+        let z = self.next();
+        Sample3D { x, y, z }
+    }
+}
+
+// shuffles i within 0..l, with the permutation determined by p. see Kensler 2013, "Correlated Multi-Jittered Sampling"
+pub fn permutation_element(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % l
+}
+
+// stratified sampler where the stratum for each sample is picked by shuffling the sample index,
+// so that it can be evaluated for any sample index without keeping state across samples.
+// 1d draws use x strata, 2d draws use an x by y grid, and 3d draws use an x by y by z grid.
+pub struct JitteredSampler {
+    pub strata: (u32, u32, u32),
+    pub seed: u32,
+    pub sample_index: u32,
+    pub dimension: u32,
+    jitter: IndependentSampler,
+}
+
+impl JitteredSampler {
+    pub fn new(strata: (usize, usize, usize), seed: u32, sample_index: usize) -> Self {
+        JitteredSampler {
+            strata: (
+                strata.0.max(1) as u32,
+                strata.1.max(1) as u32,
+                strata.2.max(1) as u32,
+            ),
+            seed,
+            sample_index: sample_index as u32,
+            dimension: 0,
+            jitter: IndependentSampler::new(seed, sample_index),
+        }
+    }
+    fn stratum(&mut self, count: u32) -> u32 {
+        // each pass through all the strata is shuffled differently
+        let pass = self.sample_index / count;
+        let seed = hash_combine(hash_combine(self.seed, self.dimension), pass);
+        self.dimension += 1;
+        permutation_element(self.sample_index % count, count, seed)
+    }
+}
+
+impl Sampler for JitteredSampler {
+    fn draw_1d(&mut self) -> Sample1D {
+        let nx = self.strata.0;
+        let stratum = self.stratum(nx);
+        let jitter = self.jitter.draw_1d();
+        Sample1D {
+            x: ((stratum as f32 + jitter.x) / nx as f32).min(1.0 - std::f32::EPSILON),
+        }
+    }
+    fn draw_2d(&mut self) -> Sample2D {
+        let (nx, ny) = (self.strata.0, self.strata.1);
+        let stratum = self.stratum(nx * ny);
+        let jitter = self.jitter.draw_2d();
+        let (sx, sy) = (stratum % nx, stratum / nx);
+        Sample2D {
+            x: ((sx as f32 + jitter.x) / nx as f32).min(1.0 - std::f32::EPSILON),
+            y: ((sy as f32 + jitter.y) / ny as f32).min(1.0 - std::f32::EPSILON),
+        }
+    }
+    fn draw_3d(&mut self) -> Sample3D {
+        let (nx, ny, nz) = self.strata;
+        let stratum = self.stratum(nx * ny * nz);
+        let jitter = self.jitter.draw_3d();
+        let (sx, sy, sz) = (stratum % nx, (stratum / nx) % ny, stratum / (nx * ny));
+        Sample3D {
+            x: ((sx as f32 + jitter.x) / nx as f32).min(1.0 - std::f32::EPSILON),
+            y: ((sy as f32 + jitter.y) / ny as f32).min(1.0 - std::f32::EPSILON),
+            z: ((sz as f32 + jitter.z) / nz as f32).min(1.0 - std::f32::EPSILON),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use super::{hash_combine, nested_uniform_scramble, sobol_dim1, u32_to_unit_float};
+use crate::math::*;
+
+// owen scrambled sobol, using the first two dimensions of the sobol sequence for every 2d draw.
+// higher dimensions are "padded", meaning every draw shuffles the sample index with its own seed.
+// see Burley 2020, "Practical Hash-based Owen Scrambling"
+pub struct SobolSampler {
+    pub seed: u32,
+    pub sample_index: u32,
+    pub dimension: u32,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u32, sample_index: usize) -> Self {
+        SobolSampler {
+            seed,
+            sample_index: sample_index as u32,
+            dimension: 0,
+        }
+    }
+
+    fn dimension_seed(&self) -> u32 {
+        hash_combine(self.seed, self.dimension)
+    }
+
+    fn shuffled_index(&self, seed: u32) -> u32 {
+        nested_uniform_scramble(self.sample_index, seed)
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn draw_1d(&mut self) -> Sample1D {
+        let seed = self.dimension_seed();
+        self.dimension += 1;
+        let index = self.shuffled_index(seed);
+        let x = nested_uniform_scramble(index.reverse_bits(), hash_combine(seed, 0));
+        Sample1D {
+            x: u32_to_unit_float(x),
+        }
+    }
+    fn draw_2d(&mut self) -> Sample2D {
+        let seed = self.dimension_seed();
+        self.dimension += 2;
+        let index = self.shuffled_index(seed);
+        let x = nested_uniform_scramble(index.reverse_bits(), hash_combine(seed, 0));
+        let y = nested_uniform_scramble(sobol_dim1(index), hash_combine(seed, 1));
+        Sample2D {
+            x: u32_to_unit_float(x),
+            y: u32_to_unit_float(y),
+        }
+    }
+    fn draw_3d(&mut self) -> Sample3D {
+        let Sample2D { x, y } = self.draw_2d();
+        let Sample1D { x: z } = self.draw_1d();
+        Sample3D { x, y, z }
+    }
+}
@@ -74,4 +74,185 @@ impl TexStack {
         }
         s
     }
+
+    // multiplies the whole stack by factor, by scaling every texture's mixing factors
+    pub fn scaled(mut self, factor: f32) -> Self {
+        for tex in self.textures.iter_mut() {
+            match tex {
+                Texture::Texture1(tex) => {
+                    for v in tex.texture.buffer.iter_mut() {
+                        *v *= factor;
+                    }
+                }
+                Texture::Texture4(tex) => {
+                    for v in tex.texture.buffer.iter_mut() {
+                        *v *= f32x4::splat(factor);
+                    }
+                }
+            }
+        }
+        self
+    }
+
+    // every curve in the stack, along with how much of it is mixed in at uv.
+    // negative factors are clamped to 0, since they can't be sampled
+    fn weighted_curves(&self, uv: (f32, f32)) -> Vec<(&CDF, f32)> {
+        let mut curves = Vec::new();
+        for tex in self.textures.iter() {
+            match tex {
+                Texture::Texture1(tex) => {
+                    curves.push((&tex.curve, tex.texture.at_uv(uv).max(0.0)));
+                }
+                Texture::Texture4(tex) => {
+                    let factors = tex.texture.at_uv(uv);
+                    for (i, curve) in tex.curves.iter().enumerate() {
+                        curves.push((curve, factors.extract(i).max(0.0)));
+                    }
+                }
+            }
+        }
+        curves
+    }
+
+    // samples a wavelength proportional to the spectrum at uv.
+    // a curve is picked based on how much it contributes over the wavelength range, and then the wavelength is sampled from that curve.
+    // returns the wavelength with the spectrum's value there, and the pdf of any of the curves generating it.
+    pub fn sample_power_and_pdf(
+        &self,
+        uv: (f32, f32),
+        wavelength_range: Bounds1D,
+        wavelength_sample: Sample1D,
+    ) -> (SingleWavelength, PDF) {
+        let curves = self.weighted_curves(uv);
+        let integrals: Vec<f32> = curves
+            .iter()
+            .map(|(curve, factor)| {
+                if *factor > 0.0 {
+                    factor * curve_integral(curve, wavelength_range)
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+        let total: f32 = integrals.iter().sum();
+        if total <= 0.0 || !total.is_finite() {
+            // nothing to importance sample, so fall back to uniform
+            let sw = SingleWavelength::new_from_range(wavelength_sample.x, wavelength_range);
+            let energy = self.eval_at(sw.lambda, uv);
+            return (
+                sw.with_energy(SingleEnergy::new(energy)),
+                PDF::from(1.0 / wavelength_range.span()),
+            );
+        }
+
+        let mut picked = 0;
+        let mut x = wavelength_sample.x * total;
+        for (i, integral) in integrals.iter().enumerate() {
+            if *integral > 0.0 {
+                picked = i;
+                if x < *integral {
+                    break;
+                }
+                x -= integral;
+            }
+        }
+        let rescaled = Sample1D {
+            x: (x / integrals[picked]).clamp(0.0, 1.0 - std::f32::EPSILON),
+        };
+        let (sw, picked_pdf) = curves[picked]
+            .0
+            .sample_power_and_pdf(wavelength_range, rescaled);
+        let lambda = sw.lambda;
+
+        // the picked curve's pdf is exact, and the others are normalized by their integrals
+        let mut pdf = 0.0;
+        for (i, ((curve, factor), integral)) in curves.iter().zip(integrals.iter()).enumerate() {
+            if *integral <= 0.0 {
+                continue;
+            }
+            pdf += if i == picked {
+                integral * picked_pdf.0
+            } else {
+                factor * curve.evaluate_power(lambda)
+            };
+        }
+        (
+            sw.with_energy(SingleEnergy::new(self.eval_at(lambda, uv))),
+            PDF::from(pdf / total),
+        )
+    }
+}
+
+// integral of curve over the wavelength range, used to weigh curves against each other
+fn curve_integral(curve: &CDF, wavelength_range: Bounds1D) -> f32 {
+    const WAVELENGTH_STEPS: usize = 64;
+    let mut sum = 0.0;
+    for i in 0..WAVELENGTH_STEPS {
+        let lambda = wavelength_range.lower
+            + wavelength_range.span() * (i as f32 + 0.5) / WAVELENGTH_STEPS as f32;
+        sum += curve.evaluate_power(lambda);
+    }
+    sum / WAVELENGTH_STEPS as f32 * wavelength_range.span()
+}
+
+// a stack with a single curve that's the same everywhere
+impl From<CDF> for TexStack {
+    fn from(curve: CDF) -> Self {
+        TexStack {
+            textures: vec![Texture::Texture1(Texture1 {
+                curve,
+                texture: Film::new(1, 1, 1.0),
+                interpolation_mode: InterpolationMode::Nearest,
+            })],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curves;
+
+    #[test]
+    fn test_tex_stack_spectral_sampling() {
+        // a mix of red and half as much blue
+        let tex = Texture4 {
+            curves: [
+                curves::red(1.0).into(),
+                curves::blue(1.0).into(),
+                curves::void().into(),
+                curves::void().into(),
+            ],
+            texture: Film::new(1, 1, f32x4::new(1.0, 0.5, 0.0, 0.0)),
+            interpolation_mode: InterpolationMode::Nearest,
+        };
+        let stack = TexStack {
+            textures: vec![Texture::Texture4(tex)],
+        };
+        let range = curves::EXTENDED_VISIBLE_RANGE;
+        let uv = (0.5, 0.5);
+        let steps = 1000;
+        let integral = (0..steps)
+            .map(|i| {
+                stack.eval_at(
+                    range.lower + range.span() * (i as f32 + 0.5) / steps as f32,
+                    uv,
+                )
+            })
+            .sum::<f32>()
+            * range.span()
+            / steps as f32;
+        for _ in 0..1000 {
+            let (sw, pdf) = stack.sample_power_and_pdf(uv, range, Sample1D::new_random_sample());
+            let expected = stack.eval_at(sw.lambda, uv);
+            assert!((sw.energy.0 - expected).abs() < 0.0001);
+            assert!(
+                (pdf.0 - expected / integral).abs() < 0.05 * pdf.0,
+                "{:?} {} {}",
+                pdf,
+                expected,
+                integral
+            );
+        }
+    }
 }
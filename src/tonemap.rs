@@ -1,10 +1,12 @@
 #![allow(unused, unused_imports)]
-use crate::config::RenderSettings;
+use crate::config::{PhysicalExposure, RenderSettings};
+use crate::curves::LUMINOUS_EFFICACY;
 use crate::renderer::Film;
 use math::XYZColor;
 
 extern crate exr;
 use exr::prelude::rgba_image::*;
+use exr::prelude::{simple_image, Text};
 use nalgebra::{Matrix3, Vector3};
 use packed_simd::f32x4;
 
@@ -56,6 +58,75 @@ impl sRGB {
     }
 }
 
+impl sRGB {
+    // exposes the film like a camera with the given settings would, where film luminance is integrated radiance against y_bar
+    pub fn new_physical(exposure: PhysicalExposure, exposure_adjustment: f32) -> Self {
+        let saturation_luminance = exposure.saturation_luminance();
+        println!(
+            "physical exposure: iso {}, {}s, f/{}, saturates at {} cd/m^2",
+            exposure.iso, exposure.shutter_time, exposure.fstop, saturation_luminance
+        );
+        sRGB {
+            factor: LUMINOUS_EFFICACY / saturation_luminance,
+            exposure_adjustment,
+        }
+    }
+
+    // uses the camera's physical exposure if it has one, and otherwise normalizes the film to its brightest pixel
+    pub fn for_settings(film: &Film<XYZColor>, render_settings: &RenderSettings) -> Self {
+        let exposure_adjustment = render_settings.exposure.unwrap_or(1.0);
+        match render_settings.physical_exposure {
+            Some(exposure) => sRGB::new_physical(exposure, exposure_adjustment),
+            None => sRGB::new(film, exposure_adjustment),
+        }
+    }
+
+    // writes the linear colors of each film to its own named layer of a single exr.
+    // every layer is exposed the same way, so that light group layers still add up to the combined film
+    pub fn write_layers_to_exr(&self, layers: &[(&str, &Film<XYZColor>)], exr_filename: &str) {
+        let now = Instant::now();
+        let layers = layers
+            .iter()
+            .map(|(name, film)| {
+                let mut channels = [
+                    Vec::with_capacity(film.total_pixels()),
+                    Vec::with_capacity(film.total_pixels()),
+                    Vec::with_capacity(film.total_pixels()),
+                ];
+                for y in 0..film.height {
+                    for x in 0..film.width {
+                        let (_mapped, linear) = self.map(film, (x, y));
+                        let [r, g, b, _]: [f32; 4] = linear.into();
+                        channels[0].push(r);
+                        channels[1].push(g);
+                        channels[2].push(b);
+                    }
+                }
+                let [r, g, b] = channels;
+                simple_image::Layer::new(
+                    Text::from(*name),
+                    (film.width, film.height),
+                    vec![
+                        simple_image::Channel::new_linear("B", simple_image::Samples::F32(b)),
+                        simple_image::Channel::new_linear("G", simple_image::Samples::F32(g)),
+                        simple_image::Channel::new_linear("R", simple_image::Samples::F32(r)),
+                    ]
+                    .into_iter()
+                    .collect(),
+                )
+            })
+            .collect();
+        simple_image::Image::new_from_layers(layers)
+            .write_to_file(exr_filename, write_options::high())
+            .unwrap();
+        println!(
+            "took {}s to write {}\n",
+            (now.elapsed().as_millis() as f32) / 1000.0,
+            exr_filename
+        );
+    }
+}
+
 impl Tonemapper for sRGB {
     fn map(&self, film: &Film<XYZColor>, pixel: (usize, usize)) -> (f32x4, f32x4) {
         let cie_xyz_color = film.at(pixel.0, pixel.1);
@@ -0,0 +1,144 @@
+use crate::math::*;
+
+// piecewise constant distribution over [0, 1), see pbrt's Distribution1D
+#[derive(Clone, Debug)]
+pub struct Distribution1D {
+    pub func: Vec<f32>,
+    cdf: Vec<f32>,
+    pub integral: f32,
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<f32>) -> Self {
+        let n = func.len();
+        assert!(n > 0);
+        let mut cdf = vec![0.0; n + 1];
+        for (i, value) in func.iter().enumerate() {
+            debug_assert!(*value >= 0.0 && value.is_finite(), "{}", value);
+            cdf[i + 1] = cdf[i] + value / n as f32;
+        }
+        let integral = cdf[n];
+        if integral == 0.0 {
+            // nothing to importance sample, so fall back to uniform
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f32 / n as f32;
+            }
+        } else {
+            for c in cdf.iter_mut() {
+                *c /= integral;
+            }
+        }
+        Distribution1D {
+            func,
+            cdf,
+            integral,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.func.len()
+    }
+
+    // returns the sampled point in [0, 1), its pdf and the index of the segment it's in
+    pub fn sample(&self, s: Sample1D) -> (f32, PDF, usize) {
+        let n = self.len();
+        // last index where cdf[index] <= s, which skips over zero width segments
+        let index = (self.cdf.partition_point(|c| *c <= s.x).max(1) - 1).min(n - 1);
+        let width = self.cdf[index + 1] - self.cdf[index];
+        let offset = if width > 0.0 {
+            (s.x - self.cdf[index]) / width
+        } else {
+            0.0
+        };
+        let x = ((index as f32 + offset) / n as f32).min(1.0 - std::f32::EPSILON);
+        (x, PDF::from(self.pdf_at(index)), index)
+    }
+
+    fn pdf_at(&self, index: usize) -> f32 {
+        if self.integral == 0.0 {
+            1.0
+        } else {
+            self.func[index] / self.integral
+        }
+    }
+
+    pub fn pdf(&self, x: f32) -> PDF {
+        let index = ((x * self.len() as f32) as usize).min(self.len() - 1);
+        PDF::from(self.pdf_at(index))
+    }
+
+    // picks one of the segments, returning its index and the probability of picking it
+    pub fn sample_discrete(&self, s: Sample1D) -> (usize, f32) {
+        let (_, _, index) = self.sample(s);
+        (index, self.discrete_pdf(index))
+    }
+
+    pub fn discrete_pdf(&self, index: usize) -> f32 {
+        self.pdf_at(index) / self.len() as f32
+    }
+}
+
+// piecewise constant distribution over [0, 1)^2, stored as rows of width values. u is the column and v is the row.
+#[derive(Clone, Debug)]
+pub struct Distribution2D {
+    rows: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(values: &[f32], width: usize, height: usize) -> Self {
+        assert_eq!(values.len(), width * height);
+        let rows: Vec<Distribution1D> = values
+            .chunks_exact(width)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(rows.iter().map(|row| row.integral).collect());
+        Distribution2D { rows, marginal }
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.marginal.integral
+    }
+
+    // returns a uv and the pdf of sampling it, with respect to area in uv space
+    pub fn sample(&self, s: Sample2D) -> ((f32, f32), PDF) {
+        let (v, v_pdf, row) = self.marginal.sample(Sample1D { x: s.y });
+        let (u, u_pdf, _) = self.rows[row].sample(Sample1D { x: s.x });
+        ((u, v), PDF::from(u_pdf.0 * v_pdf.0))
+    }
+
+    pub fn pdf(&self, uv: (f32, f32)) -> PDF {
+        let row = ((uv.1 * self.rows.len() as f32) as usize).min(self.rows.len() - 1);
+        PDF::from(self.marginal.pdf(uv.1).0 * self.rows[row].pdf(uv.0).0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distribution_2d_pdf() {
+        let (width, height) = (8, 4);
+        let values: Vec<f32> = (0..width * height).map(|i| (i % 5) as f32).collect();
+        let distribution = Distribution2D::new(&values, width, height);
+        let mut integral = 0.0;
+        for i in 0..width * height {
+            let uv = (
+                ((i % width) as f32 + 0.5) / width as f32,
+                ((i / width) as f32 + 0.5) / height as f32,
+            );
+            integral += distribution.pdf(uv).0 / (width * height) as f32;
+        }
+        assert!((integral - 1.0).abs() < 0.0001, "{}", integral);
+
+        for _ in 0..1000 {
+            let (uv, pdf) = distribution.sample(Sample2D::new_random_sample());
+            let column = (uv.0 * width as f32) as usize;
+            let row = (uv.1 * height as f32) as usize;
+            // zero valued cells are never sampled
+            assert!(values[row * width + column] > 0.0);
+            assert!((pdf.0 - distribution.pdf(uv).0).abs() < 0.0001);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 // use crate::materials::*;
 use crate::math::*;
+use crate::world::{HosekWilkieSky, ImageEnvironment};
 
 #[derive(Clone, Debug)]
 pub enum EnvironmentMap {
@@ -13,6 +14,8 @@ pub enum EnvironmentMap {
         angular_diameter: f32,
         sun_direction: Vec3,
     },
+    HosekWilkie(HosekWilkieSky),
+    ImageEnv(ImageEnvironment),
 }
 
 impl EnvironmentMap {
@@ -66,6 +69,8 @@ impl EnvironmentMap {
                     SingleEnergy::ZERO
                 }
             }
+            EnvironmentMap::HosekWilkie(sky) => sky.emission(uv_to_direction(uv), lambda),
+            EnvironmentMap::ImageEnv(image) => image.emission(uv_to_direction(uv), lambda),
         }
     }
 
@@ -106,7 +111,8 @@ impl EnvironmentMap {
                 angular_diameter: _,
                 sun_direction: _,
             } => {
-                let (mut sw, wavelength_pdf) = color.sample_power_and_pdf(wavelength_range, wavelength_sample);
+                let (mut sw, wavelength_pdf) =
+                    color.sample_power_and_pdf(wavelength_range, wavelength_sample);
                 sw.energy *= *strength;
                 let (uv, directional_pdf) =
                     self.sample_env_uv_given_wavelength(direction_sample, sw.lambda);
@@ -118,77 +124,155 @@ impl EnvironmentMap {
                     + direction * world_radius
                     + frame.to_world(&random_on_normal_disk);
 
-                (Ray::new(point, -direction), sw, directional_pdf, wavelength_pdf)
+                (
+                    Ray::new(point, -direction),
+                    sw,
+                    directional_pdf,
+                    wavelength_pdf,
+                )
+            }
+            EnvironmentMap::HosekWilkie(_) | EnvironmentMap::ImageEnv(_) => {
+                // the direction is importance sampled, but the wavelength is uniform
+                let (direction, sw, directional_pdf, wavelength_pdf) = self
+                    .sample_direction_and_wavelength(
+                        direction_sample,
+                        wavelength_range,
+                        wavelength_sample,
+                    );
+
+                let frame = TangentFrame::from_normal(direction);
+                let random_on_normal_disk = world_radius * random_in_unit_disk(position_sample);
+                let point = world_center
+                    + direction * world_radius
+                    + frame.to_world(&random_on_normal_disk);
+
+                (
+                    Ray::new(point, -direction),
+                    sw,
+                    directional_pdf,
+                    wavelength_pdf,
+                )
             }
         }
     }
 
+    // sample a direction towards the env map, proportional to the radiance at lambda.
+    // returns the direction and its solid angle pdf
     pub fn sample_direction_given_wavelength(&self, sample: Sample2D, lambda: f32) -> (Vec3, PDF) {
-        // sample the env map from a specific point in the world.
-        // if self.sample_env_uv_given_wavelength ever works out, use that instead here.
-        let (uv, pdf) = self.sample_env_uv_given_wavelength(sample, lambda);
-        let direction = uv_to_direction(uv);
+        match self {
+            // the spectrum of these doesn't depend on the direction, so the wavelength doesn't matter
+            EnvironmentMap::Constant { .. } | EnvironmentMap::Sun { .. } => {
+                self.sample_direction(sample)
+            }
+            EnvironmentMap::HosekWilkie(sky) => {
+                sky.sample_direction_given_wavelength(sample, lambda)
+            }
+            EnvironmentMap::ImageEnv(image) => {
+                image.sample_direction_given_wavelength(sample, lambda)
+            }
+        }
+    }
 
-        (direction, pdf)
+    // sample a direction towards the env map, proportional to its luminance. returns the direction and its solid angle pdf
+    pub fn sample_direction(&self, sample: Sample2D) -> (Vec3, PDF) {
+        match self {
+            EnvironmentMap::Constant { .. } => {
+                (random_on_unit_sphere(sample), PDF::from(1.0 / (4.0 * PI)))
+            }
+            EnvironmentMap::Sun {
+                angular_diameter,
+                sun_direction,
+                ..
+            } => {
+                // uniform within the cone the sun subtends
+                let cos_max = (*angular_diameter / 2.0).cos();
+                let cos_theta = 1.0 - sample.x * (1.0 - cos_max);
+                let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+                let phi = 2.0 * PI * sample.y;
+                let local_wo = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+                let frame = TangentFrame::from_normal(*sun_direction);
+                let direction = frame.to_world(&local_wo).normalized();
+                (direction, PDF::from(1.0 / (2.0 * PI * (1.0 - cos_max))))
+            }
+            EnvironmentMap::HosekWilkie(sky) => sky.sample_direction(sample),
+            EnvironmentMap::ImageEnv(image) => image.sample_direction(sample),
+        }
+    }
+
+    // solid angle pdf of sampling the direction at uv with sample_env_uv_given_wavelength
+    pub fn pdf_given_wavelength(&self, uv: (f32, f32), lambda: f32) -> PDF {
+        match self {
+            EnvironmentMap::Constant { .. } | EnvironmentMap::Sun { .. } => self.pdf(uv),
+            EnvironmentMap::HosekWilkie(sky) => {
+                sky.pdf_given_wavelength(uv_to_direction(uv), lambda)
+            }
+            EnvironmentMap::ImageEnv(image) => {
+                image.pdf_given_wavelength(uv_to_direction(uv), lambda)
+            }
+        }
+    }
+
+    // solid angle pdf of sampling the direction at uv with sample_env_uv
+    pub fn pdf(&self, uv: (f32, f32)) -> PDF {
+        let direction = uv_to_direction(uv);
+        match self {
+            EnvironmentMap::Constant { .. } => PDF::from(1.0 / (4.0 * PI)),
+            EnvironmentMap::Sun {
+                angular_diameter,
+                sun_direction,
+                ..
+            } => {
+                let cos_max = (*angular_diameter / 2.0).cos();
+                if direction * *sun_direction >= cos_max {
+                    PDF::from(1.0 / (2.0 * PI * (1.0 - cos_max)))
+                } else {
+                    PDF::from(0.0)
+                }
+            }
+            EnvironmentMap::HosekWilkie(sky) => sky.pdf(direction),
+            EnvironmentMap::ImageEnv(image) => image.pdf(direction),
+        }
     }
 
+    // sample a direction towards the env map and a wavelength, for when nothing has chosen a wavelength yet.
+    // returns the direction, the wavelength with the radiance arriving from that direction, the solid angle pdf and the wavelength pdf
     pub fn sample_direction_and_wavelength(
         &self,
-        _sample: Sample2D,
-        _wavelength_range: Bounds1D,
-        _wavelength_sample: Sample1D,
-    ) -> (Vec3, PDF) {
-        unimplemented!()
+        sample: Sample2D,
+        wavelength_range: Bounds1D,
+        wavelength_sample: Sample1D,
+    ) -> (Vec3, SingleWavelength, PDF, PDF) {
+        let (mut sw, wavelength_pdf) = match self {
+            EnvironmentMap::Constant { color, .. } | EnvironmentMap::Sun { color, .. } => {
+                color.sample_power_and_pdf(wavelength_range, wavelength_sample)
+            }
+            _ => (
+                SingleWavelength::new_from_range(wavelength_sample.x, wavelength_range),
+                PDF::from(1.0 / wavelength_range.span()),
+            ),
+        };
+        let (uv, directional_pdf) = self.sample_env_uv_given_wavelength(sample, sw.lambda);
+        sw.energy = self.emission(uv, sw.lambda);
+        (uv_to_direction(uv), sw, directional_pdf, wavelength_pdf)
     }
 
-    // sample env UV given a wavelength, based on env CDF for a specific wavelength. might be hard to evaluate, or nearly impossible.
+    // sample env UV given a wavelength, based on env CDF for a specific wavelength.
     // would be used when sampling the environment from an eye path, such as in PT or BDPT, given a wavelength
     pub fn sample_env_uv_given_wavelength(
         &self,
         sample: Sample2D,
-        _lambda: f32,
+        lambda: f32,
     ) -> ((f32, f32), PDF) {
-        self.sample_env_uv(sample)
-        // match self {
-        //     EnvironmentMap::Constant { color, strength } => self.sample_env_uv(sample),
-        //     EnvironmentMap::Sun {
-        //         color,
-        //         strength,
-        //         angular_diameter,
-        //         sun_direction,
-        //     } => {}
-        // }
-
-        // however because that's unimplemented for now, lets just return `sample_env_uv`
+        let (direction, pdf) = self.sample_direction_given_wavelength(sample, lambda);
+        (direction_to_uv(direction), pdf)
     }
 
     // sample env UV, based on env luminosity CDF (w/o prescribed wavelength)
     pub fn sample_env_uv(&self, sample: Sample2D) -> ((f32, f32), PDF) {
         // samples env CDF to find bright luminosity spikes. returns UV of those spots.
-        // CDF for this situation can be stored as the Y values of the XYZ representation, as a greyscale image potentially.
-        // consider summed area table as well.
-        match self {
-            EnvironmentMap::Constant {
-                color: _color,
-                strength: _strength,
-            } => ((sample.x, sample.y), PDF::from(1.0 / (4.0 * PI))),
-            EnvironmentMap::Sun {
-                color: _color,
-                strength: _strength,
-                 angular_diameter,
-                sun_direction,
-            } => {
-                let local_wo = Vec3::Z + (*angular_diameter / 2.0).sin() * random_in_unit_disk(sample);
-                let sun_direction = *sun_direction;
-                let frame = TangentFrame::from_normal(sun_direction);
-                let direction = frame.to_world(&local_wo);
-                (
-                    direction_to_uv(direction.normalized()),
-                    PDF::from(1.0 / (2.0 * PI * (1.0 - *angular_diameter))),
-                    // 1.0.into()
-                )
-            }
-        }
+        // the sky and image envs use a piecewise constant 2d distribution over luminance, which is equivalent to a summed area table.
+        let (direction, pdf) = self.sample_direction(sample);
+        (direction_to_uv(direction), pdf)
     }
 }
 
@@ -196,6 +280,9 @@ impl EnvironmentMap {
 mod tests {
     use super::*;
     use crate::curves;
+    use crate::renderer::Film;
+    use crate::world::HosekWilkieDataset;
+    use packed_simd::f32x4;
     #[test]
     fn test_sample_emission() {
         let env_map = EnvironmentMap::Constant {
@@ -263,7 +350,7 @@ mod tests {
     }
 
     #[test]
-    fn test_sample_env_map (){
+    fn test_sample_env_map() {
         let env_map = EnvironmentMap::Sun {
             color: curves::blackbody(5500.0, 40.0).into(),
             strength: 1.0,
@@ -273,4 +360,98 @@ mod tests {
 
         env_map.sample_direction_given_wavelength(Sample2D::new_random_sample(), 500.0);
     }
+
+    // integrates a solid angle pdf over the sphere, using a grid in uv space where dw = 2 pi^2 sin(theta) du dv
+    fn integrate_over_sphere<F>(pdf: F) -> f32
+    where
+        F: Fn((f32, f32)) -> f32,
+    {
+        let (width, height) = (512, 256);
+        let mut sum = 0.0;
+        for y in 0..height {
+            let v = (y as f32 + 0.5) / height as f32;
+            for x in 0..width {
+                let u = (x as f32 + 0.5) / width as f32;
+                let direction = uv_to_direction((u, v));
+                let sin_theta = (1.0 - direction.z() * direction.z()).max(0.0).sqrt();
+                sum += pdf((u, v)) * sin_theta;
+            }
+        }
+        sum * 2.0 * PI * PI / (width * height) as f32
+    }
+
+    #[test]
+    fn test_pdfs_integrate_to_one() {
+        // a flat sky with a big sun, since the sun's cone needs to span a good amount of grid cells
+        let mut config = vec![0.0; 2 * 10 * 6 * 9];
+        for (i, value) in config.iter_mut().enumerate() {
+            if i % 9 == 2 {
+                *value = 1.0;
+            }
+        }
+        let dataset = HosekWilkieDataset::new(vec![config; 11], vec![vec![1.0; 2 * 10 * 6]; 11]);
+
+        let mut texture = Film::new(32, 16, f32x4::splat(0.1));
+        texture.write_at(5, 4, f32x4::new(100.0, 0.0, 0.0, 0.0));
+        texture.write_at(20, 10, f32x4::new(0.0, 0.0, 50.0, 0.0));
+
+        let env_maps = vec![
+            EnvironmentMap::Constant {
+                color: curves::blackbody(5500.0, 40.0).into(),
+                strength: 1.0,
+            },
+            EnvironmentMap::Sun {
+                color: curves::blackbody(5500.0, 40.0).into(),
+                strength: 1.0,
+                angular_diameter: 1.0,
+                sun_direction: Vec3::new(1.0, 0.0, 1.0).normalized(),
+            },
+            EnvironmentMap::HosekWilkie(HosekWilkieSky::new(
+                &dataset,
+                Vec3::new(1.0, 0.0, 1.0),
+                3.0,
+                0.2,
+                1.0,
+                1.0,
+                0.0001,
+            )),
+            EnvironmentMap::ImageEnv(ImageEnvironment::new(
+                texture,
+                [
+                    curves::red(1.0).into(),
+                    curves::green(1.0).into(),
+                    curves::blue(1.0).into(),
+                ],
+                1.0,
+                // rotate by a whole number of pixels so that the pixels stay aligned with the integration grid
+                Some(Transform3::from_axis_angle(Vec3::Z, 2.0 * PI * 4.0 / 32.0)),
+            )),
+        ];
+        for env_map in env_maps.iter() {
+            let integral = integrate_over_sphere(|uv| env_map.pdf(uv).0);
+            assert!((integral - 1.0).abs() < 0.02, "{:?} {}", env_map, integral);
+            for lambda in [300.0, 450.0, 550.0, 650.0].iter() {
+                let integral =
+                    integrate_over_sphere(|uv| env_map.pdf_given_wavelength(uv, *lambda).0);
+                assert!(
+                    (integral - 1.0).abs() < 0.02,
+                    "{:?} {} {}",
+                    env_map,
+                    lambda,
+                    integral
+                );
+
+                // and the pdf that comes with a sample should be the same as the one evaluated after the fact
+                let (uv, pdf) =
+                    env_map.sample_env_uv_given_wavelength(Sample2D::new_random_sample(), *lambda);
+                let evaluated = env_map.pdf_given_wavelength(uv, *lambda);
+                assert!(
+                    (pdf.0 - evaluated.0).abs() <= 0.01 * pdf.0,
+                    "{:?} {:?}",
+                    pdf,
+                    evaluated
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,222 @@
+use crate::math::*;
+use crate::renderer::Film;
+use crate::world::distribution::Distribution2D;
+
+use packed_simd::f32x4;
+
+use std::fmt;
+
+// relative luminance of linear rgb with srgb primaries
+fn luminance(rgb: f32x4) -> f32 {
+    (0.2126 * rgb.extract(0) + 0.7152 * rgb.extract(1) + 0.0722 * rgb.extract(2)).max(0.0)
+}
+
+// an equirectangular (lat-long) image of the environment, using the same mapping as uv_to_direction.
+// pixels are linear rgb, and are uplifted to spectra by weighting one basis curve per channel.
+#[derive(Clone)]
+pub struct ImageEnvironment {
+    pub texture: Film<f32x4>,
+    pub curves: [CDF; 3],
+    pub strength: f32,
+    // rotation of the image. world space directions are brought into the image's space with to_local
+    pub transform: Option<Transform3>,
+    // importance sampling distributions over luminance and over each channel
+    distribution: Distribution2D,
+    channel_distributions: [Distribution2D; 3],
+}
+
+impl fmt::Debug for ImageEnvironment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImageEnvironment")
+            .field("width", &self.texture.width)
+            .field("height", &self.texture.height)
+            .field("strength", &self.strength)
+            .finish()
+    }
+}
+
+impl ImageEnvironment {
+    pub fn new(
+        texture: Film<f32x4>,
+        curves: [CDF; 3],
+        strength: f32,
+        transform: Option<Transform3>,
+    ) -> Self {
+        let (width, height) = (texture.width, texture.height);
+        // importance sample by luminance, times the solid angle of each pixel.
+        // this is a summed area table of sorts, with one cdf per row and a marginal cdf over the rows.
+        let mut values = Vec::with_capacity(width * height);
+        let mut channel_values = vec![Vec::with_capacity(width * height); 3];
+        for y in 0..height {
+            let v = (y as f32 + 0.5) / height as f32;
+            for x in 0..width {
+                let u = (x as f32 + 0.5) / width as f32;
+                let direction = uv_to_direction((u, v));
+                let sin_theta = (1.0 - direction.z() * direction.z()).max(0.0).sqrt();
+                let rgb = texture.at(x, y);
+                values.push(luminance(rgb) * sin_theta);
+                for (channel, channel_values) in channel_values.iter_mut().enumerate() {
+                    channel_values.push(rgb.extract(channel).max(0.0) * sin_theta);
+                }
+            }
+        }
+        let distribution = Distribution2D::new(&values, width, height);
+        let channel_distributions = [
+            Distribution2D::new(&channel_values[0], width, height),
+            Distribution2D::new(&channel_values[1], width, height),
+            Distribution2D::new(&channel_values[2], width, height),
+        ];
+        ImageEnvironment {
+            texture,
+            curves,
+            strength,
+            transform,
+            distribution,
+            channel_distributions,
+        }
+    }
+
+    fn to_local(&self, direction: Vec3) -> Vec3 {
+        match &self.transform {
+            Some(transform) => transform.to_local(direction).normalized(),
+            None => direction,
+        }
+    }
+
+    fn to_world(&self, direction: Vec3) -> Vec3 {
+        match &self.transform {
+            Some(transform) => transform.to_world(direction).normalized(),
+            None => direction,
+        }
+    }
+
+    pub fn emission(&self, direction: Vec3, lambda: f32) -> SingleEnergy {
+        let rgb = self
+            .texture
+            .at_uv(direction_to_uv(self.to_local(direction)));
+        let radiance = rgb.extract(0) * self.curves[0].evaluate_power(lambda)
+            + rgb.extract(1) * self.curves[1].evaluate_power(lambda)
+            + rgb.extract(2) * self.curves[2].evaluate_power(lambda);
+        SingleEnergy::new(radiance.max(0.0) * self.strength)
+    }
+
+    // samples a direction proportional to luminance, returning it along with its solid angle pdf
+    pub fn sample_direction(&self, s: Sample2D) -> (Vec3, PDF) {
+        let (uv, _) = self.distribution.sample(s);
+        let direction = self.to_world(uv_to_direction(uv));
+        (direction, self.pdf(direction))
+    }
+
+    // converts a pdf over the image to a solid angle pdf
+    fn image_pdf(distribution: &Distribution2D, local: Vec3) -> f32 {
+        let sin_theta = (1.0 - local.z() * local.z()).max(0.0).sqrt();
+        if sin_theta > 0.0 {
+            distribution.pdf(direction_to_uv(local)).0 / (2.0 * PI * PI * sin_theta)
+        } else {
+            0.0
+        }
+    }
+
+    // solid angle pdf of sample_direction
+    pub fn pdf(&self, direction: Vec3) -> PDF {
+        PDF::from(ImageEnvironment::image_pdf(
+            &self.distribution,
+            self.to_local(direction),
+        ))
+    }
+
+    // the radiance at lambda is a sum of each channel times its basis curve,
+    // so it can be sampled exactly by picking a channel proportional to its power at lambda
+    fn channel_probabilities(&self, lambda: f32) -> Option<[f32; 3]> {
+        let mut probabilities = [0.0; 3];
+        for (channel, probability) in probabilities.iter_mut().enumerate() {
+            *probability = self.curves[channel].evaluate_power(lambda).max(0.0)
+                * self.channel_distributions[channel].integral();
+        }
+        let total: f32 = probabilities.iter().sum();
+        if total > 0.0 {
+            for probability in probabilities.iter_mut() {
+                *probability /= total;
+            }
+            Some(probabilities)
+        } else {
+            None
+        }
+    }
+
+    // samples a direction proportional to the radiance at lambda, returning it along with its solid angle pdf
+    pub fn sample_direction_given_wavelength(&self, s: Sample2D, lambda: f32) -> (Vec3, PDF) {
+        let probabilities = match self.channel_probabilities(lambda) {
+            Some(probabilities) => probabilities,
+            None => return self.sample_direction(s),
+        };
+        let (rescaled, red) = Sample1D { x: s.x }.choose(probabilities[0], true, false);
+        let (rescaled, channel) = if red {
+            (rescaled, 0)
+        } else {
+            let (rescaled, green) =
+                rescaled.choose(probabilities[1] / (1.0 - probabilities[0]), true, false);
+            (rescaled, if green { 1 } else { 2 })
+        };
+        let (uv, _) = self.channel_distributions[channel].sample(Sample2D::new(rescaled.x, s.y));
+        let direction = self.to_world(uv_to_direction(uv));
+        (direction, self.pdf_given_wavelength(direction, lambda))
+    }
+
+    // solid angle pdf of sample_direction_given_wavelength
+    pub fn pdf_given_wavelength(&self, direction: Vec3, lambda: f32) -> PDF {
+        match self.channel_probabilities(lambda) {
+            Some(probabilities) => {
+                let local = self.to_local(direction);
+                let mut pdf = 0.0;
+                for (distribution, probability) in
+                    self.channel_distributions.iter().zip(probabilities.iter())
+                {
+                    pdf += probability * ImageEnvironment::image_pdf(distribution, local);
+                }
+                PDF::from(pdf)
+            }
+            None => self.pdf(direction),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curves;
+    use crate::sampler::IndependentSampler;
+
+    #[test]
+    fn test_image_env_sampling() {
+        // dim everywhere except for one bright pixel
+        let (width, height) = (32, 16);
+        let mut texture = Film::new(width, height, f32x4::splat(0.01));
+        texture.write_at(5, 4, f32x4::new(100.0, 50.0, 10.0, 0.0));
+        let flat: CDF = curves::cie_e(1.0).into();
+        let env = ImageEnvironment::new(
+            texture,
+            [flat.clone(), flat.clone(), flat],
+            1.0,
+            Some(Transform3::from_axis_angle(Vec3::Z, 1.0)),
+        );
+
+        let mut integral = 0.0;
+        let mut bright = 0;
+        let n = 100000;
+        let mut sampler = IndependentSampler::new(0, 0);
+        for _ in 0..n {
+            let (direction, pdf) = env.sample_direction(sampler.draw_2d());
+            assert!((pdf.0 - env.pdf(direction).0).abs() <= 0.001 * pdf.0);
+            integral += 1.0 / pdf.0;
+            if env.emission(direction, 550.0).0 > 1.0 {
+                bright += 1;
+            }
+        }
+        integral /= n as f32;
+        // the estimate of the solid angle of the sphere should be close to 4 pi
+        assert!((integral - 4.0 * PI).abs() < 0.1 * 4.0 * PI, "{}", integral);
+        // and the bright pixel should get most of the samples
+        assert!(bright > n / 2, "{}", bright);
+    }
+}
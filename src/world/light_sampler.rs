@@ -0,0 +1,424 @@
+use crate::aabb::AABB;
+use crate::curves::{y_bar, EXTENDED_VISIBLE_RANGE};
+use crate::geometry::Instance;
+use crate::hittable::Hittable;
+use crate::materials::{Material, MaterialEnum};
+use crate::math::*;
+use crate::world::distribution::{Distribution1D, Distribution2D};
+use crate::world::TransportMode;
+
+use serde::{Deserialize, Serialize};
+
+use std::cmp::Ordering;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LightSamplingStrategy {
+    Uniform,
+    // proportional to the power each light emits
+    Power,
+    // proportional to power and falloff with distance, using a bvh over the lights.
+    // falls back to Power when there's no shading point, i.e. when emitting light paths
+    BVH,
+}
+
+impl Default for LightSamplingStrategy {
+    fn default() -> Self {
+        LightSamplingStrategy::Power
+    }
+}
+
+// rough estimate of the total power emitted by a light with the given surface area, integrated over wavelength, uv and both hemispheres.
+// it's only used to pick between lights, so it doesn't need to be very accurate.
+pub fn estimate_light_power(material: &MaterialEnum, area: f32, wavelength_range: Bounds1D) -> f32 {
+    const WAVELENGTH_STEPS: usize = 32;
+    const DIRECTION_STEPS: usize = 8;
+    // textured emission can vary over the surface, so average over a grid of uvs too
+    const UV_STEPS: usize = 4;
+    let mut sum = 0.0;
+    for i in 0..WAVELENGTH_STEPS {
+        let lambda = wavelength_range.lower
+            + wavelength_range.span() * (i as f32 + 0.5) / WAVELENGTH_STEPS as f32;
+        for j in 0..DIRECTION_STEPS {
+            for k in 0..DIRECTION_STEPS {
+                let wi = random_cosine_direction(Sample2D::new(
+                    (j as f32 + 0.5) / DIRECTION_STEPS as f32,
+                    (k as f32 + 0.5) / DIRECTION_STEPS as f32,
+                ));
+                for u in 0..UV_STEPS {
+                    for v in 0..UV_STEPS {
+                        let uv = (
+                            (u as f32 + 0.5) / UV_STEPS as f32,
+                            (v as f32 + 0.5) / UV_STEPS as f32,
+                        );
+                        for side in [wi, -wi].iter() {
+                            sum += material
+                                .emission(lambda, uv, TransportMode::Importance, *side)
+                                .0;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    // the directions are cosine weighted, so each one estimates the projected solid angle integral divided by pi
+    let samples =
+        (WAVELENGTH_STEPS * DIRECTION_STEPS * DIRECTION_STEPS * UV_STEPS * UV_STEPS) as f32;
+    sum / samples * PI * area * wavelength_range.span()
+}
+
+// resolution of the grid over uv space that textured emitters are importance sampled with
+const EMISSION_SAMPLING_RESOLUTION: usize = 128;
+// fraction of the average that every cell on the surface gets at least,
+// so that emission the grid doesn't see, like outside of the visible range, can still be sampled
+const EMISSION_SAMPLING_FLOOR: f32 = 0.001;
+
+// a distribution over the uvs of a light, proportional to the luminance of its emission times the area each uv covers.
+// returns None when the emission is the same everywhere or when the light's uvs can't be mapped back onto its surface,
+// in which case uniform area sampling is used instead
+pub fn estimate_emission_distribution(
+    material: &MaterialEnum,
+    light: &Instance,
+) -> Option<Distribution2D> {
+    const WAVELENGTH_STEPS: usize = 16;
+    let resolution = EMISSION_SAMPLING_RESOLUTION;
+    let mut values = Vec::with_capacity(resolution * resolution);
+    let (mut min_luminance, mut max_luminance) = (f32::INFINITY, 0.0f32);
+    for row in 0..resolution {
+        for column in 0..resolution {
+            let uv = (
+                (column as f32 + 0.5) / resolution as f32,
+                (row as f32 + 0.5) / resolution as f32,
+            );
+            let density = light.uv_area_density(uv);
+            if density <= 0.0 {
+                values.push(0.0);
+                continue;
+            }
+            let mut luminance = 0.0;
+            for i in 0..WAVELENGTH_STEPS {
+                let lambda = EXTENDED_VISIBLE_RANGE.lower
+                    + EXTENDED_VISIBLE_RANGE.span() * (i as f32 + 0.5) / WAVELENGTH_STEPS as f32;
+                for side in [Vec3::Z, -Vec3::Z].iter() {
+                    luminance += material
+                        .emission(lambda, uv, TransportMode::Importance, *side)
+                        .0
+                        * y_bar(lambda);
+                }
+            }
+            min_luminance = min_luminance.min(luminance);
+            max_luminance = max_luminance.max(luminance);
+            values.push(luminance * density);
+        }
+    }
+    if max_luminance <= 0.0 || max_luminance - min_luminance <= 0.001 * max_luminance {
+        return None;
+    }
+    let covered = values.iter().filter(|v| **v > 0.0).count().max(1);
+    let floor = EMISSION_SAMPLING_FLOOR * values.iter().sum::<f32>() / covered as f32;
+    for (index, value) in values.iter_mut().enumerate() {
+        let uv = (
+            ((index % resolution) as f32 + 0.5) / resolution as f32,
+            ((index / resolution) as f32 + 0.5) / resolution as f32,
+        );
+        if light.uv_area_density(uv) > 0.0 {
+            *value = value.max(floor);
+        }
+    }
+    Some(Distribution2D::new(&values, resolution, resolution))
+}
+
+#[derive(Copy, Clone, Debug)]
+enum LightBVHNodeKind {
+    Interior(usize, usize),
+    Leaf(usize),
+}
+
+#[derive(Copy, Clone, Debug)]
+struct LightBVHNode {
+    aabb: AABB,
+    power: f32,
+    parent: Option<usize>,
+    kind: LightBVHNodeKind,
+}
+
+impl LightBVHNode {
+    // how much light from this node is expected to reach point, ignoring orientation and visibility
+    fn importance(&self, point: Point3) -> f32 {
+        let half_diagonal_squared = self.aabb.size().norm_squared() / 4.0;
+        let distance_squared = (self.aabb.center() - point).norm_squared();
+        // clamp to the size of the node, so that points inside or near it don't blow up
+        self.power
+            / distance_squared
+                .max(half_diagonal_squared)
+                .max(std::f32::EPSILON)
+    }
+}
+
+// binary tree over the lights, where each level is traversed stochastically based on the importance of each child.
+// the probability of picking a light is the product of the probabilities of the choices on the way to its leaf,
+// which can be recomputed exactly by walking back up from the leaf.
+#[derive(Clone, Debug)]
+pub struct LightBVH {
+    nodes: Vec<LightBVHNode>,
+    // the node index of each light's leaf
+    leaves: Vec<usize>,
+}
+
+impl LightBVH {
+    pub fn build(powers: &[f32], bounds: &[AABB]) -> Self {
+        assert!(!powers.is_empty() && powers.len() == bounds.len());
+        let mut bvh = LightBVH {
+            nodes: Vec::with_capacity(2 * powers.len()),
+            leaves: vec![0; powers.len()],
+        };
+        let mut indices: Vec<usize> = (0..powers.len()).collect();
+        bvh.build_recursive(&mut indices, powers, bounds, None);
+        bvh
+    }
+
+    fn build_recursive(
+        &mut self,
+        indices: &mut [usize],
+        powers: &[f32],
+        bounds: &[AABB],
+        parent: Option<usize>,
+    ) -> usize {
+        let node_index = self.nodes.len();
+        let mut aabb = bounds[indices[0]];
+        for index in indices.iter().skip(1) {
+            aabb.expand_mut(&bounds[*index]);
+        }
+        let power = indices.iter().map(|index| powers[*index]).sum();
+        self.nodes.push(LightBVHNode {
+            aabb,
+            power,
+            parent,
+            kind: LightBVHNodeKind::Leaf(indices[0]),
+        });
+        if indices.len() == 1 {
+            self.leaves[indices[0]] = node_index;
+            return node_index;
+        }
+
+        // split at the median along the axis the centers are most spread out on
+        let mut centers = AABB::empty();
+        for index in indices.iter() {
+            centers.grow_mut(&bounds[*index].center());
+        }
+        let spread = centers.size();
+        let axis = if spread.x() >= spread.y() && spread.x() >= spread.z() {
+            0
+        } else if spread.y() >= spread.z() {
+            1
+        } else {
+            2
+        };
+        let key = |index: &usize| {
+            let center = bounds[*index].center() - Point3::ORIGIN;
+            match axis {
+                0 => center.x(),
+                1 => center.y(),
+                _ => center.z(),
+            }
+        };
+        indices.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap_or(Ordering::Equal));
+        let middle = indices.len() / 2;
+        let (left_indices, right_indices) = indices.split_at_mut(middle);
+        let left = self.build_recursive(left_indices, powers, bounds, Some(node_index));
+        let right = self.build_recursive(right_indices, powers, bounds, Some(node_index));
+        self.nodes[node_index].kind = LightBVHNodeKind::Interior(left, right);
+        node_index
+    }
+
+    fn left_probability(&self, left: usize, right: usize, point: Point3) -> f32 {
+        let left_importance = self.nodes[left].importance(point);
+        let right_importance = self.nodes[right].importance(point);
+        let total = left_importance + right_importance;
+        if total > 0.0 && total.is_finite() {
+            left_importance / total
+        } else {
+            0.5
+        }
+    }
+
+    // returns the index of the picked light and the probability of picking it
+    pub fn sample(&self, mut s: Sample1D, point: Point3) -> (usize, f32) {
+        let mut node = 0;
+        let mut pdf = 1.0;
+        loop {
+            match self.nodes[node].kind {
+                LightBVHNodeKind::Leaf(light) => return (light, pdf),
+                LightBVHNodeKind::Interior(left, right) => {
+                    let p = self.left_probability(left, right, point);
+                    let (rescaled, pick_left) = s.choose(p, true, false);
+                    s = rescaled;
+                    if pick_left {
+                        pdf *= p;
+                        node = left;
+                    } else {
+                        pdf *= 1.0 - p;
+                        node = right;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn pdf(&self, light: usize, point: Point3) -> f32 {
+        let mut node = self.leaves[light];
+        let mut pdf = 1.0;
+        while let Some(parent) = self.nodes[node].parent {
+            if let LightBVHNodeKind::Interior(left, right) = self.nodes[parent].kind {
+                let p = self.left_probability(left, right, point);
+                pdf *= if node == left { p } else { 1.0 - p };
+            }
+            node = parent;
+        }
+        pdf
+    }
+}
+
+// picks lights for next event estimation and for emitting light paths.
+// lights are referred to by their index in the world's light list.
+#[derive(Clone, Debug)]
+pub struct LightSampler {
+    pub strategy: LightSamplingStrategy,
+    distribution: Option<Distribution1D>,
+    bvh: Option<LightBVH>,
+}
+
+impl LightSampler {
+    pub fn new(strategy: LightSamplingStrategy, powers: Vec<f32>, bounds: Vec<AABB>) -> Self {
+        if powers.is_empty() || strategy == LightSamplingStrategy::Uniform {
+            return LightSampler {
+                strategy,
+                distribution: None,
+                bvh: None,
+            };
+        }
+        // the power estimate can miss narrow beams or unusual emission entirely,
+        // so every light keeps a small chance of being picked
+        let mean = powers.iter().sum::<f32>() / powers.len() as f32;
+        let floor = if mean > 0.0 && mean.is_finite() {
+            0.01 * mean
+        } else {
+            1.0
+        };
+        let weights: Vec<f32> = powers
+            .iter()
+            .map(|power| {
+                if power.is_finite() {
+                    power.max(floor)
+                } else {
+                    floor
+                }
+            })
+            .collect();
+        let bvh = if strategy == LightSamplingStrategy::BVH {
+            Some(LightBVH::build(&weights, &bounds))
+        } else {
+            None
+        };
+        LightSampler {
+            strategy,
+            distribution: Some(Distribution1D::new(weights)),
+            bvh,
+        }
+    }
+
+    // picks a light without knowing where it will be seen from
+    pub fn sample(&self, s: Sample1D, count: usize) -> (usize, PDF) {
+        match &self.distribution {
+            Some(distribution) => {
+                let (index, pdf) = distribution.sample_discrete(s);
+                (index, PDF::from(pdf))
+            }
+            None => {
+                let index = (count as f32 * s.x).clamp(0.0, count as f32 - 1.0) as usize;
+                (index, PDF::from(1.0 / count as f32))
+            }
+        }
+    }
+
+    pub fn pdf(&self, index: usize, count: usize) -> PDF {
+        match &self.distribution {
+            Some(distribution) => PDF::from(distribution.discrete_pdf(index)),
+            None => PDF::from(1.0 / count as f32),
+        }
+    }
+
+    // picks a light to illuminate point
+    pub fn sample_from(&self, s: Sample1D, count: usize, point: Point3) -> (usize, PDF) {
+        match &self.bvh {
+            Some(bvh) => {
+                let (index, pdf) = bvh.sample(s, point);
+                (index, PDF::from(pdf))
+            }
+            None => self.sample(s, count),
+        }
+    }
+
+    pub fn pdf_from(&self, index: usize, count: usize, point: Point3) -> PDF {
+        match &self.bvh {
+            Some(bvh) => PDF::from(bvh.pdf(index, point)),
+            None => self.pdf(index, count),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box(center: Point3) -> AABB {
+        AABB::new(
+            center - Vec3::new(0.5, 0.5, 0.5),
+            center + Vec3::new(0.5, 0.5, 0.5),
+        )
+    }
+
+    #[test]
+    fn test_light_sampler_pdfs() {
+        // one bright light far away, and a bunch of dim ones in a row
+        let mut powers = vec![1000.0];
+        let mut bounds = vec![unit_box(Point3::new(0.0, 0.0, 100.0))];
+        for i in 0..20 {
+            powers.push(1.0);
+            bounds.push(unit_box(Point3::new(i as f32 * 3.0, 0.0, 0.0)));
+        }
+        let count = powers.len();
+        for strategy in [
+            LightSamplingStrategy::Uniform,
+            LightSamplingStrategy::Power,
+            LightSamplingStrategy::BVH,
+        ]
+        .iter()
+        {
+            let sampler = LightSampler::new(*strategy, powers.clone(), bounds.clone());
+            let point = Point3::new(30.0, 0.0, 1.0);
+            let total: f32 = (0..count)
+                .map(|i| sampler.pdf_from(i, count, point).0)
+                .sum();
+            assert!((total - 1.0).abs() < 0.001, "{:?} {}", strategy, total);
+            let total: f32 = (0..count).map(|i| sampler.pdf(i, count).0).sum();
+            assert!((total - 1.0).abs() < 0.001, "{:?} {}", strategy, total);
+            for _ in 0..1000 {
+                let (index, pdf) = sampler.sample_from(Sample1D::new_random_sample(), count, point);
+                let evaluated = sampler.pdf_from(index, count, point);
+                assert!(
+                    (pdf.0 - evaluated.0).abs() < 0.0001 * pdf.0,
+                    "{:?}",
+                    strategy
+                );
+            }
+        }
+
+        let power = LightSampler::new(LightSamplingStrategy::Power, powers.clone(), bounds.clone());
+        assert!(power.pdf(0, count).0 > 0.9);
+
+        // right next to one of the dim lights, it should be picked over the bright one
+        let bvh = LightSampler::new(LightSamplingStrategy::BVH, powers, bounds);
+        let point = Point3::new(30.0, 0.0, 1.0);
+        assert!(bvh.pdf_from(11, count, point).0 > bvh.pdf_from(0, count, point).0);
+    }
+}
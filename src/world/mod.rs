@@ -1,12 +1,29 @@
+mod distribution;
 mod environment;
+mod image_env;
+mod light_sampler;
+mod portal;
+mod punctual;
+mod sky;
 
+pub use distribution::{Distribution1D, Distribution2D};
 pub use environment::EnvironmentMap;
+pub use image_env::ImageEnvironment;
+pub use light_sampler::{
+    estimate_emission_distribution, estimate_light_power, LightBVH, LightSampler,
+    LightSamplingStrategy,
+};
+pub use portal::Portal;
+pub use punctual::PunctualLight;
+pub use sky::{sun_direction_from_location, HosekWilkieDataset, HosekWilkieSky};
 
 use crate::hittable::*;
 use crate::materials::MaterialTable;
 use crate::math::*;
 use crate::{camera::Camera, mediums::MediumTable};
 
+use std::collections::HashMap;
+
 pub use crate::accelerator::{Accelerator, AcceleratorType};
 pub use crate::geometry::*;
 pub use crate::materials::*;
@@ -26,14 +43,44 @@ impl Default for TransportMode {
     }
 }
 
+// a light picked by the light sampler. punctual lights aren't part of the accelerator, so they're referred to by their index
+#[derive(Copy, Clone, Debug)]
+pub enum LightRef<'a> {
+    Instance(&'a Instance),
+    Punctual(usize, &'a PunctualLight),
+}
+
 #[derive(Clone)]
 pub struct World {
     pub accelerator: Accelerator,
     pub lights: Vec<usize>,
+    // point, spot and directional lights, which come after the instance lights in the light sampler
+    pub punctual_lights: Vec<PunctualLight>,
+    // picks lights proportional to their power, and optionally their distance from the point being lit
+    pub light_sampler: LightSampler,
+    // maps instance ids to their index in lights
+    light_indices: HashMap<usize, usize>,
+    // for lights with textured emission, where on their surface to sample, over uv. indexed the same way as lights
+    emission_distributions: Vec<Option<Distribution2D>>,
+    // names of the groups lights are split into when writing each one's contribution to its own film.
+    // the environment is always the last group
+    pub light_group_names: Vec<String>,
+    // the group of each light, indexed the same way as the light sampler
+    light_groups: Vec<usize>,
     pub cameras: Vec<Camera>,
     pub materials: MaterialTable,
     pub mediums: MediumTable,
+    // the instance each medium is inside of, indexed by medium id - 1. None for mediums that aren't inside of anything
+    medium_instances: Vec<Option<usize>>,
+    // ids of the mediums that can be sampled as light sources
+    pub emissive_mediums: Vec<usize>,
+    // the medium that fills the space that isn't inside of anything else. 0 is vacuum
+    pub world_medium: usize,
     pub environment: EnvironmentMap,
+    // when there are any, environment light is only sampled through these
+    pub portals: Vec<Portal>,
+    // picks portals by area, for emitting environment light
+    portal_distribution: Option<Distribution1D>,
     env_sampling_probability: f32,
     radius: f32,
     center: Point3,
@@ -42,23 +89,32 @@ pub struct World {
 impl World {
     pub fn new(
         instances: Vec<Instance>,
-        materials: MaterialTable,
-        mediums: MediumTable,
+        mut materials: MaterialTable,
+        mut mediums: MediumTable,
+        world_medium: usize,
         environment: EnvironmentMap,
+        punctual_lights: Vec<PunctualLight>,
         mut env_sampling_probability: f32,
+        light_sampling: LightSamplingStrategy,
         accelerator_type: AcceleratorType,
     ) -> Self {
         let mut lights = Vec::new();
+        // the material each light emits with. meshes only get one entry, using the first emissive triangle's material
+        let mut light_materials = Vec::new();
         for instance in instances.iter() {
             match &instance.aggregate {
                 Aggregate::Mesh(mesh) => {
                     for tri in (&mesh).triangles.as_ref().unwrap() {
                         if let MaterialId::Light(id) = tri.get_material_id() {
+                            if lights.last() == Some(&(instance.instance_id as usize)) {
+                                continue;
+                            }
                             println!(
                             "adding light with mat id Light({:?}) and instance id {:?} to lights list",
                             id, instance.instance_id
                         );
                             lights.push(instance.instance_id as usize);
+                            light_materials.push(tri.get_material_id());
                         }
                     }
                 }
@@ -69,10 +125,42 @@ impl World {
                         id, instance.instance_id
                     );
                         lights.push(instance.instance_id as usize);
+                        light_materials.push(instance.get_material_id());
                     }
                 }
             }
         }
+        // mediums fill the bounding box of the first instance that has them on its inside
+        let mut medium_instances = vec![None; mediums.len()];
+        for instance in instances.iter() {
+            let material_id = match (&instance.aggregate, instance.material_id) {
+                (_, Some(material_id)) => material_id,
+                (Aggregate::Mesh(mesh), None) => {
+                    match mesh
+                        .triangles
+                        .as_ref()
+                        .and_then(|triangles| triangles.first())
+                    {
+                        Some(triangle) => triangle.get_material_id(),
+                        None => continue,
+                    }
+                }
+                _ => continue,
+            };
+            let medium_id = materials[usize::from(material_id)].inner_medium_id((0.0, 0.0));
+            if medium_id == 0
+                || medium_id > mediums.len()
+                || medium_instances[medium_id - 1].is_some()
+            {
+                continue;
+            }
+            println!(
+                "medium {} is inside of instance {}",
+                medium_id, instance.instance_id
+            );
+            medium_instances[medium_id - 1] = Some(instance.instance_id);
+            mediums[medium_id - 1].bind(instance.aggregate.aabb(), instance.transform);
+        }
         let accelerator = Accelerator::new(instances, accelerator_type);
 
         let world_aabb = accelerator.aabb();
@@ -83,17 +171,98 @@ impl World {
             "world radius is {:?} meters, world center is at {:?}",
             radius, center
         );
-        if lights.len() == 0 {
+        assert!(
+            world_medium <= mediums.len(),
+            "world medium id {} doesn't refer to a medium",
+            world_medium
+        );
+        if world_medium != 0 && medium_instances[world_medium - 1].is_none() {
+            // the world medium fills everything instead
+            mediums[world_medium - 1].bind(world_aabb, None);
+        }
+        let emissive_mediums: Vec<usize> = mediums
+            .iter()
+            .enumerate()
+            .filter(|(_, medium)| medium.has_sampleable_emission())
+            .map(|(index, _)| index + 1)
+            .collect();
+        // photometric lights spread their intensity over the area of everything that emits with them
+        let mut emitting_areas = vec![0.0; materials.len()];
+        for (instance_id, material_id) in lights.iter().zip(light_materials.iter()) {
+            emitting_areas[usize::from(*material_id)] +=
+                accelerator.get_primitive(*instance_id).light_area();
+        }
+        for (material, area) in materials.iter_mut().zip(emitting_areas) {
+            if let MaterialEnum::PhotometricLight(light) = material {
+                if area > 0.0 {
+                    light.bind(area);
+                }
+            }
+        }
+        let light_count = lights.len() + punctual_lights.len();
+        let mut powers = Vec::with_capacity(light_count);
+        let mut bounds = Vec::with_capacity(light_count);
+        let mut emission_distributions = Vec::with_capacity(lights.len());
+        for (instance_id, material_id) in lights.iter().zip(light_materials.iter()) {
+            let instance = accelerator.get_primitive(*instance_id);
+            let area = instance.light_area();
+            let material = &materials[usize::from(*material_id)];
+            powers.push(estimate_light_power(
+                material,
+                area,
+                crate::curves::EXTENDED_VISIBLE_RANGE,
+            ));
+            bounds.push(instance.aabb());
+            let distribution = estimate_emission_distribution(material, instance);
+            if distribution.is_some() {
+                println!(
+                    "sampling light with instance id {:?} by its emission over uv",
+                    instance_id
+                );
+            }
+            emission_distributions.push(distribution);
+        }
+        for light in punctual_lights.iter() {
+            powers.push(light.power(crate::curves::EXTENDED_VISIBLE_RANGE, radius));
+            bounds.push(light.aabb(world_aabb));
+        }
+        println!(
+            "using {:?} light sampling, estimated light powers are {:?}",
+            light_sampling, powers
+        );
+        let light_sampler = LightSampler::new(light_sampling, powers, bounds);
+        let light_indices = lights
+            .iter()
+            .enumerate()
+            .map(|(index, instance_id)| (*instance_id, index))
+            .collect();
+        // by default, every light is in a group by itself
+        let mut light_group_names: Vec<String> =
+            (0..light_count).map(|i| format!("light_{}", i)).collect();
+        light_group_names.push("environment".to_string());
+        let light_groups = (0..light_count).collect();
+        if light_count == 0 {
             println!("the world had no lights, so force-setting env_sampling_probability to 1.0");
             env_sampling_probability = 1.0;
         }
         let world = World {
             accelerator,
             lights,
+            punctual_lights,
+            light_sampler,
+            light_indices,
+            emission_distributions,
+            light_group_names,
+            light_groups,
             cameras: Vec::new(),
             materials,
             mediums,
+            medium_instances,
+            emissive_mediums,
+            world_medium,
             environment,
+            portals: Vec::new(),
+            portal_distribution: None,
             env_sampling_probability,
             radius,
             center,
@@ -106,26 +275,128 @@ impl World {
         }
         world
     }
-    pub fn pick_random_light(&self, s: Sample1D) -> Option<(&Instance, PDF)> {
-        // currently just uniform sampling
-        let length = self.lights.len();
+    // the number of lights the light sampler picks from, not counting the environment
+    pub fn light_count(&self) -> usize {
+        self.lights.len() + self.punctual_lights.len()
+    }
+
+    // puts lights with the same name into the same group. instance_groups maps instance ids to group names,
+    // and punctual_groups has an entry for each punctual light. lights without a name keep a group to themselves.
+    pub fn with_light_groups(
+        mut self,
+        instance_groups: &HashMap<usize, String>,
+        punctual_groups: &[Option<String>],
+    ) -> Self {
+        let mut names: Vec<String> = Vec::new();
+        let mut groups = Vec::with_capacity(self.light_count());
+        for idx in 0..self.light_count() {
+            let name = if idx < self.lights.len() {
+                instance_groups.get(&self.lights[idx]).cloned()
+            } else {
+                punctual_groups
+                    .get(idx - self.lights.len())
+                    .cloned()
+                    .flatten()
+            }
+            .unwrap_or_else(|| format!("light_{}", idx));
+            let group = match names.iter().position(|e| *e == name) {
+                Some(group) => group,
+                None => {
+                    names.push(name);
+                    names.len() - 1
+                }
+            };
+            groups.push(group);
+        }
+        names.push("environment".to_string());
+        println!("light groups are {:?}", names);
+        self.light_group_names = names;
+        self.light_groups = groups;
+        self
+    }
+
+    // restricts environment sampling to directions through the portals, for scenes that are lit through windows and such
+    pub fn with_portals(mut self, portals: Vec<Portal>) -> Self {
+        if !portals.is_empty() {
+            println!("sampling the environment through {} portals", portals.len());
+            self.portal_distribution = Some(Distribution1D::new(
+                portals.iter().map(|portal| portal.area).collect(),
+            ));
+        }
+        self.portals = portals;
+        self
+    }
+
+    pub fn light_group_count(&self) -> usize {
+        self.light_group_names.len()
+    }
+
+    pub fn environment_light_group(&self) -> usize {
+        self.light_group_names.len() - 1
+    }
+
+    // the group of the light that's the given instance, if it is one
+    pub fn light_group_of_instance(&self, instance_id: usize) -> Option<usize> {
+        self.light_indices
+            .get(&instance_id)
+            .map(|idx| self.light_groups[*idx])
+    }
+
+    pub fn light_group_of(&self, light: LightRef) -> usize {
+        match light {
+            LightRef::Instance(instance) => self
+                .light_group_of_instance(instance.instance_id)
+                .expect("instance light wasn't in the light list"),
+            LightRef::Punctual(idx, _) => self.light_groups[self.lights.len() + idx],
+        }
+    }
+
+    fn light_ref(&self, idx: usize) -> LightRef {
+        if idx < self.lights.len() {
+            LightRef::Instance(self.accelerator.get_primitive(self.lights[idx]))
+        } else {
+            let idx = idx - self.lights.len();
+            LightRef::Punctual(idx, &self.punctual_lights[idx])
+        }
+    }
+
+    // picks a light without regard for where it's seen from, i.e. when starting a light path
+    pub fn pick_random_light(&self, s: Sample1D) -> Option<(LightRef, PDF)> {
+        let length = self.light_count();
         if length == 0 {
             None
         } else {
-            let x = s.x;
-            let idx = (length as f32 * x).clamp(0.0, length as f32 - 1.0) as usize;
-            debug_assert!(
-                idx < self.lights.len(),
-                "{}, {}, {}, {}",
-                x,
-                length as f32 * x,
-                idx,
-                length as usize
-            );
-            Some((
-                self.accelerator.get_primitive(self.lights[idx]),
-                PDF::from(1.0 / length as f32),
-            ))
+            let (idx, pdf) = self.light_sampler.sample(s, length);
+            debug_assert!(idx < length, "{}, {}, {}", s.x, idx, length);
+            Some((self.light_ref(idx), pdf))
+        }
+    }
+
+    // picks a light to illuminate point, for next event estimation
+    pub fn pick_random_light_from(&self, s: Sample1D, point: Point3) -> Option<(LightRef, PDF)> {
+        let length = self.light_count();
+        if length == 0 {
+            None
+        } else {
+            let (idx, pdf) = self.light_sampler.sample_from(s, length, point);
+            debug_assert!(idx < length, "{}, {}, {}", s.x, idx, length);
+            Some((self.light_ref(idx), pdf))
+        }
+    }
+
+    // probability that pick_random_light returns the given instance
+    pub fn light_pick_pdf(&self, instance_id: usize) -> PDF {
+        match self.light_indices.get(&instance_id) {
+            Some(idx) => self.light_sampler.pdf(*idx, self.light_count()),
+            None => PDF::from(0.0),
+        }
+    }
+
+    // probability that pick_random_light_from returns the given instance when called with point
+    pub fn light_pick_pdf_from(&self, instance_id: usize, point: Point3) -> PDF {
+        match self.light_indices.get(&instance_id) {
+            Some(idx) => self.light_sampler.pdf_from(*idx, self.light_count(), point),
+            None => PDF::from(0.0),
         }
     }
 
@@ -149,8 +420,45 @@ impl World {
         }
     }
 
+    // samples a point on a light's surface, by its emission over uv where it's textured and uniformly by area otherwise.
+    // the pdf is with respect to area
+    pub fn sample_light_surface(
+        &self,
+        light: &Instance,
+        s: Sample2D,
+    ) -> (Point3, Vec3, (f32, f32), PDF) {
+        if let Some(distribution) = self.emission_distribution(light.instance_id) {
+            if let Some(sample) = light.sample_surface_with_uv(s, distribution) {
+                return sample;
+            }
+        }
+        light.sample_surface(s)
+    }
+
+    // how much more likely sample_light_surface is to pick the point at uv than uniform area sampling is.
+    // pdfs of hitting a light that assume uniform area sampling are multiplied by this
+    pub fn light_uv_pdf_scale(&self, light: &Instance, uv: (f32, f32)) -> f32 {
+        match self.emission_distribution(light.instance_id) {
+            Some(distribution) => {
+                let density = light.uv_area_density(uv);
+                if density > 0.0 {
+                    distribution.pdf(uv).0 * light.light_area() / density
+                } else {
+                    0.0
+                }
+            }
+            None => 1.0,
+        }
+    }
+
+    fn emission_distribution(&self, instance_id: usize) -> Option<&Distribution2D> {
+        self.light_indices
+            .get(&instance_id)
+            .and_then(|idx| self.emission_distributions[*idx].as_ref())
+    }
+
     pub fn instance_is_light(&self, instance_id: usize) -> bool {
-        self.lights.contains(&instance_id)
+        self.light_indices.contains_key(&instance_id)
     }
 
     pub fn get_material(&self, mat_id: MaterialId) -> &MaterialEnum {
@@ -166,12 +474,55 @@ impl World {
         &self.cameras[index]
     }
 
+    // the medium camera paths start in, which is the world medium unless the camera was put somewhere else
+    pub fn get_camera_medium(&self, index: usize) -> usize {
+        self.cameras[index].medium_id().unwrap_or(self.world_medium)
+    }
+
     pub fn hit(&self, r: Ray, t0: f32, t1: f32) -> Option<HitRecord> {
         self.accelerator.hit(r, t0, t1)
     }
 
+    pub fn pick_random_emissive_medium(&self, s: Sample1D) -> Option<(usize, PDF)> {
+        // uniform, same as pick_random_light
+        let length = self.emissive_mediums.len();
+        if length == 0 {
+            None
+        } else {
+            let idx = (length as f32 * s.x).clamp(0.0, length as f32 - 1.0) as usize;
+            Some((self.emissive_mediums[idx], PDF::from(1.0 / length as f32)))
+        }
+    }
+
+    // whether point is inside of the instance that the medium with id medium_id is inside of.
+    // emission is sampled in the bounding box of that instance, so points outside of the instance itself don't emit.
+    pub fn medium_contains(&self, medium_id: usize, point: Point3) -> bool {
+        let instance_id = match self.medium_instances[medium_id - 1] {
+            Some(instance_id) => instance_id,
+            // the world medium is everywhere
+            None => return true,
+        };
+        // the first hit from inside of a closed surface is on its back side
+        let ray = Ray::new(point, Vec3::Z);
+        match self.get_primitive(instance_id).hit(ray, 0.0, ray.tmax) {
+            Some(hit) => hit.normal * ray.direction > 0.0,
+            None => false,
+        }
+    }
+
+    // for light tracing, the probability of emitting from a medium instead of a light or the environment.
+    // the environment counts as a single light.
+    pub fn get_medium_emission_probability(&self) -> f32 {
+        let count = self.emissive_mediums.len();
+        if count == 0 {
+            0.0
+        } else {
+            count as f32 / (count + self.light_count() + 1) as f32
+        }
+    }
+
     pub fn get_env_sampling_probability(&self) -> f32 {
-        if self.lights.len() > 0 {
+        if self.light_count() > 0 {
             self.env_sampling_probability
         } else {
             1.0
@@ -186,6 +537,156 @@ impl World {
         self.center
     }
 
+    // samples a direction from point towards the environment, proportional to the environment's radiance at lambda,
+    // or through the portals if there are any. returns the direction and its solid angle pdf
+    pub fn sample_environment_from(
+        &self,
+        point: Point3,
+        sample: Sample2D,
+        lambda: f32,
+    ) -> (Vec3, PDF) {
+        if self.portals.is_empty() {
+            return self
+                .environment
+                .sample_direction_given_wavelength(sample, lambda);
+        }
+        // pick a portal based on how much of the view from point it covers, then a point on it uniformly
+        let importances: Vec<f32> = self
+            .portals
+            .iter()
+            .map(|portal| portal.importance(point))
+            .collect();
+        let total: f32 = importances.iter().sum();
+        if !(total > 0.0) {
+            // point isn't inside of any portal
+            return (Vec3::Z, PDF::from(0.0));
+        }
+        let mut x = sample.x * total;
+        let mut picked = 0;
+        for (index, importance) in importances.iter().enumerate() {
+            if *importance > 0.0 {
+                picked = index;
+                if x < *importance {
+                    break;
+                }
+                x -= importance;
+            }
+        }
+        let x = (x / importances[picked]).clamp(0.0, 1.0);
+        let on_portal = self.portals[picked].sample_point(Sample2D::new(x, sample.y));
+        let direction = (on_portal - point).normalized();
+        // portals can overlap when seen from point, so the pdf has to account for all of them
+        (
+            direction,
+            self.environment_pdf_from(point, direction, lambda),
+        )
+    }
+
+    // solid angle pdf of sample_environment_from sampling direction from point
+    pub fn environment_pdf_from(&self, point: Point3, direction: Vec3, lambda: f32) -> PDF {
+        if self.portals.is_empty() {
+            return self
+                .environment
+                .pdf_given_wavelength(direction_to_uv(direction), lambda);
+        }
+        let total: f32 = self
+            .portals
+            .iter()
+            .map(|portal| portal.importance(point))
+            .sum();
+        if !(total > 0.0) {
+            return PDF::from(0.0);
+        }
+        let mut pdf = 0.0;
+        for portal in self.portals.iter() {
+            let cos = -(direction * portal.normal);
+            if cos <= 0.0 {
+                continue;
+            }
+            if let Some(distance) = portal.intersect(point, direction) {
+                let area_pdf = portal.importance(point) / total / portal.area;
+                pdf += area_pdf * distance * distance / cos;
+            }
+        }
+        PDF::from(pdf)
+    }
+
+    // samples a ray of environment light coming into the world, returning it with its wavelength, directional pdf and wavelength pdf.
+    // without portals, rays start on a disk of radius world_radius outside of the world, perpendicular to the direction.
+    // with portals, rays go through a point picked uniformly over the portals instead, and the directional pdf is scaled
+    // so that the position pdf can still be taken as 1 / (pi * world_radius^2), like it is for the disk.
+    pub fn sample_environment_emission(
+        &self,
+        position_sample: Sample2D,
+        direction_sample: Sample2D,
+        wavelength_range: Bounds1D,
+        wavelength_sample: Sample1D,
+    ) -> (Ray, SingleWavelength, PDF, PDF) {
+        let distribution = match &self.portal_distribution {
+            Some(distribution) => distribution,
+            None => {
+                return self.environment.sample_emission(
+                    self.radius,
+                    self.center,
+                    position_sample,
+                    direction_sample,
+                    wavelength_range,
+                    wavelength_sample,
+                )
+            }
+        };
+        let (x, _, picked) = distribution.sample(Sample1D {
+            x: position_sample.x,
+        });
+        let x = x * self.portals.len() as f32 - picked as f32;
+        let portal = &self.portals[picked];
+        let on_portal = portal.sample_point(Sample2D::new(x, position_sample.y));
+
+        let (mut direction, mut sw, _, wavelength_pdf) = self
+            .environment
+            .sample_direction_and_wavelength(direction_sample, wavelength_range, wavelength_sample);
+        if direction * portal.normal > 0.0 {
+            // the light would leave through the portal instead of coming in, so mirror it to come in instead.
+            // that way no samples are wasted, and the directional pdf just has to include both ways of getting each direction
+            direction = direction - portal.normal * (2.0 * (direction * portal.normal));
+            sw.energy = self
+                .environment
+                .emission(direction_to_uv(direction), sw.lambda);
+        }
+        // start outside of the world, so that anything outside of the portal can still block the light
+        let origin = on_portal + direction * 2.0 * self.radius;
+
+        // the density of rays over the plane perpendicular to direction and over directions, which includes every portal the ray crosses
+        let total_area: f32 = self.portals.iter().map(|portal| portal.area).sum();
+        let mut pdf = 0.0;
+        for (index, portal) in self.portals.iter().enumerate() {
+            let cos = -(direction * portal.normal);
+            if cos <= 0.0 {
+                continue;
+            }
+            // the picked portal might be missed due to floating point error
+            if index != picked && portal.intersect(origin, -direction).is_none() {
+                continue;
+            }
+            let mirrored = direction + portal.normal * (2.0 * cos);
+            let directional_pdf = self
+                .environment
+                .pdf_given_wavelength(direction_to_uv(direction), sw.lambda)
+                .0
+                + self
+                    .environment
+                    .pdf_given_wavelength(direction_to_uv(mirrored), sw.lambda)
+                    .0;
+            pdf += directional_pdf / (total_area * cos);
+        }
+        (
+            Ray::new(origin, -direction),
+            sw,
+            PDF::from(pdf * PI * self.radius * self.radius),
+            wavelength_pdf,
+        )
+    }
+
     pub fn assign_cameras(&mut self, cameras: Vec<Camera>, add_and_rebuild_scene: bool) {
         // reconfigures the scene's cameras and rebuilds the scene accelerator if specified
         if add_and_rebuild_scene {
@@ -263,3 +764,207 @@ impl HasBoundingBox for World {
         self.accelerator.aabb()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curves;
+
+    #[test]
+    fn test_light_groups() {
+        let point_light = |x: f32| PunctualLight::Point {
+            position: Point3::new(x, 0.0, 2.0),
+            color: curves::cie_e(1.0).into(),
+            strength: 1.0,
+        };
+        let world = World::new(
+            vec![Instance::new(
+                Aggregate::from(Sphere::new(1.0, Point3::ORIGIN)),
+                None,
+                Some(MaterialId::Material(0)),
+                0,
+            )],
+            Vec::new(),
+            Vec::new(),
+            0,
+            EnvironmentMap::Constant {
+                color: curves::cie_e(1.0).into(),
+                strength: 1.0,
+            },
+            vec![point_light(-1.0), point_light(0.0), point_light(1.0)],
+            0.5,
+            LightSamplingStrategy::Uniform,
+            AcceleratorType::List,
+        );
+        assert_eq!(world.light_group_count(), 4);
+
+        // the first and last light share a group, and the unnamed one gets its own
+        let world = world.with_light_groups(
+            &HashMap::new(),
+            &[Some("key".to_string()), None, Some("key".to_string())],
+        );
+        assert_eq!(
+            world.light_group_names,
+            vec![
+                "key".to_string(),
+                "light_1".to_string(),
+                "environment".to_string()
+            ]
+        );
+        let groups: Vec<usize> = (0..world.light_count())
+            .map(|idx| world.light_group_of(world.light_ref(idx)))
+            .collect();
+        assert_eq!(groups, vec![0, 1, 0]);
+        assert_eq!(world.environment_light_group(), 2);
+        // the sphere isn't a light
+        assert_eq!(world.light_group_of_instance(0), None);
+    }
+
+    #[test]
+    fn test_textured_light_sampling() {
+        use crate::sampler::IndependentSampler;
+        use crate::texture::{TexStack, Texture, Texture1};
+        // a light that's bright on the left half and almost black on the right half
+        let mut texture = crate::renderer::Film::new(2, 1, 1.0);
+        texture.write_at(1, 0, 0.01);
+        let light = DiffuseLight::new(
+            TexStack {
+                textures: vec![Texture::Texture1(Texture1 {
+                    curve: curves::cie_e(1.0).into(),
+                    texture,
+                    interpolation_mode: InterpolationMode::Nearest,
+                })],
+            },
+            Sidedness::Forward,
+        );
+        let world = World::new(
+            vec![Instance::new(
+                Aggregate::from(AARect::new((2.0, 2.0), Point3::ORIGIN, Axis::Z, false)),
+                None,
+                Some(MaterialId::Light(0)),
+                0,
+            )],
+            vec![MaterialEnum::DiffuseLight(light)],
+            Vec::new(),
+            0,
+            EnvironmentMap::Constant {
+                color: curves::cie_e(1.0).into(),
+                strength: 1.0,
+            },
+            Vec::new(),
+            0.0,
+            LightSamplingStrategy::Uniform,
+            AcceleratorType::List,
+        );
+        let instance = world.get_primitive(0);
+        let mut sampler = IndependentSampler::new(0, 0);
+        let n = 1000;
+        let mut bright = 0;
+        for _ in 0..n {
+            let (point, _, uv, pdf) = world.sample_light_surface(instance, sampler.draw_2d());
+            if point.x() < 0.0 {
+                bright += 1;
+            }
+            // the pdf of hitting the light has to agree with the pdf of sampling it
+            let expected = world.light_uv_pdf_scale(instance, uv) / instance.light_area();
+            assert!(
+                (pdf.0 - expected).abs() < 0.001 * expected,
+                "{:?} {}",
+                pdf,
+                expected
+            );
+        }
+        assert!(bright > n * 9 / 10, "{}", bright);
+    }
+
+    #[test]
+    fn test_portal_sampling() {
+        use crate::sampler::IndependentSampler;
+        let world = World::new(
+            vec![Instance::new(
+                Aggregate::from(Sphere::new(1.0, Point3::ORIGIN)),
+                None,
+                Some(MaterialId::Material(0)),
+                0,
+            )],
+            Vec::new(),
+            Vec::new(),
+            0,
+            EnvironmentMap::Constant {
+                color: curves::cie_e(1.0).into(),
+                strength: 1.0,
+            },
+            Vec::new(),
+            1.0,
+            LightSamplingStrategy::Uniform,
+            AcceleratorType::List,
+        )
+        .with_portals(vec![
+            // a window above, and a window off to the side
+            Portal::new(
+                Point3::new(-1.0, -1.0, 2.0),
+                (Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)),
+                -Vec3::Z,
+            ),
+            Portal::new(
+                Point3::new(2.0, -1.0, -1.0),
+                (Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 2.0, 0.0)),
+                -Vec3::X,
+            ),
+        ]);
+        let lambda = 550.0;
+        let point = Point3::ORIGIN;
+        let mut sampler = IndependentSampler::new(0, 0);
+
+        // the pdf has to integrate to 1 over all directions
+        let n = 200000;
+        let mut integral = 0.0;
+        for _ in 0..n {
+            let direction = random_on_unit_sphere(sampler.draw_2d());
+            integral += world.environment_pdf_from(point, direction, lambda).0 * 4.0 * PI;
+        }
+        integral /= n as f32;
+        assert!((integral - 1.0).abs() < 0.05, "{}", integral);
+
+        for _ in 0..1000 {
+            let (direction, pdf) = world.sample_environment_from(point, sampler.draw_2d(), lambda);
+            let evaluated = world.environment_pdf_from(point, direction, lambda);
+            assert!(pdf.0 > 0.0);
+            assert!((pdf.0 - evaluated.0).abs() < 0.001 * pdf.0);
+        }
+        // from the other side of a portal, nothing comes in through it
+        assert_eq!(
+            world
+                .environment_pdf_from(Point3::new(0.0, -0.5, 3.0), -Vec3::Z, lambda)
+                .0,
+            0.0
+        );
+
+        // emitted rays always come in through a portal, and since the environment is uniform,
+        // the directional pdf only depends on the angle they come in at
+        let radius = world.get_world_radius();
+        let total_area = 4.0;
+        for _ in 0..1000 {
+            let (ray, _, pdf, _) = world.sample_environment_emission(
+                sampler.draw_2d(),
+                sampler.draw_2d(),
+                curves::EXTENDED_VISIBLE_RANGE,
+                sampler.draw_1d(),
+            );
+            let portal = world
+                .portals
+                .iter()
+                .find(|portal| portal.intersect(ray.origin, ray.direction).is_some())
+                .expect("emitted ray didn't go through a portal");
+            let cos = ray.direction * portal.normal;
+            assert!(cos > 0.0);
+            let expected = 2.0 / (4.0 * PI) / (total_area * cos) * PI * radius * radius;
+            assert!(
+                (pdf.0 - expected).abs() < 0.001 * expected,
+                "{:?} {}",
+                pdf,
+                expected
+            );
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use crate::math::*;
+
+// a rectangle that all of the environment light reaching the inside of the scene comes through, like a window or a doorway.
+// it's not part of the scene's geometry, it only restricts environment sampling to directions through it.
+// the normal points towards the inside, which is the direction light crosses the portal in.
+#[derive(Copy, Clone, Debug)]
+pub struct Portal {
+    pub corner: Point3,
+    pub edges: (Vec3, Vec3),
+    pub normal: Vec3,
+    pub area: f32,
+}
+
+impl Portal {
+    // the edges should be perpendicular. the normal is perpendicular to both of them, on the same side as inside
+    pub fn new(corner: Point3, edges: (Vec3, Vec3), inside: Vec3) -> Self {
+        let cross = edges.0.cross(edges.1);
+        let area = cross.norm();
+        let mut normal = cross / area;
+        if normal * inside < 0.0 {
+            normal = -normal;
+        }
+        Portal {
+            corner,
+            edges,
+            normal,
+            area,
+        }
+    }
+
+    pub fn center(&self) -> Point3 {
+        self.corner + (self.edges.0 + self.edges.1) / 2.0
+    }
+
+    // uniform over the area of the portal
+    pub fn sample_point(&self, s: Sample2D) -> Point3 {
+        self.corner + self.edges.0 * s.x + self.edges.1 * s.y
+    }
+
+    // distance along the ray from origin in the normalized direction to the portal, if it passes through it
+    pub fn intersect(&self, origin: Point3, direction: Vec3) -> Option<f32> {
+        let denominator = direction * self.normal;
+        if denominator == 0.0 {
+            return None;
+        }
+        let t = ((self.corner - origin) * self.normal) / denominator;
+        if t <= 0.0 || !t.is_finite() {
+            return None;
+        }
+        let local = origin + direction * t - self.corner;
+        let u = (local * self.edges.0) / self.edges.0.norm_squared();
+        let v = (local * self.edges.1) / self.edges.1.norm_squared();
+        if u < 0.0 || u > 1.0 || v < 0.0 || v > 1.0 {
+            None
+        } else {
+            Some(t)
+        }
+    }
+
+    // roughly the solid angle the portal covers as seen from point, or 0 if point isn't on the inside
+    pub fn importance(&self, point: Point3) -> f32 {
+        let to_center = self.center() - point;
+        let distance_squared = to_center.norm_squared();
+        let cos = -(to_center * self.normal) / distance_squared.sqrt();
+        if cos > 0.0 && distance_squared > 0.0 {
+            // clamp to the size of the portal, so that points right next to it don't blow up
+            self.area * cos / distance_squared.max(self.area)
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_portal_intersection() {
+        // a 2x1 window in the x y plane, with the inside towards -z
+        let portal = Portal::new(
+            Point3::new(-1.0, 0.0, 0.0),
+            (Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)),
+            -Vec3::Z,
+        );
+        assert!(portal.normal * Vec3::Z < -0.9999);
+        assert!((portal.area - 2.0).abs() < 0.0001);
+
+        let inside = Point3::new(0.0, 0.5, -1.0);
+        let t = portal.intersect(inside, Vec3::Z).unwrap();
+        assert!((t - 1.0).abs() < 0.0001);
+        assert!(portal.intersect(inside, -Vec3::Z).is_none());
+        assert!(portal
+            .intersect(inside, Vec3::new(0.0, 1.0, 1.0).normalized())
+            .is_none());
+        for _ in 0..100 {
+            let point = portal.sample_point(Sample2D::new_random_sample());
+            let direction = (point - inside).normalized();
+            assert!(portal.intersect(inside, direction).is_some());
+        }
+
+        assert!(portal.importance(inside) > 0.0);
+        assert_eq!(portal.importance(Point3::new(0.0, 0.5, 1.0)), 0.0);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::aabb::AABB;
+use crate::math::*;
+
+// lights without any surface, which rays can't hit and which are only found by sampling them directly.
+// point and spot lights emit intensity (power per solid angle) from a single point,
+// and directional lights emit irradiance (power per area) along a single direction.
+#[derive(Clone, Debug)]
+pub enum PunctualLight {
+    Point {
+        position: Point3,
+        color: CDF,
+        strength: f32,
+    },
+    Spot {
+        position: Point3,
+        // the direction the spot points in
+        direction: Vec3,
+        color: CDF,
+        strength: f32,
+        // cosines of the angles from direction where the light ends, and where it starts falling off
+        cos_cone: f32,
+        cos_falloff_start: f32,
+    },
+    Directional {
+        // the direction the light travels in
+        direction: Vec3,
+        color: CDF,
+        strength: f32,
+    },
+}
+
+impl PunctualLight {
+    fn color_and_strength(&self) -> (&CDF, f32) {
+        match self {
+            PunctualLight::Point {
+                color, strength, ..
+            }
+            | PunctualLight::Spot {
+                color, strength, ..
+            }
+            | PunctualLight::Directional {
+                color, strength, ..
+            } => (color, *strength),
+        }
+    }
+
+    pub fn is_directional(&self) -> bool {
+        matches!(self, PunctualLight::Directional { .. })
+    }
+
+    // fraction of the light's intensity that leaves it in the given direction
+    pub fn falloff(&self, direction: Vec3) -> f32 {
+        match self {
+            PunctualLight::Spot {
+                direction: spot_direction,
+                cos_cone,
+                cos_falloff_start,
+                ..
+            } => {
+                let cos = direction * *spot_direction;
+                if cos < *cos_cone {
+                    0.0
+                } else if cos >= *cos_falloff_start {
+                    1.0
+                } else {
+                    // smoothstep between the edge of the cone and the start of the falloff
+                    let t = (cos - cos_cone) / (cos_falloff_start - cos_cone);
+                    t * t * (3.0 - 2.0 * t)
+                }
+            }
+            _ => 1.0,
+        }
+    }
+
+    // the intensity leaving the light in the given direction, or for directional lights, the irradiance
+    pub fn emission(&self, direction: Vec3, lambda: f32) -> SingleEnergy {
+        let (color, strength) = self.color_and_strength();
+        SingleEnergy::new(color.evaluate_power(lambda) * strength * self.falloff(direction))
+    }
+
+    // samples the light as seen from point, which is deterministic.
+    // returns the point the light comes from, the direction towards it, and the light arriving at point,
+    // which is the intensity over the distance squared for point and spot lights.
+    pub fn sample_li(
+        &self,
+        point: Point3,
+        lambda: f32,
+        world_radius: f32,
+    ) -> (Point3, Vec3, SingleEnergy) {
+        match self {
+            PunctualLight::Point { position, .. } | PunctualLight::Spot { position, .. } => {
+                let to_light = *position - point;
+                let distance_squared = to_light.norm_squared();
+                let direction = to_light.normalized();
+                let emission = if distance_squared > 0.0 {
+                    self.emission(-direction, lambda) / distance_squared
+                } else {
+                    SingleEnergy::ZERO
+                };
+                (*position, direction, emission)
+            }
+            PunctualLight::Directional { direction, .. } => (
+                // anywhere outside the world sphere counts as reaching the light
+                point - *direction * 2.0 * world_radius,
+                -*direction,
+                self.emission(*direction, lambda),
+            ),
+        }
+    }
+
+    // total power emitted over the wavelength range, which is used for picking lights.
+    // directional lights only count the power that reaches the world.
+    pub fn power(&self, wavelength_range: Bounds1D, world_radius: f32) -> f32 {
+        const WAVELENGTH_STEPS: usize = 32;
+        let (color, strength) = self.color_and_strength();
+        let mut sum = 0.0;
+        for i in 0..WAVELENGTH_STEPS {
+            let lambda = wavelength_range.lower
+                + wavelength_range.span() * (i as f32 + 0.5) / WAVELENGTH_STEPS as f32;
+            sum += color.evaluate_power(lambda);
+        }
+        let spectral_power = sum / WAVELENGTH_STEPS as f32 * wavelength_range.span() * strength;
+        match self {
+            PunctualLight::Point { .. } => 4.0 * PI * spectral_power,
+            PunctualLight::Spot {
+                cos_cone,
+                cos_falloff_start,
+                ..
+            } => 2.0 * PI * (1.0 - 0.5 * (cos_cone + cos_falloff_start)) * spectral_power,
+            PunctualLight::Directional { .. } => PI * world_radius * world_radius * spectral_power,
+        }
+    }
+
+    pub fn aabb(&self, world_aabb: AABB) -> AABB {
+        match self {
+            PunctualLight::Point { position, .. } | PunctualLight::Spot { position, .. } => {
+                AABB::new(*position, *position)
+            }
+            PunctualLight::Directional { .. } => world_aabb,
+        }
+    }
+
+    // pdf of the direction of a ray from sample_emission, in solid angle.
+    // for directional lights, it's the pdf of the ray's origin over the area of the disk instead.
+    pub fn emission_pdf(&self, direction: Vec3, world_radius: f32) -> PDF {
+        match self {
+            PunctualLight::Point { .. } => PDF::from(1.0 / (4.0 * PI)),
+            PunctualLight::Spot {
+                direction: spot_direction,
+                cos_cone,
+                ..
+            } => {
+                if direction * *spot_direction >= *cos_cone {
+                    PDF::from(1.0 / (2.0 * PI * (1.0 - cos_cone)))
+                } else {
+                    PDF::from(0.0)
+                }
+            }
+            PunctualLight::Directional { .. } => {
+                PDF::from(1.0 / (PI * world_radius * world_radius))
+            }
+        }
+    }
+
+    // samples a ray leaving the light, along with a wavelength proportional to the light's color.
+    // returns the ray, the wavelength and emission in the ray's direction, the pdf from emission_pdf, and the wavelength pdf.
+    pub fn sample_emission(
+        &self,
+        world_radius: f32,
+        world_center: Point3,
+        position_sample: Sample2D,
+        direction_sample: Sample2D,
+        wavelength_range: Bounds1D,
+        wavelength_sample: Sample1D,
+    ) -> (Ray, SingleWavelength, PDF, PDF) {
+        let (color, strength) = self.color_and_strength();
+        let (mut sw, wavelength_pdf) =
+            color.sample_power_and_pdf(wavelength_range, wavelength_sample);
+        sw.energy *= strength;
+        let ray = match self {
+            PunctualLight::Point { position, .. } => {
+                Ray::new(*position, random_on_unit_sphere(direction_sample))
+            }
+            PunctualLight::Spot {
+                position,
+                direction,
+                cos_cone,
+                ..
+            } => {
+                // uniform within the cone
+                let cos_theta = 1.0 - direction_sample.x * (1.0 - cos_cone);
+                let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+                let phi = 2.0 * PI * direction_sample.y;
+                let local_wo = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+                let frame = TangentFrame::from_normal(*direction);
+                Ray::new(*position, frame.to_world(&local_wo).normalized())
+            }
+            PunctualLight::Directional { direction, .. } => {
+                // rays start on a disk of radius world_radius outside of the world, perpendicular to the direction
+                let frame = TangentFrame::from_normal(*direction);
+                let random_on_normal_disk = world_radius * random_in_unit_disk(position_sample);
+                let point = world_center - *direction * world_radius
+                    + frame.to_world(&random_on_normal_disk);
+                Ray::new(point, *direction)
+            }
+        };
+        sw.energy *= self.falloff(ray.direction);
+        (
+            ray,
+            sw,
+            self.emission_pdf(ray.direction, world_radius),
+            wavelength_pdf,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curves;
+    use crate::sampler::IndependentSampler;
+
+    #[test]
+    fn test_spot_light() {
+        let light = PunctualLight::Spot {
+            position: Point3::new(0.0, 0.0, 1.0),
+            direction: -Vec3::Z,
+            color: curves::cie_e(1.0).into(),
+            strength: 10.0,
+            cos_cone: (0.5f32).cos(),
+            cos_falloff_start: (0.3f32).cos(),
+        };
+        // the falloff integrates to the same thing as the power estimate
+        let mut sampler = IndependentSampler::new(0, 0);
+        let n = 100000;
+        let mut integral = 0.0;
+        for _ in 0..n {
+            let direction = random_on_unit_sphere(sampler.draw_2d());
+            integral += light.falloff(direction) * 4.0 * PI;
+        }
+        integral /= n as f32;
+        let span = curves::EXTENDED_VISIBLE_RANGE.span();
+        let power = light.power(curves::EXTENDED_VISIBLE_RANGE, 1.0) / (10.0 * span);
+        assert!(
+            (integral - power).abs() < 0.05 * power,
+            "{} {}",
+            integral,
+            power
+        );
+
+        for _ in 0..1000 {
+            let (ray, sw, pdf, _) = light.sample_emission(
+                1.0,
+                Point3::ORIGIN,
+                sampler.draw_2d(),
+                sampler.draw_2d(),
+                curves::EXTENDED_VISIBLE_RANGE,
+                sampler.draw_1d(),
+            );
+            assert!(ray.direction * -Vec3::Z >= (0.5f32).cos() - 0.0001);
+            assert!((pdf.0 - light.emission_pdf(ray.direction, 1.0).0).abs() < 0.0001);
+            assert!(sw.energy.0 <= 10.0 + 0.0001);
+        }
+
+        // straight below the light, the light falls off with the distance squared
+        let (_, direction, emission) = light.sample_li(Point3::new(0.0, 0.0, -1.0), 550.0, 1.0);
+        assert!((direction * Vec3::Z - 1.0).abs() < 0.0001);
+        assert!((emission.0 - 10.0 / 4.0).abs() < 0.001, "{:?}", emission);
+    }
+}
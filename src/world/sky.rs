@@ -0,0 +1,547 @@
+use crate::math::*;
+use crate::world::distribution::Distribution2D;
+
+// the spectral Hosek-Wilkie dataset has 11 channels, from 320nm to 720nm in 40nm steps
+const CHANNELS: usize = 11;
+const FIRST_CHANNEL_WAVELENGTH: f32 = 320.0;
+const CHANNEL_SPACING: f32 = 40.0;
+
+// resolution of the grid the sky is importance sampled with
+const SAMPLING_WIDTH: usize = 128;
+const SAMPLING_HEIGHT: usize = 64;
+
+const SUN_TEMPERATURE: f32 = 5778.0;
+
+// coefficients of the spectral Hosek-Wilkie model, one entry per channel.
+// see Hosek and Wilkie 2012, "An Analytic Model for Full Spectral Sky-Dome Radiance".
+// each entry of config is 2 albedos * 10 turbidities * 6 bezier control points * 9 parameters,
+// and each entry of radiance is 2 albedos * 10 turbidities * 6 bezier control points.
+#[derive(Clone, Debug)]
+pub struct HosekWilkieDataset {
+    pub config: Vec<Vec<f32>>,
+    pub radiance: Vec<Vec<f32>>,
+}
+
+impl HosekWilkieDataset {
+    pub fn new(config: Vec<Vec<f32>>, radiance: Vec<Vec<f32>>) -> Self {
+        assert_eq!(config.len(), CHANNELS);
+        assert_eq!(radiance.len(), CHANNELS);
+        for (config, radiance) in config.iter().zip(radiance.iter()) {
+            assert_eq!(config.len(), 2 * 10 * 6 * 9);
+            assert_eq!(radiance.len(), 2 * 10 * 6);
+        }
+        HosekWilkieDataset { config, radiance }
+    }
+}
+
+fn quintic_bezier(control_points: &[f32], stride: usize, x: f32) -> f32 {
+    const BINOMIAL: [f32; 6] = [1.0, 5.0, 10.0, 10.0, 5.0, 1.0];
+    let mut sum = 0.0;
+    for (k, coefficient) in BINOMIAL.iter().enumerate() {
+        sum += coefficient
+            * (1.0 - x).powi(5 - k as i32)
+            * x.powi(k as i32)
+            * control_points[k * stride];
+    }
+    sum
+}
+
+// evaluates one parameter of the model for a solar elevation, interpolating between the tabulated turbidities and albedos.
+// stride is the number of parameters per control point
+fn cook_parameter(
+    dataset: &[f32],
+    stride: usize,
+    parameter: usize,
+    turbidity: f32,
+    albedo: f32,
+    solar_elevation: f32,
+) -> f32 {
+    let x = (solar_elevation.clamp(0.0, PI / 2.0) / (PI / 2.0)).powf(1.0 / 3.0);
+    let turbidity = turbidity.clamp(1.0, 10.0);
+    let low_turbidity = (turbidity as usize).min(10);
+    let remainder = turbidity - low_turbidity as f32;
+    let block = stride * 6;
+    let at = |albedo_index: usize, turbidity_index: usize| {
+        let start = block * (10 * albedo_index + turbidity_index - 1) + parameter;
+        quintic_bezier(&dataset[start..], stride, x)
+    };
+    let mut value = (1.0 - albedo) * (1.0 - remainder) * at(0, low_turbidity)
+        + albedo * (1.0 - remainder) * at(1, low_turbidity);
+    if low_turbidity < 10 {
+        value += (1.0 - albedo) * remainder * at(0, low_turbidity + 1)
+            + albedo * remainder * at(1, low_turbidity + 1);
+    }
+    value
+}
+
+// the extended Perez function from the paper, where gamma is the angle to the sun
+fn hosek_wilkie(config: &[f32; 9], cos_theta: f32, gamma: f32) -> f32 {
+    let cos_gamma = gamma.cos();
+    let exp_m = (config[4] * gamma).exp();
+    let ray_m = cos_gamma * cos_gamma;
+    let mie_m =
+        (1.0 + ray_m) / (1.0 + config[8] * config[8] - 2.0 * config[8] * cos_gamma).powf(1.5);
+    let zenith = cos_theta.sqrt();
+    (1.0 + config[0] * (config[1] / (cos_theta + 0.01)).exp())
+        * (config[2]
+            + config[3] * exp_m
+            + config[5] * ray_m
+            + config[6] * mie_m
+            + config[7] * zenith)
+}
+
+// spectral radiance of a blackbody in W/(m^2 sr nm)
+fn planck(lambda: f32, temperature: f32) -> f32 {
+    let lambda = lambda as f64 * 1e-9;
+    let (h, c, k) = (6.62607015e-34f64, 2.99792458e8f64, 1.380649e-23f64);
+    let radiance = 2.0 * h * c * c
+        / (lambda.powi(5) * ((h * c / (lambda * k * temperature as f64)).exp() - 1.0));
+    // per meter to per nanometer
+    (radiance * 1e-9) as f32
+}
+
+// relative optical air mass, see Kasten and Young 1989, "Revised optical air mass tables and approximation formula"
+pub fn air_mass(cos_zenith: f32) -> f32 {
+    let zenith = cos_zenith.clamp(-1.0, 1.0).acos().to_degrees();
+    if zenith >= 90.0 {
+        return std::f32::INFINITY;
+    }
+    1.0 / (cos_zenith + 0.50572 * (96.07995 - zenith).powf(-1.6364))
+}
+
+// fraction of sunlight that makes it through air_mass atmospheres, from rayleigh scattering and aerosols.
+// the aerosol optical depth comes from turbidity through the angstrom formula, see appendix A of Preetham et al. 1999, "A Practical Analytic Model for Daylight"
+pub fn solar_transmittance(lambda: f32, turbidity: f32, air_mass: f32) -> f32 {
+    let micrometers = lambda / 1000.0;
+    let rayleigh = 0.008735 * micrometers.powf(-4.08);
+    let beta = (0.04608365822 * turbidity - 0.04586025928).max(0.0);
+    let aerosol = beta * micrometers.powf(-1.3);
+    (-air_mass * (rayleigh + aerosol)).exp()
+}
+
+fn day_of_year(year: i32, month: u32, day: u32) -> (u32, u32) {
+    const CUMULATIVE_DAYS: [u32; 12] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let month = month.clamp(1, 12);
+    let leap_day = if leap && month > 2 { 1 } else { 0 };
+    (
+        CUMULATIVE_DAYS[month as usize - 1] + day + leap_day,
+        if leap { 366 } else { 365 },
+    )
+}
+
+// direction to the sun for an observer at latitude and longitude (in degrees, north and east are positive) at a local time in hours,
+// where timezone is the local offset from UTC in hours.
+// uses the NOAA approximations for the equation of time and the solar declination.
+// +x is east, +y is north and +z is up.
+pub fn sun_direction_from_location(
+    latitude: f32,
+    longitude: f32,
+    year: i32,
+    month: u32,
+    day: u32,
+    hour: f32,
+    timezone: f32,
+) -> Vec3 {
+    let (day_of_year, days_in_year) = day_of_year(year, month, day);
+    let utc_hour = hour - timezone;
+    let gamma =
+        2.0 * PI / days_in_year as f32 * (day_of_year as f32 - 1.0 + (utc_hour - 12.0) / 24.0);
+    // in minutes
+    let equation_of_time = 229.18
+        * (0.000075 + 0.001868 * gamma.cos()
+            - 0.032077 * gamma.sin()
+            - 0.014615 * (2.0 * gamma).cos()
+            - 0.040849 * (2.0 * gamma).sin());
+    let declination = 0.006918 - 0.399912 * gamma.cos() + 0.070257 * gamma.sin()
+        - 0.006758 * (2.0 * gamma).cos()
+        + 0.000907 * (2.0 * gamma).sin()
+        - 0.002697 * (3.0 * gamma).cos()
+        + 0.00148 * (3.0 * gamma).sin();
+    let true_solar_time = hour * 60.0 + equation_of_time + 4.0 * longitude - 60.0 * timezone;
+    let hour_angle = (true_solar_time / 4.0 - 180.0).to_radians();
+    let latitude = latitude.to_radians();
+    Vec3::new(
+        -declination.cos() * hour_angle.sin(),
+        latitude.cos() * declination.sin() - latitude.sin() * declination.cos() * hour_angle.cos(),
+        latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos(),
+    )
+    .normalized()
+}
+
+// spectral sky dome from the Hosek-Wilkie model, along with a sun disk whose spectrum is attenuated by the air mass it passes through.
+// the ground (anything below the horizon) is black.
+#[derive(Clone, Debug)]
+pub struct HosekWilkieSky {
+    pub sun_direction: Vec3,
+    pub turbidity: f32,
+    pub albedo: f32,
+    pub angular_diameter: f32,
+    pub strength: f32,
+    pub sun_strength: f32,
+    configs: Vec<[f32; 9]>,
+    radiances: Vec<f32>,
+    air_mass: f32,
+    // importance sampling distributions over the average radiance and over each channel's radiance
+    distribution: Distribution2D,
+    channel_distributions: Vec<Distribution2D>,
+    sun_probability: f32,
+}
+
+impl HosekWilkieSky {
+    pub fn new(
+        dataset: &HosekWilkieDataset,
+        sun_direction: Vec3,
+        turbidity: f32,
+        albedo: f32,
+        angular_diameter: f32,
+        strength: f32,
+        sun_strength: f32,
+    ) -> Self {
+        let sun_direction = sun_direction.normalized();
+        let solar_elevation = sun_direction.z().clamp(-1.0, 1.0).asin().max(0.0);
+        let mut configs = Vec::with_capacity(CHANNELS);
+        let mut radiances = Vec::with_capacity(CHANNELS);
+        for channel in 0..CHANNELS {
+            let mut config = [0.0f32; 9];
+            for (parameter, value) in config.iter_mut().enumerate() {
+                *value = cook_parameter(
+                    &dataset.config[channel],
+                    9,
+                    parameter,
+                    turbidity,
+                    albedo,
+                    solar_elevation,
+                );
+            }
+            configs.push(config);
+            radiances.push(cook_parameter(
+                &dataset.radiance[channel],
+                1,
+                0,
+                turbidity,
+                albedo,
+                solar_elevation,
+            ));
+        }
+        let mut sky = HosekWilkieSky {
+            sun_direction,
+            turbidity,
+            albedo,
+            angular_diameter,
+            strength,
+            sun_strength,
+            configs,
+            radiances,
+            air_mass: air_mass(sun_direction.z()),
+            distribution: Distribution2D::new(&[1.0], 1, 1),
+            channel_distributions: Vec::new(),
+            sun_probability: 0.0,
+        };
+
+        // importance sample the sky by its radiance, times the solid angle of each cell.
+        // once with the average radiance over the visible channels, and once per channel for when the wavelength is known
+        let mut values = Vec::with_capacity(SAMPLING_WIDTH * SAMPLING_HEIGHT);
+        let mut channel_values =
+            vec![Vec::with_capacity(SAMPLING_WIDTH * SAMPLING_HEIGHT); CHANNELS];
+        for row in 0..SAMPLING_HEIGHT {
+            let v = (row as f32 + 0.5) / SAMPLING_HEIGHT as f32;
+            for column in 0..SAMPLING_WIDTH {
+                let u = (column as f32 + 0.5) / SAMPLING_WIDTH as f32;
+                let direction = uv_to_direction((u, v));
+                let sin_theta = (1.0 - direction.z() * direction.z()).max(0.0).sqrt();
+                values.push(sky.average_sky_radiance(direction) * sin_theta);
+                let (cos_theta, gamma) = sky.angles(direction);
+                for (channel, channel_values) in channel_values.iter_mut().enumerate() {
+                    channel_values.push(if cos_theta > 0.0 {
+                        sky.channel_radiance(channel, cos_theta, gamma).max(0.0) * sin_theta
+                    } else {
+                        0.0
+                    });
+                }
+            }
+        }
+        sky.distribution = Distribution2D::new(&values, SAMPLING_WIDTH, SAMPLING_HEIGHT);
+        sky.channel_distributions = channel_values
+            .iter()
+            .map(|values| Distribution2D::new(values, SAMPLING_WIDTH, SAMPLING_HEIGHT))
+            .collect();
+
+        // and pick between the sun and the sky based on how much power each one emits
+        let sky_power = sky.distribution.integral() * 2.0 * PI * PI;
+        let sun_power = if sun_direction.z() > 0.0 {
+            let mut average = 0.0;
+            for channel in 2..CHANNELS {
+                let lambda = FIRST_CHANNEL_WAVELENGTH + CHANNEL_SPACING * channel as f32;
+                average += sky.sun_radiance(lambda);
+            }
+            average / (CHANNELS - 2) as f32 * sky.sun_solid_angle()
+        } else {
+            0.0
+        };
+        sky.sun_probability = if sun_power + sky_power > 0.0 {
+            sun_power / (sun_power + sky_power)
+        } else {
+            0.0
+        };
+        sky
+    }
+
+    fn cos_sun_radius(&self) -> f32 {
+        (self.angular_diameter / 2.0).cos()
+    }
+
+    fn sun_solid_angle(&self) -> f32 {
+        2.0 * PI * (1.0 - self.cos_sun_radius())
+    }
+
+    // cosine of the zenith angle, and the angle to the sun
+    fn angles(&self, direction: Vec3) -> (f32, f32) {
+        (
+            direction.z(),
+            (direction * self.sun_direction).clamp(-1.0, 1.0).acos(),
+        )
+    }
+
+    // the channel below lambda and how far lambda is towards the next one.
+    // wavelengths outside of the dataset, like the red end of the visible range above 720nm, use the nearest channel
+    fn channel_position(lambda: f32) -> (usize, f32) {
+        let x = ((lambda - FIRST_CHANNEL_WAVELENGTH) / CHANNEL_SPACING)
+            .clamp(0.0, (CHANNELS - 1) as f32);
+        let low = (x as usize).min(CHANNELS - 2);
+        (low, x - low as f32)
+    }
+
+    fn channel_radiance(&self, channel: usize, cos_theta: f32, gamma: f32) -> f32 {
+        hosek_wilkie(&self.configs[channel], cos_theta, gamma) * self.radiances[channel]
+    }
+
+    // radiance of the sky dome alone, without strength applied
+    pub fn sky_radiance(&self, direction: Vec3, lambda: f32) -> f32 {
+        let (cos_theta, gamma) = self.angles(direction);
+        if cos_theta <= 0.0 {
+            return 0.0;
+        }
+        let (low, t) = HosekWilkieSky::channel_position(lambda);
+        let radiance = (1.0 - t) * self.channel_radiance(low, cos_theta, gamma)
+            + t * self.channel_radiance(low + 1, cos_theta, gamma);
+        radiance.max(0.0)
+    }
+
+    fn average_sky_radiance(&self, direction: Vec3) -> f32 {
+        let (cos_theta, gamma) = self.angles(direction);
+        if cos_theta <= 0.0 {
+            return 0.0;
+        }
+        let mut sum = 0.0;
+        for channel in 2..CHANNELS {
+            sum += self.channel_radiance(channel, cos_theta, gamma).max(0.0);
+        }
+        sum / (CHANNELS - 2) as f32
+    }
+
+    // radiance of the sun disk, without strength applied
+    pub fn sun_radiance(&self, lambda: f32) -> f32 {
+        if self.sun_direction.z() <= 0.0 {
+            return 0.0;
+        }
+        self.sun_strength
+            * planck(lambda, SUN_TEMPERATURE)
+            * solar_transmittance(lambda, self.turbidity, self.air_mass)
+    }
+
+    pub fn emission(&self, direction: Vec3, lambda: f32) -> SingleEnergy {
+        let mut radiance = self.sky_radiance(direction, lambda);
+        if direction * self.sun_direction >= self.cos_sun_radius() {
+            radiance += self.sun_radiance(lambda);
+        }
+        SingleEnergy::new(radiance * self.strength)
+    }
+
+    // uniform over the cone the sun subtends
+    fn sample_sun(&self, s: Sample2D) -> Vec3 {
+        let cos_theta = 1.0 - s.x * (1.0 - self.cos_sun_radius());
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * s.y;
+        let local = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        TangentFrame::from_normal(self.sun_direction)
+            .to_world(&local)
+            .normalized()
+    }
+
+    fn sun_pdf(&self, direction: Vec3) -> f32 {
+        if direction * self.sun_direction >= self.cos_sun_radius() {
+            1.0 / self.sun_solid_angle()
+        } else {
+            0.0
+        }
+    }
+
+    // converts a pdf over the sampling grid to a solid angle pdf
+    fn grid_pdf(distribution: &Distribution2D, direction: Vec3) -> f32 {
+        let sin_theta = (1.0 - direction.z() * direction.z()).max(0.0).sqrt();
+        if sin_theta > 0.0 {
+            distribution.pdf(direction_to_uv(direction)).0 / (2.0 * PI * PI * sin_theta)
+        } else {
+            0.0
+        }
+    }
+
+    // samples a direction towards the sky, returning it along with its solid angle pdf
+    pub fn sample_direction(&self, s: Sample2D) -> (Vec3, PDF) {
+        let (rescaled, sample_sun) = Sample1D { x: s.x }.choose(self.sun_probability, true, false);
+        let direction = if sample_sun {
+            self.sample_sun(Sample2D::new(rescaled.x, s.y))
+        } else {
+            let (uv, _) = self.distribution.sample(Sample2D::new(rescaled.x, s.y));
+            uv_to_direction(uv)
+        };
+        (direction, self.pdf(direction))
+    }
+
+    // solid angle pdf of sample_direction
+    pub fn pdf(&self, direction: Vec3) -> PDF {
+        PDF::from(
+            self.sun_probability * self.sun_pdf(direction)
+                + (1.0 - self.sun_probability)
+                    * HosekWilkieSky::grid_pdf(&self.distribution, direction),
+        )
+    }
+
+    // probabilities of sampling the sun, the channel below lambda and the channel above lambda,
+    // proportional to how much power each of them contributes at lambda
+    fn spectral_mixture(&self, lambda: f32) -> Option<(f32, usize, f32, f32)> {
+        let sun_power = self.sun_radiance(lambda) * self.sun_solid_angle();
+        let (low, t) = HosekWilkieSky::channel_position(lambda);
+        let grid_solid_angle = 2.0 * PI * PI;
+        let low_power = (1.0 - t) * self.channel_distributions[low].integral() * grid_solid_angle;
+        let high_power = t * self.channel_distributions[low + 1].integral() * grid_solid_angle;
+        let total = sun_power + low_power + high_power;
+        if total > 0.0 && total.is_finite() {
+            Some((
+                sun_power / total,
+                low,
+                low_power / total,
+                high_power / total,
+            ))
+        } else {
+            None
+        }
+    }
+
+    // samples a direction proportional to the radiance at lambda, returning it along with its solid angle pdf
+    pub fn sample_direction_given_wavelength(&self, s: Sample2D, lambda: f32) -> (Vec3, PDF) {
+        let (sun_probability, low, low_probability, _) = match self.spectral_mixture(lambda) {
+            Some(mixture) => mixture,
+            None => return self.sample_direction(s),
+        };
+        let (rescaled, sample_sun) = Sample1D { x: s.x }.choose(sun_probability, true, false);
+        let direction = if sample_sun {
+            self.sample_sun(Sample2D::new(rescaled.x, s.y))
+        } else {
+            let (rescaled, sample_low) =
+                rescaled.choose(low_probability / (1.0 - sun_probability), true, false);
+            let channel = if sample_low { low } else { low + 1 };
+            let (uv, _) =
+                self.channel_distributions[channel].sample(Sample2D::new(rescaled.x, s.y));
+            uv_to_direction(uv)
+        };
+        (direction, self.pdf_given_wavelength(direction, lambda))
+    }
+
+    // solid angle pdf of sample_direction_given_wavelength
+    pub fn pdf_given_wavelength(&self, direction: Vec3, lambda: f32) -> PDF {
+        match self.spectral_mixture(lambda) {
+            Some((sun_probability, low, low_probability, high_probability)) => PDF::from(
+                sun_probability * self.sun_pdf(direction)
+                    + low_probability
+                        * HosekWilkieSky::grid_pdf(&self.channel_distributions[low], direction)
+                    + high_probability
+                        * HosekWilkieSky::grid_pdf(&self.channel_distributions[low + 1], direction),
+            ),
+            None => self.pdf(direction),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::IndependentSampler;
+
+    // a dataset where the sky is the same everywhere, radiance * C with every other parameter 0
+    fn flat_dataset(radiance: f32) -> HosekWilkieDataset {
+        let mut config = vec![0.0; 2 * 10 * 6 * 9];
+        for (i, value) in config.iter_mut().enumerate() {
+            if i % 9 == 2 {
+                *value = 1.0;
+            }
+        }
+        HosekWilkieDataset::new(
+            vec![config; CHANNELS],
+            vec![vec![radiance; 2 * 10 * 6]; CHANNELS],
+        )
+    }
+
+    #[test]
+    fn test_sun_position() {
+        // summer solstice at solar noon, 40 degrees north
+        let direction = sun_direction_from_location(40.0, 0.0, 2020, 6, 20, 12.0, 0.0);
+        let elevation = direction.z().asin().to_degrees();
+        assert!((elevation - 73.4).abs() < 1.0, "{}", elevation);
+        // the sun is due south
+        assert!(
+            direction.y() < 0.0 && direction.x().abs() < 0.05,
+            "{:?}",
+            direction
+        );
+
+        // morning on the equator during the equinox, the sun is in the east
+        let direction = sun_direction_from_location(0.0, 0.0, 2021, 3, 20, 9.0, 0.0);
+        let elevation = direction.z().asin().to_degrees();
+        // 3 hours before noon, a little less because of the equation of time
+        assert!((elevation - 43.1).abs() < 1.0, "{}", elevation);
+        assert!(direction.x() > 0.0);
+    }
+
+    #[test]
+    fn test_air_mass_attenuation() {
+        assert!((air_mass(1.0) - 1.0).abs() < 0.01);
+        // lower suns pass through more atmosphere, and blue is scattered out first
+        let noon = solar_transmittance(450.0, 3.0, air_mass(0.9));
+        let evening = solar_transmittance(450.0, 3.0, air_mass(0.1));
+        assert!(evening < noon);
+        assert!(solar_transmittance(450.0, 3.0, 5.0) < solar_transmittance(650.0, 3.0, 5.0));
+    }
+
+    #[test]
+    fn test_sky_sampling_pdf() {
+        let sky = HosekWilkieSky::new(
+            &flat_dataset(1.0),
+            Vec3::new(1.0, 0.0, 1.0),
+            3.0,
+            0.2,
+            0.0093,
+            1.0,
+            1.0,
+        );
+        assert!((sky.emission(Vec3::Z, 550.0).0 - 1.0).abs() < 0.0001);
+        assert!(sky.emission(-Vec3::Z, 550.0).0 == 0.0);
+        // the dataset ends at 720nm, past that the last channel is used
+        assert!((sky.emission(Vec3::Z, 750.0).0 - 1.0).abs() < 0.0001);
+
+        let mut integral = 0.0;
+        let n = 100000;
+        let mut sampler = IndependentSampler::new(0, 0);
+        for _ in 0..n {
+            let (direction, pdf) = sky.sample_direction(sampler.draw_2d());
+            assert!((pdf.0 - sky.pdf(direction).0).abs() <= 0.001 * pdf.0);
+            if direction.z() > 0.0 {
+                // importance sampled estimate of the solid angle of the upper hemisphere
+                integral += 1.0 / pdf.0;
+            }
+        }
+        integral /= n as f32;
+        assert!((integral - 2.0 * PI).abs() < 0.1 * 2.0 * PI, "{}", integral);
+    }
+}